@@ -0,0 +1,309 @@
+/* The C API of pullauta, see src/capi.rs. Generated with cbindgen, do not edit. */
+
+#ifndef PULLAUTA_H
+#define PULLAUTA_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The result of a call.
+ */
+typedef enum PullautaStatus {
+  PULLAUTA_STATUS_OK = 0,
+  /**
+   * The call failed, see [`pullauta_last_error`].
+   */
+  PULLAUTA_STATUS_ERROR = 1,
+  /**
+   * An argument was null or invalid.
+   */
+  PULLAUTA_STATUS_INVALID_ARGUMENT = 2,
+} PullautaStatus;
+
+/**
+ * The vector layers of a processed tile. Passed as an integer, since C callers can pass any value.
+ */
+typedef enum PullautaLayer {
+  /**
+   * The final contours, including index contours and depressions.
+   */
+  PULLAUTA_LAYER_CONTOURS = 0,
+  /**
+   * Dot knolls and small depressions.
+   */
+  PULLAUTA_LAYER_DOT_KNOLLS = 1,
+  PULLAUTA_LAYER_SMALL_CLIFFS = 2,
+  PULLAUTA_LAYER_BIG_CLIFFS = 3,
+  /**
+   * Streams and ditches, if `detectstreams` is enabled.
+   */
+  PULLAUTA_LAYER_STREAMS = 4,
+  /**
+   * The outlines of the marshes, if `detectmarshes` is enabled.
+   */
+  PULLAUTA_LAYER_MARSHES = 5,
+  /**
+   * The shorelines of lakes and ponds, if `detectwater` is enabled.
+   */
+  PULLAUTA_LAYER_WATER_BODIES = 6,
+  /**
+   * Boulders and boulder clusters, if `detectboulders` is enabled.
+   */
+  PULLAUTA_LAYER_BOULDERS = 7,
+  /**
+   * The outlines of stony ground and boulder fields, if `detectstony` is enabled.
+   */
+  PULLAUTA_LAYER_STONY_GROUND = 8,
+  /**
+   * Pits and small depressions, if `detectpits` is enabled.
+   */
+  PULLAUTA_LAYER_PITS = 9,
+  /**
+   * Earth banks and erosion gullies, if `detectbanks` is enabled.
+   */
+  PULLAUTA_LAYER_BANKS = 10,
+  /**
+   * Paths, with their confidence as the height, if `detectpaths` is enabled.
+   */
+  PULLAUTA_LAYER_PATHS = 11,
+  /**
+   * The footprints of the buildings, if `detectbuildings` is enabled.
+   */
+  PULLAUTA_LAYER_BUILDINGS = 12,
+  /**
+   * Power lines projected on the ground, if `detectpowerlines` is enabled.
+   */
+  PULLAUTA_LAYER_POWER_LINES = 13,
+  /**
+   * The pylons of the power lines, if `detectpowerlines` is enabled.
+   */
+  PULLAUTA_LAYER_PYLONS = 14,
+} PullautaLayer;
+
+/**
+ * A configuration.
+ */
+typedef struct PullautaConfig PullautaConfig;
+
+/**
+ * Vector data, like contours or cliffs.
+ */
+typedef struct PullautaGeometry PullautaGeometry;
+
+/**
+ * A heightmap.
+ */
+typedef struct PullautaHeightMap PullautaHeightMap;
+
+/**
+ * The layers of a processed tile.
+ */
+typedef struct PullautaTile PullautaTile;
+
+/**
+ * The extent of a geometry.
+ */
+typedef struct PullautaBounds {
+  double xmin;
+  double ymin;
+  double xmax;
+  double ymax;
+} PullautaBounds;
+
+/**
+ * A point of a geometry.
+ */
+typedef struct PullautaPoint {
+  /**
+   * The DXF layer of the point.
+   */
+  const char *layer;
+  double x;
+  double y;
+} PullautaPoint;
+
+/**
+ * A polyline of a geometry.
+ */
+typedef struct PullautaPolyline {
+  /**
+   * The DXF layer of the line.
+   */
+  const char *layer;
+  /**
+   * The vertices as `len` pairs of x and y.
+   */
+  const double *coords;
+  size_t len;
+  /**
+   * The height of the line, NaN if it has none.
+   */
+  double height;
+} PullautaPolyline;
+
+/**
+ * The grid of a heightmap. The height of the cell `(x, y)` is `data[x * height + y]`, and its
+ * coordinates are `(xoffset + scale * x, yoffset + scale * y)`.
+ */
+typedef struct PullautaGrid {
+  size_t width;
+  size_t height;
+  double xoffset;
+  double yoffset;
+  double scale;
+  const double *data;
+} PullautaGrid;
+
+/**
+ * A rendered map as RGBA pixels, row by row from the top, with the georeference of its world
+ * file. Released with [`pullauta_image_free`].
+ */
+typedef struct PullautaImage {
+  uint8_t *data;
+  uint32_t width;
+  uint32_t height;
+  /**
+   * The world file parameters: x scale, y skew, x skew, y scale, x and y of the upper left
+   * pixel.
+   */
+  double world_file[6];
+} PullautaImage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The version of the library.
+ */
+const char *pullauta_version(void);
+
+/**
+ * The message of the last failed call on this thread, or null. Valid until the next failing
+ * call on this thread.
+ */
+const char *pullauta_last_error(void);
+
+/**
+ * Creates the default configuration.
+ */
+struct PullautaConfig *pullauta_config_new(void);
+
+/**
+ * Reads a configuration from an .ini file.
+ */
+struct PullautaConfig *pullauta_config_from_file(const char *path);
+
+/**
+ * Sets an option of a configuration, as written in the .ini file. The configuration is left
+ * unchanged if the result is invalid.
+ */
+enum PullautaStatus pullauta_config_set(struct PullautaConfig *config,
+                                        const char *key,
+                                        const char *value);
+
+void pullauta_config_free(struct PullautaConfig *config);
+
+/**
+ * Runs all the stages needed for rendering a map on a .las, .laz, .xyz or .xyz.bin file,
+ * honoring the `vegeonly`, `cliffsonly`, `contoursonly` and `detect*` options.
+ */
+struct PullautaTile *pullauta_tile_process(const struct PullautaConfig *config, const char *path);
+
+/**
+ * Like [`pullauta_tile_process`], with the file contents in a buffer of `len` bytes. The format
+ * is determined from the extension of `file_name`.
+ */
+struct PullautaTile *pullauta_tile_process_buffer(const struct PullautaConfig *config,
+                                                  const char *file_name,
+                                                  const uint8_t *data,
+                                                  size_t len);
+
+/**
+ * A copy of the heightmap of a tile.
+ */
+struct PullautaHeightMap *pullauta_tile_heightmap(const struct PullautaTile *tile);
+
+/**
+ * A copy of a vector layer of a tile, one of [`PullautaLayer`]. Fails if the layer is unknown or
+ * was not generated.
+ */
+struct PullautaGeometry *pullauta_tile_geometry(const struct PullautaTile *tile, uint32_t layer);
+
+/**
+ * Renders the map of a tile, with or without depressions, into `image`.
+ */
+enum PullautaStatus pullauta_tile_render(const struct PullautaConfig *config,
+                                         const struct PullautaTile *tile,
+                                         bool depressions,
+                                         struct PullautaImage *image);
+
+void pullauta_tile_free(struct PullautaTile *tile);
+
+/**
+ * Releases the pixels of a rendered image and clears it.
+ */
+void pullauta_image_free(struct PullautaImage *image);
+
+/**
+ * Gets the grid of a heightmap.
+ */
+enum PullautaStatus pullauta_heightmap_grid(const struct PullautaHeightMap *heightmap,
+                                            struct PullautaGrid *grid);
+
+/**
+ * Generates raw contours with the given interval from a heightmap.
+ */
+struct PullautaGeometry *pullauta_heightmap_contours(const struct PullautaConfig *config,
+                                                     const struct PullautaHeightMap *heightmap,
+                                                     double interval);
+
+void pullauta_heightmap_free(struct PullautaHeightMap *heightmap);
+
+/**
+ * Gets the extent of a geometry.
+ */
+enum PullautaStatus pullauta_geometry_bounds(const struct PullautaGeometry *geometry,
+                                             struct PullautaBounds *bounds);
+
+/**
+ * The number of points in a geometry, 0 if `geometry` is null.
+ */
+size_t pullauta_geometry_point_count(const struct PullautaGeometry *geometry);
+
+/**
+ * Gets the point at `index`.
+ */
+enum PullautaStatus pullauta_geometry_point(const struct PullautaGeometry *geometry,
+                                            size_t index,
+                                            struct PullautaPoint *point);
+
+/**
+ * The number of polylines in a geometry, 0 if `geometry` is null.
+ */
+size_t pullauta_geometry_polyline_count(const struct PullautaGeometry *geometry);
+
+/**
+ * Gets the polyline at `index`.
+ */
+enum PullautaStatus pullauta_geometry_polyline(const struct PullautaGeometry *geometry,
+                                               size_t index,
+                                               struct PullautaPolyline *polyline);
+
+/**
+ * Writes a geometry as a DXF file.
+ */
+enum PullautaStatus pullauta_geometry_write_dxf(const struct PullautaGeometry *geometry,
+                                                const char *path);
+
+void pullauta_geometry_free(struct PullautaGeometry *geometry);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PULLAUTA_H */
@@ -0,0 +1,330 @@
+//! Helpers for features detected as masks on the heightmap grid, like marshes, water bodies and
+//! earth banks.
+use image::{Rgba, RgbaImage};
+
+use crate::contours;
+use crate::geometry::Point2;
+use crate::io::heightmap::HeightMap;
+use crate::vec2d::Vec2D;
+
+/// Smooths a mask by keeping the cells where most of the 3x3 neighbourhood is set.
+pub fn majority_filter(mask: &Vec2D<bool>) -> Vec2D<bool> {
+    let (w, h) = (mask.width(), mask.height());
+    let mut filtered = Vec2D::new(w, h, false);
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let set = (x - 1..=x + 1)
+                .flat_map(|nx| (y - 1..=y + 1).map(move |ny| (nx, ny)))
+                .filter(|&cell| mask[cell])
+                .count();
+            filtered[(x, y)] = set >= 5;
+        }
+    }
+    filtered
+}
+
+/// The cells of the 4-connected areas of a mask.
+pub fn components(mask: &Vec2D<bool>) -> Vec<Vec<(usize, usize)>> {
+    connected(mask, false)
+}
+
+/// The cells of the 8-connected areas of a mask, like the networks of thin lines.
+pub fn components8(mask: &Vec2D<bool>) -> Vec<Vec<(usize, usize)>> {
+    connected(mask, true)
+}
+
+fn connected(mask: &Vec2D<bool>, diagonals: bool) -> Vec<Vec<(usize, usize)>> {
+    let (w, h) = (mask.width(), mask.height());
+    let mut seen = Vec2D::new(w, h, false);
+    let mut stack = Vec::new();
+    let mut areas = Vec::new();
+    for x in 0..w {
+        for y in 0..h {
+            if !mask[(x, y)] || seen[(x, y)] {
+                continue;
+            }
+            let mut area = Vec::new();
+            seen[(x, y)] = true;
+            stack.push((x, y));
+            while let Some((cx, cy)) = stack.pop() {
+                area.push((cx, cy));
+                let diagonal = [
+                    (cx.wrapping_sub(1), cy.wrapping_sub(1)),
+                    (cx + 1, cy.wrapping_sub(1)),
+                    (cx.wrapping_sub(1), cy + 1),
+                    (cx + 1, cy + 1),
+                ];
+                let diagonal = if diagonals { &diagonal[..] } else { &[] };
+                for (nx, ny) in neighbours4(cx, cy)
+                    .into_iter()
+                    .chain(diagonal.iter().copied())
+                {
+                    if nx < w && ny < h && mask[(nx, ny)] && !seen[(nx, ny)] {
+                        seen[(nx, ny)] = true;
+                        stack.push((nx, ny));
+                    }
+                }
+            }
+            areas.push(area);
+        }
+    }
+    areas
+}
+
+/// The 4 neighbours of a cell. The ones outside of the grid wrap around to `usize::MAX`.
+pub fn neighbours4(x: usize, y: usize) -> [(usize, usize); 4] {
+    [
+        (x.wrapping_sub(1), y),
+        (x + 1, y),
+        (x, y.wrapping_sub(1)),
+        (x, y + 1),
+    ]
+}
+
+/// Clears the 4-connected areas of a mask with fewer than `min_cells` cells.
+pub fn remove_small_areas(mask: &mut Vec2D<bool>, min_cells: usize) {
+    for area in components(mask) {
+        if area.len() < min_cells {
+            for cell in area {
+                mask[cell] = false;
+            }
+        }
+    }
+}
+
+/// Traces the lines of a mask of thin, 8-connected lines into chains of cells, starting from the
+/// ends of the lines. A line continuing from a junction starts from the junction cell.
+pub fn trace_lines(mask: &Vec2D<bool>) -> Vec<Vec<(usize, usize)>> {
+    let (w, h) = (mask.width(), mask.height());
+    let neighbours = |(x, y): (usize, usize)| {
+        // the 4 nearest neighbours first, so lines don't cut corners
+        let diagonal = [
+            (x.wrapping_sub(1), y.wrapping_sub(1)),
+            (x + 1, y.wrapping_sub(1)),
+            (x.wrapping_sub(1), y + 1),
+            (x + 1, y + 1),
+        ];
+        neighbours4(x, y)
+            .into_iter()
+            .chain(diagonal)
+            .filter(move |&(nx, ny)| nx < w && ny < h && mask[(nx, ny)])
+    };
+
+    let cells = (0..w)
+        .flat_map(|x| (0..h).map(move |y| (x, y)))
+        .filter(|&cell| mask[cell]);
+    let ends = cells.clone().filter(|&cell| neighbours(cell).count() == 1);
+
+    let mut seen = Vec2D::new(w, h, false);
+    let mut lines = Vec::new();
+    for start in ends.chain(cells) {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut line = vec![start];
+        let mut cell = start;
+        while let Some(next) = neighbours(cell).find(|&n| !seen[n]) {
+            seen[next] = true;
+            line.push(next);
+            cell = next;
+        }
+        // join the line to a junction it ends at
+        if let Some(junction) =
+            neighbours(cell).find(|n| !line[line.len().saturating_sub(3)..].contains(n))
+        {
+            line.push(junction);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Smooths the steps of the grid out of a line, keeping its ends in place.
+pub fn smooth_line(mut line: Vec<Point2>, passes: usize) -> Vec<Point2> {
+    for _ in 0..passes {
+        let previous = line.clone();
+        for i in 1..line.len().saturating_sub(1) {
+            line[i].x = 0.25 * previous[i - 1].x + 0.5 * previous[i].x + 0.25 * previous[i + 1].x;
+            line[i].y = 0.25 * previous[i - 1].y + 0.5 * previous[i].y + 0.25 * previous[i + 1].y;
+        }
+    }
+    line
+}
+
+/// The uphill gradient of the heightmap at a cell by central differences, NaN on the edges of
+/// the grid.
+pub fn gradient(hmap: &HeightMap, x: usize, y: usize) -> (f64, f64) {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    if x == 0 || y == 0 || x + 1 >= w || y + 1 >= h {
+        return (f64::NAN, f64::NAN);
+    }
+    let grid = &hmap.grid;
+    (
+        (grid[(x + 1, y)] - grid[(x - 1, y)]) / (2.0 * hmap.scale),
+        (grid[(x, y + 1)] - grid[(x, y - 1)]) / (2.0 * hmap.scale),
+    )
+}
+
+/// The slope of the heightmap at a cell as rise over run, NaN on the edges of the grid.
+pub fn slope(hmap: &HeightMap, x: usize, y: usize) -> f64 {
+    let (dzdx, dzdy) = gradient(hmap, x, y);
+    (dzdx * dzdx + dzdy * dzdy).sqrt()
+}
+
+/// Rasterizes masks on the heightmap grid into an image of one pixel per meter, with the pixel
+/// centers mapped to the nearest cell. A pixel gets the color of the first mask set at its cell.
+pub fn mask_image(hmap: &HeightMap, masks_with_colors: &[(&Vec2D<bool>, Rgba<u8>)]) -> RgbaImage {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let width = ((w - 1) as f64 * hmap.scale) as u32;
+    let height = ((h - 1) as f64 * hmap.scale) as u32;
+    let mut img = RgbaImage::new(width, height);
+    for (px, py, pixel) in img.enumerate_pixels_mut() {
+        let x = ((px as f64 + 0.5) / hmap.scale).round() as usize;
+        let y = ((height as f64 - py as f64 - 0.5) / hmap.scale).round() as usize;
+        let cell = (x.min(w - 1), y.min(h - 1));
+        if let Some(&(_, color)) = masks_with_colors.iter().find(|(mask, _)| mask[cell]) {
+            *pixel = color;
+        }
+    }
+    img
+}
+
+/// Traces the outlines of an area into closed polylines in world coordinates, the outer
+/// boundary and the boundaries of any holes. The steps of the grid are smoothed out of the lines
+/// with `passes` rounds of smoothing.
+pub fn outlines(hmap: &HeightMap, cells: &[(usize, usize)], passes: usize) -> Vec<Vec<Point2>> {
+    let Some(xmin) = cells.iter().map(|c| c.0).min() else {
+        return Vec::new();
+    };
+    let ymin = cells.iter().map(|c| c.1).min().unwrap();
+    let xmax = cells.iter().map(|c| c.0).max().unwrap();
+    let ymax = cells.iter().map(|c| c.1).max().unwrap();
+
+    // a border of cells outside the area closes all the outlines, and the outlines are the
+    // contour at 0 between the cells outside and inside
+    let mut grid = Vec2D::new(xmax - xmin + 3, ymax - ymin + 3, -0.5);
+    for &(x, y) in cells {
+        grid[(x - xmin + 1, y - ymin + 1)] = 0.5;
+    }
+
+    contours::grid2contours(&grid, 1.0)
+        .into_iter()
+        .map(|ring| {
+            let ring = ring
+                .into_iter()
+                .map(|(x, y)| Point2 {
+                    x: hmap.xoffset + hmap.scale * (x + xmin as f64 - 1.0),
+                    y: hmap.yoffset + hmap.scale * (y + ymin as f64 - 1.0),
+                })
+                .collect();
+            (0..passes).fold(ring, |ring, _| smooth_ring(ring))
+        })
+        .collect()
+}
+
+/// Smooths a closed polyline whose last point repeats the first one.
+fn smooth_ring(mut ring: Vec<Point2>) -> Vec<Point2> {
+    let n = ring.len().saturating_sub(1);
+    if n < 3 {
+        return ring;
+    }
+    let previous = ring.clone();
+    for i in 0..n {
+        let (before, after) = (&previous[(i + n - 1) % n], &previous[(i + 1) % n]);
+        ring[i].x = 0.25 * before.x + 0.5 * previous[i].x + 0.25 * after.x;
+        ring[i].y = 0.25 * before.y + 0.5 * previous[i].y + 0.25 * after.y;
+    }
+    ring[n] = ring[0].clone();
+    ring
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outlines_of_an_area_with_a_hole() {
+        // a 5x5 square with a hole in the middle
+        let mut mask = Vec2D::new(9, 9, false);
+        for x in 2..7 {
+            for y in 2..7 {
+                mask[(x, y)] = (x, y) != (4, 4);
+            }
+        }
+        let areas = components(&mask);
+        assert_eq!(areas.len(), 1);
+        assert_eq!(areas[0].len(), 24);
+
+        let hmap = HeightMap {
+            xoffset: 100.0,
+            yoffset: 200.0,
+            scale: 2.0,
+            grid: Vec2D::new(9, 9, 0.0),
+        };
+        let rings = outlines(&hmap, &areas[0], 1);
+        assert_eq!(rings.len(), 2);
+        for ring in &rings {
+            assert_eq!(ring.first(), ring.last());
+            for p in ring {
+                assert!(
+                    p.x > 102.0 && p.x < 114.0 && p.y > 202.0 && p.y < 214.0,
+                    "{p:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_lines_with_a_junction() {
+        // a T: a line along y = 5 and a branch from (5, 5) up to (5, 9)
+        let mut mask = Vec2D::new(10, 10, false);
+        for x in 1..9 {
+            mask[(x, 5)] = true;
+        }
+        for y in 6..10 {
+            mask[(5, y)] = true;
+        }
+        let lines = trace_lines(&mask);
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        // every cell is in a line, and the branch is joined to the line
+        let cells = lines.iter().map(|line| line.len()).sum::<usize>();
+        assert_eq!(cells, 8 + 4 + 1);
+        assert!(lines.iter().all(|line| line.contains(&(5, 5))));
+    }
+
+    #[test]
+    fn test_mask_image_uses_the_first_mask_set() {
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid: Vec2D::new(5, 5, 0.0),
+        };
+        let mut first = Vec2D::new(5, 5, false);
+        let mut second = Vec2D::new(5, 5, false);
+        first[(0, 0)] = true;
+        second[(0, 0)] = true;
+        second[(4, 4)] = true;
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let img = mask_image(&hmap, &[(&first, red), (&second, blue)]);
+
+        assert_eq!(img.dimensions(), (8, 8));
+        // the image is flipped, so the cell (0, 0) is at the bottom left
+        assert_eq!(*img.get_pixel(0, 7), red);
+        assert_eq!(*img.get_pixel(7, 0), blue);
+        assert_eq!(*img.get_pixel(4, 4), Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_remove_small_areas() {
+        let mut mask = Vec2D::new(10, 10, false);
+        mask[(1, 1)] = true;
+        for x in 5..8 {
+            mask[(x, 5)] = true;
+        }
+        remove_small_areas(&mut mask, 2);
+        assert!(!mask[(1, 1)]);
+        assert!(mask[(6, 5)]);
+    }
+}
@@ -0,0 +1,289 @@
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::cliffs::Cliffs;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Geometry, Point2, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// The directions across a gully, the nearest neighbours and the diagonals.
+const ACROSS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+
+/// Detects earth banks and erosion gullies from the heightmap. They are too low for the cliff
+/// detection but stand out from the ground around them.
+///
+/// An earth bank is a line of cells where the ground is steeper than on both sides of it by at
+/// least `bankslopebreak`, rising `bankminheight` to `bankmaxheight` meters across it. The banks
+/// run with their lower side on the right, the side the ticks are drawn on. Banks next to `cliffs`
+/// are left out.
+///
+/// An erosion gully is a line of cells at least `gullymindepth` meters lower than the ground on
+/// both sides of it, up to 4 meters away. Gullies at least `gullydepth` meters deep on average are
+/// `erosion_gully`, the others `small_erosion_gully`. Banks and gullies shorter than
+/// `bankminlength` meters are dropped.
+pub fn makebanks(
+    config: &Config,
+    hmap: &HeightMap,
+    cliffs: Option<&Cliffs>,
+) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting earth banks and gullies...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the gully depths and the masks
+    job::reserve(
+        config,
+        "makebanks",
+        job::grid_bytes::<f64>(w, h) + 4 * job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let depth = gully_depths(config, hmap);
+    let gullies = Vec2D::from_vec(w, h, depth.as_slice().iter().map(|&d| d > 0.0).collect());
+    let cliffs = cliff_cells(hmap, cliffs)?;
+    job::check_cancelled()?;
+    let banks = banks(config, hmap, &gullies, &cliffs);
+
+    let to_world = |(x, y): (usize, usize)| Point2 {
+        x: hmap.xoffset + hmap.scale * x as f64,
+        y: hmap.yoffset + hmap.scale * y as f64,
+    };
+    let length = |line: &[Point2]| {
+        line.windows(2)
+            .map(|s| ((s[1].x - s[0].x).powi(2) + (s[1].y - s[0].y).powi(2)).sqrt())
+            .sum::<f64>()
+    };
+
+    let mut lines = Polylines::new();
+    for mut cells in areas::trace_lines(&banks) {
+        // the lower side is on the right when the line crosses the gradient from left to right
+        let crossing = cells
+            .windows(2)
+            .map(|s| {
+                let (dx, dy) = (s[1].0 as f64 - s[0].0 as f64, s[1].1 as f64 - s[0].1 as f64);
+                let (gx, gy) = areas::gradient(hmap, s[0].0, s[0].1);
+                dx * gy - dy * gx
+            })
+            .filter(|c| c.is_finite())
+            .sum::<f64>();
+        if crossing < 0.0 {
+            cells.reverse();
+        }
+        let line = cells.into_iter().map(to_world).collect::<Vec<_>>();
+        if length(&line) >= config.bankminlength {
+            lines.push(areas::smooth_line(line, 2), Classification::EarthBank);
+        }
+    }
+    for cells in areas::trace_lines(&gullies) {
+        let mean_depth = cells.iter().map(|&c| depth[c]).sum::<f64>() / cells.len() as f64;
+        let class = if mean_depth >= config.gullydepth {
+            Classification::ErosionGully
+        } else {
+            Classification::SmallErosionGully
+        };
+        let line = cells.into_iter().map(to_world).collect::<Vec<_>>();
+        if length(&line) >= config.bankminlength {
+            lines.push(areas::smooth_line(line, 2), class);
+        }
+    }
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![lines.into()],
+    ))
+}
+
+/// The depth of the gully at each cell, 0 outside of gullies.
+fn gully_depths(config: &Config, hmap: &HeightMap) -> Vec2D<f64> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let grid = &hmap.grid;
+    let mut depth = Vec2D::new(w, h, 0.0);
+    for x in 2..w.saturating_sub(2) {
+        for y in 2..h.saturating_sub(2) {
+            let z = grid[(x, y)];
+            let at = |(dx, dy): (isize, isize), k: isize| {
+                grid[(
+                    (x as isize + k * dx) as usize,
+                    (y as isize + k * dy) as usize,
+                )]
+            };
+            // the deepest section across the cell, with the sides 1 or 2 cells away, where the
+            // cell is the lowest point of the section
+            let deepest = ACROSS
+                .iter()
+                .filter(|&&d| z <= at(d, 1).min(at(d, -1)))
+                .flat_map(|&d| (1..=2).map(move |k| at(d, k).min(at(d, -k)) - z))
+                .filter(|d| d.is_finite())
+                .fold(0.0, f64::max);
+            if deepest >= config.gullymindepth {
+                depth[(x, y)] = deepest;
+            }
+        }
+    }
+    depth
+}
+
+/// Marks the cells the cliffs of the cliff detection run through, if it has been run.
+fn cliff_cells(hmap: &HeightMap, cliffs: Option<&Cliffs>) -> Result<Vec2D<bool>, Box<dyn Error>> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let mut cells = Vec2D::new(w, h, false);
+    for dxf in cliffs.into_iter().flat_map(|c| [&c.small, &c.big]) {
+        let Some(Geometry::Polylines2(lines)) = dxf.geometry().first() else {
+            return Err(anyhow::anyhow!("the cliffs should be polylines").into());
+        };
+        for (line, _) in lines.iter() {
+            for segment in line.windows(2) {
+                let (a, b) = (&segment[0], &segment[1]);
+                let length = ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+                // a step of half a cell doesn't skip any cells
+                let steps = (2.0 * length / hmap.scale).ceil().max(1.0) as usize;
+                for i in 0..=steps {
+                    let t = i as f64 / steps as f64;
+                    let x = ((a.x + t * (b.x - a.x) - hmap.xoffset) / hmap.scale).round();
+                    let y = ((a.y + t * (b.y - a.y) - hmap.yoffset) / hmap.scale).round();
+                    if x >= 0.0 && y >= 0.0 && x < w as f64 && y < h as f64 {
+                        cells[(x as usize, y as usize)] = true;
+                    }
+                }
+            }
+        }
+    }
+    Ok(cells)
+}
+
+/// Marks the earth bank cells, see [`makebanks`]. The sides of the gullies and the cells next to
+/// cliffs are not banks.
+fn banks(
+    config: &Config,
+    hmap: &HeightMap,
+    gullies: &Vec2D<bool>,
+    cliffs: &Vec2D<bool>,
+) -> Vec2D<bool> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let grid = &hmap.grid;
+    let mut banks = Vec2D::new(w, h, false);
+    for x in 3..w.saturating_sub(3) {
+        for y in 3..h.saturating_sub(3) {
+            let (gx, gy) = areas::gradient(hmap, x, y);
+            if !(gx.is_finite() && gy.is_finite()) || (gx == 0.0 && gy == 0.0) {
+                continue;
+            }
+            // the uphill direction snapped to one of the 8 neighbours
+            let angle = gy.atan2(gx) / std::f64::consts::FRAC_PI_4;
+            let (dx, dy) = match angle.round() as i32 {
+                0 => (1, 0),
+                1 => (1, 1),
+                2 => (0, 1),
+                3 => (-1, 1),
+                -1 => (1, -1),
+                -2 => (0, -1),
+                -3 => (-1, -1),
+                _ => (-1, 0),
+            };
+            // the cell `k` steps uphill
+            let cell = |k: isize| {
+                (
+                    (x as isize + k * dx) as usize,
+                    (y as isize + k * dy) as usize,
+                )
+            };
+            let step = hmap.scale * ((dx * dx + dy * dy) as f64).sqrt();
+            // the slope along the direction at the cell `k` steps uphill
+            let slope = |k: isize| (grid[cell(k + 1)] - grid[cell(k - 1)]) / (2.0 * step);
+
+            let s0 = slope(0);
+            let is_steepest = s0 >= slope(1) && s0 > slope(-1);
+            let height = grid[cell(1)] - grid[cell(-1)];
+            let slope_break = s0 - slope(2).max(slope(-2));
+            let next_to = |mask: &Vec2D<bool>| {
+                (x - 1..=x + 1).any(|nx| (y - 1..=y + 1).any(|ny| mask[(nx, ny)]))
+            };
+
+            banks[(x, y)] = is_steepest
+                && height >= config.bankminheight
+                && height <= config.bankmaxheight
+                && slope_break >= config.bankslopebreak
+                && !next_to(gullies)
+                && !next_to(cliffs);
+        }
+    }
+    banks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_bank_and_gully() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // a 1 m step up at x = 41 m along the y-axis, and a gully 1.2 m deep at x = 70 m from
+        // y = 10 m to y = 70 m on the upper terrace
+        let mut grid = Vec2D::new(41, 41, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            *z = if x <= 20 { 100.0 } else { 101.0 };
+            if x == 35 && (5..=35).contains(&y) {
+                *z -= 1.2;
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let dxf = makebanks(&config, &hmap, None).unwrap();
+        let Geometry::Polylines2(lines) = dxf.take_geometry().swap_remove(0) else {
+            panic!("banks should be polylines");
+        };
+        let banks = lines
+            .iter()
+            .filter(|(_, c)| **c == Classification::EarthBank)
+            .collect::<Vec<_>>();
+        assert_eq!(banks.len(), 1, "{lines:?}");
+        let bank = banks[0].0;
+        for p in bank {
+            assert!((p.x - 40.0).abs() < 1.0, "{p:?}");
+        }
+        // the lower side to the west is on the right, so the bank runs south
+        assert!(bank.first().unwrap().y > bank.last().unwrap().y);
+
+        let gullies = lines
+            .iter()
+            .filter(|(_, c)| **c == Classification::SmallErosionGully)
+            .collect::<Vec<_>>();
+        assert_eq!(gullies.len(), 1, "{lines:?}");
+        for p in gullies[0].0 {
+            assert!((p.x - 70.0).abs() < 1.0, "{p:?}");
+        }
+        assert!(
+            !lines
+                .iter()
+                .any(|(_, c)| *c == Classification::ErosionGully)
+        );
+
+        // the bank is left out once the cliff detection has found a cliff there
+        let mut cliffs = Polylines::new();
+        cliffs.push(
+            vec![Point2::new(41.0, 0.0), Point2::new(41.0, 80.0)],
+            Classification::Cliff2,
+        );
+        let cliffs = Cliffs {
+            small: BinaryDxf::new(Bounds::new(0.0, 80.0, 0.0, 80.0), vec![cliffs.into()]),
+            big: BinaryDxf::new(
+                Bounds::new(0.0, 80.0, 0.0, 80.0),
+                vec![Polylines::<Point2, Classification>::new().into()],
+            ),
+        };
+        let dxf = makebanks(&config, &hmap, Some(&cliffs)).unwrap();
+        let Geometry::Polylines2(lines) = dxf.take_geometry().swap_remove(0) else {
+            panic!("banks should be polylines");
+        };
+        assert!(!lines.iter().any(|(_, c)| *c == Classification::EarthBank));
+    }
+}
@@ -4,15 +4,47 @@ use imageproc::filter::median_filter;
 use imageproc::rect::Rect;
 use log::info;
 use rustc_hash::FxHashMap as HashMap;
-use std::{error::Error, path::Path};
+use std::error::Error;
 
-use crate::io::{bytes::FromToBytes, fs::FileSystem, heightmap::HeightMap, xyz::XyzInternalReader};
+use crate::areas;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Polylines};
+use crate::io::{heightmap::HeightMap, xyz::PointCloud};
+use crate::job;
+use crate::vec2d::Vec2D;
 
-pub fn blocks(fs: &impl FileSystem, tmpfolder: &Path) -> Result<(), Box<dyn Error>> {
-    info!("Identifying blocks...");
+/// The size of the cells the building footprints are traced on, in meters.
+const CELL: f64 = 1.0;
+
+/// The shortest edge of a building footprint, in meters. Shorter steps of the traced outline are
+/// straightened out.
+const MIN_EDGE: f64 = 2.0;
 
-    let heightmap_in = tmpfolder.join("xyz2.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(heightmap_in)?)?;
+/// The LAS class of buildings, used when `buildingsclass` is not set.
+const BUILDING_CLASS: u8 = 6;
+
+/// The output of the building detection.
+#[derive(Debug, Clone)]
+pub struct Buildings {
+    /// The buildings, using the grid of the heightmap with 1 pixel per meter.
+    pub image: DynamicImage,
+    /// The footprint polygons of the buildings, drawn instead of `image` if `buildingfootprints`
+    /// is set.
+    pub footprints: BinaryDxf,
+}
+
+/// Detects buildings from the points, as a raster and as footprint polygons.
+///
+/// A point belongs to a building if it is of `buildingsclass` (class 6 by default) or if it is a
+/// single return that is not ground or water and is more than 2 meters above the ground. The
+/// footprints are the areas of at least `buildingminarea` square meters where most of the points
+/// belong to buildings, traced with right angles along the main direction of each building.
+pub fn blocks(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<Buildings, Box<dyn Error>> {
+    info!("Identifying blocks...");
 
     let xstartxyz = hmap.xoffset;
     let ystartxyz = hmap.yoffset;
@@ -33,9 +65,20 @@ pub fn blocks(fs: &impl FileSystem, tmpfolder: &Path) -> Result<(), Box<dyn Erro
     let black = Rgb([0, 0, 0]);
     let white = Rgba([255, 255, 255, 255]);
 
-    let xyz_file_in = tmpfolder.join("xyztemp.xyz.bin");
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?).unwrap();
-    while let Some(chunk) = reader.next_chunk().unwrap() {
+    let building_class = if config.buildings != 0 {
+        config.buildings
+    } else {
+        BUILDING_CLASS
+    };
+    let (gw, gh) = (
+        (xmax as f64 * size / CELL).ceil() as usize + 1,
+        (ymax as f64 * size / CELL).ceil() as usize + 1,
+    );
+    let mut building_hits = Vec2D::new(gw, gh, 0u32);
+    let mut other_hits = Vec2D::new(gw, gh, 0u32);
+
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
         for r in chunk {
             let (x, y, h) = (r.x, r.y, r.z as f64);
             let r3 = r.classification;
@@ -44,12 +87,25 @@ pub fn blocks(fs: &impl FileSystem, tmpfolder: &Path) -> Result<(), Box<dyn Erro
 
             let xx = ((x - xstartxyz) / size).floor() as u64;
             let yy = ((y - ystartxyz) / size).floor() as u64;
-            if r3 != 2
-                && r3 != 9
-                && r4 == 1
-                && r5 == 1
-                && h - *xyz.get(&(xx, yy)).unwrap_or(&0.0) > 2.0
-            {
+            let building = r3 == building_class
+                || (r3 != 2
+                    && r3 != 9
+                    && r4 == 1
+                    && r5 == 1
+                    && h - *xyz.get(&(xx, yy)).unwrap_or(&0.0) > 2.0);
+
+            let cx = ((x - xstartxyz) / CELL).floor();
+            let cy = ((y - ystartxyz) / CELL).floor();
+            if cx >= 0.0 && cy >= 0.0 && (cx as usize) < gw && (cy as usize) < gh {
+                let hits = if building {
+                    &mut building_hits
+                } else {
+                    &mut other_hits
+                };
+                hits[(cx as usize, cy as usize)] += 1;
+            }
+
+            if building {
                 draw_filled_rect_mut(
                     &mut img,
                     Rect::at(
@@ -73,14 +129,6 @@ pub fn blocks(fs: &impl FileSystem, tmpfolder: &Path) -> Result<(), Box<dyn Erro
         }
     }
 
-    img2.write_to(
-        &mut fs
-            .create(tmpfolder.join("blocks2.png"))
-            .expect("error saving png"),
-        image::ImageFormat::Png,
-    )
-    .expect("error saving png");
-
     let mut img = DynamicImage::ImageRgb8(img);
 
     image::imageops::overlay(&mut img, &DynamicImage::ImageRgba8(img2), 0, 0);
@@ -88,13 +136,332 @@ pub fn blocks(fs: &impl FileSystem, tmpfolder: &Path) -> Result<(), Box<dyn Erro
     let filter_size = 2;
     img = image::DynamicImage::ImageRgb8(median_filter(&img.to_rgb8(), filter_size, filter_size));
 
-    img.write_to(
-        &mut fs
-            .create(tmpfolder.join("blocks.png"))
-            .expect("error saving png"),
-        image::ImageFormat::Png,
-    )
-    .expect("error saving png");
+    let mut mask = Vec2D::new(gw, gh, false);
+    for x in 0..gw {
+        for y in 0..gh {
+            mask[(x, y)] = building_hits[(x, y)] > other_hits[(x, y)];
+        }
+    }
+    // the cells without points take the majority of their neighbours with points
+    let mut filled = mask.clone();
+    for x in 1..gw.saturating_sub(1) {
+        for y in 1..gh.saturating_sub(1) {
+            if building_hits[(x, y)] + other_hits[(x, y)] > 0 {
+                continue;
+            }
+            let (mut set, mut seen) = (0, 0);
+            for nx in x - 1..=x + 1 {
+                for ny in y - 1..=y + 1 {
+                    if building_hits[(nx, ny)] + other_hits[(nx, ny)] > 0 {
+                        seen += 1;
+                        set += mask[(nx, ny)] as u32;
+                    }
+                }
+            }
+            filled[(x, y)] = set * 2 > seen;
+        }
+    }
+    let min_cells = (config.buildingminarea / (CELL * CELL)).ceil().max(1.0) as usize;
+    let mut mask = areas::majority_filter(&filled);
+    areas::remove_small_areas(&mut mask, min_cells);
+
+    let mut lines = Polylines::new();
+    for ring in footprints(&mask, Point2::new(xstartxyz, ystartxyz), min_cells) {
+        lines.push(ring, Classification::Building);
+    }
     info!("Done");
-    Ok(())
+    Ok(Buildings {
+        image: img,
+        footprints: BinaryDxf::new(
+            Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+            vec![lines.into()],
+        ),
+    })
+}
+
+/// Traces the areas of a mask of `CELL` sized cells, whose corner is at `origin`, into closed
+/// footprint polygons with right angles. The holes of the areas, like courtyards, are polygons
+/// too. Parts smaller than `min_cells` are left out.
+fn footprints(mask: &Vec2D<bool>, origin: Point2, min_cells: usize) -> Vec<Vec<Point2>> {
+    let (w, h) = (mask.width(), mask.height());
+    let mut labels = Vec2D::new(w, h, 0usize);
+    let areas = areas::components(mask);
+    for (i, area) in areas.iter().enumerate() {
+        for &cell in area {
+            labels[cell] = i + 1;
+        }
+    }
+    let center = |(x, y): (usize, usize)| {
+        Point2::new(
+            origin.x + (x as f64 + 0.5) * CELL,
+            origin.y + (y as f64 + 0.5) * CELL,
+        )
+    };
+
+    let mut rings = Vec::new();
+    for (i, area) in areas.iter().enumerate() {
+        let centers: Vec<Point2> = area.iter().map(|&cell| center(cell)).collect();
+        let angle = main_direction(&centers);
+        let (sin, cos) = angle.sin_cos();
+        let rotate = |p: &Point2| Point2::new(p.x * cos + p.y * sin, -p.x * sin + p.y * cos);
+        let unrotate = |p: Point2| Point2::new(p.x * cos - p.y * sin, p.x * sin + p.y * cos);
+
+        // resample the area on a grid along the building, with a border of cells outside
+        let rotated: Vec<Point2> = centers.iter().map(rotate).collect();
+        let umin = rotated.iter().map(|p| p.x).fold(f64::INFINITY, f64::min) - 2.0 * CELL;
+        let vmin = rotated.iter().map(|p| p.y).fold(f64::INFINITY, f64::min) - 2.0 * CELL;
+        let umax = rotated
+            .iter()
+            .map(|p| p.x)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + 2.0 * CELL;
+        let vmax = rotated
+            .iter()
+            .map(|p| p.y)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + 2.0 * CELL;
+        let (rw, rh) = (
+            ((umax - umin) / CELL).ceil() as usize,
+            ((vmax - vmin) / CELL).ceil() as usize,
+        );
+        let mut grid = Vec2D::new(rw, rh, false);
+        for gx in 0..rw {
+            for gy in 0..rh {
+                let p = unrotate(Point2::new(
+                    umin + (gx as f64 + 0.5) * CELL,
+                    vmin + (gy as f64 + 0.5) * CELL,
+                ));
+                let x = ((p.x - origin.x) / CELL).floor();
+                let y = ((p.y - origin.y) / CELL).floor();
+                grid[(gx, gy)] = x >= 0.0
+                    && y >= 0.0
+                    && (x as usize) < w
+                    && (y as usize) < h
+                    && labels[(x as usize, y as usize)] == i + 1;
+            }
+        }
+        if angle != 0.0 {
+            grid = areas::majority_filter(&grid);
+        }
+
+        let min_edge = (MIN_EDGE / CELL).round() as i64;
+        for part in areas::components(&grid) {
+            if part.len() < min_cells {
+                continue;
+            }
+            let mut part_mask = Vec2D::new(rw, rh, false);
+            for &cell in &part {
+                part_mask[cell] = true;
+            }
+            for ring in trace_rings(&part_mask) {
+                let ring = straighten(ring, min_edge);
+                if ring.len() < 4 || ring_area(&ring).abs() < min_cells as i64 / 2 {
+                    continue;
+                }
+                let mut points: Vec<Point2> = ring
+                    .iter()
+                    .map(|&(x, y)| {
+                        unrotate(Point2::new(umin + x as f64 * CELL, vmin + y as f64 * CELL))
+                    })
+                    .collect();
+                points.push(points[0].clone());
+                rings.push(points);
+            }
+        }
+    }
+    rings
+}
+
+/// The direction of the walls of a building as an angle in radians between 0 and 90 degrees,
+/// chosen so that the rectangle along it around the cell centers is as small as possible.
+fn main_direction(centers: &[Point2]) -> f64 {
+    let mut best = (f64::INFINITY, 0.0);
+    for degrees in 0..90 {
+        let angle = (degrees as f64).to_radians();
+        let (sin, cos) = angle.sin_cos();
+        let (mut umin, mut umax, mut vmin, mut vmax) = (
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        );
+        for p in centers {
+            let (u, v) = (p.x * cos + p.y * sin, -p.x * sin + p.y * cos);
+            umin = umin.min(u);
+            umax = umax.max(u);
+            vmin = vmin.min(v);
+            vmax = vmax.max(v);
+        }
+        let area = (umax - umin + CELL) * (vmax - vmin + CELL);
+        if area < best.0 {
+            best = (area, angle);
+        }
+    }
+    best.1
+}
+
+/// Traces the outlines of the cells of a mask into rings of cell corners, with the inside on the
+/// left. The outer rings go counterclockwise and the holes clockwise. The cells of the mask on
+/// the border of the grid are not traced.
+fn trace_rings(mask: &Vec2D<bool>) -> Vec<Vec<(i64, i64)>> {
+    let (w, h) = (mask.width(), mask.height());
+    let set = |x: i64, y: i64| {
+        x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h && mask[(x as usize, y as usize)]
+    };
+
+    // the edges between the cells in and out of the mask, by their start corner
+    let mut edges: HashMap<(i64, i64), Vec<(i64, i64)>> = HashMap::default();
+    for x in 0..w as i64 {
+        for y in 0..h as i64 {
+            if !set(x, y) {
+                continue;
+            }
+            let sides = [
+                (!set(x, y - 1), (x, y), (x + 1, y)),
+                (!set(x + 1, y), (x + 1, y), (x + 1, y + 1)),
+                (!set(x, y + 1), (x + 1, y + 1), (x, y + 1)),
+                (!set(x - 1, y), (x, y + 1), (x, y)),
+            ];
+            for (outside, start, end) in sides {
+                if outside {
+                    edges.entry(start).or_default().push(end);
+                }
+            }
+        }
+    }
+
+    let mut starts: Vec<(i64, i64)> = edges.keys().copied().collect();
+    starts.sort_unstable();
+    let mut rings = Vec::new();
+    for start in starts {
+        while let Some(first) = edges.get_mut(&start).and_then(|ends| ends.pop()) {
+            let mut ring = vec![start];
+            let (mut from, mut to) = (start, first);
+            while to != start {
+                ring.push(to);
+                let ends = edges.get_mut(&to).unwrap();
+                // where two cells touch at a corner, turn left to stay on the same cell
+                let left = (from.1 - to.1, to.0 - from.0);
+                let i = ends
+                    .iter()
+                    .position(|&end| (end.0 - to.0, end.1 - to.1) == left)
+                    .unwrap_or(0);
+                let next = ends.swap_remove(i);
+                (from, to) = (to, next);
+            }
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+/// Straightens a ring of right angles by removing the edges shorter than `min_edge` while the
+/// ring has more than 4 corners. The shorter one of the edges next to a short edge is moved onto
+/// the line of the longer one.
+fn straighten(mut ring: Vec<(i64, i64)>, min_edge: i64) -> Vec<(i64, i64)> {
+    let length = |a: (i64, i64), b: (i64, i64)| (a.0 - b.0).abs() + (a.1 - b.1).abs();
+    ring = remove_collinear(ring);
+    while ring.len() > 4 {
+        let n = ring.len();
+        let Some((i, shortest)) = (0..n)
+            .map(|i| (i, length(ring[i], ring[(i + 1) % n])))
+            .min_by_key(|&(_, length)| length)
+        else {
+            break;
+        };
+        if shortest >= min_edge {
+            break;
+        }
+        let (prev, a, b, next) = ((i + n - 1) % n, i, (i + 1) % n, (i + 2) % n);
+        let horizontal = ring[a].1 == ring[b].1;
+        if length(ring[prev], ring[a]) >= length(ring[b], ring[next]) {
+            // move the next edge onto the line of the previous one
+            for j in [b, next] {
+                if horizontal {
+                    ring[j].0 = ring[a].0;
+                } else {
+                    ring[j].1 = ring[a].1;
+                }
+            }
+        } else {
+            for j in [prev, a] {
+                if horizontal {
+                    ring[j].0 = ring[b].0;
+                } else {
+                    ring[j].1 = ring[b].1;
+                }
+            }
+        }
+        ring = remove_collinear(ring);
+    }
+    ring
+}
+
+/// Removes the repeated corners of a ring and the corners in the middle of straight lines,
+/// including the tips of spikes that double back on themselves.
+fn remove_collinear(mut ring: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    loop {
+        let n = ring.len();
+        if n < 3 {
+            return ring;
+        }
+        let Some(i) = (0..n).find(|&i| {
+            let (a, b, c) = (ring[(i + n - 1) % n], ring[i], ring[(i + 1) % n]);
+            (b.0 - a.0) * (c.1 - b.1) - (b.1 - a.1) * (c.0 - b.0) == 0
+        }) else {
+            return ring;
+        };
+        ring.remove(i);
+    }
+}
+
+/// The signed area of a ring, positive for counterclockwise rings.
+fn ring_area(ring: &[(i64, i64)]) -> i64 {
+    let n = ring.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % n]);
+            a.0 * b.1 - b.0 * a.1
+        })
+        .sum::<i64>()
+        / 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotated_building_footprint() {
+        // a 20 x 12 meter building turned by 30 degrees, with ragged edges
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        let mut mask = Vec2D::new(60, 60, false);
+        for x in 0..60 {
+            for y in 0..60 {
+                let (px, py) = (x as f64 + 0.5 - 30.0, y as f64 + 0.5 - 30.0);
+                let (u, v) = (px * cos + py * sin, -px * sin + py * cos);
+                mask[(x, y)] = u.abs() <= 10.0 && v.abs() <= 6.0 && (x * 7 + y * 3) % 23 != 0;
+            }
+        }
+        let mask = areas::majority_filter(&mask);
+
+        let rings = footprints(&mask, Point2::new(1000.0, 2000.0), 20);
+        assert_eq!(rings.len(), 1);
+        let ring = &rings[0];
+        assert_eq!(ring.len(), 5, "{ring:?}");
+        assert_eq!(ring[0], ring[4]);
+
+        for i in 0..4 {
+            let (a, b, c) = (&ring[i], &ring[i + 1], &ring[(i + 2) % 4]);
+            let dot = (b.x - a.x) * (c.x - b.x) + (b.y - a.y) * (c.y - b.y);
+            assert!(dot.abs() < 1e-6, "not a right angle at {b:?}");
+            let angle = (b.y - a.y).atan2(b.x - a.x).to_degrees().rem_euclid(90.0);
+            assert!((angle - 30.0).abs() < 2.0, "edge at {angle} degrees");
+        }
+        let area: f64 = (0..4)
+            .map(|i| ring[i].x * ring[i + 1].y - ring[i + 1].x * ring[i].y)
+            .sum::<f64>()
+            / 2.0;
+        assert!((area - 240.0).abs() < 40.0, "area {area}");
+    }
 }
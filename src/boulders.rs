@@ -0,0 +1,351 @@
+use log::info;
+use std::error::Error;
+
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Points};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::{PointCloud, XyzRecord};
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// Candidates with a larger share of vegetation points around their top are bushes, not boulders.
+const MAX_VEGETATION_SHARE: f64 = 0.5;
+
+/// The offset of a cell from another cell of the grid.
+type Offset = (isize, isize);
+
+/// A detected boulder.
+#[derive(Debug, Clone, Copy)]
+struct Boulder {
+    x: f64,
+    y: f64,
+    height: f64,
+}
+
+/// Detects boulders from the points, and returns them as points classified as boulders, large
+/// boulders (at least `largeboulderheight` high) and boulder clusters.
+///
+/// The tops of the ground points and the single returns are gridded at `boulderresolution`. A
+/// boulder is a local maximum of the grid rising between `boulderminheight` and
+/// `bouldermaxheight` above the median of the ring `boulderradius` away from it, with most of
+/// the ring at least half of the height below the top so only compact, steep-sided bumps
+/// qualify. Bumps with more vegetation points than ground points and single returns are bushes
+/// and dropped. Boulders closer than `boulderclusterdistance` to each other are merged into a
+/// boulder cluster.
+pub fn makeboulders(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting boulders...");
+
+    let size = config.boulderresolution;
+    let w = ((hmap.maxx() - hmap.minx()) / size).ceil() as usize + 1;
+    let h = ((hmap.maxy() - hmap.miny()) / size).ceil() as usize + 1;
+    // the tops and the point counts
+    job::reserve(
+        config,
+        "makeboulders",
+        job::grid_bytes::<f32>(w, h) + 2 * job::grid_bytes::<u16>(w, h),
+    )?;
+
+    let grid = BoulderGrid::new(points, hmap, size, w, h)?;
+    job::check_cancelled()?;
+    let boulders = grid.find_boulders(config);
+
+    let mut boulder_points = Points::new();
+    for cluster in clusters(&boulders, config.boulderclusterdistance) {
+        let (x, y, height) = cluster.iter().fold((0.0, 0.0, 0.0f64), |acc, b| {
+            (acc.0 + b.x, acc.1 + b.y, acc.2.max(b.height))
+        });
+        let n = cluster.len() as f64;
+        let class = if cluster.len() > 1 {
+            Classification::BoulderCluster
+        } else if height >= config.largeboulderheight {
+            Classification::LargeBoulder
+        } else {
+            Classification::Boulder
+        };
+        boulder_points.push(Point2 { x: x / n, y: y / n }, class);
+    }
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![boulder_points.into()],
+    ))
+}
+
+/// The points gridded for the boulder detection.
+struct BoulderGrid {
+    xoffset: f64,
+    yoffset: f64,
+    size: f64,
+    /// The highest ground point or single return of each cell, NaN for empty cells.
+    top: Vec2D<f32>,
+    /// The number of ground points and single returns in each cell.
+    solid: Vec2D<u16>,
+    /// The number of vegetation points 0.5 to 4 meters above the ground in each cell.
+    vegetation: Vec2D<u16>,
+}
+
+impl BoulderGrid {
+    fn new(
+        points: &PointCloud,
+        hmap: &HeightMap,
+        size: f64,
+        w: usize,
+        h: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut grid = Self {
+            xoffset: hmap.minx(),
+            yoffset: hmap.miny(),
+            size,
+            top: Vec2D::new(w, h, f32::NAN),
+            solid: Vec2D::new(w, h, 0),
+            vegetation: Vec2D::new(w, h, 0),
+        };
+
+        let ground = |r: &XyzRecord| {
+            let x = ((r.x - hmap.xoffset) / hmap.scale).round() as usize;
+            let y = ((r.y - hmap.yoffset) / hmap.scale).round() as usize;
+            hmap.grid[(x.min(hmap.grid.width() - 1), y.min(hmap.grid.height() - 1))]
+        };
+
+        for chunk in points.chunks() {
+            job::check_cancelled()?;
+            for r in chunk {
+                let Some(cell) = grid.cell(r.x, r.y) else {
+                    continue;
+                };
+                if r.classification == 2 || (r.classification == 1 && r.number_of_returns <= 1) {
+                    let top = &mut grid.top[cell];
+                    if top.is_nan() || r.z > *top {
+                        *top = r.z;
+                    }
+                    grid.solid[cell] = grid.solid[cell].saturating_add(1);
+                } else if (3..=5).contains(&r.classification) || r.number_of_returns > 1 {
+                    let above = r.z as f64 - ground(r);
+                    if (0.5..=4.0).contains(&above) {
+                        grid.vegetation[cell] = grid.vegetation[cell].saturating_add(1);
+                    }
+                }
+            }
+        }
+        Ok(grid)
+    }
+
+    fn cell(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+        let x = ((x - self.xoffset) / self.size).floor();
+        let y = ((y - self.yoffset) / self.size).floor();
+        let (w, h) = (self.top.width() as f64, self.top.height() as f64);
+        (x >= 0.0 && y >= 0.0 && x < w && y < h).then_some((x as usize, y as usize))
+    }
+
+    /// The offsets of the cells at about `radius` meters from a cell, and within it.
+    fn offsets(&self, radius: f64) -> (Vec<Offset>, Vec<Offset>) {
+        let r = (radius / self.size).round() as isize;
+        let (mut ring, mut inside) = (Vec::new(), Vec::new());
+        for dx in -r - 1..=r + 1 {
+            for dy in -r - 1..=r + 1 {
+                let distance = ((dx * dx + dy * dy) as f64).sqrt();
+                if (distance - r as f64).abs() <= 0.5 {
+                    ring.push((dx, dy));
+                } else if distance < r as f64 - 0.5 {
+                    inside.push((dx, dy));
+                }
+            }
+        }
+        (ring, inside)
+    }
+
+    fn find_boulders(&self, config: &Config) -> Vec<Boulder> {
+        let (w, h) = (self.top.width(), self.top.height());
+        let (ring, inside) = self.offsets(config.boulderradius);
+        let margin = (config.boulderradius / self.size).round() as usize + 1;
+        let at = |x: usize, y: usize, (dx, dy): (isize, isize)| {
+            ((x as isize + dx) as usize, (y as isize + dy) as usize)
+        };
+
+        let mut candidates = Vec::new();
+        let mut values = Vec::with_capacity(ring.len());
+        for x in margin..w.saturating_sub(margin) {
+            for y in margin..h.saturating_sub(margin) {
+                let top = self.top[(x, y)];
+                if top.is_nan() {
+                    continue;
+                }
+                // the highest cell of its neighbourhood, the first one of equal cells
+                let is_max = (-1..=1).all(|dx| {
+                    (-1..=1).all(|dy| {
+                        let other = self.top[at(x, y, (dx, dy))];
+                        (dx, dy) == (0, 0) || !(other > top || (other == top && (dx, dy) < (0, 0)))
+                    })
+                });
+                if !is_max {
+                    continue;
+                }
+
+                values.clear();
+                values.extend(
+                    ring.iter()
+                        .map(|&d| self.top[at(x, y, d)])
+                        .filter(|z| !z.is_nan()),
+                );
+                if values.len() < ring.len() / 2 {
+                    continue;
+                }
+                values.sort_by(f32::total_cmp);
+                let base = values[values.len() / 2] as f64;
+                let height = top as f64 - base;
+                // most of the ring, allowing for other boulders next to this one
+                let high = values[values.len() * 3 / 4] as f64;
+                if height < config.boulderminheight
+                    || height > config.bouldermaxheight
+                    || high > top as f64 - 0.5 * height
+                {
+                    continue;
+                }
+
+                // the points of the bump
+                let (solid, vegetation) = inside
+                    .iter()
+                    .map(|&d| at(x, y, d))
+                    .filter(|&cell| self.top[cell] as f64 > base + 0.5 * height)
+                    .fold((0, 0), |acc, cell| {
+                        (
+                            acc.0 + self.solid[cell] as usize,
+                            acc.1 + self.vegetation[cell] as usize,
+                        )
+                    });
+                if vegetation as f64 > MAX_VEGETATION_SHARE * (solid + vegetation) as f64 {
+                    continue;
+                }
+
+                candidates.push(Boulder {
+                    x: self.xoffset + (x as f64 + 0.5) * self.size,
+                    y: self.yoffset + (y as f64 + 0.5) * self.size,
+                    height,
+                });
+            }
+        }
+
+        // a boulder may have several tops, keep the highest one
+        candidates.sort_by(|a, b| b.height.total_cmp(&a.height));
+        let mut boulders: Vec<Boulder> = Vec::new();
+        for c in candidates {
+            if boulders
+                .iter()
+                .all(|b| (b.x - c.x).hypot(b.y - c.y) > config.boulderradius / 2.0)
+            {
+                boulders.push(c);
+            }
+        }
+        boulders
+    }
+}
+
+/// Groups the boulders closer than `distance` to each other, directly or through other boulders.
+fn clusters(boulders: &[Boulder], distance: f64) -> Vec<Vec<Boulder>> {
+    let mut cluster_of: Vec<Option<usize>> = vec![None; boulders.len()];
+    let mut clusters = Vec::new();
+    for start in 0..boulders.len() {
+        if cluster_of[start].is_some() {
+            continue;
+        }
+        let mut members = vec![start];
+        cluster_of[start] = Some(clusters.len());
+        let mut i = 0;
+        while i < members.len() {
+            let b = boulders[members[i]];
+            for (j, other) in boulders.iter().enumerate() {
+                if cluster_of[j].is_none() && (b.x - other.x).hypot(b.y - other.y) < distance {
+                    cluster_of[j] = Some(clusters.len());
+                    members.push(j);
+                }
+            }
+            i += 1;
+        }
+        clusters.push(members.into_iter().map(|i| boulders[i]).collect());
+    }
+    clusters
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use std::path::Path;
+
+    #[test]
+    fn test_boulders_bushes_and_clusters() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // gently sloping ground with a boulder at (20, 20), a bush at (10, 10) and two boulders
+        // next to each other at (30, 10) and (32, 10)
+        let ground = |x: f64, _y: f64| 100.0 + 0.1 * x;
+        let bump = |x: f64, y: f64, cx: f64, cy: f64| {
+            if (x - cx).hypot(y - cy) < 0.7 {
+                1.5
+            } else {
+                0.0
+            }
+        };
+        let mut records = Vec::new();
+        for i in 0..160 {
+            for j in 0..160 {
+                let (x, y) = (i as f64 * 0.25, j as f64 * 0.25);
+                let boulder =
+                    bump(x, y, 20.0, 20.0) + bump(x, y, 30.0, 10.0) + bump(x, y, 32.0, 10.0);
+                let bush = bump(x, y, 10.0, 10.0);
+                records.push(XyzRecord {
+                    x,
+                    y,
+                    z: (ground(x, y) + boulder + bush) as f32,
+                    classification: if bush > 0.0 { 1 } else { 2 },
+                    number_of_returns: 1,
+                    return_number: 1,
+                    ..Default::default()
+                });
+                if bush > 0.0 {
+                    for k in 0..3 {
+                        records.push(XyzRecord {
+                            x,
+                            y,
+                            z: (ground(x, y) + 0.6 + 0.3 * k as f64) as f32,
+                            classification: 3,
+                            number_of_returns: 4,
+                            return_number: k + 2,
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+        let points = PointCloud { records };
+
+        let mut grid = Vec2D::new(21, 21, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            *z = ground(2.0 * x as f64, 2.0 * y as f64);
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let dxf = makeboulders(&config, &points, &hmap).unwrap();
+        let Geometry::Points(points) = dxf.take_geometry().swap_remove(0) else {
+            panic!("boulders should be points");
+        };
+        let mut points = points.into_iter().collect::<Vec<_>>();
+        points.sort_by(|a, b| a.0.x.total_cmp(&b.0.x));
+        assert_eq!(points.len(), 2, "{points:?}");
+        assert_eq!(points[0].1, Classification::Boulder);
+        // the points are at the highest part of the boulders, on the uphill side
+        assert!((points[0].0.x - 20.0).abs() < 1.0 && (points[0].0.y - 20.0).abs() < 1.0);
+        assert_eq!(points[1].1, Classification::BoulderCluster);
+        assert!((points[1].0.x - 31.0).abs() < 1.0 && (points[1].0.y - 10.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,826 @@
+//! A C API for embedding pullauta in other applications, declared for C and C++ in
+//! `include/pullauta.h`. It is built as a shared library with `cargo build --release --lib`.
+//!
+//! All objects are opaque handles created by the `pullauta_*` functions and released with the
+//! matching `*_free` function, which accepts null. Functions returning a handle return null on
+//! failure, and functions returning a [`PullautaStatus`] return [`PullautaStatus::Ok`] on
+//! success. The message of the last failure on the calling thread is returned by
+//! [`pullauta_last_error`].
+//!
+//! Strings are NUL-terminated UTF-8. Pointers handed out by a handle stay valid until the handle
+//! is freed. A handle may be used from any thread, but not from several threads at once.
+#![allow(clippy::missing_safety_doc)]
+
+use ini::Ini;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::fmt::Display;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+
+use crate::config::{Config, DEFAULT_CONFIG};
+use crate::geometry::{BinaryDxf, Geometry};
+use crate::io::heightmap::HeightMap;
+use crate::pipeline::{MapLayers, Pipeline};
+
+/// The result of a call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullautaStatus {
+    Ok = 0,
+    /// The call failed, see [`pullauta_last_error`].
+    Error = 1,
+    /// An argument was null or invalid.
+    InvalidArgument = 2,
+}
+
+/// The vector layers of a processed tile. Passed as an integer, since C callers can pass any value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullautaLayer {
+    /// The final contours, including index contours and depressions.
+    Contours = 0,
+    /// Dot knolls and small depressions.
+    DotKnolls = 1,
+    SmallCliffs = 2,
+    BigCliffs = 3,
+    /// Streams and ditches, if `detectstreams` is enabled.
+    Streams = 4,
+    /// The outlines of the marshes, if `detectmarshes` is enabled.
+    Marshes = 5,
+    /// The shorelines of lakes and ponds, if `detectwater` is enabled.
+    WaterBodies = 6,
+    /// Boulders and boulder clusters, if `detectboulders` is enabled.
+    Boulders = 7,
+    /// The outlines of stony ground and boulder fields, if `detectstony` is enabled.
+    StonyGround = 8,
+    /// Pits and small depressions, if `detectpits` is enabled.
+    Pits = 9,
+    /// Earth banks and erosion gullies, if `detectbanks` is enabled.
+    Banks = 10,
+    /// Paths, with their confidence as the height, if `detectpaths` is enabled.
+    Paths = 11,
+    /// The footprints of the buildings, if `detectbuildings` is enabled.
+    Buildings = 12,
+    /// Power lines projected on the ground, if `detectpowerlines` is enabled.
+    PowerLines = 13,
+    /// The pylons of the power lines, if `detectpowerlines` is enabled.
+    Pylons = 14,
+}
+
+impl TryFrom<u32> for PullautaLayer {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, u32> {
+        use PullautaLayer::*;
+        [
+            Contours,
+            DotKnolls,
+            SmallCliffs,
+            BigCliffs,
+            Streams,
+            Marshes,
+            WaterBodies,
+            Boulders,
+            StonyGround,
+            Pits,
+            Banks,
+            Paths,
+            Buildings,
+            PowerLines,
+            Pylons,
+        ]
+        .into_iter()
+        .find(|&layer| layer as u32 == value)
+        .ok_or(value)
+    }
+}
+
+/// The extent of a geometry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PullautaBounds {
+    pub xmin: f64,
+    pub ymin: f64,
+    pub xmax: f64,
+    pub ymax: f64,
+}
+
+/// A point of a geometry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PullautaPoint {
+    /// The DXF layer of the point.
+    pub layer: *const c_char,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// A polyline of a geometry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PullautaPolyline {
+    /// The DXF layer of the line.
+    pub layer: *const c_char,
+    /// The vertices as `len` pairs of x and y.
+    pub coords: *const f64,
+    pub len: usize,
+    /// The height of the line, NaN if it has none.
+    pub height: f64,
+}
+
+/// The grid of a heightmap. The height of the cell `(x, y)` is `data[x * height + y]`, and its
+/// coordinates are `(xoffset + scale * x, yoffset + scale * y)`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PullautaGrid {
+    pub width: usize,
+    pub height: usize,
+    pub xoffset: f64,
+    pub yoffset: f64,
+    pub scale: f64,
+    pub data: *const f64,
+}
+
+/// A rendered map as RGBA pixels, row by row from the top, with the georeference of its world
+/// file. Released with [`pullauta_image_free`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct PullautaImage {
+    pub data: *mut u8,
+    pub width: u32,
+    pub height: u32,
+    /// The world file parameters: x scale, y skew, x skew, y scale, x and y of the upper left
+    /// pixel.
+    pub world_file: [f64; 6],
+}
+
+/// A configuration.
+pub struct PullautaConfig {
+    ini: Ini,
+    config: Config,
+}
+
+/// The layers of a processed tile.
+pub struct PullautaTile {
+    layers: MapLayers,
+}
+
+/// A heightmap.
+pub struct PullautaHeightMap {
+    heightmap: HeightMap,
+}
+
+/// Vector data, like contours or cliffs.
+pub struct PullautaGeometry {
+    dxf: BinaryDxf,
+    points: Vec<(CString, f64, f64)>,
+    lines: Vec<Polyline>,
+}
+
+struct Polyline {
+    layer: CString,
+    coords: Vec<f64>,
+    height: f64,
+}
+
+impl PullautaGeometry {
+    fn new(dxf: BinaryDxf) -> Self {
+        let layer = |name: &str| CString::new(name).unwrap_or_default();
+        let mut points = Vec::new();
+        let mut lines = Vec::new();
+        for geometry in dxf.geometry() {
+            match geometry {
+                Geometry::Points(p) => {
+                    points.extend(
+                        p.iter()
+                            .map(|(p, class)| (layer(class.to_layer()), p.x, p.y)),
+                    );
+                }
+                Geometry::Polylines2(l) => {
+                    lines.extend(l.iter().map(|(line, class)| Polyline {
+                        layer: layer(class.to_layer()),
+                        coords: line.iter().flat_map(|p| [p.x, p.y]).collect(),
+                        height: f64::NAN,
+                    }));
+                }
+                Geometry::Polylines3(l) => {
+                    lines.extend(l.iter().map(|(line, (class, height))| Polyline {
+                        layer: layer(class.to_layer()),
+                        coords: line.iter().flat_map(|p| [p.x, p.y]).collect(),
+                        height: *height,
+                    }));
+                }
+            }
+        }
+        Self { dxf, points, lines }
+    }
+}
+
+/// A failed call, reported through [`pullauta_last_error`].
+struct Failure {
+    status: PullautaStatus,
+    message: String,
+}
+
+impl<E: Display> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Self {
+            status: PullautaStatus::Error,
+            message: e.to_string(),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Failure {
+    Failure {
+        status: PullautaStatus::InvalidArgument,
+        message: message.into(),
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', "")).ok();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+/// Runs `f`, turning failures and panics into a status.
+fn call(f: impl FnOnce() -> Result<(), Failure>) -> PullautaStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => PullautaStatus::Ok,
+        Ok(Err(failure)) => {
+            set_last_error(&failure.message);
+            failure.status
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "processing panicked".to_string());
+            set_last_error(&message);
+            PullautaStatus::Error
+        }
+    }
+}
+
+/// Runs `f` and returns its result as a new handle, or null if it failed.
+fn new_handle<T>(f: impl FnOnce() -> Result<T, Failure>) -> *mut T {
+    let mut result = None;
+    match call(|| {
+        result = Some(f()?);
+        Ok(())
+    }) {
+        PullautaStatus::Ok => result.map_or(ptr::null_mut(), |r| Box::into_raw(Box::new(r))),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Converts a handle or out-pointer argument.
+///
+/// # Safety
+/// `p` must be null or valid for the returned lifetime.
+unsafe fn arg<'a, T>(p: *const T, name: &str) -> Result<&'a T, Failure> {
+    // SAFETY: guaranteed by the caller
+    unsafe { p.as_ref() }.ok_or_else(|| invalid(format!("{name} is null")))
+}
+
+/// Converts a mutable handle or out-pointer argument.
+///
+/// # Safety
+/// `p` must be null or valid and unaliased for the returned lifetime.
+unsafe fn arg_mut<'a, T>(p: *mut T, name: &str) -> Result<&'a mut T, Failure> {
+    // SAFETY: guaranteed by the caller
+    unsafe { p.as_mut() }.ok_or_else(|| invalid(format!("{name} is null")))
+}
+
+/// Converts a string argument.
+///
+/// # Safety
+/// `s` must be null or a NUL-terminated string valid for the returned lifetime.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(invalid(format!("{name} is null")));
+    }
+    // SAFETY: guaranteed by the caller
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| invalid(format!("{name} is not valid UTF-8")))
+}
+
+/// Releases a handle.
+///
+/// # Safety
+/// `p` must be null or a handle created by this module that is not used afterwards.
+unsafe fn free<T>(p: *mut T) {
+    if !p.is_null() {
+        // SAFETY: handles are created with `Box::into_raw` in `new_handle`
+        drop(unsafe { Box::from_raw(p) });
+    }
+}
+
+/// The version of the library.
+#[unsafe(no_mangle)]
+pub extern "C" fn pullauta_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// The message of the last failed call on this thread, or null. Valid until the next failing
+/// call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn pullauta_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Creates the default configuration.
+#[unsafe(no_mangle)]
+pub extern "C" fn pullauta_config_new() -> *mut PullautaConfig {
+    new_handle(|| {
+        let ini = Ini::load_from_str(DEFAULT_CONFIG)?;
+        let config = Config::from_ini(&ini)?;
+        Ok(PullautaConfig { ini, config })
+    })
+}
+
+/// Reads a configuration from an .ini file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_config_from_file(path: *const c_char) -> *mut PullautaConfig {
+    new_handle(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let path = unsafe { str_arg(path, "path")? };
+        let ini = Ini::load_from_file(path)?;
+        let config = Config::from_ini(&ini)?;
+        Ok(PullautaConfig { ini, config })
+    })
+}
+
+/// Sets an option of a configuration, as written in the .ini file. The configuration is left
+/// unchanged if the result is invalid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_config_set(
+    config: *mut PullautaConfig,
+    key: *const c_char,
+    value: *const c_char,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (config, key, value) = unsafe {
+            (
+                arg_mut(config, "config")?,
+                str_arg(key, "key")?,
+                str_arg(value, "value")?,
+            )
+        };
+        let mut ini = config.ini.clone();
+        ini.with_general_section().set(key, value);
+        config.config = Config::from_ini(&ini).map_err(|e| invalid(e.to_string()))?;
+        config.ini = ini;
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_config_free(config: *mut PullautaConfig) {
+    // SAFETY: the handle is not used afterwards, see the module documentation
+    unsafe { free(config) }
+}
+
+/// Runs all the stages needed for rendering a map on a .las, .laz, .xyz or .xyz.bin file,
+/// honoring the `vegeonly`, `cliffsonly`, `contoursonly` and `detect*` options.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_process(
+    config: *const PullautaConfig,
+    path: *const c_char,
+) -> *mut PullautaTile {
+    new_handle(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (config, path) = unsafe { (arg(config, "config")?, str_arg(path, "path")?) };
+        process(config, path, &std::fs::read(path)?)
+    })
+}
+
+/// Like [`pullauta_tile_process`], with the file contents in a buffer of `len` bytes. The format
+/// is determined from the extension of `file_name`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_process_buffer(
+    config: *const PullautaConfig,
+    file_name: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> *mut PullautaTile {
+    new_handle(|| {
+        if data.is_null() {
+            return Err(invalid("data is null"));
+        }
+        // SAFETY: the arguments are valid or null and `data` holds `len` bytes, see the module
+        // documentation
+        let (config, file_name, data) = unsafe {
+            (
+                arg(config, "config")?,
+                str_arg(file_name, "file_name")?,
+                std::slice::from_raw_parts(data, len),
+            )
+        };
+        process(config, file_name, data)
+    })
+}
+
+fn process(config: &PullautaConfig, file_name: &str, data: &[u8]) -> Result<PullautaTile, Failure> {
+    let pipeline = Pipeline::new(&config.config);
+    let points = pipeline.read_points(file_name, data)?;
+    Ok(PullautaTile {
+        layers: pipeline.layers(&points)?,
+    })
+}
+
+/// A copy of the heightmap of a tile.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_heightmap(
+    tile: *const PullautaTile,
+) -> *mut PullautaHeightMap {
+    new_handle(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let tile = unsafe { arg(tile, "tile")? };
+        Ok(PullautaHeightMap {
+            heightmap: tile.layers.heightmap.clone(),
+        })
+    })
+}
+
+/// A copy of a vector layer of a tile, one of [`PullautaLayer`]. Fails if the layer is unknown or
+/// was not generated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_geometry(
+    tile: *const PullautaTile,
+    layer: u32,
+) -> *mut PullautaGeometry {
+    new_handle(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let layers = &unsafe { arg(tile, "tile")? }.layers;
+        let layer = PullautaLayer::try_from(layer)
+            .map_err(|layer| invalid(format!("unknown layer {layer}")))?;
+        let dxf = match layer {
+            PullautaLayer::Contours => layers.contours.as_ref().map(|c| &c.contours),
+            PullautaLayer::DotKnolls => layers.contours.as_ref().map(|c| &c.dotknolls),
+            PullautaLayer::SmallCliffs => layers.cliffs.as_ref().map(|c| &c.small),
+            PullautaLayer::BigCliffs => layers.cliffs.as_ref().map(|c| &c.big),
+            PullautaLayer::Streams => layers.streams.as_ref(),
+            PullautaLayer::Marshes => layers.marshes.as_ref().map(|m| &m.outlines),
+            PullautaLayer::WaterBodies => layers.water_bodies.as_ref(),
+            PullautaLayer::Boulders => layers.boulders.as_ref(),
+            PullautaLayer::StonyGround => layers.stony_ground.as_ref().map(|s| &s.outlines),
+            PullautaLayer::Pits => layers.pits.as_ref(),
+            PullautaLayer::Banks => layers.banks.as_ref(),
+            PullautaLayer::Paths => layers.paths.as_ref(),
+            PullautaLayer::Buildings => layers.buildings.as_ref().map(|b| &b.footprints),
+            PullautaLayer::PowerLines => layers.power_lines.as_ref().map(|p| &p.lines),
+            PullautaLayer::Pylons => layers.power_lines.as_ref().map(|p| &p.pylons),
+        };
+        let dxf = dxf.ok_or_else(|| invalid(format!("{layer:?} were not generated")))?;
+        Ok(PullautaGeometry::new(dxf.clone()))
+    })
+}
+
+/// Renders the map of a tile, with or without depressions, into `image`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_render(
+    config: *const PullautaConfig,
+    tile: *const PullautaTile,
+    depressions: bool,
+    image: *mut PullautaImage,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (config, tile, image) = unsafe {
+            (
+                arg(config, "config")?,
+                arg(tile, "tile")?,
+                arg_mut(image, "image")?,
+            )
+        };
+        let rendered = Pipeline::new(&config.config).render(&tile.layers, depressions)?;
+        let pixels = rendered.map.image.to_rgba8();
+        let w = &rendered.map.world_file;
+        *image = PullautaImage {
+            width: pixels.width(),
+            height: pixels.height(),
+            data: Box::into_raw(pixels.into_raw().into_boxed_slice()).cast(),
+            world_file: [w.x_scale, w.y_skew, w.x_skew, w.y_scale, w.x, w.y],
+        };
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_tile_free(tile: *mut PullautaTile) {
+    // SAFETY: the handle is not used afterwards, see the module documentation
+    unsafe { free(tile) }
+}
+
+/// Releases the pixels of a rendered image and clears it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_image_free(image: *mut PullautaImage) {
+    // SAFETY: the arguments are valid or null, see the module documentation
+    let Ok(image) = (unsafe { arg_mut(image, "image") }) else {
+        return;
+    };
+    if !image.data.is_null() {
+        let len = image.width as usize * image.height as usize * 4;
+        // SAFETY: the pixels were allocated as a boxed slice of this length in
+        // `pullauta_tile_render`
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(image.data, len)) });
+    }
+    image.data = ptr::null_mut();
+    image.width = 0;
+    image.height = 0;
+}
+
+/// Gets the grid of a heightmap.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_heightmap_grid(
+    heightmap: *const PullautaHeightMap,
+    grid: *mut PullautaGrid,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (heightmap, grid) = unsafe { (arg(heightmap, "heightmap")?, arg_mut(grid, "grid")?) };
+        let hm = &heightmap.heightmap;
+        *grid = PullautaGrid {
+            width: hm.grid.width(),
+            height: hm.grid.height(),
+            xoffset: hm.xoffset,
+            yoffset: hm.yoffset,
+            scale: hm.scale,
+            data: hm.grid.as_slice().as_ptr(),
+        };
+        Ok(())
+    })
+}
+
+/// Generates raw contours with the given interval from a heightmap.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_heightmap_contours(
+    config: *const PullautaConfig,
+    heightmap: *const PullautaHeightMap,
+    interval: f64,
+) -> *mut PullautaGeometry {
+    new_handle(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (config, heightmap) = unsafe { (arg(config, "config")?, arg(heightmap, "heightmap")?) };
+        if interval.is_nan() || interval <= 0.0 {
+            return Err(invalid("the interval must be positive"));
+        }
+        let dxf = Pipeline::new(&config.config).contours(&heightmap.heightmap, interval)?;
+        Ok(PullautaGeometry::new(dxf))
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_heightmap_free(heightmap: *mut PullautaHeightMap) {
+    // SAFETY: the handle is not used afterwards, see the module documentation
+    unsafe { free(heightmap) }
+}
+
+/// Gets the extent of a geometry.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_bounds(
+    geometry: *const PullautaGeometry,
+    bounds: *mut PullautaBounds,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (geometry, bounds) =
+            unsafe { (arg(geometry, "geometry")?, arg_mut(bounds, "bounds")?) };
+        let b = geometry.dxf.bounds();
+        *bounds = PullautaBounds {
+            xmin: b.xmin,
+            ymin: b.ymin,
+            xmax: b.xmax,
+            ymax: b.ymax,
+        };
+        Ok(())
+    })
+}
+
+/// The number of points in a geometry, 0 if `geometry` is null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_point_count(geometry: *const PullautaGeometry) -> usize {
+    // SAFETY: the arguments are valid or null, see the module documentation
+    unsafe { arg(geometry, "geometry") }.map_or(0, |g| g.points.len())
+}
+
+/// Gets the point at `index`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_point(
+    geometry: *const PullautaGeometry,
+    index: usize,
+    point: *mut PullautaPoint,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (geometry, point) = unsafe { (arg(geometry, "geometry")?, arg_mut(point, "point")?) };
+        let (layer, x, y) = geometry
+            .points
+            .get(index)
+            .ok_or_else(|| invalid(format!("no point {index}")))?;
+        *point = PullautaPoint {
+            layer: layer.as_ptr(),
+            x: *x,
+            y: *y,
+        };
+        Ok(())
+    })
+}
+
+/// The number of polylines in a geometry, 0 if `geometry` is null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_polyline_count(
+    geometry: *const PullautaGeometry,
+) -> usize {
+    // SAFETY: the arguments are valid or null, see the module documentation
+    unsafe { arg(geometry, "geometry") }.map_or(0, |g| g.lines.len())
+}
+
+/// Gets the polyline at `index`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_polyline(
+    geometry: *const PullautaGeometry,
+    index: usize,
+    polyline: *mut PullautaPolyline,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (geometry, polyline) =
+            unsafe { (arg(geometry, "geometry")?, arg_mut(polyline, "polyline")?) };
+        let line = geometry
+            .lines
+            .get(index)
+            .ok_or_else(|| invalid(format!("no polyline {index}")))?;
+        *polyline = PullautaPolyline {
+            layer: line.layer.as_ptr(),
+            coords: line.coords.as_ptr(),
+            len: line.coords.len() / 2,
+            height: line.height,
+        };
+        Ok(())
+    })
+}
+
+/// Writes a geometry as a DXF file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_write_dxf(
+    geometry: *const PullautaGeometry,
+    path: *const c_char,
+) -> PullautaStatus {
+    call(|| {
+        // SAFETY: the arguments are valid or null, see the module documentation
+        let (geometry, path) = unsafe { (arg(geometry, "geometry")?, str_arg(path, "path")?) };
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        geometry.dxf.to_dxf(&mut file)?;
+        Ok(())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pullauta_geometry_free(geometry: *mut PullautaGeometry) {
+    // SAFETY: the handle is not used afterwards, see the module documentation
+    unsafe { free(geometry) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn last_error() -> String {
+        // SAFETY: only called after a failed call, which sets the message
+        unsafe { CStr::from_ptr(pullauta_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_config() {
+        // SAFETY: the handles are created and freed by the API
+        unsafe {
+            let config = pullauta_config_new();
+            assert!(!config.is_null());
+            assert_eq!(
+                pullauta_config_set(config, c"scalefactor".as_ptr(), c"1.5".as_ptr()),
+                PullautaStatus::Ok
+            );
+            assert_eq!((*config).config.scalefactor, 1.5);
+
+            pullauta_config_set(config, c"vegeonly".as_ptr(), c"1".as_ptr());
+            assert_eq!(
+                pullauta_config_set(config, c"cliffsonly".as_ptr(), c"1".as_ptr()),
+                PullautaStatus::InvalidArgument
+            );
+            assert!(last_error().contains("Only one of"));
+            assert!(!(*config).config.cliffsonly);
+            pullauta_config_free(config);
+
+            assert!(pullauta_config_from_file(c"missing.ini".as_ptr()).is_null());
+            assert_eq!(
+                pullauta_config_set(ptr::null_mut(), c"a".as_ptr(), c"b".as_ptr()),
+                PullautaStatus::InvalidArgument
+            );
+            assert_eq!(last_error(), "config is null");
+        }
+    }
+
+    #[test]
+    fn test_process_and_render_tile() {
+        // a 40x40 m slope rising 1 m per 10 m in the x-direction
+        let mut xyz = String::new();
+        for x in 0..40 {
+            for y in 0..40 {
+                let z = 100.0 + x as f64 / 10.0;
+                xyz += &format!("{} {} {z} 2 1 1\n", 1000 + x, 2000 + y);
+            }
+        }
+
+        // SAFETY: the handles are created and freed by the API, and the out-pointers are valid
+        unsafe {
+            let config = pullauta_config_new();
+            let tile =
+                pullauta_tile_process_buffer(config, c"tile.xyz".as_ptr(), xyz.as_ptr(), xyz.len());
+            assert!(!tile.is_null(), "{}", last_error());
+
+            let heightmap = pullauta_tile_heightmap(tile);
+            let mut grid = std::mem::zeroed::<PullautaGrid>();
+            assert_eq!(
+                pullauta_heightmap_grid(heightmap, &mut grid),
+                PullautaStatus::Ok
+            );
+            assert!(grid.width > 0 && grid.height > 0);
+            let data = std::slice::from_raw_parts(grid.data, grid.width * grid.height);
+            assert!(data[grid.height] > data[0]);
+
+            let contours = pullauta_heightmap_contours(config, heightmap, 1.0);
+            assert!(pullauta_geometry_polyline_count(contours) > 0);
+            let mut line = std::mem::zeroed::<PullautaPolyline>();
+            assert_eq!(
+                pullauta_geometry_polyline(contours, 0, &mut line),
+                PullautaStatus::Ok
+            );
+            assert!(line.len > 1);
+            assert_eq!(
+                pullauta_geometry_polyline(contours, usize::MAX, &mut line),
+                PullautaStatus::InvalidArgument
+            );
+            let mut bounds = PullautaBounds::default();
+            assert_eq!(
+                pullauta_geometry_bounds(contours, &mut bounds),
+                PullautaStatus::Ok
+            );
+            assert!(bounds.xmax > bounds.xmin);
+            pullauta_geometry_free(contours);
+            pullauta_heightmap_free(heightmap);
+
+            let cliffs = pullauta_tile_geometry(tile, PullautaLayer::SmallCliffs as u32);
+            assert!(!cliffs.is_null());
+            pullauta_geometry_free(cliffs);
+            assert!(pullauta_tile_geometry(tile, PullautaLayer::Pylons as u32 + 1).is_null());
+            assert_eq!(last_error(), "unknown layer 15");
+            assert_eq!(
+                PullautaLayer::try_from(PullautaLayer::Pylons as u32),
+                Ok(PullautaLayer::Pylons)
+            );
+
+            let mut image = std::mem::zeroed::<PullautaImage>();
+            assert_eq!(
+                pullauta_tile_render(config, tile, false, &mut image),
+                PullautaStatus::Ok,
+                "{}",
+                last_error()
+            );
+            assert!(image.width > 0 && image.height > 0 && !image.data.is_null());
+            assert!(image.world_file[0] > 0.0);
+            pullauta_image_free(&mut image);
+            assert!(image.data.is_null());
+
+            pullauta_tile_free(tile);
+            pullauta_config_free(config);
+        }
+    }
+
+    #[test]
+    fn test_header_declares_all_functions() {
+        let header = include_str!("../include/pullauta.h");
+        let source = include_str!("capi.rs");
+        for line in source.lines() {
+            let Some(rest) = line
+                .strip_prefix("pub unsafe extern \"C\" fn ")
+                .or_else(|| line.strip_prefix("pub extern \"C\" fn "))
+            else {
+                continue;
+            };
+            let name = &rest[..rest.find('(').unwrap()];
+            assert!(header.contains(&format!("{name}(")), "{name} is missing");
+        }
+    }
+}
@@ -3,21 +3,28 @@ use log::info;
 use rand::prelude::*;
 use std::borrow::Cow;
 use std::error::Error;
-use std::path::Path;
 
 use crate::config::Config;
 use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Polylines};
-use crate::io::bytes::FromToBytes;
-use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
-use crate::io::xyz::XyzInternalReader;
+use crate::io::xyz::PointCloud;
+use crate::job;
 use crate::vec2d::Vec2D;
 
+/// The output of the cliff generation.
+#[derive(Debug, Clone)]
+pub struct Cliffs {
+    /// Small cliffs (`c2g`).
+    pub small: BinaryDxf,
+    /// Big cliffs (`c3g`).
+    pub big: BinaryDxf,
+}
+
 pub fn makecliffs(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-) -> Result<(), Box<dyn Error>> {
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<Cliffs, Box<dyn Error>> {
     info!("Identifying cliffs...");
 
     let &Config {
@@ -36,9 +43,6 @@ pub fn makecliffs(
         no_small_ciffs -= flat_place;
     }
 
-    let heightmap_in = tmpfolder.join("xyz2.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(&heightmap_in)?)?;
-
     // in world coordinates
     let xmax = hmap.maxx();
     let ymax = hmap.maxy();
@@ -52,6 +56,16 @@ pub fn makecliffs(
     let sxmax = hmap.grid.width() - 1;
     let symax = hmap.grid.height() - 1;
 
+    // the steepness, the cliff image and at most all points sorted into 3 m cells
+    let n_points = points.records.len();
+    job::reserve(
+        config,
+        "makecliffs",
+        job::grid_bytes::<f64>(sxmax + 1, symax + 1)
+            + job::grid_bytes::<Rgb<u8>>((xmax - xmin) as usize, (ymax - ymin) as usize)
+            + (n_points as f64 * cliff_thin) as u64 * size_of::<(f64, f64, f64)>() as u64,
+    )?;
+
     let mut steepness = Vec2D::new(sxmax + 1, symax + 1, f64::NAN);
 
     for i in 3..sxmax - 4 {
@@ -89,13 +103,11 @@ pub fn makecliffs(
         Vec::<(f64, f64, f64)>::new(),
     );
 
-    let xyz_file_in = tmpfolder.join("xyztemp.xyz.bin");
-
     let mut rng = rand::rng();
     let randdist = rand::distr::Bernoulli::new(cliff_thin).unwrap();
 
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-    while let Some(chunk) = reader.next_chunk()? {
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
         for r in chunk {
             if cliff_thin == 1.0 || rng.sample(randdist) {
                 let (x, y, h) = (r.x, r.y, r.z as f64);
@@ -121,6 +133,7 @@ pub fn makecliffs(
     // temporary vector to reuse memory allocations
     let mut t = Vec::<(f64, f64, f64)>::new();
     for x in 0..w + 1 {
+        job::check_cancelled()?;
         for y in 0..h + 1 {
             if !list_alt[(x, y)].is_empty() {
                 t.clear();
@@ -266,18 +279,6 @@ pub fn makecliffs(
 
     let f2_dxf = BinaryDxf::new(Bounds::new(xmin, xmax, ymin, ymax), vec![f2_lines.into()]);
 
-    // save binary file
-    let mut f2 = fs
-        .create(tmpfolder.join("c2g.dxf.bin"))
-        .expect("Unable to create file");
-    f2_dxf.to_writer(&mut f2).expect("Cannot write c2g.dxf.bin");
-
-    if config.output_dxf {
-        f2_dxf.to_dxf(&mut fs.create(tmpfolder.join("c2g.dxf"))?)?;
-    }
-
-    drop(f2_dxf);
-
     let c2_limit = 2.6 * 2.75;
 
     // if we drop this already here, we can reuse the memory for the second list_alt
@@ -302,6 +303,7 @@ pub fn makecliffs(
     // temporary vector to reuse memory allocations
     let mut t = Vec::<(f64, f64, f64)>::new();
     for x in 0..w + 1 {
+        job::check_cancelled()?;
         for y in 0..h + 1 {
             let d = &list_alt[(x, y)];
             if !d.is_empty() {
@@ -361,24 +363,9 @@ pub fn makecliffs(
 
     let f3_dxf = BinaryDxf::new(Bounds::new(xmin, xmax, ymin, ymax), vec![f3_lines.into()]);
 
-    // save binary file
-    let mut f3 = fs
-        .create(tmpfolder.join("c3g.dxf.bin"))
-        .expect("Unable to create file");
-    f3_dxf.to_writer(&mut f3).expect("Cannot write c3g.dxf.bin");
-
-    if config.output_dxf {
-        f3_dxf.to_dxf(&mut fs.create(tmpfolder.join("c3g.dxf"))?)?;
-    }
-
-    img.write_to(
-        &mut fs
-            .create(tmpfolder.join("c2.png"))
-            .expect("could not save output png"),
-        image::ImageFormat::Png,
-    )
-    .expect("could not save output png");
-
     info!("Done");
-    Ok(())
+    Ok(Cliffs {
+        small: f2_dxf,
+        big: f3_dxf,
+    })
 }
@@ -3,18 +3,46 @@ use std::{path::Path, str::FromStr};
 use ini::Ini;
 
 /// The config parsed from the .ini configuration file.
+#[derive(serde::Serialize)]
 pub struct Config {
     pub batch: bool,
     pub processes: u64,
 
+    /// If true, process each batch tile in its own worker process.
+    pub isolated_workers: bool,
+    /// The longest time in seconds a worker process may spend on a tile, 0 for no limit.
+    pub worker_timeout: u64,
+    /// The largest resident memory in megabytes of a worker process, 0 for no limit.
+    pub worker_memory_limit: u64,
+    /// How many times a failed tile is retried with a new worker process.
+    pub worker_retries: u32,
+    /// The time in seconds after which the claim of a batch tile by an instance that stopped
+    /// renewing it may be taken over.
+    pub lease_timeout: u64,
+
     /// If true, use parallel decompression for LAZ files.
     pub laz_parallell: bool,
 
     pub experimental_use_in_memory_fs: bool,
+    /// The largest amount of file data in megabytes kept in memory by the in-memory file system,
+    /// 0 for no limit.
+    pub experimental_memory_fs_budget: u64,
+    /// The URL of a remote folder read with HTTP range requests in place of `lazfolder` in batch
+    /// mode, empty for none.
+    pub experimental_remote_lazfolder: String,
 
     /// Whether to output the result as DXF.
     pub output_dxf: bool,
 
+    /// Whether to write a JSON report of each processed tile.
+    pub report: bool,
+
+    /// Whether to print progress events as JSON lines on stdout.
+    pub progress_json: bool,
+
+    /// The largest amount of memory in megabytes a single stage may need, 0 for no limit.
+    pub memory_budget: u64,
+
     // only one can be set at a time
     pub vegeonly: bool,
     pub cliffsonly: bool,
@@ -44,6 +72,59 @@ pub struct Config {
     pub basemapcontours: f64,
 
     pub detectbuildings: bool,
+    pub buildingminarea: f64,
+    pub buildingfootprints: bool,
+
+    pub detectstreams: bool,
+    pub streamditcharea: f64,
+    pub streamminorarea: f64,
+    pub streamarea: f64,
+    pub streamminlength: f64,
+
+    pub detectmarshes: bool,
+    pub marshslope: f64,
+    pub marshtwi: f64,
+    pub marshdensity: f64,
+    pub marshintensity: f64,
+    pub marshminarea: f64,
+
+    pub detectwater: bool,
+    pub waterslope: f64,
+    pub waterminarea: f64,
+    pub watersmoothing: usize,
+
+    pub detectboulders: bool,
+    pub boulderresolution: f64,
+    pub boulderminheight: f64,
+    pub bouldermaxheight: f64,
+    pub largeboulderheight: f64,
+    pub boulderradius: f64,
+    pub boulderclusterdistance: f64,
+    pub detectstony: bool,
+    pub stonyroughness: f64,
+    pub boulderfieldroughness: f64,
+    pub stonyminarea: f64,
+    pub detectpits: bool,
+    pub pitmindepth: f64,
+    pub pitmaxdiameter: f64,
+    pub pitsteepness: f64,
+    pub detectbanks: bool,
+    pub bankminheight: f64,
+    pub bankmaxheight: f64,
+    pub bankslopebreak: f64,
+    pub gullymindepth: f64,
+    pub gullydepth: f64,
+    pub bankminlength: f64,
+    pub detectpaths: bool,
+    pub pathundergrowth: f64,
+    pub pathrelief: f64,
+    pub pathintensity: f64,
+    pub pathminconfidence: f64,
+    pub pathminlength: f64,
+
+    pub detectpowerlines: bool,
+    pub powerlineminheight: f64,
+    pub powerlineminlength: f64,
 
     pub water_class: u8,
 
@@ -109,6 +190,7 @@ pub struct Config {
     pub remove_touching_contours: bool,
 }
 
+#[derive(serde::Serialize)]
 pub struct Zone {
     pub low: f64,
     pub high: f64,
@@ -116,21 +198,29 @@ pub struct Zone {
     pub factor: f64,
 }
 
-const DEFAULT_CONFIG_FILE: &str = "pullauta.ini";
+/// The configuration file read from the working directory.
+pub const DEFAULT_CONFIG_FILE: &str = "pullauta.ini";
+
+/// The contents of the default configuration file.
+pub const DEFAULT_CONFIG: &str = include_str!("../pullauta.default.ini");
 
 impl Config {
     pub fn load_or_create_default() -> Result<Self, Box<dyn std::error::Error>> {
         let path = Path::new(DEFAULT_CONFIG_FILE);
         // populate the default if no file was found
         if !path.exists() {
-            std::fs::write(path, include_bytes!("../pullauta.default.ini"))?;
+            std::fs::write(path, DEFAULT_CONFIG)?;
         }
         Self::from_file(path)
     }
 
-    fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let conf = Ini::load_from_file(path)?;
+    /// Reads the configuration from an ini file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_ini(&Ini::load_from_file(path)?)
+    }
 
+    /// Reads the configuration from the general section of a parsed ini file.
+    pub fn from_ini(conf: &Ini) -> Result<Self, Box<dyn std::error::Error>> {
         let gs = conf.general_section();
 
         // only one can be set at a time
@@ -160,13 +250,27 @@ impl Config {
 
         let laz_parallell: bool = gs.get("parallel_laz_decompression").unwrap_or("0") == "1";
         let output_dxf: bool = gs.get("output_dxf").unwrap_or("0") == "1";
+        let report: bool = gs.get("report").unwrap_or("0") == "1";
+        let progress_json: bool = gs.get("progress_json").unwrap_or("0") == "1";
+        let memory_budget: u64 = parse_typed(gs, "memory_budget", 0);
 
         let pnorthlinesangle: f64 = parse_typed(gs, "northlinesangle", 0.0);
         let pnorthlineswidth: usize = parse_typed(gs, "northlineswidth", 0);
 
         let processes: u64 = gs.get("processes").unwrap().parse::<u64>().unwrap();
+        let isolated_workers: bool = gs.get("isolated_workers").unwrap_or("0") == "1";
+        let worker_timeout: u64 = parse_typed(gs, "worker_timeout", 0);
+        let worker_memory_limit: u64 = parse_typed(gs, "worker_memory_limit", 0);
+        let worker_retries: u32 = parse_typed(gs, "worker_retries", 1);
+        let lease_timeout: u64 = parse_typed(gs, "lease_timeout", 600);
         let experimental_use_in_memory_fs: bool =
             gs.get("experimental_use_in_memory_fs").unwrap_or("0") == "1";
+        let experimental_memory_fs_budget: u64 =
+            parse_typed(gs, "experimental_memory_fs_budget", 0);
+        let experimental_remote_lazfolder = gs
+            .get("experimental_remote_lazfolder")
+            .unwrap_or("")
+            .to_string();
 
         let lazfolder = gs.get("lazfolder").unwrap_or("").to_string();
         let batchoutfolder = gs.get("batchoutfolder").unwrap_or("").to_string();
@@ -213,6 +317,63 @@ impl Config {
         let basemapcontours: f64 = parse_typed(gs, "basemapinterval", 0.0);
 
         let detectbuildings: bool = gs.get("detectbuildings").unwrap_or("0") == "1";
+        let buildingminarea: f64 = parse_typed(gs, "buildingminarea", 20.0);
+        let buildingfootprints: bool = gs.get("buildingfootprints").unwrap_or("0") == "1";
+
+        let detectstreams: bool = gs.get("detectstreams").unwrap_or("0") == "1";
+        let streamditcharea: f64 = parse_typed(gs, "streamditcharea", 5000.0);
+        let streamminorarea: f64 = parse_typed(gs, "streamminorarea", 50000.0);
+        let streamarea: f64 = parse_typed(gs, "streamarea", 500000.0);
+        let streamminlength: f64 = parse_typed(gs, "streamminlength", 30.0);
+
+        let detectmarshes: bool = gs.get("detectmarshes").unwrap_or("0") == "1";
+        let marshslope: f64 = parse_typed(gs, "marshslope", 0.03);
+        let marshtwi: f64 = parse_typed(gs, "marshtwi", 8.0);
+        let marshdensity: f64 = parse_typed(gs, "marshdensity", 0.5);
+        let marshintensity: f64 = parse_typed(gs, "marshintensity", 0.0);
+        let marshminarea: f64 = parse_typed(gs, "marshminarea", 400.0);
+
+        let detectwater: bool = gs.get("detectwater").unwrap_or("0") == "1";
+        let waterslope: f64 = parse_typed(gs, "waterslope", 0.01);
+        let waterminarea: f64 = parse_typed(gs, "waterminarea", 100.0);
+        let watersmoothing: usize = parse_typed(gs, "watersmoothing", 3);
+
+        let detectboulders: bool = gs.get("detectboulders").unwrap_or("0") == "1";
+        let boulderresolution: f64 = parse_typed(gs, "boulderresolution", 0.5);
+        let boulderminheight: f64 = parse_typed(gs, "boulderminheight", 1.0);
+        let bouldermaxheight: f64 = parse_typed(gs, "bouldermaxheight", 3.0);
+        let largeboulderheight: f64 = parse_typed(gs, "largeboulderheight", 2.0);
+        let boulderradius: f64 = parse_typed(gs, "boulderradius", 2.5);
+        let boulderclusterdistance: f64 = parse_typed(gs, "boulderclusterdistance", 3.0);
+
+        let detectstony: bool = gs.get("detectstony").unwrap_or("0") == "1";
+        let stonyroughness: f64 = parse_typed(gs, "stonyroughness", 0.1);
+        let boulderfieldroughness: f64 = parse_typed(gs, "boulderfieldroughness", 0.25);
+        let stonyminarea: f64 = parse_typed(gs, "stonyminarea", 200.0);
+
+        let detectpits: bool = gs.get("detectpits").unwrap_or("0") == "1";
+        let pitmindepth: f64 = parse_typed(gs, "pitmindepth", 0.5);
+        let pitmaxdiameter: f64 = parse_typed(gs, "pitmaxdiameter", 15.0);
+        let pitsteepness: f64 = parse_typed(gs, "pitsteepness", 0.5);
+
+        let detectbanks: bool = gs.get("detectbanks").unwrap_or("0") == "1";
+        let bankminheight: f64 = parse_typed(gs, "bankminheight", 0.5);
+        let bankmaxheight: f64 = parse_typed(gs, "bankmaxheight", 3.0);
+        let bankslopebreak: f64 = parse_typed(gs, "bankslopebreak", 0.1);
+        let gullymindepth: f64 = parse_typed(gs, "gullymindepth", 1.0);
+        let gullydepth: f64 = parse_typed(gs, "gullydepth", 1.5);
+        let bankminlength: f64 = parse_typed(gs, "bankminlength", 8.0);
+
+        let detectpaths: bool = gs.get("detectpaths").unwrap_or("0") == "1";
+        let pathundergrowth: f64 = parse_typed(gs, "pathundergrowth", 0.3);
+        let pathrelief: f64 = parse_typed(gs, "pathrelief", 0.05);
+        let pathintensity: f64 = parse_typed(gs, "pathintensity", 0.2);
+        let pathminconfidence: f64 = parse_typed(gs, "pathminconfidence", 0.5);
+        let pathminlength: f64 = parse_typed(gs, "pathminlength", 20.0);
+
+        let detectpowerlines: bool = gs.get("detectpowerlines").unwrap_or("0") == "1";
+        let powerlineminheight: f64 = parse_typed(gs, "powerlineminheight", 5.0);
+        let powerlineminlength: f64 = parse_typed(gs, "powerlineminlength", 30.0);
 
         let water_class = parse_typed(gs, "waterclass", 9);
 
@@ -351,9 +512,19 @@ impl Config {
         Ok(Self {
             batch,
             processes,
+            isolated_workers,
+            worker_timeout,
+            worker_memory_limit,
+            worker_retries,
+            lease_timeout,
             output_dxf,
+            report,
+            progress_json,
+            memory_budget,
             laz_parallell,
             experimental_use_in_memory_fs,
+            experimental_memory_fs_budget,
+            experimental_remote_lazfolder,
             vegeonly,
             cliffsonly,
             contoursonly,
@@ -375,6 +546,54 @@ impl Config {
             contour_interval,
             basemapcontours,
             detectbuildings,
+            buildingminarea,
+            buildingfootprints,
+            detectstreams,
+            streamditcharea,
+            streamminorarea,
+            streamarea,
+            streamminlength,
+            detectmarshes,
+            marshslope,
+            marshtwi,
+            marshdensity,
+            marshintensity,
+            marshminarea,
+            detectwater,
+            waterslope,
+            waterminarea,
+            watersmoothing,
+            detectboulders,
+            boulderresolution,
+            boulderminheight,
+            bouldermaxheight,
+            largeboulderheight,
+            boulderradius,
+            boulderclusterdistance,
+            detectstony,
+            stonyroughness,
+            boulderfieldroughness,
+            stonyminarea,
+            detectpits,
+            pitmindepth,
+            pitmaxdiameter,
+            pitsteepness,
+            detectbanks,
+            bankminheight,
+            bankmaxheight,
+            bankslopebreak,
+            gullymindepth,
+            gullydepth,
+            bankminlength,
+            detectpaths,
+            pathundergrowth,
+            pathrelief,
+            pathintensity,
+            pathminconfidence,
+            pathminlength,
+            detectpowerlines,
+            powerlineminheight,
+            powerlineminlength,
             water_class,
             inidotknolls,
             smoothing,
@@ -429,6 +648,15 @@ impl Config {
             remove_touching_contours,
         })
     }
+
+    /// True if a stage that is run uses the intensity of the points, so that it has to be read.
+    pub fn uses_intensity(&self) -> bool {
+        // the detected features are only drawn on full maps
+        let features = !self.vegeonly && !self.cliffsonly && !self.contoursonly;
+        features
+            && ((self.detectmarshes && self.marshintensity > 0.0)
+                || (self.detectpaths && self.pathintensity > 0.0))
+    }
 }
 
 #[cfg(test)]
@@ -1,24 +1,19 @@
 use log::info;
 use rustc_hash::FxHashMap as HashMap;
 use std::error::Error;
-use std::path::Path;
 
 use crate::config::Config;
 use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Polylines};
-use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
-use crate::io::xyz::XyzInternalReader;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::progress::Progress;
 use crate::vec2d::Vec2D;
 
-/// Create a heightmap from a point cloud file.
+/// Create a heightmap from a point cloud.
 ///
-/// Loads all the points and uses those that are classified as ground or water to create a heightmap using averages.
-pub fn xyz2heightmap(
-    fs: &impl FileSystem,
-    config: &Config,
-    tmpfolder: &Path,
-    xyzfilein: &str, // this should be point cloud in
-) -> Result<HeightMap, Box<dyn Error>> {
+/// Uses those that are classified as ground or water to create a heightmap using averages.
+pub fn xyz2heightmap(config: &Config, points: &PointCloud) -> Result<HeightMap, Box<dyn Error>> {
     info!("Generating heightmap...");
 
     // read all points to find the bounding box
@@ -31,9 +26,8 @@ pub fn xyz2heightmap(
     let mut hmin: f64 = f64::MAX;
     let mut hmax: f64 = f64::MIN;
 
-    let xyz_file_in = tmpfolder.join(xyzfilein);
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-    while let Some(chunk) = reader.next_chunk()? {
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
         for r in chunk {
             let x: f64 = r.x;
             let y: f64 = r.y;
@@ -64,8 +58,6 @@ pub fn xyz2heightmap(
             }
         }
     }
-    drop(reader);
-
     let scale = 2.0 * config.scalefactor;
 
     // align bounding box to a grid with the required scale
@@ -77,12 +69,18 @@ pub fn xyz2heightmap(
     let w: usize = ((xmax - xmin) / scale) as usize + 1;
     let h: usize = ((ymax - ymin) / scale) as usize + 1;
 
+    // the sums and counts, the averages and the resulting heightmap
+    job::reserve(
+        config,
+        "xyz2heightmap",
+        job::grid_bytes::<(f64, usize)>(w, h) + 2 * job::grid_bytes::<f64>(w, h),
+    )?;
+
     // a two-dimensional vector of (sum, count) pairs for computing averages
     let mut list_alt = Vec2D::new(w, h, (0f64, 0usize));
 
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-
-    while let Some(chunk) = reader.next_chunk()? {
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
         for r in chunk {
             if r.classification == 2 || r.classification == config.water_class {
                 let x: f64 = r.x;
@@ -99,8 +97,6 @@ pub fn xyz2heightmap(
         }
     }
 
-    drop(reader);
-
     let mut avg_alt = Vec2D::new(w, h, f64::NAN);
 
     for x in 0..list_alt.width() {
@@ -113,7 +109,10 @@ pub fn xyz2heightmap(
         }
     }
 
+    let mut progress = Progress::new("xyz2heightmap", w as u64);
     for x in 0..avg_alt.width() {
+        progress.inc(1);
+        job::check_cancelled()?;
         for y in 0..avg_alt.height() {
             if avg_alt[(x, y)].is_nan() {
                 // interpolate altitude of pixel
@@ -224,14 +223,11 @@ pub fn xyz2heightmap(
 
 /// Creates contour lines from a heightmap.
 pub fn heightmap2contours(
-    fs: &impl FileSystem,
-    tmpfolder: &Path,
     cinterval: f64,
     heightmap: &HeightMap,
-    dxffile: &str,
-    output_dxf: bool,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<BinaryDxf, Box<dyn Error>> {
     info!("Generating curves...");
+    job::check_cancelled()?;
     let polylines = grid2contours(&heightmap.grid, cinterval);
 
     let xmin = heightmap.xoffset;
@@ -266,21 +262,12 @@ pub fn heightmap2contours(
             Classification::ContourSimple,
         );
     }
-    let dxf = BinaryDxf::new(Bounds::new(xmin, xmax, ymin, ymax), vec![lines.into()]);
-
-    // write to disk
-    let mut f = fs
-        .create(tmpfolder.join(dxffile))
-        .expect("Unable to create file");
-    dxf.to_writer(&mut f).expect("Cannot write binary dxf file");
-
-    if output_dxf {
-        dxf.to_dxf(&mut fs.create(tmpfolder.join(dxffile.strip_suffix(".bin").unwrap()))?)?;
-    }
-
     info!("Done");
 
-    Ok(())
+    Ok(BinaryDxf::new(
+        Bounds::new(xmin, xmax, ymin, ymax),
+        vec![lines.into()],
+    ))
 }
 
 /// Inner function to generate contours from a heightmap.
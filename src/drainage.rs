@@ -0,0 +1,213 @@
+//! Routing of surface water over a heightmap.
+//!
+//! Depressions are first filled with the priority-flood algorithm, raising every cell a tiny bit
+//! above the cell it was flooded from, so that water from every cell reaches the edge of the
+//! grid. Each cell then drains to its steepest lower neighbour (the D8 method), and the catchment
+//! area of a cell is the area of all the cells draining through it.
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::io::heightmap::HeightMap;
+use crate::vec2d::Vec2D;
+
+/// How much a filled cell is raised above the cell it drains to, so filled areas still drain.
+const FILL_EPSILON: f64 = 1e-5;
+
+/// The offsets of the 8 neighbours of a cell.
+pub const NEIGHBOURS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// The drainage of a heightmap.
+pub struct Drainage {
+    /// The heights with the depressions filled.
+    pub filled: Vec2D<f64>,
+    /// The cell each cell drains to, `None` for the cells at the edge and outside of the data.
+    pub receiver: Vec2D<Option<(u32, u32)>>,
+    /// The catchment area of each cell in square meters, including the cell itself.
+    pub area: Vec2D<f64>,
+}
+
+impl Drainage {
+    pub fn new(hmap: &HeightMap) -> Self {
+        let (filled, order) = fill_depressions(&hmap.grid);
+        let (w, h) = (filled.width(), filled.height());
+
+        let mut receiver = Vec2D::new(w, h, None);
+        for x in 0..w {
+            for y in 0..h {
+                let z = filled[(x, y)];
+                if x == 0 || y == 0 || x == w - 1 || y == h - 1 || !z.is_finite() {
+                    continue;
+                }
+                let mut steepest = 0.0;
+                for (dx, dy) in NEIGHBOURS {
+                    let (nx, ny) = ((x as isize + dx) as usize, (y as isize + dy) as usize);
+                    let drop = (z - filled[(nx, ny)]) / ((dx * dx + dy * dy) as f64).sqrt();
+                    if drop > steepest {
+                        steepest = drop;
+                        receiver[(x, y)] = Some((nx as u32, ny as u32));
+                    }
+                }
+            }
+        }
+
+        // the cells were flooded from the lowest up, so visiting them in reverse visits every cell
+        // before the cell it drains to
+        let cell_area = hmap.scale * hmap.scale;
+        let mut area = Vec2D::new(w, h, cell_area);
+        for &(x, y) in order.iter().rev() {
+            let (x, y) = (x as usize, y as usize);
+            if let Some((rx, ry)) = receiver[(x, y)] {
+                area[(rx as usize, ry as usize)] += area[(x, y)];
+            }
+        }
+
+        Self {
+            filled,
+            receiver,
+            area,
+        }
+    }
+
+    /// The depth of the filled depression at a cell, 0 outside of depressions.
+    pub fn fill_depth(&self, hmap: &HeightMap, x: usize, y: usize) -> f64 {
+        self.filled[(x, y)] - hmap.grid[(x, y)]
+    }
+}
+
+/// A cell in the flooding queue, ordered by height.
+#[derive(PartialEq)]
+struct Cell(f64, u32, u32);
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Fills the depressions of a grid, flooding it from the edges and the cells without data.
+/// Returns the filled grid and the order in which the cells were flooded, from the lowest.
+fn fill_depressions(grid: &Vec2D<f64>) -> (Vec2D<f64>, Vec<(u32, u32)>) {
+    let (w, h) = (grid.width(), grid.height());
+    let mut filled = Vec2D::new(w, h, f64::NAN);
+    let mut done = Vec2D::new(w, h, false);
+    let mut order = Vec::with_capacity(w * h);
+    let mut queue = BinaryHeap::new();
+
+    for x in 0..w {
+        for y in 0..h {
+            let z = grid[(x, y)];
+            if !z.is_finite() {
+                done[(x, y)] = true;
+                order.push((x as u32, y as u32));
+            } else if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                done[(x, y)] = true;
+                filled[(x, y)] = z;
+                queue.push(Reverse(Cell(z, x as u32, y as u32)));
+            }
+        }
+    }
+    // the cells next to missing data drain into it like to the edge
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let z = grid[(x, y)];
+            if !done[(x, y)]
+                && NEIGHBOURS.iter().any(|&(dx, dy)| {
+                    !grid[((x as isize + dx) as usize, (y as isize + dy) as usize)].is_finite()
+                })
+            {
+                done[(x, y)] = true;
+                filled[(x, y)] = z;
+                queue.push(Reverse(Cell(z, x as u32, y as u32)));
+            }
+        }
+    }
+
+    while let Some(Reverse(Cell(z, x, y))) = queue.pop() {
+        order.push((x, y));
+        for (dx, dy) in NEIGHBOURS {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < 0 || ny < 0 || nx >= w as isize || ny >= h as isize {
+                continue;
+            }
+            let (nx, ny) = (nx as usize, ny as usize);
+            if done[(nx, ny)] {
+                continue;
+            }
+            done[(nx, ny)] = true;
+            let nz = grid[(nx, ny)].max(z + FILL_EPSILON);
+            filled[(nx, ny)] = nz;
+            queue.push(Reverse(Cell(nz, nx as u32, ny as u32)));
+        }
+    }
+    (filled, order)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A heightmap sloping down in the x-direction with a pit at (5, 5).
+    fn heightmap() -> HeightMap {
+        let mut grid = Vec2D::new(10, 10, 0.0);
+        for x in 0..10 {
+            for y in 0..10 {
+                grid[(x, y)] = x as f64 + (y as f64 - 4.5).abs() * 0.1;
+            }
+        }
+        grid[(5, 5)] = 0.0;
+        HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        }
+    }
+
+    #[test]
+    fn test_depressions_are_filled() {
+        let hmap = heightmap();
+        let drainage = Drainage::new(&hmap);
+        assert!(drainage.fill_depth(&hmap, 5, 5) > 3.9);
+        assert_eq!(drainage.fill_depth(&hmap, 5, 2), 0.0);
+        // every inner cell drains somewhere
+        for x in 1..9 {
+            for y in 1..9 {
+                let (rx, ry) = drainage.receiver[(x, y)].unwrap();
+                assert!(drainage.filled[(rx as usize, ry as usize)] < drainage.filled[(x, y)]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_catchment_area() {
+        let hmap = heightmap();
+        let drainage = Drainage::new(&hmap);
+        // the valley bottom collects the water of the cells uphill from it
+        assert!(drainage.area[(1, 4)] > drainage.area[(8, 4)] * 4.0);
+        assert_eq!(drainage.area[(8, 0)], 4.0);
+        // all water ends up at the edge
+        let total: f64 = (0..10)
+            .flat_map(|x| (0..10).map(move |y| (x, y)))
+            .filter(|&(x, y)| drainage.receiver[(x, y)].is_none())
+            .map(|(x, y)| drainage.area[(x, y)])
+            .sum();
+        assert_eq!(total, 400.0);
+    }
+}
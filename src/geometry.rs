@@ -2,6 +2,9 @@
 //! and a list of Points.
 //!
 //! These types also have helpers for exporting them to DXF format.
+use std::path::Path;
+
+use crate::io::fs::FileSystem;
 
 /// A 2D point
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -141,7 +144,8 @@ pub enum Geometry {
     /// Polylines2 is used for 2D polylines with a classification.
     Polylines2(Polylines<Point2, Classification>),
 
-    /// Polylines3 is used for 2D polylines with a height (z coordinate).
+    /// Polylines3 is used for 2D polylines with a height (z coordinate), or another value like the
+    /// confidence of a path.
     Polylines3(Polylines<Point3, (Classification, f64)>), // Classification + height
 }
 
@@ -165,7 +169,7 @@ impl From<Polylines<Point3, (Classification, f64)>> for Geometry {
 /// including any sub-fields (basically anything in this mod) we need to increase this version.
 const BINARY_DXF_VERSION: usize = 1;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct BinaryDxf {
     /// the version of the program that created this file, used to detect stale temp files
     version: String,
@@ -173,7 +177,7 @@ pub struct BinaryDxf {
     data: Vec<Geometry>,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Bounds {
     pub xmin: f64,
     pub xmax: f64,
@@ -205,11 +209,37 @@ impl BinaryDxf {
         &self.bounds
     }
 
+    /// The geometries of this file.
+    pub fn geometry(&self) -> &[Geometry] {
+        &self.data
+    }
+
     /// Get the points in this geometry, or [`None`] if does not contain [`Polylines`] data.
     pub fn take_geometry(self) -> Vec<Geometry> {
         self.data
     }
 
+    /// Helper for easily reading this object from a `.dxf.bin` file.
+    pub fn from_file(fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_reader(&mut fs.open(path)?)
+    }
+
+    /// Helper for easily writing this object to a `.dxf.bin` file, and as a DXF file without the
+    /// `.bin` extension next to it if `output_dxf` is set.
+    pub fn to_file(
+        &self,
+        fs: &impl FileSystem,
+        path: impl AsRef<Path>,
+        output_dxf: bool,
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        self.to_writer(&mut fs.create(path)?)?;
+        if output_dxf {
+            self.to_dxf(&mut fs.create(path.with_extension(""))?)?;
+        }
+        Ok(())
+    }
+
     /// Serialize this object to a writer.
     pub fn to_writer<W: std::io::Write>(&self, writer: &mut W) -> anyhow::Result<()> {
         crate::util::write_object(writer, self)
@@ -334,6 +364,44 @@ pub enum Classification {
     Cliff2,
     Cliff3,
     Cliff4,
+
+    /// Used for stream detection
+    Ditch,
+    MinorStream,
+    Stream,
+
+    /// Used for marsh detection
+    Marsh,
+
+    /// Used for water body detection
+    Water,
+
+    /// Used for boulder detection
+    Boulder,
+    LargeBoulder,
+    BoulderCluster,
+
+    /// Used for stony ground detection
+    StonyGround,
+    BoulderField,
+
+    /// Used for pit detection, along with `Udepression` for small depressions
+    Pit,
+
+    /// Used for earth bank and erosion gully detection
+    EarthBank,
+    ErosionGully,
+    SmallErosionGully,
+
+    /// Used for path detection
+    Path,
+
+    /// Used for building footprints
+    Building,
+
+    /// Used for power line detection
+    PowerLine,
+    Pylon,
 }
 
 impl Classification {
@@ -365,6 +433,32 @@ impl Classification {
             Self::Cliff2 => "cliff2",
             Self::Cliff3 => "cliff3",
             Self::Cliff4 => "cliff4",
+
+            Self::Ditch => "ditch",
+            Self::MinorStream => "minor_stream",
+            Self::Stream => "stream",
+
+            Self::Marsh => "marsh",
+
+            Self::Water => "water",
+
+            Self::Boulder => "boulder",
+            Self::LargeBoulder => "large_boulder",
+            Self::BoulderCluster => "boulder_cluster",
+
+            Self::StonyGround => "stony_ground",
+            Self::BoulderField => "boulder_field",
+
+            Self::Pit => "pit",
+
+            Self::EarthBank => "earth_bank",
+            Self::ErosionGully => "erosion_gully",
+            Self::SmallErosionGully => "small_erosion_gully",
+
+            Self::Path => "path",
+            Self::Building => "building",
+            Self::PowerLine => "power_line",
+            Self::Pylon => "pylon",
         }
     }
 
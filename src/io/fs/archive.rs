@@ -0,0 +1,428 @@
+use anyhow::Context;
+use rustc_hash::FxHashMap as HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::{Either, FileSystem};
+
+/// The default amount of decompressed file data kept in memory by a [`ZipFileSystem`].
+const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A read-only [`FileSystem`] view of the contents of a zip archive on the local file system.
+///
+/// Files stored without compression are read directly from the archive. Compressed files are
+/// decompressed into memory when opened, and the most recently used ones are cached so that
+/// repeatedly opening the same file (for example to read the header of a LAZ file) is cheap.
+///
+/// This object is thread-safe and can be shared between threads. Uses [`Arc`] internally so it is
+/// cheap to clone.
+#[derive(Debug, Clone)]
+pub struct ZipFileSystem {
+    inner: Arc<Inner>,
+    /// The directory of the archive that is the root of this view.
+    root: PathBuf,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    files: HashMap<PathBuf, Entry>,
+    /// The names of the files and directories in each directory, including implicit ones.
+    dirs: HashMap<PathBuf, Vec<OsString>>,
+    archive: Mutex<zip::ZipArchive<BufReader<File>>>,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    index: usize,
+    size: u64,
+    /// The offset of the data in the archive if it is stored without compression.
+    stored_at: Option<u64>,
+}
+
+impl ZipFileSystem {
+    /// Open a zip archive.
+    pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::with_cache_size(path, DEFAULT_CACHE_SIZE)
+    }
+
+    /// Open a zip archive, keeping at most `cache_size` bytes of decompressed files in memory.
+    pub fn with_cache_size(path: impl AsRef<Path>, cache_size: u64) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        let mut archive =
+            zip::ZipArchive::new(BufReader::new(file)).context("reading zip archive")?;
+
+        let mut files = HashMap::default();
+        let mut dirs: HashMap<PathBuf, Vec<OsString>> = HashMap::default();
+        dirs.insert(PathBuf::new(), Vec::new());
+        for index in 0..archive.len() {
+            let file = archive
+                .by_index_raw(index)
+                .with_context(|| format!("get file index {index}"))?;
+            let Some(name) = file.enclosed_name() else {
+                log::warn!(
+                    "Skipping file {} in zip archive as it is not a valid path",
+                    file.name(),
+                );
+                continue;
+            };
+            let name = normalize(&name);
+            if name.as_os_str().is_empty() || file.is_symlink() || file.encrypted() {
+                continue;
+            }
+
+            // register the entry and all its parents in their parent directories
+            let mut child = name.clone();
+            while let Some(parent) = child.parent() {
+                let siblings = dirs.entry(parent.to_path_buf()).or_default();
+                let child_name = child.file_name().unwrap().to_os_string();
+                if !siblings.contains(&child_name) {
+                    siblings.push(child_name);
+                }
+                child = parent.to_path_buf();
+            }
+
+            if file.is_dir() {
+                dirs.entry(name).or_default();
+            } else {
+                let stored_at = match file.compression() {
+                    zip::CompressionMethod::Stored => file.data_start(),
+                    _ => None,
+                };
+                files.insert(
+                    name,
+                    Entry {
+                        index,
+                        size: file.size(),
+                        stored_at,
+                    },
+                );
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                path,
+                files,
+                dirs,
+                archive: Mutex::new(archive),
+                cache: Mutex::new(Cache {
+                    capacity: cache_size,
+                    entries: Vec::new(),
+                }),
+            }),
+            root: PathBuf::new(),
+        })
+    }
+
+    /// A view of a directory of the archive, sharing the archive and its cache with this one.
+    pub fn subdir(&self, dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            root: self.resolve(dir.as_ref()),
+        }
+    }
+
+    /// The path of an entry in the archive.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        normalize(&self.root.join(path))
+    }
+
+    fn entry(&self, path: &Path) -> Result<&Entry, io::Error> {
+        self.inner
+            .files
+            .get(&self.resolve(path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))
+    }
+
+    /// Get the decompressed data of a file, from the cache if possible.
+    fn decompress(&self, path: &Path, entry: &Entry) -> Result<SharedData, io::Error> {
+        let mut cache = self.inner.cache.lock().expect("cache lock poisoned");
+        if let Some(data) = cache.get(entry.index) {
+            return Ok(data);
+        }
+        drop(cache);
+
+        log::debug!(
+            "Decompressing {} from {}",
+            path.display(),
+            self.inner.path.display()
+        );
+        let mut archive = self.inner.archive.lock().expect("archive lock poisoned");
+        let mut file = archive.by_index(entry.index).map_err(io::Error::other)?;
+        let mut data = Vec::with_capacity(entry.size as usize);
+        file.read_to_end(&mut data)?;
+        drop(file);
+        drop(archive);
+
+        let data = SharedData(Arc::new(data));
+        let mut cache = self.inner.cache.lock().expect("cache lock poisoned");
+        cache.insert(entry.index, data.clone());
+        Ok(data)
+    }
+}
+
+/// Remove "." and "/" components so that paths can be looked up.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ReadOnlyFilesystem,
+        "zip file system is read-only",
+    )
+}
+
+/// Decompressed file data, shared between the cache and the readers.
+#[derive(Debug, Clone)]
+struct SharedData(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The most recently used decompressed files, the most recent last.
+#[derive(Debug)]
+struct Cache {
+    capacity: u64,
+    entries: Vec<(usize, SharedData)>,
+}
+
+impl Cache {
+    fn get(&mut self, index: usize) -> Option<SharedData> {
+        let position = self.entries.iter().position(|(i, _)| *i == index)?;
+        let entry = self.entries.remove(position);
+        let data = entry.1.clone();
+        self.entries.push(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, index: usize, data: SharedData) {
+        self.entries.retain(|(i, _)| *i != index);
+        self.entries.push((index, data));
+        let mut size: u64 = self.entries.iter().map(|(_, d)| d.0.len() as u64).sum();
+        // always keep the newest entry, even if it is larger than the cache
+        while size > self.capacity && self.entries.len() > 1 {
+            size -= self.entries.remove(0).1.0.len() as u64;
+        }
+    }
+}
+
+/// A reader of a part of a file, used for files stored without compression.
+#[derive(Debug)]
+struct Window {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for Window {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let n = (buf.len() as u64).min(remaining) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Window {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = pos as u64;
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        Ok(self.pos)
+    }
+}
+
+impl FileSystem for ZipFileSystem {
+    fn create_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        if self.inner.dirs.contains_key(&self.resolve(path.as_ref())) {
+            return Ok(());
+        }
+        Err(read_only())
+    }
+
+    fn list(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>, io::Error> {
+        let path = path.as_ref();
+        let names = self
+            .inner
+            .dirs
+            .get(&self.resolve(path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "directory not found"))?;
+        Ok(names.iter().map(|name| path.join(name)).collect())
+    }
+
+    fn exists(&self, path: impl AsRef<Path>) -> bool {
+        let path = self.resolve(path.as_ref());
+        self.inner.files.contains_key(&path) || self.inner.dirs.contains_key(&path)
+    }
+
+    fn open(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
+        let path = path.as_ref();
+        let entry = self.entry(path)?;
+        match entry.stored_at {
+            Some(start) => {
+                let mut file = File::open(&self.inner.path)?;
+                file.seek(SeekFrom::Start(start))?;
+                Ok(Either::Left(BufReader::with_capacity(
+                    crate::ONE_MEGABYTE,
+                    Window {
+                        file,
+                        start,
+                        len: entry.size,
+                        pos: 0,
+                    },
+                )))
+            }
+            None => Ok(Either::Right(io::Cursor::new(
+                self.decompress(path, entry)?,
+            ))),
+        }
+    }
+
+    fn create(&self, _path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        Err::<io::Cursor<Vec<u8>>, _>(read_only())
+    }
+
+    fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error> {
+        let mut data = String::new();
+        self.open(path)?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+
+    fn remove_file(&self, _path: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn remove_dir_all(&self, _path: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn file_size(&self, path: impl AsRef<Path>) -> Result<u64, io::Error> {
+        Ok(self.entry(path.as_ref())?.size)
+    }
+
+    fn copy(&self, _from: impl AsRef<Path>, _to: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn extract_zip(
+        &self,
+        _archive: impl AsRef<Path>,
+        _target: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        Err(read_only().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use zip::write::SimpleFileOptions;
+
+    /// Writes a zip archive with a stored and a compressed file, unique to each test.
+    fn write_archive(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "pullauta_archive_{name}_{}.zip",
+            std::process::id()
+        ));
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let deflated =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        writer.start_file("stored.txt", stored).unwrap();
+        writer.write_all(b"stored contents").unwrap();
+        writer.start_file("shapes/deflated.txt", deflated).unwrap();
+        writer.write_all(&b"deflated ".repeat(100)).unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_files() {
+        let path = write_archive("read");
+        let fs = ZipFileSystem::new(&path).unwrap();
+
+        assert_eq!(fs.read_to_string("stored.txt").unwrap(), "stored contents");
+        assert_eq!(
+            fs.read_to_string("./shapes/deflated.txt").unwrap(),
+            "deflated ".repeat(100)
+        );
+        assert_eq!(fs.file_size("shapes/deflated.txt").unwrap(), 900);
+
+        let mut reader = fs.open("stored.txt").unwrap();
+        reader.seek(SeekFrom::Start(7)).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "contents");
+
+        assert!(fs.create("new.txt").is_err());
+        assert!(fs.remove_file("stored.txt").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_list() {
+        let path = write_archive("list");
+        let fs = ZipFileSystem::new(&path).unwrap();
+
+        let mut root = fs.list("").unwrap();
+        root.sort();
+        assert_eq!(root, [PathBuf::from("shapes"), PathBuf::from("stored.txt")]);
+        assert_eq!(
+            fs.list("shapes").unwrap(),
+            [PathBuf::from("shapes/deflated.txt")]
+        );
+        assert!(fs.exists("shapes"));
+        assert!(!fs.exists("missing.txt"));
+        assert!(fs.list("missing").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_subdir() {
+        let path = write_archive("subdir");
+        let fs = ZipFileSystem::new(&path).unwrap().subdir("shapes");
+
+        assert_eq!(fs.list("").unwrap(), [PathBuf::from("deflated.txt")]);
+        assert_eq!(
+            fs.read_to_string("deflated.txt").unwrap(),
+            "deflated ".repeat(100)
+        );
+        assert_eq!(fs.file_size("./deflated.txt").unwrap(), 900);
+        assert!(!fs.exists("stored.txt"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
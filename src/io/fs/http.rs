@@ -0,0 +1,747 @@
+use rustc_hash::FxHashMap as HashMap;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use super::FileSystem;
+
+/// The default size of the blocks requested from the server.
+const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// The default amount of downloaded data kept in memory.
+const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A read-only [`FileSystem`] reading files from a HTTP server, such as an object store serving
+/// point cloud tiles.
+///
+/// Files are downloaded in blocks with HTTP range requests when they are read, and the most
+/// recently used blocks are cached in memory so that reading the header of a file and then its
+/// points does not download anything twice. From servers without range requests, whole files are
+/// downloaded once and cached if they fit in the cache. Directories are listed from the HTML index
+/// pages most static file servers generate, or with the S3 `ListObjectsV2` API if there is no
+/// index page.
+///
+/// This object is thread-safe and can be shared between threads. Uses [`Arc`] internally so it is
+/// cheap to clone.
+#[derive(Debug, Clone)]
+pub struct HttpFileSystem {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The URL of the root directory, without a trailing slash.
+    base_url: String,
+    agent: ureq::Agent,
+    block_size: u64,
+    /// The sizes of the files looked up so far, `None` for missing files.
+    sizes: RwLock<HashMap<PathBuf, Option<u64>>>,
+    cache: Mutex<BlockCache>,
+}
+
+impl HttpFileSystem {
+    /// Create a file system for the files under `base_url`.
+    pub fn new(base_url: &str) -> Self {
+        Self::with_cache(base_url, DEFAULT_BLOCK_SIZE, DEFAULT_CACHE_SIZE)
+    }
+
+    /// Create a file system downloading `block_size` bytes at a time and keeping at most
+    /// `cache_size` bytes of downloaded blocks in memory.
+    pub fn with_cache(base_url: &str, block_size: u64, cache_size: u64) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(300))
+            .build();
+        Self {
+            inner: Arc::new(Inner {
+                base_url: base_url.trim_end_matches('/').to_string(),
+                agent,
+                block_size: block_size.max(1),
+                sizes: RwLock::new(HashMap::default()),
+                cache: Mutex::new(BlockCache {
+                    capacity: cache_size,
+                    size: 0,
+                    clock: 0,
+                    blocks: HashMap::default(),
+                }),
+            }),
+        }
+    }
+
+    fn url(&self, path: &Path) -> String {
+        let mut url = self.inner.base_url.clone();
+        for segment in segments(path) {
+            url.push('/');
+            url.push_str(&encode(&segment));
+        }
+        url
+    }
+
+    /// Get the size of a file, or `None` if it does not exist.
+    fn size(&self, path: &Path) -> io::Result<Option<u64>> {
+        let path = normalize(path);
+        if let Some(size) = self
+            .inner
+            .sizes
+            .read()
+            .expect("sizes lock poisoned")
+            .get(&path)
+        {
+            return Ok(*size);
+        }
+
+        let size = match self.inner.agent.head(&self.url(&path)).call() {
+            Ok(response) => Some(
+                response
+                    .header("Content-Length")
+                    .and_then(|l| l.parse::<u64>().ok())
+                    .ok_or_else(|| io::Error::other("missing Content-Length in response"))?,
+            ),
+            Err(ureq::Error::Status(403 | 404, _)) => None,
+            Err(e) => return Err(to_io_error(e)),
+        };
+        self.inner
+            .sizes
+            .write()
+            .expect("sizes lock poisoned")
+            .insert(path, size);
+        Ok(size)
+    }
+
+    /// Get a block of a file, downloading it if it is not in the cache.
+    fn block(&self, path: &Path, index: u64, len: u64) -> io::Result<Arc<Vec<u8>>> {
+        let key = (path.to_path_buf(), index);
+        if let Some(block) = self
+            .inner
+            .cache
+            .lock()
+            .expect("cache lock poisoned")
+            .get(&key)
+        {
+            return Ok(block);
+        }
+
+        let block_size = self.inner.block_size;
+        let start = index * block_size;
+        let end = (start + block_size).min(len);
+        log::trace!("Downloading bytes {start}-{end} of {}", path.display());
+        let response = self
+            .inner
+            .agent
+            .get(&self.url(path))
+            .set("Range", &format!("bytes={start}-{}", end - 1))
+            .call()
+            .map_err(to_io_error)?;
+        if response.status() != 206 {
+            return self.whole_file(path, index, len, response);
+        }
+
+        let mut data = Vec::with_capacity((end - start) as usize);
+        response
+            .into_reader()
+            .take(end - start)
+            .read_to_end(&mut data)?;
+        if (data.len() as u64) < end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file too short",
+            ));
+        }
+
+        // only lock the cache once the data is in memory
+        let data = Arc::new(data);
+        self.inner
+            .cache
+            .lock()
+            .expect("cache lock poisoned")
+            .insert(key, data.clone());
+        Ok(data)
+    }
+
+    /// Cache all the blocks of a file from a response with the whole file, sent by servers without
+    /// support for range requests, and return the block at `index`.
+    fn whole_file(
+        &self,
+        path: &Path,
+        index: u64,
+        len: u64,
+        response: ureq::Response,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        let capacity = self
+            .inner
+            .cache
+            .lock()
+            .expect("cache lock poisoned")
+            .capacity;
+        if len > capacity {
+            return Err(io::Error::other(format!(
+                "the server does not support range requests, and {} ({len} bytes) does not fit \
+                 in the cache of {capacity} bytes",
+                path.display()
+            )));
+        }
+        log::debug!(
+            "The server does not support range requests, downloading all of {}",
+            path.display()
+        );
+
+        let mut data = Vec::with_capacity(len as usize);
+        response.into_reader().take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file too short",
+            ));
+        }
+
+        let blocks: Vec<Arc<Vec<u8>>> = data
+            .chunks(self.inner.block_size as usize)
+            .map(|block| Arc::new(block.to_vec()))
+            .collect();
+        let mut cache = self.inner.cache.lock().expect("cache lock poisoned");
+        for (i, block) in blocks.iter().enumerate() {
+            cache.insert((path.to_path_buf(), i as u64), block.clone());
+        }
+        Ok(blocks[index as usize].clone())
+    }
+
+    /// List a directory from the index page served for it.
+    fn list_index(&self, path: &Path) -> io::Result<Vec<String>> {
+        let url = format!("{}/", self.url(path));
+        let body = self
+            .inner
+            .agent
+            .get(&url)
+            .call()
+            .map_err(to_io_error)?
+            .into_string()?;
+
+        let mut names: Vec<String> = Vec::new();
+        for href in body.split("href=\"").skip(1) {
+            let Some(href) = href.split('"').next() else {
+                continue;
+            };
+            // only relative links to the files and directories in this directory
+            if href.is_empty()
+                || href.starts_with(['?', '#', '/', '.'])
+                || href.contains("://")
+                || href.trim_end_matches('/').contains('/')
+            {
+                continue;
+            }
+            let name = decode(href.trim_end_matches('/'));
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    /// List a directory with the S3 `ListObjectsV2` API, recording the sizes of the files.
+    fn list_objects(&self, path: &Path) -> io::Result<Vec<String>> {
+        let mut prefix = segments(path).join("/");
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+
+        let mut names = Vec::new();
+        let mut sizes = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut request = self
+                .inner
+                .agent
+                .get(&format!("{}/", self.inner.base_url))
+                .query("list-type", "2")
+                .query("delimiter", "/")
+                .query("prefix", &prefix);
+            if let Some(token) = &continuation {
+                request = request.query("continuation-token", token);
+            }
+            let body = request.call().map_err(to_io_error)?.into_string()?;
+            if !body.contains("<ListBucketResult") {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "directory not found",
+                ));
+            }
+
+            for contents in elements(&body, "Contents") {
+                let Some(key) = elements(contents, "Key").next() else {
+                    continue;
+                };
+                let name = unescape(key.strip_prefix(prefix.as_str()).unwrap_or(key));
+                if name.is_empty() {
+                    continue;
+                }
+                if let Some(size) = elements(contents, "Size").next() {
+                    sizes.push((name.clone(), size.parse::<u64>().ok()));
+                }
+                names.push(name);
+            }
+            for common in elements(&body, "CommonPrefixes") {
+                if let Some(dir) = elements(common, "Prefix").next() {
+                    let name = unescape(dir.strip_prefix(prefix.as_str()).unwrap_or(dir));
+                    names.push(name.trim_end_matches('/').to_string());
+                }
+            }
+
+            continuation = elements(&body, "NextContinuationToken")
+                .next()
+                .map(unescape);
+            if continuation.is_none() {
+                break;
+            }
+        }
+
+        let mut cached = self.inner.sizes.write().expect("sizes lock poisoned");
+        for (name, size) in sizes {
+            if size.is_some() {
+                cached.insert(normalize(path).join(name), size);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// A file and the index of a block in it.
+type BlockKey = (PathBuf, u64);
+
+/// The downloaded blocks, evicting the least recently used ones when full.
+#[derive(Debug)]
+struct BlockCache {
+    capacity: u64,
+    size: u64,
+    clock: u64,
+    /// The data of each block and when it was last used.
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+}
+
+impl BlockCache {
+    fn get(&mut self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let (data, last_used) = self.blocks.get_mut(key)?;
+        *last_used = self.clock;
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, data: Arc<Vec<u8>>) {
+        self.clock += 1;
+        self.size += data.len() as u64;
+        if let Some((old, _)) = self.blocks.insert(key, (data, self.clock)) {
+            self.size -= old.len() as u64;
+        }
+        // always keep the newest block, even if it is larger than the cache
+        while self.size > self.capacity && self.blocks.len() > 1 {
+            let oldest = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            let (old, _) = self.blocks.remove(&oldest).unwrap();
+            self.size -= old.len() as u64;
+        }
+    }
+}
+
+/// A reader of a remote file, downloading the blocks as they are needed.
+#[derive(Debug)]
+struct HttpReader {
+    fs: HttpFileSystem,
+    path: PathBuf,
+    len: u64,
+    pos: u64,
+    block: Option<(u64, Arc<Vec<u8>>)>,
+}
+
+impl BufRead for HttpReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.len {
+            return Ok(&[]);
+        }
+        let block_size = self.fs.inner.block_size;
+        let index = self.pos / block_size;
+        if !matches!(&self.block, Some((i, _)) if *i == index) {
+            let block = self.fs.block(&self.path, index, self.len)?;
+            self.block = Some((index, block));
+        }
+        let (_, block) = self.block.as_ref().unwrap();
+        let offset = ((self.pos - index * block_size) as usize).min(block.len());
+        Ok(&block[offset..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    segments(path).into_iter().collect()
+}
+
+fn segments(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Percent-encode a path segment.
+fn encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Decode a percent-encoded link.
+fn decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    unescape(&String::from_utf8_lossy(&decoded))
+}
+
+/// Replace the XML and HTML entities that can appear in names.
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The contents of the XML elements with the given name.
+fn elements<'a>(xml: &'a str, name: &str) -> std::vec::IntoIter<&'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|s| s.split(close.as_str()).next())
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+fn to_io_error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::Status(404, response) => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found", response.get_url()),
+        ),
+        e => io::Error::other(e),
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ReadOnlyFilesystem,
+        "http file system is read-only",
+    )
+}
+
+impl FileSystem for HttpFileSystem {
+    fn create_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        if self.exists(path) {
+            return Ok(());
+        }
+        Err(read_only())
+    }
+
+    fn list(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>, io::Error> {
+        let path = path.as_ref();
+        let names = match self.list_index(path) {
+            Ok(names) => names,
+            Err(e) => {
+                log::debug!("No index page for {}: {e}", path.display());
+                self.list_objects(path)?
+            }
+        };
+        Ok(names.iter().map(|name| path.join(name)).collect())
+    }
+
+    fn exists(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        if segments(path).is_empty() {
+            return true;
+        }
+        match self.size(path) {
+            Ok(Some(_)) => true,
+            Ok(None) => self.list(path).is_ok(),
+            Err(e) => {
+                log::warn!("Could not check if {} exists: {e}", path.display());
+                false
+            }
+        }
+    }
+
+    fn open(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
+        let path = normalize(path.as_ref());
+        let len = self
+            .size(&path)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        Ok(HttpReader {
+            fs: self.clone(),
+            path,
+            len,
+            pos: 0,
+            block: None,
+        })
+    }
+
+    fn create(&self, _path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        Err::<io::Cursor<Vec<u8>>, _>(read_only())
+    }
+
+    fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error> {
+        let mut data = String::new();
+        self.open(path)?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+
+    fn remove_file(&self, _path: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn remove_dir_all(&self, _path: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn file_size(&self, path: impl AsRef<Path>) -> Result<u64, io::Error> {
+        self.size(path.as_ref())?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))
+    }
+
+    fn copy(&self, _from: impl AsRef<Path>, _to: impl AsRef<Path>) -> Result<(), io::Error> {
+        Err(read_only())
+    }
+
+    fn extract_zip(
+        &self,
+        _archive: impl AsRef<Path>,
+        _target: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        Err(read_only().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves the files of a directory with index pages like a static file server, counting the
+    /// requests made. Range requests are supported if `ranges` is set.
+    fn serve(root: PathBuf, ranges: bool) -> (String, Arc<AtomicUsize>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let path = root.join(decode(request.url().trim_start_matches('/')));
+                if request.url().ends_with('/') && path.is_dir() {
+                    let mut index = String::from("<html><a href=\"../\">../</a>");
+                    for entry in std::fs::read_dir(&path).unwrap() {
+                        let entry = entry.unwrap();
+                        let mut name = encode(&entry.file_name().to_string_lossy());
+                        if entry.path().is_dir() {
+                            name.push('/');
+                        }
+                        index.push_str(&format!("<a href=\"{name}\">{name}</a>"));
+                    }
+                    let _ = request.respond(tiny_http::Response::from_string(index));
+                    continue;
+                }
+                let Ok(data) = std::fs::read(&path) else {
+                    let _ = request.respond(tiny_http::Response::empty(404));
+                    continue;
+                };
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|h| ranges && h.field.equiv("Range"))
+                    .map(|h| h.value.as_str().trim_start_matches("bytes=").to_string());
+                let response = match range.as_deref().and_then(|r| r.split_once('-')) {
+                    Some((start, end)) => {
+                        let (start, end) = (start.parse().unwrap(), end.parse::<usize>().unwrap());
+                        let end = end.min(data.len() - 1);
+                        let range = format!("bytes {start}-{end}/{}", data.len());
+                        tiny_http::Response::from_data(data[start..=end].to_vec())
+                            .with_status_code(206)
+                            .with_header(
+                                tiny_http::Header::from_bytes("Content-Range", range).unwrap(),
+                            )
+                    }
+                    None => tiny_http::Response::from_data(data),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        (url, requests)
+    }
+
+    fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pullauta_http_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("tiles")).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        std::fs::write(dir.join("tiles").join("tile 1.laz"), &data).unwrap();
+        std::fs::write(dir.join("readme.txt"), "remote tiles").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_range_reads_are_cached() {
+        let dir = setup("range");
+        let (url, requests) = serve(dir.clone(), true);
+        let fs = HttpFileSystem::with_cache(&url, 16, 1024);
+        let tile = Path::new("tiles/tile 1.laz");
+
+        assert_eq!(fs.file_size(tile).unwrap(), 100);
+        let mut reader = fs.open(tile).unwrap();
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        let mut buf = [0u8; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), (10..30).collect::<Vec<u8>>());
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, (0..100).collect::<Vec<u8>>());
+
+        // one HEAD request and one request for each of the 7 blocks
+        assert_eq!(requests.load(Ordering::SeqCst), 8);
+        let mut data = Vec::new();
+        fs.open(tile).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 8);
+
+        assert_eq!(fs.read_to_string("readme.txt").unwrap(), "remote tiles");
+        assert!(!fs.exists("missing.laz"));
+        assert_eq!(
+            fs.open("missing.laz").err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(fs.create("new.txt").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_whole_file_responses() {
+        let dir = setup("whole");
+        let (url, requests) = serve(dir.clone(), false);
+        let fs = HttpFileSystem::with_cache(&url, 16, 1024);
+        let tile = Path::new("tiles/tile 1.laz");
+
+        let mut reader = fs.open(tile).unwrap();
+        reader.seek(SeekFrom::Start(40)).unwrap();
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), (40..48).collect::<Vec<u8>>());
+        // one HEAD request and one for the whole file, which is cached
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, (0..100).collect::<Vec<u8>>());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // files larger than the cache cannot be read without range requests
+        let fs = HttpFileSystem::with_cache(&url, 16, 64);
+        let error = fs.open(tile).unwrap().read_to_end(&mut data).unwrap_err();
+        assert!(error.to_string().contains("range requests"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_index_pages() {
+        let dir = setup("list");
+        let (url, _) = serve(dir.clone(), true);
+        let fs = HttpFileSystem::new(&url);
+
+        let mut root = fs.list("").unwrap();
+        root.sort();
+        assert_eq!(root, [PathBuf::from("readme.txt"), PathBuf::from("tiles")]);
+        assert_eq!(
+            fs.list("tiles").unwrap(),
+            [PathBuf::from("tiles/tile 1.laz")]
+        );
+        assert!(fs.exists("tiles"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_object_listing() {
+        let xml = "<ListBucketResult><Prefix>tiles/</Prefix>\
+            <Contents><Key>tiles/a&amp;b.laz</Key><Size>42</Size></Contents>\
+            <CommonPrefixes><Prefix>tiles/sub/</Prefix></CommonPrefixes></ListBucketResult>";
+        let keys: Vec<_> = elements(xml, "Contents")
+            .filter_map(|c| elements(c, "Key").next())
+            .map(unescape)
+            .collect();
+        assert_eq!(keys, ["tiles/a&b.laz"]);
+        let sizes: Vec<_> = elements(xml, "Size").collect();
+        assert_eq!(sizes, ["42"]);
+        assert_eq!(decode("tile%201.laz"), "tile 1.laz");
+        assert_eq!(encode("tile 1.laz"), "tile%201.laz");
+    }
+}
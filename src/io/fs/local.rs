@@ -1,5 +1,6 @@
 use std::io::{self, BufRead, BufReader, BufWriter, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use anyhow::Context;
 
@@ -35,7 +36,7 @@ impl FileSystem for LocalFileSystem {
     fn open(
         &self,
         path: impl AsRef<Path>,
-    ) -> Result<impl BufRead + Seek + Send + 'static, io::Error> {
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
         Ok(BufReader::with_capacity(
             crate::ONE_MEGABYTE,
             std::fs::File::open(path)?,
@@ -49,6 +50,13 @@ impl FileSystem for LocalFileSystem {
         ))
     }
 
+    fn create_new(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        Ok(BufWriter::with_capacity(
+            crate::ONE_MEGABYTE,
+            std::fs::File::create_new(path)?,
+        ))
+    }
+
     fn remove_file(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
         std::fs::remove_file(path)
     }
@@ -67,6 +75,14 @@ impl FileSystem for LocalFileSystem {
         Ok(())
     }
 
+    fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        std::fs::rename(from, to)
+    }
+
+    fn modified(&self, path: impl AsRef<Path>) -> Result<SystemTime, io::Error> {
+        std::fs::metadata(path)?.modified()
+    }
+
     fn extract_zip(
         &self,
         archive: impl AsRef<Path>,
@@ -1,20 +1,47 @@
-use super::FileSystem;
-use anyhow::Context;
+use super::{Either, FileSystem};
+use log::{debug, warn};
 use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
 
 use core::str;
 use std::io::{self, BufRead, Seek, Write};
 use std::path::{Component, Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::SystemTime;
 
 /// An in-memory implementation of [`FileSystem`] for use whenever there is no access to a local
 /// file system (such as on WASM), or to speed up the processing when there is a lot of RAM available.
 ///
+/// The memory use can be limited with [`MemoryFileSystem::with_budget`], in which case the least
+/// recently used files are moved to a scratch directory on disk whenever the budget is exceeded.
+///
 /// This object is thread-safe and can be shared between threads. Uses [`Arc`] internally so it is
 /// cheap to clone.
 #[derive(Debug, Clone)]
 pub struct MemoryFileSystem {
     root: Arc<RwLock<Root>>,
+    /// Keeps track of the memory use of all files, and spills them to disk.
+    spill: Arc<Spill>,
+    /// Incremented on every access, for finding the least recently used files.
+    clock: Arc<AtomicU64>,
+}
+
+/// Statistics of the memory use of a [`MemoryFileSystem`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Number of files.
+    pub files: usize,
+    /// Bytes of file data kept in memory.
+    pub memory_bytes: u64,
+    /// The highest value of `memory_bytes` so far.
+    pub peak_memory_bytes: u64,
+    /// Number of files currently spilled to disk.
+    pub spilled_files: usize,
+    /// Bytes of file data currently spilled to disk.
+    pub spilled_bytes: u64,
+    /// Number of times a file has been spilled to disk.
+    pub spills: u64,
 }
 
 impl Default for MemoryFileSystem {
@@ -26,9 +53,115 @@ impl Default for MemoryFileSystem {
 impl MemoryFileSystem {
     /// Create a new empty memory file system.
     pub fn new() -> Self {
+        Self::with_spill(Spill::new(None, PathBuf::new()))
+    }
+
+    /// Create a new empty memory file system that keeps at most `budget` bytes of file data in
+    /// memory. Whenever the budget is exceeded, the least recently used files are moved to
+    /// `scratch_dir` on disk, from where they are read until they are written again. Each file
+    /// system uses its own subdirectory, so several processes can share `scratch_dir`.
+    pub fn with_budget(budget: u64, scratch_dir: impl Into<PathBuf>) -> Self {
+        Self::with_spill(Spill::new(Some(budget), scratch_dir.into()))
+    }
+
+    fn with_spill(spill: Spill) -> Self {
         Self {
             root: Arc::new(RwLock::new(Root(Directory::default()))),
+            spill: Arc::new(spill),
+            clock: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Get statistics of the current memory use.
+    pub fn stats(&self) -> MemoryStats {
+        let root = self.root.read().expect("root lock poisoned");
+        let mut stats = MemoryStats::default();
+        let mut seen = HashSet::default();
+        let mut spilled = HashSet::default();
+
+        let mut dirs = vec![&root.0];
+        while let Some(dir) = dirs.pop() {
+            dirs.extend(dir.subdirs.values());
+            for file in dir.files.values() {
+                stats.files += 1;
+                match &*file.node.contents.read().expect("file data lock poisoned") {
+                    // copies share the same data, so only count it once
+                    Contents::Memory(data) => {
+                        if seen.insert(Arc::as_ptr(&data.0)) {
+                            stats.memory_bytes += data.0.len() as u64;
+                        }
+                    }
+                    Contents::Spilled(file) => {
+                        if spilled.insert(Arc::as_ptr(file)) {
+                            stats.spilled_files += 1;
+                            stats.spilled_bytes += file.len;
+                        }
+                    }
+                }
+            }
         }
+        drop(root);
+
+        stats.peak_memory_bytes = self
+            .spill
+            .peak
+            .load(Ordering::Relaxed)
+            .max(stats.memory_bytes);
+        stats.spills = self.spill.spills.load(Ordering::Relaxed);
+        stats
+    }
+
+    /// Create a new empty file entry that is tracked for spilling.
+    fn new_entry(&self) -> FileEntry {
+        let entry = FileEntry::new();
+        self.spill
+            .nodes
+            .lock()
+            .expect("spill lock poisoned")
+            .push(Arc::downgrade(&entry.node));
+        entry
+    }
+
+    /// Mark a file as used now.
+    fn touch(&self, node: &FileNode) {
+        node.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Open a file for writing, failing if it already exists and `new` is set. The check is
+    /// done while holding the lock so only one caller can create a new file.
+    fn create_file(&self, path: &Path, new: bool) -> Result<WritableFile, io::Error> {
+        let mut root = self.root.write().expect("root lock poisoned");
+
+        let parent = file_parent(path)?;
+
+        // find the parent directory
+        let dir = root.get_directory_mut(parent)?;
+
+        // get file name
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        if new && (dir.files.contains_key(&name) || dir.subdirs.contains_key(&name)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+
+        // open or create new file
+        let file = dir.files.entry(name).or_insert_with(|| self.new_entry());
+        self.touch(&file.node);
+
+        // now we replace the arc with a new one which we will write to. This way existing readers
+        // will continue to read the old data, while we start filling up some new data)
+        let writer = WritableFile {
+            data: io::Cursor::new(Vec::new()),
+            node: file.node.clone(), // linked to the place where the data is stored
+            spill: self.spill.clone(),
+        };
+        Ok(writer)
     }
 
     /// Load the contents of a file on the local file system into the memory file system.
@@ -146,19 +279,215 @@ fn file_parent(path: &Path) -> Result<&Path, io::Error> {
 
 #[derive(Debug)]
 struct FileEntry {
-    /// data is stored as an Arc to allow for multiple readers.
-    /// Wrapped in an [`RwLock`] to allow for swapping the value when the Writer is dropped / finished.
-    data: Arc<RwLock<FileData>>,
+    /// Shared with the writers of the file and the spill bookkeeping.
+    node: Arc<FileNode>,
 }
 
 impl FileEntry {
     /// Create a new empty file entry.
     fn new() -> Self {
         Self {
-            data: Arc::new(RwLock::new(FileData::new())),
+            node: Arc::new(FileNode {
+                contents: RwLock::new(Contents::Memory(FileData::new())),
+                last_used: AtomicU64::new(0),
+                modified: Mutex::new(SystemTime::now()),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileNode {
+    /// Wrapped in an [`RwLock`] to allow for swapping the value when the Writer is dropped / finished,
+    /// or when the file is spilled to disk.
+    contents: RwLock<Contents>,
+    /// The value of the clock when the file was last used.
+    last_used: AtomicU64,
+    /// When the file was last written.
+    modified: Mutex<SystemTime>,
+}
+
+impl FileNode {
+    fn set_contents(&self, contents: Contents) {
+        *self.contents.write().expect("file data lock poisoned") = contents;
+        *self.modified.lock().expect("modified lock poisoned") = SystemTime::now();
+    }
+}
+
+/// Where the data of a file is. Cheap to clone, which is used for sharing the data of copies.
+#[derive(Debug, Clone)]
+enum Contents {
+    /// data is stored as an Arc to allow for multiple readers.
+    Memory(FileData),
+    Spilled(Arc<SpilledFile>),
+}
+
+/// A file that has been moved to the scratch directory. Removed from disk when dropped.
+#[derive(Debug)]
+struct SpilledFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl Drop for SpilledFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Bookkeeping of all files for keeping the memory use within the budget.
+#[derive(Debug)]
+struct Spill {
+    budget: Option<u64>,
+    /// The scratch directory shared with other file systems, possibly in other processes.
+    scratch_dir: PathBuf,
+    /// The subdirectory of the scratch directory used only by this file system.
+    dir: PathBuf,
+    /// Bytes of file data in memory, kept up to date by the [`Buffer`]s themselves.
+    used: Arc<AtomicU64>,
+    peak: AtomicU64,
+    spills: AtomicU64,
+    /// All files that have been created, the ones that have been removed are pruned on update.
+    nodes: Mutex<Vec<Weak<FileNode>>>,
+    /// The data being written to disk by some thread, so that it is only spilled once.
+    spilling: Mutex<HashSet<usize>>,
+    next_id: AtomicU64,
+    /// Set when the scratch directory was created by us, so it is removed when dropped if no
+    /// other file system uses it any more.
+    created_dir: AtomicBool,
+}
+
+impl Spill {
+    fn new(budget: Option<u64>, scratch_dir: PathBuf) -> Self {
+        static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+        let dir = scratch_dir.join(format!(
+            "{}_{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self {
+            budget,
+            scratch_dir,
+            dir,
+            used: Arc::new(AtomicU64::new(0)),
+            peak: AtomicU64::new(0),
+            spills: AtomicU64::new(0),
+            nodes: Mutex::new(Vec::new()),
+            spilling: Mutex::new(HashSet::default()),
+            next_id: AtomicU64::new(0),
+            created_dir: AtomicBool::new(false),
+        }
+    }
+
+    /// Update the peak memory use, and spill the least recently used files if over budget.
+    fn update(&self) {
+        let used = self.used.load(Ordering::Relaxed);
+        self.peak.fetch_max(used, Ordering::Relaxed);
+
+        let Some(budget) = self.budget else {
+            return;
+        };
+        if used <= budget {
+            return;
+        }
+
+        let victims = self.pick_victims(used - budget);
+        for (data, nodes) in &victims {
+            let spilled = match self.write(data) {
+                Ok(spilled) => Arc::new(spilled),
+                Err(e) => {
+                    warn!("Could not spill file to {}: {e}", self.dir.display());
+                    break;
+                }
+            };
+            // all copies sharing the data are spilled together, unless they have been written
+            // to in the meantime
+            for node in nodes {
+                let mut contents = node.contents.write().expect("file data lock poisoned");
+                if matches!(&*contents, Contents::Memory(d) if Arc::ptr_eq(&d.0, &data.0)) {
+                    *contents = Contents::Spilled(spilled.clone());
+                }
+            }
+            self.spills.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut spilling = self.spilling.lock().expect("spill lock poisoned");
+        for (data, _) in &victims {
+            spilling.remove(&(Arc::as_ptr(&data.0) as usize));
+        }
+    }
+
+    /// Pick the least recently used data of at least `excess` bytes to spill, together with the
+    /// files sharing it. Data already being spilled by another thread is skipped.
+    fn pick_victims(&self, excess: u64) -> Vec<(FileData, Vec<Arc<FileNode>>)> {
+        let mut files = Vec::new();
+        {
+            let mut nodes = self.nodes.lock().expect("spill lock poisoned");
+            nodes.retain(|node| node.strong_count() > 0);
+            for node in nodes.iter().filter_map(Weak::upgrade) {
+                let Contents::Memory(data) = node
+                    .contents
+                    .read()
+                    .expect("file data lock poisoned")
+                    .clone()
+                else {
+                    continue;
+                };
+                if !data.0.is_empty() {
+                    files.push((node, data));
+                }
+            }
+        }
+        files.sort_by_key(|(node, _)| node.last_used.load(Ordering::Relaxed));
+
+        let mut spilling = self.spilling.lock().expect("spill lock poisoned");
+        let mut victims: Vec<(FileData, Vec<Arc<FileNode>>)> = Vec::new();
+        let mut picked = 0;
+        for (node, data) in files {
+            if let Some((_, nodes)) = victims.iter_mut().find(|(d, _)| Arc::ptr_eq(&d.0, &data.0)) {
+                nodes.push(node);
+            } else if picked < excess && spilling.insert(Arc::as_ptr(&data.0) as usize) {
+                picked += data.0.len() as u64;
+                victims.push((data, vec![node]));
+            }
+        }
+        victims
+    }
+
+    /// Write the data of a file into the scratch directory.
+    fn write(&self, data: &FileData) -> io::Result<SpilledFile> {
+        if !self.dir.exists() {
+            if !self.scratch_dir.exists() {
+                self.created_dir.store(true, Ordering::Relaxed);
+            }
+            std::fs::create_dir_all(&self.dir)?;
+        }
+        let path = self.dir.join(format!(
+            "{}.spill",
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        ));
+        debug!("Spilling {} bytes to {}", data.0.len(), path.display());
+        std::fs::write(&path, &data.0.bytes)?;
+        Ok(SpilledFile {
+            path,
+            len: data.0.len() as u64,
+        })
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if self.budget.is_none() {
+            return;
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+        if *self.created_dir.get_mut() {
+            // fails while other file systems have files in it
+            let _ = std::fs::remove_dir(&self.scratch_dir);
         }
     }
 }
+
 impl std::fmt::Debug for FileData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let len = self.0.len();
@@ -166,13 +495,15 @@ impl std::fmt::Debug for FileData {
     }
 }
 
-/// A file that is currently being written too. Has a link back to the [`FileData`] so it can
-/// swap it whenever the writer is dropped.
+/// A file that is currently being written too. Has a link back to the [`FileNode`] so it can
+/// swap the data whenever the writer is dropped.
 struct WritableFile {
     /// The data beeing written to the file
     data: io::Cursor<Vec<u8>>,
     /// links back to the file entry so we can swap the data when the writer is dropped
-    data_link: Arc<RwLock<FileData>>,
+    node: Arc<FileNode>,
+    /// for keeping the memory use within the budget once the data is in place
+    spill: Arc<Spill>,
 }
 
 impl Write for WritableFile {
@@ -195,18 +526,57 @@ impl Drop for WritableFile {
     // swap the data into the file entry on drop
     fn drop(&mut self) {
         let data = core::mem::replace(&mut self.data, io::Cursor::new(Vec::new()));
-        let mut data_link = self.data_link.write().expect("file data lock poisoned");
-        *data_link = FileData(Arc::new(data.into_inner()));
+        let data = Buffer::counted(data.into_inner(), &self.spill.used);
+        self.node
+            .set_contents(Contents::Memory(FileData(Arc::new(data))));
+        self.spill.update();
     }
 }
 
 /// Holds the data of a file. Cheap to clone because the data is behind an [`Arc`].
 #[derive(Clone)]
-struct FileData(Arc<Vec<u8>>);
+struct FileData(Arc<Buffer>);
 
 impl FileData {
     fn new() -> Self {
-        Self(Arc::new(Vec::new()))
+        Self(Arc::new(Buffer::uncounted(Vec::new())))
+    }
+}
+
+/// The bytes of a file. Counted in the memory use of the file system from when the writer is
+/// finished until the last copy or reader of the data is dropped.
+struct Buffer {
+    bytes: Vec<u8>,
+    used: Option<Arc<AtomicU64>>,
+}
+
+impl Buffer {
+    fn counted(bytes: Vec<u8>, used: &Arc<AtomicU64>) -> Self {
+        used.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Self {
+            bytes,
+            used: Some(used.clone()),
+        }
+    }
+
+    fn uncounted(bytes: Vec<u8>) -> Self {
+        Self { bytes, used: None }
+    }
+}
+
+impl std::ops::Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(used) = &self.used {
+            used.fetch_sub(self.bytes.len() as u64, Ordering::Relaxed);
+        }
     }
 }
 
@@ -272,7 +642,7 @@ impl FileSystem for MemoryFileSystem {
     fn open(
         &self,
         path: impl AsRef<Path>,
-    ) -> Result<impl BufRead + Seek + Send + 'static, io::Error> {
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
         let root = self.root.read().expect("root lock poisoned");
         let path = path.as_ref();
 
@@ -290,33 +660,25 @@ impl FileSystem for MemoryFileSystem {
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
         };
 
+        self.touch(&file.node);
+
         // create a reader by cloning the Arc
-        let data = file.data.read().unwrap().clone();
-        Ok(io::Cursor::new(data))
+        let contents = file.node.contents.read().unwrap().clone();
+        match contents {
+            Contents::Memory(data) => Ok(Either::Left(io::Cursor::new(data))),
+            Contents::Spilled(spilled) => Ok(Either::Right(io::BufReader::with_capacity(
+                crate::ONE_MEGABYTE,
+                std::fs::File::open(&spilled.path)?,
+            ))),
+        }
     }
 
     fn create(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
-        let mut root = self.root.write().expect("root lock poisoned");
-        let path = path.as_ref();
-
-        let parent = file_parent(path)?;
-
-        // find the parent directory
-        let dir = root.get_directory_mut(parent)?;
-
-        // get file name
-        let name = path.file_name().unwrap().to_string_lossy().to_string();
-
-        // open or create new file
-        let file = dir.files.entry(name).or_insert(FileEntry::new());
+        self.create_file(path.as_ref(), false)
+    }
 
-        // now we replace the arc with a new one which we will write to. This way existing readers
-        // will continue to read the old data, while we start filling up some new data)
-        let writer = WritableFile {
-            data: io::Cursor::new(Vec::new()),
-            data_link: file.data.clone(), // linked to the place where the data is stored
-        };
-        Ok(writer)
+    fn create_new(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        self.create_file(path.as_ref(), true)
     }
 
     fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error> {
@@ -337,8 +699,17 @@ impl FileSystem for MemoryFileSystem {
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
         };
 
+        self.touch(&file.node);
+
         // create a reader by cloning the Arc
-        let data = file.data.read().unwrap();
+        let contents = file.node.contents.read().unwrap().clone();
+        drop(root);
+        let data = match contents {
+            Contents::Memory(data) => data,
+            Contents::Spilled(spilled) => {
+                FileData(Arc::new(Buffer::uncounted(std::fs::read(&spilled.path)?)))
+            }
+        };
 
         // convert to string lossily expecting all data to be valid utf8
         let str = str::from_utf8(&data.0).map_err(|e| {
@@ -405,8 +776,11 @@ impl FileSystem for MemoryFileSystem {
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
         };
 
-        let data = file.data.read().expect("file data lock poisoned");
-        Ok(data.0.len() as u64)
+        let contents = file.node.contents.read().expect("file data lock poisoned");
+        Ok(match &*contents {
+            Contents::Memory(data) => data.0.len() as u64,
+            Contents::Spilled(spilled) => spilled.len,
+        })
     }
 
     fn copy(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
@@ -426,8 +800,10 @@ impl FileSystem for MemoryFileSystem {
             Some(file) => file,
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
         };
+        self.touch(&from_file.node);
         let from_data = from_file
-            .data
+            .node
+            .contents
             .read()
             .expect("file data lock poisoned")
             .clone();
@@ -437,64 +813,57 @@ impl FileSystem for MemoryFileSystem {
 
         // get file names
         let to_name = to.file_name().unwrap().to_string_lossy().to_string();
-        let to_file = to_dir.files.entry(to_name).or_insert(FileEntry::new());
+        let to_file = to_dir
+            .files
+            .entry(to_name)
+            .or_insert_with(|| self.new_entry());
         // copy the data
-        let mut to_data = to_file.data.write().expect("file data lock poisoned");
-        *to_data = from_data;
+        self.touch(&to_file.node);
+        to_file.node.set_contents(from_data);
 
         Ok(())
     }
 
-    fn extract_zip(
-        &self,
-        archive: impl AsRef<Path>,
-        target: impl AsRef<Path>,
-    ) -> anyhow::Result<()> {
-        let file = self.open(&archive).context("opening zip file")?;
-        let mut zip_archive = zip::ZipArchive::new(file).context("reading zip archive")?;
-        log::info!(
-            "Extracting {:?} kB from {}",
-            zip_archive.decompressed_size().map(|s| s / 1024),
-            archive.as_ref().display()
-        );
-
-        for i in 0..zip_archive.len() {
-            let mut file = zip_archive
-                .by_index(i)
-                .with_context(|| format!("get file index {i}"))?;
-
-            if file.is_symlink() {
-                log::warn!(
-                    "Skipping symlink {} in zip archive as it is not supported",
-                    file.name(),
-                );
-                continue;
-            }
+    fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        let mut root = self.root.write().expect("root lock poisoned");
+        let (from, to) = (from.as_ref(), to.as_ref());
 
-            let Some(name) = file.enclosed_name() else {
-                log::warn!(
-                    "Skipping file {} in zip archive as it is not a valid path",
-                    file.name(),
-                );
-                continue;
-            };
+        // make sure the target directory exists before taking the file out
+        let to_name = to.file_name().unwrap().to_string_lossy().to_string();
+        root.get_directory(file_parent(to)?)?;
 
-            let target_name = target.as_ref().join(&name);
+        let from_name = from.file_name().unwrap().to_string_lossy().to_string();
+        let file = root
+            .get_directory_mut(file_parent(from)?)?
+            .files
+            .remove(&from_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        root.get_directory_mut(file_parent(to)?)?
+            .files
+            .insert(to_name, file);
 
-            if file.is_dir() {
-                log::debug!("Creating directory {}", target_name.display());
-                self.create_dir_all(&target_name)?;
-                continue;
-            }
+        Ok(())
+    }
 
-            log::debug!("Extracting file {}", name.display());
+    fn modified(&self, path: impl AsRef<Path>) -> Result<SystemTime, io::Error> {
+        let root = self.root.read().expect("root lock poisoned");
+        let path = path.as_ref();
 
-            // at this point the file is a file and so we copy it into the FS
-            let mut output = self.create(target_name)?;
-            std::io::copy(&mut file, &mut output)?;
-        }
+        let dir = root.get_directory(file_parent(path)?)?;
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let file = dir
+            .files
+            .get(&name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        Ok(*file.node.modified.lock().expect("modified lock poisoned"))
+    }
 
-        Ok(())
+    fn extract_zip(
+        &self,
+        archive: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        super::extract_zip_entries(self, archive, target)
     }
 }
 
@@ -713,6 +1082,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_create_new() {
+        let fs = super::MemoryFileSystem::new();
+        fs.create_dir_all("dir").unwrap();
+
+        fs.create_new("test.txt").unwrap().write_all(b"a").unwrap();
+        for path in ["test.txt", "dir"] {
+            match fs.create_new(path) {
+                Ok(_) => panic!("{path} should exist"),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            }
+        }
+        assert_eq!(fs.read_to_string("test.txt").unwrap(), "a");
+    }
+
     #[test]
     fn test_create_and_list_files_and_folders() {
         let fs = super::MemoryFileSystem::new();
@@ -768,4 +1152,74 @@ mod test {
         let read = fs.read_to_string(path2).unwrap();
         assert_eq!(read, content);
     }
+
+    #[test]
+    fn test_budget_spills_least_recently_used() {
+        let scratch = std::env::temp_dir().join(format!("pullauta_spill_{}", std::process::id()));
+        let fs = super::MemoryFileSystem::with_budget(25, &scratch);
+
+        fs.create("a.txt").unwrap().write_all(&[1; 10]).unwrap();
+        fs.create("b.txt").unwrap().write_all(&[2; 10]).unwrap();
+        fs.copy("b.txt", "c.txt").unwrap();
+        // use a.txt so that b.txt is the least recently used
+        fs.open("a.txt").unwrap();
+        assert_eq!(fs.stats().memory_bytes, 20);
+
+        fs.create("d.txt").unwrap().write_all(&[3; 10]).unwrap();
+        let stats = fs.stats();
+        assert_eq!(stats.files, 4);
+        assert_eq!(stats.memory_bytes, 20);
+        assert_eq!(stats.peak_memory_bytes, 30);
+        assert_eq!(stats.spilled_files, 1);
+        assert_eq!(stats.spilled_bytes, 10);
+        assert_eq!(stats.spills, 1);
+
+        // spilled files are read transparently
+        let mut data = Vec::new();
+        fs.open("c.txt").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, [2; 10]);
+        assert_eq!(fs.file_size("b.txt").unwrap(), 10);
+
+        // the spilled data is removed from disk together with the last file using it
+        let dir = std::fs::read_dir(&scratch)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        fs.remove_file("b.txt").unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        fs.remove_file("c.txt").unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // the scratch directory is removed with the file system
+        drop(fs);
+        assert!(!scratch.exists());
+    }
+
+    #[test]
+    fn test_shared_scratch_dir() {
+        let scratch =
+            std::env::temp_dir().join(format!("pullauta_shared_spill_{}", std::process::id()));
+        let first = super::MemoryFileSystem::with_budget(5, &scratch);
+        let second = super::MemoryFileSystem::with_budget(5, &scratch);
+        first.create("a.txt").unwrap().write_all(&[1; 10]).unwrap();
+        second.create("a.txt").unwrap().write_all(&[2; 10]).unwrap();
+        assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 2);
+
+        // dropping one file system leaves the spilled files of the other
+        drop(first);
+        let mut data = Vec::new();
+        second
+            .open("a.txt")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [2; 10]);
+
+        // the scratch directory was in use by the other file system when its creator was dropped
+        drop(second);
+        assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+        std::fs::remove_dir(scratch).unwrap();
+    }
 }
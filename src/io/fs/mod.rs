@@ -1,10 +1,17 @@
+use anyhow::Context;
 use std::{
-    io::{self, BufRead, Seek, Write},
+    io::{self, BufRead, Read, Seek, Write},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
+pub mod archive;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod local;
 pub mod memory;
+pub mod mount;
+pub mod overlay;
 
 /// Trait for file system operations.
 pub trait FileSystem: std::fmt::Debug {
@@ -26,6 +33,20 @@ pub trait FileSystem: std::fmt::Debug {
     /// Open a file for writing. This is always Buffered.
     fn create(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error>;
 
+    /// Create a new file for writing, failing with [`io::ErrorKind::AlreadyExists`] if it exists.
+    ///
+    /// Implementations backed by storage shared between processes do the check and the creation
+    /// atomically, so this can be used to claim work.
+    fn create_new(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        if self.exists(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+        self.create(path)
+    }
+
     /// Read a file into a String.
     fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error>;
 
@@ -41,6 +62,24 @@ pub trait FileSystem: std::fmt::Debug {
     /// Copy a file.
     fn copy(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error>;
 
+    /// Rename a file, replacing `to` if it exists.
+    ///
+    /// Implementations backed by storage shared between processes replace the file atomically, so
+    /// readers see either the old or the new contents.
+    fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        self.copy(&from, to)?;
+        self.remove_file(from)
+    }
+
+    /// Get the time a file was last written.
+    fn modified(&self, path: impl AsRef<Path>) -> Result<SystemTime, io::Error> {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "modification times are not supported",
+        ))
+    }
+
     /// Extract a ZIP archive to a directory.
     fn extract_zip(
         &self,
@@ -86,3 +125,115 @@ pub trait FileSystem: std::fmt::Debug {
         Ok(shapefile::Reader::new(shape_reader, dbf_reader))
     }
 }
+
+/// Extract a ZIP archive entry by entry through the given [`FileSystem`], for implementations
+/// that cannot extract directly to the local file system.
+fn extract_zip_entries(
+    fs: &impl FileSystem,
+    archive: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let file = fs.open(&archive).context("opening zip file")?;
+    let mut zip_archive = zip::ZipArchive::new(file).context("reading zip archive")?;
+    log::info!(
+        "Extracting {:?} kB from {}",
+        zip_archive.decompressed_size().map(|s| s / 1024),
+        archive.as_ref().display()
+    );
+
+    for i in 0..zip_archive.len() {
+        let mut file = zip_archive
+            .by_index(i)
+            .with_context(|| format!("get file index {i}"))?;
+
+        if file.is_symlink() {
+            log::warn!(
+                "Skipping symlink {} in zip archive as it is not supported",
+                file.name(),
+            );
+            continue;
+        }
+
+        let Some(name) = file.enclosed_name() else {
+            log::warn!(
+                "Skipping file {} in zip archive as it is not a valid path",
+                file.name(),
+            );
+            continue;
+        };
+
+        let target_name = target.as_ref().join(&name);
+
+        if file.is_dir() {
+            log::debug!("Creating directory {}", target_name.display());
+            fs.create_dir_all(&target_name)?;
+            continue;
+        }
+
+        log::debug!("Extracting file {}", name.display());
+
+        // at this point the file is a file and so we copy it into the FS
+        let mut output = fs.create(target_name)?;
+        std::io::copy(&mut file, &mut output)?;
+    }
+
+    Ok(())
+}
+
+/// Either of two readers or writers, for [`FileSystem`] implementations that return files from
+/// different sources.
+#[derive(Debug)]
+enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L: Read, R: Read> Read for Either<L, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Either::Left(l) => l.read(buf),
+            Either::Right(r) => r.read(buf),
+        }
+    }
+}
+
+impl<L: BufRead, R: BufRead> BufRead for Either<L, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Either::Left(l) => l.fill_buf(),
+            Either::Right(r) => r.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Either::Left(l) => l.consume(amt),
+            Either::Right(r) => r.consume(amt),
+        }
+    }
+}
+
+impl<L: Seek, R: Seek> Seek for Either<L, R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            Either::Left(l) => l.seek(pos),
+            Either::Right(r) => r.seek(pos),
+        }
+    }
+}
+
+impl<L: Write, R: Write> Write for Either<L, R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Either::Left(l) => l.write(buf),
+            Either::Right(r) => r.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Either::Left(l) => l.flush(),
+            Either::Right(r) => r.flush(),
+        }
+    }
+}
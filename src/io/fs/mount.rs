@@ -0,0 +1,281 @@
+use std::io::{self, BufRead, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use super::archive::ZipFileSystem;
+use super::{Either, FileSystem};
+
+/// A [`FileSystem`] that makes other file systems, such as zip archives, visible under a path of
+/// a base file system.
+///
+/// The contents of a mounted file system are merged with the contents of the base file system at
+/// the mount point, and several file systems can be mounted at the same path. Files are looked up
+/// in the base file system first and then in the mounts in the order they were added. Everything
+/// written goes to the base file system.
+#[derive(Debug, Clone)]
+pub struct MountFileSystem<B, M = ZipFileSystem> {
+    base: B,
+    mounts: Vec<(PathBuf, M)>,
+}
+
+impl<B: FileSystem, M: FileSystem> MountFileSystem<B, M> {
+    /// Create a new file system without any mounts on top of `base`.
+    pub fn new(base: B) -> Self {
+        Self {
+            base,
+            mounts: Vec::new(),
+        }
+    }
+
+    /// Make the contents of `fs` visible under `path`.
+    pub fn mount(mut self, path: impl AsRef<Path>, fs: M) -> Self {
+        self.mounts.push((normalize(path.as_ref()), fs));
+        self
+    }
+
+    /// The mounted file systems containing `path`, with the path relative to each mount point.
+    fn resolve<'a>(&'a self, path: &Path) -> impl Iterator<Item = (&'a M, PathBuf)> + 'a {
+        let path = normalize(path);
+        self.mounts.iter().filter_map(move |(mount_point, fs)| {
+            path.strip_prefix(mount_point)
+                .ok()
+                .map(|relative| (fs, relative.to_path_buf()))
+        })
+    }
+
+    /// The first mounted file system that has `path`, if it is not in the base file system.
+    fn find(&self, path: &Path) -> Option<(&M, PathBuf)> {
+        if self.base.exists(path) {
+            return None;
+        }
+        self.resolve(path)
+            .find(|(fs, relative)| fs.exists(relative))
+    }
+
+    /// Check if `path` is a parent directory of a mount point.
+    fn contains_mount_point(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.mounts
+            .iter()
+            .any(|(mount_point, _)| mount_point.starts_with(&path) && *mount_point != path)
+    }
+}
+
+/// Remove "." components so that paths can be compared.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+impl<B: FileSystem, M: FileSystem> FileSystem for MountFileSystem<B, M> {
+    fn create_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        self.base.create_dir_all(path)
+    }
+
+    fn list(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>, io::Error> {
+        let path = path.as_ref();
+        let mut found = false;
+        let mut entries = match self.base.list(path) {
+            Ok(entries) => {
+                found = true;
+                entries
+            }
+            Err(_) => Vec::new(),
+        };
+
+        for (fs, relative) in self.resolve(path) {
+            let Ok(children) = fs.list(&relative) else {
+                continue;
+            };
+            found = true;
+            for child in children {
+                let child = match child.strip_prefix(&relative) {
+                    Ok(name) => path.join(name),
+                    Err(_) => path.join(child.file_name().unwrap_or_default()),
+                };
+                if !entries.contains(&child) {
+                    entries.push(child);
+                }
+            }
+        }
+
+        // mount points that do not exist in the base file system are listed in their parent
+        let normalized = normalize(path);
+        for (mount_point, _) in &self.mounts {
+            if mount_point.parent() == Some(&normalized) {
+                found = true;
+                let child = path.join(mount_point.file_name().unwrap_or_default());
+                if !entries.contains(&child) {
+                    entries.push(child);
+                }
+            }
+        }
+
+        if !found {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory not found",
+            ));
+        }
+        Ok(entries)
+    }
+
+    fn exists(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.base.exists(path)
+            || self.resolve(path).any(|(fs, relative)| fs.exists(relative))
+            || self.contains_mount_point(path)
+    }
+
+    fn open(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
+        // pass an owned path, as the returned readers capture the type of the path
+        let path = path.as_ref().to_path_buf();
+        match self.find(&path) {
+            Some((fs, relative)) => Ok(Either::Right(fs.open(relative)?)),
+            None => Ok(Either::Left(self.base.open(path)?)),
+        }
+    }
+
+    fn create(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        self.base.create(path.as_ref().to_path_buf())
+    }
+
+    fn create_new(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        if self.find(&path).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+        self.base.create_new(path)
+    }
+
+    fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error> {
+        let path = path.as_ref();
+        match self.find(path) {
+            Some((fs, relative)) => fs.read_to_string(relative),
+            None => self.base.read_to_string(path),
+        }
+    }
+
+    fn remove_file(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        match self.find(path) {
+            Some((fs, relative)) => fs.remove_file(relative),
+            None => self.base.remove_file(path),
+        }
+    }
+
+    fn remove_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        match self.find(path) {
+            Some((fs, relative)) => fs.remove_dir_all(relative),
+            None => self.base.remove_dir_all(path),
+        }
+    }
+
+    fn file_size(&self, path: impl AsRef<Path>) -> Result<u64, io::Error> {
+        let path = path.as_ref();
+        match self.find(path) {
+            Some((fs, relative)) => fs.file_size(relative),
+            None => self.base.file_size(path),
+        }
+    }
+
+    fn copy(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if self.find(from).is_none() {
+            return self.base.copy(from, to);
+        }
+        let mut reader = self.open(from)?;
+        let mut writer = self.create(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()
+    }
+
+    fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if self.find(from).is_none() {
+            return self.base.rename(from, to);
+        }
+        self.copy(from, to)?;
+        self.remove_file(from)
+    }
+
+    fn modified(&self, path: impl AsRef<Path>) -> Result<SystemTime, io::Error> {
+        let path = path.as_ref();
+        match self.find(path) {
+            Some((fs, relative)) => fs.modified(relative),
+            None => self.base.modified(path),
+        }
+    }
+
+    fn extract_zip(
+        &self,
+        archive: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        if self.find(archive.as_ref()).is_none() {
+            return self.base.extract_zip(archive, target);
+        }
+        super::extract_zip_entries(self, archive, target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::fs::memory::MemoryFileSystem;
+
+    #[test]
+    fn test_mounts_are_merged_with_base() {
+        let base = MemoryFileSystem::new();
+        base.create_dir_all("in").unwrap();
+        base.create("in/base.txt")
+            .unwrap()
+            .write_all(b"base")
+            .unwrap();
+
+        let first = MemoryFileSystem::new();
+        first.create("a.txt").unwrap().write_all(b"a").unwrap();
+        first
+            .create("base.txt")
+            .unwrap()
+            .write_all(b"hidden")
+            .unwrap();
+        let second = MemoryFileSystem::new();
+        second.create_dir_all("sub").unwrap();
+        second.create("sub/b.txt").unwrap().write_all(b"b").unwrap();
+
+        let fs = MountFileSystem::new(base)
+            .mount("in", first)
+            .mount("./in", second)
+            .mount("shapes/zip", MemoryFileSystem::new());
+
+        assert_eq!(fs.read_to_string("in/base.txt").unwrap(), "base");
+        assert_eq!(fs.read_to_string("in/a.txt").unwrap(), "a");
+        assert_eq!(fs.read_to_string("./in/sub/b.txt").unwrap(), "b");
+        assert_eq!(fs.file_size("in/sub/b.txt").unwrap(), 1);
+
+        let mut entries = fs.list("in").unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            ["in/a.txt", "in/base.txt", "in/sub"].map(PathBuf::from)
+        );
+        assert_eq!(fs.list("in/sub").unwrap(), [PathBuf::from("in/sub/b.txt")]);
+        assert!(fs.exists("shapes"));
+        assert_eq!(fs.list("shapes").unwrap(), [PathBuf::from("shapes/zip")]);
+        assert!(fs.list("missing").is_err());
+
+        // writes go to the base file system
+        fs.copy("in/a.txt", "in/copy.txt").unwrap();
+        assert_eq!(fs.read_to_string("in/copy.txt").unwrap(), "a");
+        assert!(fs.base.exists("in/copy.txt"));
+    }
+}
@@ -0,0 +1,373 @@
+use rustc_hash::FxHashSet as HashSet;
+use std::io::{self, BufRead, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use super::local::LocalFileSystem;
+use super::memory::MemoryFileSystem;
+use super::{Either, FileSystem};
+
+/// A [`FileSystem`] that reads from the local file system and keeps all writes in memory.
+///
+/// Files are read lazily from disk the first time they are opened, so large input folders are
+/// never copied into memory. Everything written, copied or removed only changes the in-memory
+/// layer, and the files on disk are never modified. The exception are the `persistent` paths
+/// (such as the batch output folder), which are written through to the local file system.
+///
+/// This object is thread-safe and can be shared between threads. Uses [`Arc`] internally so it is
+/// cheap to clone.
+#[derive(Debug, Clone)]
+pub struct OverlayFileSystem {
+    lower: LocalFileSystem,
+    upper: MemoryFileSystem,
+    /// Paths on disk that have been removed in the overlay.
+    whiteouts: Arc<RwLock<HashSet<PathBuf>>>,
+    /// Files and directories that are read from and written to disk directly.
+    persistent: Arc<Vec<PathBuf>>,
+}
+
+impl OverlayFileSystem {
+    /// Create a new overlay on top of the local file system, writing the given files and
+    /// directories directly to disk.
+    pub fn new(persistent: impl IntoIterator<Item = impl AsRef<Path>>) -> Self {
+        Self::with_memory(persistent, MemoryFileSystem::new())
+    }
+
+    /// Create a new overlay like [`OverlayFileSystem::new`], keeping the written files in the
+    /// given memory file system, for example one with a memory budget.
+    pub fn with_memory(
+        persistent: impl IntoIterator<Item = impl AsRef<Path>>,
+        memory: MemoryFileSystem,
+    ) -> Self {
+        Self {
+            lower: LocalFileSystem,
+            upper: memory,
+            whiteouts: Arc::new(RwLock::new(HashSet::default())),
+            persistent: Arc::new(
+                persistent
+                    .into_iter()
+                    .map(|p| normalize(p.as_ref()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The in-memory layer holding everything written to non-persistent paths.
+    pub fn memory(&self) -> &MemoryFileSystem {
+        &self.upper
+    }
+
+    fn is_persistent(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.persistent.iter().any(|p| path.starts_with(p))
+    }
+
+    /// Check if a path on disk, or any of its parents, has been removed in the overlay.
+    fn is_whiteout(&self, path: &Path) -> bool {
+        let whiteouts = self.whiteouts.read().expect("whiteouts lock poisoned");
+        if whiteouts.is_empty() {
+            return false;
+        }
+        normalize(path)
+            .ancestors()
+            .any(|ancestor| whiteouts.contains(ancestor))
+    }
+
+    /// Check if a path should be read from the local file system.
+    fn in_lower(&self, path: &Path) -> bool {
+        !self.upper.exists(path) && !self.is_whiteout(path) && self.lower.exists(path)
+    }
+
+    fn add_whiteout(&self, path: &Path) {
+        self.whiteouts
+            .write()
+            .expect("whiteouts lock poisoned")
+            .insert(normalize(path));
+    }
+
+    fn remove_whiteout(&self, path: &Path) {
+        self.whiteouts
+            .write()
+            .expect("whiteouts lock poisoned")
+            .remove(&normalize(path));
+    }
+
+    /// Make sure the parent of `path` exists in memory if it exists on disk.
+    fn prepare_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") && !self.upper.exists(parent) => {
+                if self.in_lower(parent) {
+                    self.upper.create_dir_all(parent)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Remove "." components so that paths can be compared.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+impl FileSystem for OverlayFileSystem {
+    fn create_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) {
+            return self.lower.create_dir_all(path);
+        }
+        self.upper.create_dir_all(path)
+    }
+
+    fn list(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>, io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) {
+            return self.lower.list(path);
+        }
+
+        let upper = self.upper.list(path);
+        let lower = if self.is_whiteout(path) {
+            Err(io::Error::new(io::ErrorKind::NotFound, "directory removed"))
+        } else {
+            self.lower.list(path)
+        };
+
+        match (upper, lower) {
+            (Ok(mut entries), Ok(lower)) => {
+                for entry in lower {
+                    if !entries.contains(&entry) && !self.is_whiteout(&entry) {
+                        entries.push(entry);
+                    }
+                }
+                Ok(entries)
+            }
+            (Ok(entries), Err(_)) => Ok(entries),
+            (Err(_), Ok(lower)) => Ok(lower
+                .into_iter()
+                .filter(|entry| !self.is_whiteout(entry))
+                .collect()),
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+
+    fn exists(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        if self.is_persistent(path) {
+            return self.lower.exists(path);
+        }
+        self.upper.exists(path) || (!self.is_whiteout(path) && self.lower.exists(path))
+    }
+
+    fn open(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<impl BufRead + Seek + Send + Sync + 'static, io::Error> {
+        // pass an owned path, as the returned readers capture the type of the path
+        let path = path.as_ref().to_path_buf();
+        if self.is_persistent(&path) || self.in_lower(&path) {
+            Ok(Either::Left(self.lower.open(path)?))
+        } else {
+            Ok(Either::Right(self.upper.open(path)?))
+        }
+    }
+
+    fn create(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        if self.is_persistent(&path) {
+            return Ok(Either::Left(self.lower.create(path)?));
+        }
+        self.prepare_parent(&path)?;
+        self.remove_whiteout(&path);
+        Ok(Either::Right(self.upper.create(path)?))
+    }
+
+    fn create_new(&self, path: impl AsRef<Path>) -> Result<impl Write + Seek, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        if self.is_persistent(&path) {
+            return Ok(Either::Left(self.lower.create_new(path)?));
+        }
+        if self.in_lower(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+        self.prepare_parent(&path)?;
+        let file = self.upper.create_new(path.clone())?;
+        self.remove_whiteout(&path);
+        Ok(Either::Right(file))
+    }
+
+    fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String, io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) || self.in_lower(path) {
+            self.lower.read_to_string(path)
+        } else {
+            self.upper.read_to_string(path)
+        }
+    }
+
+    fn remove_file(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) {
+            return self.lower.remove_file(path);
+        }
+        if !self.exists(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "file not found"));
+        }
+        if self.upper.exists(path) {
+            self.upper.remove_file(path)?;
+        }
+        // the file on disk is kept, but hidden from now on
+        if self.lower.exists(path) {
+            self.add_whiteout(path);
+        }
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) {
+            return self.lower.remove_dir_all(path);
+        }
+        if !self.exists(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "subdir not found"));
+        }
+        if self.upper.exists(path) {
+            self.upper.remove_dir_all(path)?;
+        }
+        if self.lower.exists(path) {
+            self.add_whiteout(path);
+        }
+        Ok(())
+    }
+
+    fn file_size(&self, path: impl AsRef<Path>) -> Result<u64, io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) || self.in_lower(path) {
+            self.lower.file_size(path)
+        } else {
+            self.upper.file_size(path)
+        }
+    }
+
+    fn copy(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !self.is_persistent(from) && !self.in_lower(from) && !self.is_persistent(to) {
+            // both files are in memory, so the data can be shared
+            self.prepare_parent(to)?;
+            self.upper.copy(from, to)?;
+            self.remove_whiteout(to);
+            return Ok(());
+        }
+        let mut reader = self.open(from)?;
+        let mut writer = self.create(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()
+    }
+
+    fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if self.is_persistent(from) && self.is_persistent(to) {
+            return self.lower.rename(from, to);
+        }
+        if !self.is_persistent(from) && !self.in_lower(from) && !self.is_persistent(to) {
+            self.prepare_parent(to)?;
+            self.upper.rename(from, to)?;
+            self.remove_whiteout(to);
+            if self.lower.exists(from) {
+                self.add_whiteout(from);
+            }
+            return Ok(());
+        }
+        self.copy(from, to)?;
+        self.remove_file(from)
+    }
+
+    fn modified(&self, path: impl AsRef<Path>) -> Result<SystemTime, io::Error> {
+        let path = path.as_ref();
+        if self.is_persistent(path) || self.in_lower(path) {
+            self.lower.modified(path)
+        } else {
+            self.upper.modified(path)
+        }
+    }
+
+    fn extract_zip(
+        &self,
+        archive: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        super::extract_zip_entries(self, archive, target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    /// Creates a directory on disk with an input file, unique to each test.
+    fn setup(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("pullauta_overlay_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("in")).unwrap();
+        std::fs::write(dir.join("in").join("input.txt"), "input").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_reads_from_disk_and_writes_to_memory() {
+        let dir = setup("memory");
+        let fs = OverlayFileSystem::new(Vec::<PathBuf>::new());
+        let input = dir.join("in").join("input.txt");
+
+        assert!(fs.exists(&input));
+        assert_eq!(fs.read_to_string(&input).unwrap(), "input");
+
+        // overwriting and removing only changes the overlay
+        fs.create(&input).unwrap().write_all(b"changed").unwrap();
+        assert_eq!(fs.read_to_string(&input).unwrap(), "changed");
+        fs.remove_file(&input).unwrap();
+        assert!(!fs.exists(&input));
+        assert!(!fs.list(dir.join("in")).unwrap().contains(&input));
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), "input");
+
+        // new files in existing directories on disk are kept in memory
+        let temp = dir.join("in").join("temp.txt");
+        fs.create(&temp).unwrap().write_all(b"temp").unwrap();
+        let mut data = String::new();
+        fs.open(&temp).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "temp");
+        assert!(fs.list(dir.join("in")).unwrap().contains(&temp));
+        assert!(!temp.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_paths_are_written_to_disk() {
+        let dir = setup("persistent");
+        let out = dir.join("out");
+        let fs = OverlayFileSystem::new([&out]);
+
+        fs.create_dir_all(&out).unwrap();
+        fs.copy(dir.join("in").join("input.txt"), out.join("output.txt"))
+            .unwrap();
+        fs.create(dir.join("temp.txt")).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(out.join("output.txt")).unwrap(),
+            "input"
+        );
+        assert!(fs.exists(dir.join("temp.txt")));
+        assert!(!dir.join("temp.txt").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -44,6 +44,23 @@ impl HeightMap {
             )
         })
     }
+
+    /// The bilinear interpolation of the heightmap at grid coordinates, clamped to the grid.
+    pub fn interpolate(&self, fx: f64, fy: f64) -> f64 {
+        let (w, h) = (self.grid.width(), self.grid.height());
+        let x = (fx.floor().max(0.0) as usize).min(w.saturating_sub(2));
+        let y = (fy.floor().max(0.0) as usize).min(h.saturating_sub(2));
+        let (dx, dy) = (
+            (fx - x as f64).clamp(0.0, 1.0),
+            (fy - y as f64).clamp(0.0, 1.0),
+        );
+        let grid = &self.grid;
+        let (x1, y1) = ((x + 1).min(w - 1), (y + 1).min(h - 1));
+        (1.0 - dx) * (1.0 - dy) * grid[(x, y)]
+            + dx * (1.0 - dy) * grid[(x1, y)]
+            + (1.0 - dx) * dy * grid[(x, y1)]
+            + dx * dy * grid[(x1, y1)]
+    }
 }
 
 impl HeightMap {
@@ -115,4 +132,23 @@ mod test {
 
         assert_eq!(heightmap, heightmap2);
     }
+
+    #[test]
+    fn test_interpolate() {
+        let mut grid = Vec2D::new(2, 3, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            *z = (x + 10 * y) as f64;
+        }
+        let heightmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        assert_eq!(heightmap.interpolate(1.0, 2.0), 21.0);
+        assert_eq!(heightmap.interpolate(0.5, 1.5), 15.5);
+        // clamped to the grid
+        assert_eq!(heightmap.interpolate(-1.0, 5.0), 20.0);
+    }
 }
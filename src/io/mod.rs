@@ -8,6 +8,7 @@ use crate::geometry::BinaryDxf;
 pub mod bytes;
 pub mod fs;
 pub mod heightmap;
+pub mod worldfile;
 pub mod xyz;
 
 /// Helper function to convert an internal xyz file to a regular xyz file.
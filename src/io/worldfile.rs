@@ -0,0 +1,108 @@
+use image::DynamicImage;
+use std::io::Write;
+use std::path::Path;
+
+use super::fs::FileSystem;
+
+/// The six parameters of an ESRI world file (`.pgw`, `.jgw`) that georeference a raster image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldFile {
+    /// Pixel size in the x-direction.
+    pub x_scale: f64,
+    /// Rotation about the y-axis.
+    pub y_skew: f64,
+    /// Rotation about the x-axis.
+    pub x_skew: f64,
+    /// Pixel size in the y-direction, normally negative.
+    pub y_scale: f64,
+    /// X-coordinate of the upper left pixel.
+    pub x: f64,
+    /// Y-coordinate of the upper left pixel.
+    pub y: f64,
+}
+
+impl WorldFile {
+    /// A world file for a north-up image with square pixels.
+    pub fn new(resolution: f64, x: f64, y: f64) -> Self {
+        Self {
+            x_scale: resolution,
+            y_skew: 0.0,
+            x_skew: 0.0,
+            y_scale: -resolution,
+            x,
+            y,
+        }
+    }
+
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let values = data
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| l.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let [x_scale, y_skew, x_skew, y_scale, x, y] = values[..] else {
+            anyhow::bail!("a world file should have 6 lines, found {}", values.len());
+        };
+        Ok(Self {
+            x_scale,
+            y_skew,
+            x_skew,
+            y_scale,
+            x,
+            y,
+        })
+    }
+
+    pub fn from_file(fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::parse(&fs.read_to_string(path)?)
+    }
+
+    pub fn to_file(&self, fs: &impl FileSystem, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut file = fs.create(path)?;
+        write!(
+            file,
+            "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+            self.x_scale, self.y_skew, self.x_skew, self.y_scale, self.x, self.y
+        )
+    }
+}
+
+/// A raster image together with its georeference.
+#[derive(Debug, Clone)]
+pub struct GeoRaster {
+    pub image: DynamicImage,
+    pub world_file: WorldFile,
+}
+
+impl GeoRaster {
+    /// Reads a PNG image and the `.pgw` world file next to it.
+    pub fn from_file(fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            image: fs.read_image_png(path)?,
+            world_file: WorldFile::from_file(fs, path.with_extension("pgw"))?,
+        })
+    }
+
+    /// Writes the image as a PNG file and the world file next to it as a `.pgw` file.
+    pub fn to_file(&self, fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        self.image
+            .write_to(&mut fs.create(path)?, image::ImageFormat::Png)?;
+        self.world_file.to_file(fs, path.with_extension("pgw"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let wf = WorldFile::parse("0.5\r\n0\r\n0\r\n-0.5\r\n1000.25\r\n2000.75\r\n").unwrap();
+        assert_eq!(wf, WorldFile::new(0.5, 1000.25, 2000.75));
+        assert!(WorldFile::parse("1.0\n0\n").is_err());
+    }
+}
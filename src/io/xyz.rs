@@ -1,6 +1,8 @@
 use crate::io::bytes::FromToBytes;
+use crate::io::fs::FileSystem;
 use std::{
     io::{Read, Seek, Write},
+    path::Path,
     time::Instant,
 };
 
@@ -19,8 +21,45 @@ pub struct XyzRecord {
     pub classification: u8,
     pub number_of_returns: u8,
     pub return_number: u8,
-    // padding bytes to make the struct exactly 24 bytes long
-    pub _padding: u8,
+    /// The intensity scaled to 1-255 by the brightest point of its file, 0 if it is not known.
+    /// This also keeps the struct exactly 24 bytes long.
+    pub intensity: u8,
+}
+
+/// The classified points of a tile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PointCloud {
+    pub records: Vec<XyzRecord>,
+}
+
+impl PointCloud {
+    /// The number of records in the chunks of [`Self::chunks`], the same as read at a time from
+    /// a file.
+    const CHUNK_SIZE: usize = 1024;
+
+    /// Iterates over the records in chunks, for the loops that check for cancellation between
+    /// the chunks.
+    pub fn chunks(&self) -> impl Iterator<Item = &[XyzRecord]> {
+        self.records.chunks(Self::CHUNK_SIZE)
+    }
+
+    /// Helper for easily reading all the points of a file in the internal binary format.
+    pub fn from_file(fs: &impl FileSystem, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut reader = XyzInternalReader::new(fs.open(path)?)?;
+        let mut records = Vec::with_capacity(reader.n_records() as usize);
+        while let Some(chunk) = reader.next_chunk()? {
+            records.extend_from_slice(chunk);
+        }
+        Ok(Self { records })
+    }
+
+    /// Helper for easily writing the points to a file in the internal binary format.
+    pub fn to_file(&self, fs: &impl FileSystem, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = XyzInternalWriter::new(fs.create(path)?);
+        writer.write_records(&self.records)?;
+        writer.finish()?;
+        Ok(())
+    }
 }
 
 pub struct XyzInternalWriter<W: Write + Seek> {
@@ -132,6 +171,11 @@ impl<R: Read> XyzInternalReader<R> {
         })
     }
 
+    /// The total number of records in the file.
+    pub fn n_records(&self) -> u64 {
+        self.n_records
+    }
+
     pub fn next_chunk(&mut self) -> std::io::Result<Option<&[XyzRecord]>> {
         if self.records_read >= self.n_records {
             // TODO: log statistics about the read records
@@ -190,7 +234,7 @@ mod test {
             classification: 4,
             number_of_returns: 5,
             return_number: 6,
-            _padding: 0,
+            intensity: 7,
         };
 
         writer.write_records(&[record]).unwrap();
@@ -0,0 +1,192 @@
+//! Cancellation and resource limits for applications embedding the processing stages.
+//!
+//! A [`CancellationToken`] is installed for the current thread with [`with_cancellation`], and
+//! the long running loops of the stages call [`check_cancelled`] to stop early. Before allocating
+//! their large grids and images the stages call [`reserve`] with an estimate of their peak memory
+//! use, which fails if it exceeds the `memory_budget` of the [`Config`]. Inputs kept in memory
+//! while the stages run, such as the point cloud, are counted on top of it with [`hold`].
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::debug;
+
+use crate::config::Config;
+
+/// A cloneable flag for cancelling the processing running on other threads.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests all processing using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The reason a stage was stopped before completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobError {
+    /// The [`CancellationToken`] of the thread was cancelled.
+    Cancelled,
+    /// A stage estimated that it needs more memory than the configured budget.
+    MemoryBudgetExceeded {
+        stage: &'static str,
+        required: u64,
+        budget: u64,
+    },
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Cancelled => write!(f, "processing was cancelled"),
+            JobError::MemoryBudgetExceeded {
+                stage,
+                required,
+                budget,
+            } => write!(
+                f,
+                "{stage} needs about {} MB of memory which exceeds the memory budget of {} MB, \
+                 split the input into smaller tiles or use batch mode",
+                required.div_ceil(MEGABYTE),
+                budget / MEGABYTE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+thread_local! {
+    static TOKEN: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+    static HELD: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Runs `f` with `token` as the cancellation token of the current thread.
+pub fn with_cancellation<R>(token: &CancellationToken, f: impl FnOnce() -> R) -> R {
+    // restore the previous token also if `f` panics
+    struct Restore(Option<CancellationToken>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            TOKEN.with_borrow_mut(|t| *t = self.0.take());
+        }
+    }
+    let _restore = Restore(TOKEN.replace(Some(token.clone())));
+    f()
+}
+
+/// Returns the cancellation token of the current thread, for passing it on to other threads.
+pub fn current_token() -> Option<CancellationToken> {
+    TOKEN.with_borrow(|t| t.clone())
+}
+
+/// Returns true if the cancellation token of the current thread has been cancelled.
+pub fn is_cancelled() -> bool {
+    TOKEN.with_borrow(|t| t.as_ref().is_some_and(|t| t.is_cancelled()))
+}
+
+/// Returns an error if the cancellation token of the current thread has been cancelled.
+pub fn check_cancelled() -> Result<(), JobError> {
+    if is_cancelled() {
+        Err(JobError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that a stage estimated to need `bytes` of memory fits in the configured budget,
+/// together with the memory held by the current thread.
+pub fn reserve(config: &Config, stage: &'static str, bytes: u64) -> Result<(), JobError> {
+    check_cancelled()?;
+    debug!(
+        "{stage} needs about {} MB of memory",
+        bytes.div_ceil(MEGABYTE)
+    );
+    let required = bytes + HELD.get();
+    let budget = config.memory_budget * MEGABYTE;
+    if budget > 0 && required > budget {
+        return Err(JobError::MemoryBudgetExceeded {
+            stage,
+            required,
+            budget,
+        });
+    }
+    Ok(())
+}
+
+/// Memory kept by the current thread for the following stages, counted by [`reserve`] until
+/// dropped.
+#[derive(Debug)]
+pub struct Held(u64);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.set(HELD.get() - self.0);
+    }
+}
+
+/// Checks that `bytes` kept in memory fit in the budget like [`reserve`], and counts them in the
+/// stages run by the current thread until the returned [`Held`] is dropped.
+pub fn hold(config: &Config, stage: &'static str, bytes: u64) -> Result<Held, JobError> {
+    reserve(config, stage, bytes)?;
+    HELD.set(HELD.get() + bytes);
+    Ok(Held(bytes))
+}
+
+/// The memory needed by a grid of `width` x `height` cells of `T`.
+pub fn grid_bytes<T>(width: usize, height: usize) -> u64 {
+    width as u64 * height as u64 * size_of::<T>() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_is_per_thread() {
+        let token = CancellationToken::new();
+        token.cancel();
+
+        assert!(check_cancelled().is_ok());
+        let result = with_cancellation(&token, check_cancelled);
+        assert_eq!(result, Err(JobError::Cancelled));
+        assert!(!is_cancelled());
+
+        let other = token.clone();
+        let result = std::thread::spawn(move || with_cancellation(&other, is_cancelled));
+        assert!(result.join().unwrap());
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut config = Config::from_file(std::path::Path::new("pullauta.default.ini")).unwrap();
+        assert!(reserve(&config, "stage", 100 * MEGABYTE).is_ok());
+
+        config.memory_budget = 64;
+        assert!(reserve(&config, "stage", 64 * MEGABYTE).is_ok());
+        let err = reserve(&config, "stage", 100 * MEGABYTE).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stage needs about 100 MB of memory which exceeds the memory budget of 64 MB, \
+             split the input into smaller tiles or use batch mode"
+        );
+
+        // the held memory is counted until it is released
+        let held = hold(&config, "points", 40 * MEGABYTE).unwrap();
+        assert!(reserve(&config, "stage", 30 * MEGABYTE).is_err());
+        drop(held);
+        assert!(reserve(&config, "stage", 30 * MEGABYTE).is_ok());
+    }
+}
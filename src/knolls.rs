@@ -3,37 +3,36 @@ use imageproc::drawing::draw_line_segment_mut;
 use log::info;
 use rustc_hash::FxHashMap as HashMap;
 use std::error::Error;
-use std::path::Path;
 
 use crate::config::Config;
 use crate::geometry::{BinaryDxf, Bounds, Classification, Geometry, Point2, Points, Polylines};
-use crate::io::bytes::FromToBytes;
-use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
+use crate::job;
+use crate::progress::Progress;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub(crate) struct Dotknolls {
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Dotknolls {
     pub dotknolls: Vec<Dotknoll>,
 }
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub(crate) struct Dotknoll {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Dotknoll {
     pub x: f64,
     pub y: f64,
     pub is_knoll: bool,
 }
 
+/// Classifies the dot knolls and depressions found by `merge::smoothjoin` by whether they have
+/// room around them between the contours of `out2`. `hmap` is the knoll-adjusted heightmap.
 pub fn dotknolls(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-) -> Result<(), Box<dyn Error>> {
+    hmap: &HeightMap,
+    out2: &BinaryDxf,
+    dotknolls: &Dotknolls,
+) -> Result<BinaryDxf, Box<dyn Error>> {
     info!("Identifying dotknolls...");
 
     let scalefactor = config.scalefactor;
 
-    let heightmap_in = tmpfolder.join("xyz_knolls.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(heightmap_in)?)?;
-
     // in world coordinates
     let xstart = hmap.xoffset;
     let ystart = hmap.yoffset;
@@ -49,8 +48,7 @@ pub fn dotknolls(
         Luma([0xff]),
     );
 
-    let data = BinaryDxf::from_reader(&mut fs.open(tmpfolder.join("out2.dxf.bin"))?)?;
-    let Geometry::Polylines3(lines) = data.take_geometry().swap_remove(0) else {
+    let Some(Geometry::Polylines3(lines)) = out2.geometry().first() else {
         return Err(anyhow::anyhow!("out2.dxf.bin should contain polylines").into());
     };
 
@@ -73,11 +71,8 @@ pub fn dotknolls(
 
     let mut dotknoll_points = Points::new();
 
-    let dotknolls: Dotknolls =
-        crate::util::read_object(&mut fs.open(tmpfolder.join("dotknolls.bin"))?)?;
-
-    for dot in dotknolls.dotknolls {
-        let Dotknoll { x, y, is_knoll } = dot;
+    for dot in &dotknolls.dotknolls {
+        let &Dotknoll { x, y, is_knoll } = dot;
 
         let mut ok = true;
         let mut i = (x - xstart) / scalefactor - 3.0;
@@ -117,25 +112,17 @@ pub fn dotknolls(
         vec![dotknoll_points.into()],
     );
 
-    // write binary
-    let mut f = fs
-        .create(tmpfolder.join("dotknolls.dxf.bin"))
-        .expect("Unable to create file");
-    dxf.to_writer(&mut f)
-        .expect("could not write dotknolls.dxf.bin");
-
-    if config.output_dxf {
-        dxf.to_dxf(&mut fs.create(tmpfolder.join("dotknolls.dxf"))?)?;
-    }
-
     info!("Done");
-    Ok(())
+    Ok(dxf)
 }
+
+/// Detects the knolls too small for the contour interval from the 0.3 m contours of the
+/// heightmap. Returns the detected knoll outlines and the pins `xyzknolls` raises them with.
 pub fn knolldetector(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-) -> anyhow::Result<()> {
+    hmap: &HeightMap,
+    contours03: &BinaryDxf,
+) -> anyhow::Result<(BinaryDxf, Vec<Pin>)> {
     info!("Detecting knolls...");
     let scalefactor = config.scalefactor;
     let contour_interval = config.contour_interval;
@@ -146,9 +133,6 @@ pub fn knolldetector(
 
     let interval = 0.3 * scalefactor;
 
-    let heightmap_in = tmpfolder.join("xyz_03.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(heightmap_in)?)?;
-
     // in world coordinates
     let xstart = hmap.xoffset;
     let ystart = hmap.yoffset;
@@ -165,8 +149,7 @@ pub fn knolldetector(
         xyz.insert((x as u64, y as u64), h);
     }
 
-    let data = BinaryDxf::from_reader(&mut fs.open(tmpfolder.join("contours03.dxf.bin"))?)?;
-    let Geometry::Polylines2(lines) = data.take_geometry().swap_remove(0) else {
+    let Some(Geometry::Polylines2(lines)) = contours03.geometry().first() else {
         anyhow::bail!("contours03.dxf.bin should contain polylines");
     };
 
@@ -485,7 +468,11 @@ pub fn knolldetector(
         maxy: f64,
     }
     let mut bb: HashMap<usize, BoundingBox> = HashMap::default();
+    // the two loops below compare every closed contour with each other and take most of the time
+    let mut progress = Progress::new("knolldetector", 2 * lines.len() as u64);
     for l in 0..lines.len() {
+        progress.inc(1);
+        job::check_cancelled()?;
         let mut skip = false;
         if !el_x[l].is_empty() {
             let mut x = el_x[l].to_vec();
@@ -577,6 +564,8 @@ pub fn knolldetector(
     let mut canditates = Vec::<Candidate>::new();
 
     for l in 0..lines.len() {
+        progress.inc(1);
+        job::check_cancelled()?;
         let mut skip = true;
         if !el_x[l].is_empty() {
             let mut x = el_x[l].to_vec();
@@ -802,25 +791,14 @@ pub fn knolldetector(
     }
 
     let detected_dxf = BinaryDxf::new(detected_bounds, vec![detected_lines.into()]);
-    detected_dxf.to_writer(&mut fs.create(tmpfolder.join("detected.dxf.bin"))?)?;
-
-    if config.output_dxf {
-        detected_dxf.to_dxf(&mut fs.create(tmpfolder.join("detected.dxf"))?)?;
-    }
-
-    // write pins to file
-    let file_pins = fs
-        .create(tmpfolder.join("pins.bin"))
-        .expect("Unable to create file");
-    crate::util::write_object(file_pins, &pins).expect("Unable to write pins");
 
     info!("Done");
-    Ok(())
+    Ok((detected_dxf, pins))
 }
 
-/// Struct used to store temporary data about pins on disk
-#[derive(serde::Serialize, serde::Deserialize)]
-struct Pin {
+/// A knoll found by `knolldetector`, stored in pins.bin between the command line steps
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Pin {
     xx: f64,
     yy: f64,
     ele: f64,
@@ -829,21 +807,15 @@ struct Pin {
     ylist: Vec<f64>,
 }
 
-pub fn xyzknolls(
-    fs: &impl FileSystem,
-    config: &Config,
-    tmpfolder: &Path,
-) -> Result<(), Box<dyn Error>> {
+/// Smooths the flat areas of the heightmap and raises the knolls at `pins` so that the
+/// contours show them. Returns the knoll-adjusted heightmap.
+pub fn xyzknolls(config: &Config, hmap: &HeightMap, pins: &[Pin]) -> HeightMap {
     info!("Identifying knolls...");
     let scalefactor = config.scalefactor;
     let contour_interval = config.contour_interval;
 
     let interval = contour_interval / 2.0 * scalefactor;
 
-    // load the binary file
-    let heightmap_in = tmpfolder.join("xyz_03.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(heightmap_in)?)?;
-
     let xmax = hmap.grid.width() - 1;
     let ymax = hmap.grid.height() - 1;
     let size = hmap.scale;
@@ -880,15 +852,6 @@ pub fn xyzknolls(
         }
     }
 
-    // read pins from file if it exists
-    let pins_file_in = tmpfolder.join("pins.bin");
-    let pins: Vec<Pin> = if fs.exists(&pins_file_in) {
-        let pins_file_in = fs.open(pins_file_in).expect("Unable to open file");
-        crate::util::read_object(pins_file_in).expect("Unable to write pins")
-    } else {
-        Vec::new()
-    };
-
     // compute closest distance from each pin to another pin
     let mut dist: HashMap<usize, f64> = HashMap::default();
     for (l, pin) in pins.iter().enumerate() {
@@ -914,15 +877,12 @@ pub fn xyzknolls(
         dist.insert(l, min);
     }
 
-    for (l, line) in pins.into_iter().enumerate() {
+    for (l, line) in pins.iter().enumerate() {
         let Pin {
-            xx,
-            yy,
-            ele,
-            ele2,
-            xlist: mut x,
-            ylist: mut y,
-        } = line;
+            xx, yy, ele, ele2, ..
+        } = *line;
+        let mut x = line.xlist.clone();
+        let mut y = line.ylist.clone();
 
         let elenew = ((ele - 0.09) / interval + 1.0).floor() * interval;
         let mut move1 = elenew - ele + 0.15;
@@ -1035,11 +995,6 @@ pub fn xyzknolls(
         }
     }
 
-    // write the updated heightmap
-    let heightmap_out = tmpfolder.join("xyz_knolls.hmap");
-    let mut writer = fs.create(heightmap_out)?;
-    xyz2.to_bytes(&mut writer)?;
-
     info!("Done");
-    Ok(())
+    xyz2
 }
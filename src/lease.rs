@@ -0,0 +1,559 @@
+//! Lease based claiming of batch tiles, so that several `pullauta` instances, also on different
+//! machines, can process the same batch folder on shared storage.
+//!
+//! A tile is claimed by atomically creating the lease file `<tile>.0.lease` in the batch output
+//! folder. The holder renews the lease by replacing the file while it processes the tile, and
+//! removes it when done. If the holder stops renewing, for example because it crashed, the lease
+//! expires and another instance takes over the tile by creating the next generation
+//! `<tile>.1.lease`. A holder that finds a newer generation has lost its lease and stops. A
+//! finished tile is recorded in `<tile>.done` before its lease is removed, and is not claimed
+//! again while the record exists, unless the png it rendered has been removed. Output folders of
+//! earlier versions have no done records, so there a non-empty `<tile>.png` without a lease marks
+//! the tile as done.
+//!
+//! A lease expires when its file has not been written for the lease timeout. The age of the file
+//! is measured with the clock of the shared storage, by comparing its modification time with the
+//! one of a probe file written next to it, so the clocks of the machines do not need to agree.
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::io::fs::FileSystem;
+use crate::job::{self, CancellationToken};
+
+/// The contents of a lease file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseInfo {
+    /// Identifies the holder as `<instance>:<thread>`.
+    pub owner: String,
+    /// The random id of the `pullauta` process holding the lease.
+    pub instance: String,
+    /// Seconds since the unix epoch when the lease was acquired.
+    pub acquired: u64,
+    /// Seconds since the unix epoch after which the holder expects the lease to be taken over,
+    /// by the clock of its machine. Only used when the storage has no modification times.
+    pub expires: u64,
+}
+
+/// The contents of the done record of a finished tile.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DoneInfo {
+    /// The holder of the lease that finished the tile, as `<instance>:<thread>`.
+    pub owner: String,
+    /// Seconds since the unix epoch when the tile was finished.
+    pub finished: u64,
+    /// True if the tile rendered `<tile>.png`, which is processed again if the png is removed.
+    pub png: bool,
+}
+
+impl LeaseInfo {
+    fn new(thread: &str, ttl: Duration) -> Self {
+        let instance = instance_id().to_string();
+        Self {
+            owner: format!("{instance}:{thread}"),
+            instance,
+            acquired: now(),
+            expires: now() + ttl.as_secs(),
+        }
+    }
+}
+
+/// A lease held on a tile.
+#[derive(Debug)]
+pub struct Lease {
+    folder: PathBuf,
+    tile: String,
+    generation: u64,
+    ttl: Duration,
+    info: LeaseInfo,
+}
+
+impl Lease {
+    /// Tries to claim `tile` in `folder`, taking over stale leases. Returns `None` if the tile is
+    /// held by someone else.
+    pub fn acquire(
+        fs: &impl FileSystem,
+        folder: impl AsRef<Path>,
+        tile: &str,
+        thread: &str,
+        ttl: Duration,
+    ) -> io::Result<Option<Lease>> {
+        let folder = folder.as_ref();
+        let mut generation = 0;
+        loop {
+            let path = lease_path(folder, tile, generation);
+            let info = LeaseInfo::new(thread, ttl);
+            match fs.create_new(&path) {
+                Ok(file) => {
+                    serde_json::to_writer(file, &info)?;
+                    if generation > 0 {
+                        info!("Took over the stale lease of {tile}");
+                    }
+                    return Ok(Some(Lease {
+                        folder: folder.to_path_buf(),
+                        tile: tile.to_string(),
+                        generation,
+                        ttl,
+                        info,
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+
+            // only the newest generation is valid
+            if fs.exists(lease_path(folder, tile, generation + 1)) {
+                generation += 1;
+                continue;
+            }
+            let expired = is_expired(fs, folder, &path, ttl);
+            match read_info(fs, &path) {
+                Some(holder) if expired => {
+                    debug!("Lease of {tile} held by {} is stale", holder.owner);
+                    generation += 1;
+                }
+                Some(holder) => {
+                    debug!("{tile} is being processed by {}", holder.owner);
+                    return Ok(None);
+                }
+                // the holder crashed while creating the file
+                None if expired => {
+                    debug!("Lease of {tile} is unreadable and older than the lease timeout");
+                    generation += 1;
+                }
+                // the file is being written by its holder
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Claims a tile of a batch unless it is done or held by someone else.
+    pub fn claim_tile(
+        fs: &impl FileSystem,
+        config: &Config,
+        tile: &str,
+        thread: &str,
+    ) -> Option<Lease> {
+        let folder = Path::new(&config.batchoutfolder);
+        if Lease::is_done(fs, folder, tile) {
+            info!("Skipping {tile}, it is done already in output folder.");
+            return None;
+        }
+
+        let ttl = Duration::from_secs(config.lease_timeout.max(1));
+        let lease = match Lease::acquire(fs, folder, tile, thread, ttl) {
+            Ok(Some(lease)) => lease,
+            Ok(None) => {
+                info!("Skipping {tile}, it is being processed by another instance.");
+                return None;
+            }
+            Err(e) => {
+                warn!("Could not claim {tile}: {e}");
+                return None;
+            }
+        };
+        // the tile may have been finished after the check above
+        if Lease::is_done(fs, folder, tile) {
+            lease.release(fs).ok();
+            info!("Skipping {tile}, it is done already in output folder.");
+            return None;
+        }
+        Some(lease)
+    }
+
+    /// Check if `tile` has been finished, see the [module documentation](self).
+    pub fn is_done(fs: &impl FileSystem, folder: impl AsRef<Path>, tile: &str) -> bool {
+        let folder = folder.as_ref();
+        let png = png_path(folder, tile);
+        let done = done_path(folder, tile);
+        if fs.exists(&done) {
+            let rendered = fs
+                .read_to_string(&done)
+                .ok()
+                .and_then(|data| serde_json::from_str::<DoneInfo>(&data).ok())
+                .is_some_and(|info| info.png);
+            return !rendered || fs.exists(png);
+        }
+        !Lease::exists(fs, folder, tile) && fs.file_size(png).is_ok_and(|size| size > 0)
+    }
+
+    /// Removes the done record and the png of `tile`, so that it is processed again.
+    pub fn reset(fs: &impl FileSystem, folder: impl AsRef<Path>, tile: &str) -> io::Result<()> {
+        let folder = folder.as_ref();
+        for path in [done_path(folder, tile), png_path(folder, tile)] {
+            if fs.exists(&path) {
+                fs.remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if `path` is a lease file or a done record, which are not outputs of a tile.
+    pub fn is_record(path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.ends_with(".lease") || name.ends_with(".lease.tmp") || name.ends_with(".done")
+            })
+    }
+
+    /// Check if `tile` has a lease file, held or stale.
+    pub fn exists(fs: &impl FileSystem, folder: impl AsRef<Path>, tile: &str) -> bool {
+        fs.exists(lease_path(folder.as_ref(), tile, 0))
+    }
+
+    /// True if the lease was taken over from a holder that did not finish the tile.
+    pub fn is_takeover(&self) -> bool {
+        self.generation > 0
+    }
+
+    /// Extends the lease. Returns false if it has been taken over by someone else.
+    pub fn renew(&mut self, fs: &impl FileSystem) -> io::Result<bool> {
+        if fs.exists(lease_path(&self.folder, &self.tile, self.generation + 1)) {
+            return Ok(false);
+        }
+        self.info.expires = now() + self.ttl.as_secs();
+        // replace the file at once, so that others never read a partially written lease
+        let path = lease_path(&self.folder, &self.tile, self.generation);
+        let temp = path.with_extension("lease.tmp");
+        let mut file = fs.create(&temp)?;
+        serde_json::to_writer(&mut file, &self.info)?;
+        file.flush()?;
+        drop(file);
+        fs.rename(temp, path)?;
+        Ok(true)
+    }
+
+    /// Records the tile as done, with the holder that finished it and whether it rendered a png,
+    /// and gives up the lease.
+    pub fn finish(self, fs: &impl FileSystem) -> io::Result<()> {
+        let info = DoneInfo {
+            owner: self.info.owner.clone(),
+            finished: now(),
+            png: fs.exists(png_path(&self.folder, &self.tile)),
+        };
+        let mut file = fs.create(done_path(&self.folder, &self.tile))?;
+        serde_json::to_writer(&mut file, &info)?;
+        file.flush()?;
+        drop(file);
+        self.release(fs)
+    }
+
+    /// Gives up the lease, removing the lease files of all generations.
+    pub fn release(self, fs: &impl FileSystem) -> io::Result<()> {
+        for generation in 0..=self.generation {
+            let path = lease_path(&self.folder, &self.tile, generation);
+            if fs.exists(&path) {
+                fs.remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` while renewing the lease in the background. The cancellation token of the current
+    /// thread is replaced for `f` with one that is also cancelled if the lease is lost. Returns
+    /// the result of `f` and whether the lease is still held.
+    pub fn hold<R>(&mut self, fs: &(impl FileSystem + Sync), f: impl FnOnce() -> R) -> (R, bool) {
+        let parent = job::current_token();
+        let token = CancellationToken::new();
+        let done = AtomicBool::new(false);
+        let lost = AtomicBool::new(false);
+        // renew well before the lease expires
+        let interval = self.ttl / 3;
+
+        let lease = &mut *self;
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut renewed = std::time::Instant::now();
+                while !done.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(100).min(interval));
+                    if parent.as_ref().is_some_and(|p| p.is_cancelled()) {
+                        token.cancel();
+                    }
+                    if renewed.elapsed() < interval {
+                        continue;
+                    }
+                    renewed = std::time::Instant::now();
+                    match lease.renew(fs) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!("Lost the lease of {} to another instance", lease.tile);
+                            lost.store(true, Ordering::Relaxed);
+                            token.cancel();
+                            break;
+                        }
+                        Err(e) => warn!("Could not renew the lease of {}: {e}", lease.tile),
+                    }
+                }
+            });
+            let result = job::with_cancellation(&token, f);
+            done.store(true, Ordering::Relaxed);
+            result
+        });
+        (result, !lost.load(Ordering::Relaxed))
+    }
+}
+
+fn lease_path(folder: &Path, tile: &str, generation: u64) -> PathBuf {
+    folder.join(format!("{tile}.{generation}.lease"))
+}
+
+fn done_path(folder: &Path, tile: &str) -> PathBuf {
+    folder.join(format!("{tile}.done"))
+}
+
+fn png_path(folder: &Path, tile: &str) -> PathBuf {
+    folder.join(format!("{tile}.png"))
+}
+
+fn read_info(fs: &impl FileSystem, path: &Path) -> Option<LeaseInfo> {
+    serde_json::from_str(&fs.read_to_string(path).ok()?).ok()
+}
+
+/// Check if the lease file at `path` was last written longer than `ttl` ago by the clock of the
+/// storage of `folder`. Without modification times the expiry written by the holder is compared
+/// with the local clock instead.
+fn is_expired(fs: &impl FileSystem, folder: &Path, path: &Path, ttl: Duration) -> bool {
+    let Ok(modified) = fs.modified(path) else {
+        return read_info(fs, path).is_some_and(|holder| now() > holder.expires);
+    };
+    match storage_now(fs, folder) {
+        Ok(storage_now) => storage_now
+            .duration_since(modified)
+            .is_ok_and(|age| age > ttl),
+        Err(e) => {
+            warn!(
+                "Could not read the time of the storage of {}: {e}",
+                folder.display()
+            );
+            false
+        }
+    }
+}
+
+/// The current time of the storage of `folder`, which is the modification time of a probe file
+/// written there.
+fn storage_now(fs: &impl FileSystem, folder: &Path) -> io::Result<SystemTime> {
+    static NEXT_PROBE: AtomicU64 = AtomicU64::new(0);
+    let probe = folder.join(format!(
+        "{}_{}.clock",
+        instance_id(),
+        NEXT_PROBE.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs.create(&probe)?.flush()?;
+    let modified = fs.modified(&probe);
+    fs.remove_file(&probe)?;
+    modified
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A random id of this process, to tell the leases of different `pullauta` instances apart.
+/// Unlike the host name and the process id, it is unique across machines.
+fn instance_id() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::fs::memory::MemoryFileSystem;
+    use std::io::Write;
+
+    #[test]
+    fn test_lease_is_exclusive() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let lease = Lease::acquire(&fs, "out", "a.laz", "1", ttl)
+            .unwrap()
+            .unwrap();
+        assert!(!lease.is_takeover());
+        assert!(
+            Lease::acquire(&fs, "out", "a.laz", "2", ttl)
+                .unwrap()
+                .is_none()
+        );
+        assert!(Lease::exists(&fs, "out", "a.laz"));
+
+        lease.release(&fs).unwrap();
+        assert!(!Lease::exists(&fs, "out", "a.laz"));
+        assert!(
+            Lease::acquire(&fs, "out", "a.laz", "2", ttl)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_finished_tile_is_not_claimed() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.batchoutfolder = "out".to_string();
+
+        let lease = Lease::claim_tile(&fs, &config, "a.laz", "1").unwrap();
+        lease.finish(&fs).unwrap();
+        assert!(Lease::is_done(&fs, "out", "a.laz"));
+        assert!(!Lease::exists(&fs, "out", "a.laz"));
+        assert!(Lease::claim_tile(&fs, &config, "a.laz", "2").is_none());
+
+        Lease::reset(&fs, "out", "a.laz").unwrap();
+        assert!(Lease::claim_tile(&fs, &config, "a.laz", "2").is_some());
+    }
+
+    #[test]
+    fn test_removed_png_is_processed_again() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.batchoutfolder = "out".to_string();
+
+        let lease = Lease::claim_tile(&fs, &config, "a.laz", "1").unwrap();
+        fs.create("out/a.laz.png")
+            .unwrap()
+            .write_all(b"png")
+            .unwrap();
+        lease.finish(&fs).unwrap();
+        assert!(Lease::is_done(&fs, "out", "a.laz"));
+
+        fs.remove_file("out/a.laz.png").unwrap();
+        assert!(!Lease::is_done(&fs, "out", "a.laz"));
+        assert!(Lease::claim_tile(&fs, &config, "a.laz", "2").is_some());
+    }
+
+    #[test]
+    fn test_png_of_earlier_versions_is_done() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let ttl = Duration::from_secs(60);
+
+        // earlier versions only wrote the png, or an empty one in the modes without a map
+        fs.create("out/a.laz.png")
+            .unwrap()
+            .write_all(b"png")
+            .unwrap();
+        fs.create("out/b.laz.png").unwrap();
+        assert!(Lease::is_done(&fs, "out", "a.laz"));
+        assert!(!Lease::is_done(&fs, "out", "b.laz"));
+
+        // a png with a lease may be incomplete
+        let lease = Lease::acquire(&fs, "out", "a.laz", "1", ttl)
+            .unwrap()
+            .unwrap();
+        assert!(!Lease::is_done(&fs, "out", "a.laz"));
+        lease.release(&fs).unwrap();
+
+        Lease::reset(&fs, "out", "a.laz").unwrap();
+        assert!(!Lease::is_done(&fs, "out", "a.laz"));
+    }
+
+    #[test]
+    fn test_stale_lease_is_taken_over() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let ttl = Duration::from_secs(1);
+
+        let mut stale = LeaseInfo::new("1", ttl);
+        stale.instance = "other".to_string();
+        let file = fs.create("out/a.laz.0.lease").unwrap();
+        serde_json::to_writer(file, &stale).unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+
+        let mut lease = Lease::acquire(&fs, "out", "a.laz", "2", ttl)
+            .unwrap()
+            .unwrap();
+        assert!(lease.is_takeover());
+        assert!(fs.exists("out/a.laz.1.lease"));
+        assert!(
+            Lease::acquire(&fs, "out", "a.laz", "3", ttl)
+                .unwrap()
+                .is_none()
+        );
+        assert!(lease.renew(&fs).unwrap());
+
+        // a newer generation means the lease was lost
+        fs.create("out/a.laz.2.lease")
+            .unwrap()
+            .write_all(b"{}")
+            .unwrap();
+        assert!(!lease.renew(&fs).unwrap());
+        lease.release(&fs).unwrap();
+        assert!(!fs.exists("out/a.laz.0.lease"));
+    }
+
+    #[test]
+    fn test_lease_expiry_ignores_the_clock_of_the_holder() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let ttl = Duration::from_secs(60);
+
+        // a holder with a clock behind ours, renewing its lease
+        let mut behind = LeaseInfo::new("1", ttl);
+        behind.instance = "other".to_string();
+        behind.expires = now() - 3600;
+        let file = fs.create("out/a.laz.0.lease").unwrap();
+        serde_json::to_writer(file, &behind).unwrap();
+
+        assert!(
+            Lease::acquire(&fs, "out", "a.laz", "2", ttl)
+                .unwrap()
+                .is_none()
+        );
+        // the clock probes are removed
+        assert_eq!(fs.list("out").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unreadable_lease_is_taken_over_after_timeout() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+
+        // a holder that crashed while writing its lease
+        fs.create("out/a.laz.0.lease")
+            .unwrap()
+            .write_all(b"{\"own")
+            .unwrap();
+        assert!(
+            Lease::acquire(&fs, "out", "a.laz", "1", Duration::from_secs(60))
+                .unwrap()
+                .is_none()
+        );
+        std::thread::sleep(Duration::from_millis(1100));
+        let lease = Lease::acquire(&fs, "out", "a.laz", "1", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert!(lease.is_takeover());
+    }
+
+    #[test]
+    fn test_renew_replaces_the_lease() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let mut lease = Lease::acquire(&fs, "out", "a.laz", "1", ttl)
+            .unwrap()
+            .unwrap();
+        assert!(lease.renew(&fs).unwrap());
+        let info = read_info(&fs, Path::new("out/a.laz.0.lease")).unwrap();
+        assert_eq!(info.instance, instance_id());
+        assert_eq!(
+            fs.list("out").unwrap(),
+            [PathBuf::from("out/a.laz.0.lease")]
+        );
+    }
+}
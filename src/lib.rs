@@ -3,21 +3,45 @@
 // make sure any use of unsafe is documented
 #![deny(clippy::undocumented_unsafe_blocks)]
 
+pub mod areas;
+pub mod banks;
 pub mod blocks;
+pub mod boulders;
+pub mod capi;
 pub mod cliffs;
 pub mod config;
 pub mod contours;
 pub mod crop;
+pub mod drainage;
 pub mod geometry;
 pub mod io;
+pub mod job;
 pub mod knolls;
+pub mod lease;
+pub mod marshes;
 pub mod merge;
+pub mod paths;
+pub mod pipeline;
+pub mod pits;
+pub mod powerlines;
 pub mod process;
+pub mod progress;
+#[cfg(feature = "python")]
+pub mod python;
 pub mod render;
+pub mod report;
+pub mod stony;
+pub mod streams;
+pub mod tiles;
 pub mod util;
 pub mod vec2d;
 pub mod vegetation;
+pub mod watch;
+pub mod water;
+pub mod worker;
 
+#[cfg(feature = "server")]
+pub mod server;
 #[cfg(feature = "shapefile")]
 pub mod shapefile;
 
@@ -1,11 +1,20 @@
-use log::debug;
+use image::DynamicImage;
 use log::info;
 use pullauta::config::Config;
+use pullauta::geometry::BinaryDxf;
 use pullauta::io::fs::FileSystem;
+use pullauta::io::fs::archive::ZipFileSystem;
+#[cfg(feature = "http")]
+use pullauta::io::fs::http::HttpFileSystem;
 use pullauta::io::fs::memory::MemoryFileSystem;
-use pullauta::shapefile;
+use pullauta::io::fs::mount::MountFileSystem;
+use pullauta::io::fs::overlay::OverlayFileSystem;
+use pullauta::io::heightmap::HeightMap;
+use pullauta::io::xyz::PointCloud;
+use pullauta::knolls::{Dotknolls, Pin};
 use std::env;
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -35,6 +44,18 @@ fn main() {
     let config =
         Arc::new(Config::load_or_create_default().expect("Could not open or create config file"));
 
+    if config.progress_json {
+        pullauta::progress::set_json_lines_handler();
+        // everything that fails ends in a panic, so report those as errors
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            pullauta::progress::emit(pullauta::progress::ProgressEvent::Error {
+                message: info.to_string(),
+            });
+            default_hook(info);
+        }));
+    }
+
     let fs = pullauta::io::fs::local::LocalFileSystem;
 
     let mut args: Vec<String> = env::args().collect();
@@ -60,7 +81,14 @@ fn main() {
         || command_lowercase.ends_with(".xyz.bin")
     {
         const VERSION: &str = env!("CARGO_PKG_VERSION");
-        println!("Karttapullautin v{VERSION}\nThere is no warranty. Use it at your own risk!\n");
+        let banner =
+            format!("Karttapullautin v{VERSION}\nThere is no warranty. Use it at your own risk!\n");
+        // stdout is for the progress events when they are written as JSON
+        if config.progress_json {
+            eprintln!("{banner}");
+        } else {
+            println!("{banner}");
+        }
     }
 
     let batch: bool = config.batch;
@@ -73,7 +101,7 @@ fn main() {
 
     if command.is_empty() && fs.exists(tmpfolder.join("vegetation.png")) && !batch {
         info!("Rendering png map with depressions");
-        pullauta::render::render(
+        pullauta::render::render_files(
             &fs,
             &config,
             &thread,
@@ -84,7 +112,7 @@ fn main() {
         )
         .unwrap();
         info!("Rendering png map without depressions");
-        pullauta::render::render(
+        pullauta::render::render_files(
             &fs,
             &config,
             &thread,
@@ -99,9 +127,12 @@ fn main() {
     }
 
     if command.is_empty() && !batch {
-        println!(
-            "USAGE:\npullauta [parameter 1] [parameter 2] [parameter 3] ... [parameter n]\nSee README.MD for more details"
-        );
+        let usage = "USAGE:\npullauta [parameter 1] [parameter 2] [parameter 3] ... [parameter n]\nSee README.MD for more details";
+        if config.progress_json {
+            eprintln!("{usage}");
+        } else {
+            println!("{usage}");
+        }
         return;
     }
 
@@ -170,12 +201,25 @@ fn main() {
     }
 
     if command == "blocks" {
-        pullauta::blocks::blocks(&fs, &tmpfolder).unwrap();
+        let (points, hmap) = read_tile(&fs, &tmpfolder);
+        let buildings = pullauta::blocks::blocks(&config, &points, &hmap).unwrap();
+        write_png(&fs, tmpfolder.join("blocks.png"), &buildings.image);
+        buildings
+            .footprints
+            .to_file(&fs, tmpfolder.join("blocks.dxf.bin"), config.output_dxf)
+            .unwrap();
         return;
     }
 
     if command == "dotknolls" {
-        pullauta::knolls::dotknolls(&fs, &config, &tmpfolder).unwrap();
+        let hmap = HeightMap::from_file(&fs, tmpfolder.join("xyz_knolls.hmap")).unwrap();
+        let out2 = BinaryDxf::from_file(&fs, tmpfolder.join("out2.dxf.bin")).unwrap();
+        let dotknolls: Dotknolls =
+            pullauta::util::read_object(fs.open(tmpfolder.join("dotknolls.bin")).unwrap()).unwrap();
+        pullauta::knolls::dotknolls(&config, &hmap, &out2, &dotknolls)
+            .unwrap()
+            .to_file(&fs, tmpfolder.join("dotknolls.dxf.bin"), config.output_dxf)
+            .unwrap();
         return;
     }
 
@@ -195,17 +239,50 @@ fn main() {
     }
 
     if command == "knolldetector" {
-        pullauta::knolls::knolldetector(&fs, &config, &tmpfolder).unwrap();
+        let hmap = HeightMap::from_file(&fs, tmpfolder.join("xyz2.hmap")).unwrap();
+        let contours03 = BinaryDxf::from_file(&fs, tmpfolder.join("contours03.dxf.bin")).unwrap();
+        let (detected, pins) =
+            pullauta::knolls::knolldetector(&config, &hmap, &contours03).unwrap();
+        detected
+            .to_file(&fs, tmpfolder.join("detected.dxf.bin"), config.output_dxf)
+            .unwrap();
+        pullauta::util::write_object(fs.create(tmpfolder.join("pins.bin")).unwrap(), &pins)
+            .unwrap();
         return;
     }
 
     if command == "makecliffs" {
-        pullauta::cliffs::makecliffs(&fs, &config, &tmpfolder).unwrap();
+        let (points, hmap) = read_tile(&fs, &tmpfolder);
+        let cliffs = pullauta::cliffs::makecliffs(&config, &points, &hmap).unwrap();
+        for (name, dxf) in [("c2g.dxf.bin", &cliffs.small), ("c3g.dxf.bin", &cliffs.big)] {
+            dxf.to_file(&fs, tmpfolder.join(name), config.output_dxf)
+                .unwrap();
+        }
         return;
     }
 
     if command == "makevege" {
-        pullauta::vegetation::makevege(&fs, &config, &tmpfolder).unwrap();
+        let (points, hmap) = read_tile(&fs, &tmpfolder);
+        let vegetation = pullauta::vegetation::makevege(&config, &points, &hmap).unwrap();
+        vegetation
+            .vegetation
+            .to_file(&fs, tmpfolder.join("vegetation.png"))
+            .unwrap();
+        vegetation
+            .undergrowth
+            .to_file(&fs, tmpfolder.join("undergrowth.png"))
+            .unwrap();
+        if let Some(water) = &vegetation.water {
+            write_png(&fs, tmpfolder.join("blueblack.png"), water);
+        }
+        if let Some(bits) = &vegetation.bits {
+            write_png(&fs, tmpfolder.join("vegetation_bit.png"), &bits.vegetation);
+            write_png(
+                &fs,
+                tmpfolder.join("undergrowth_bit.png"),
+                &bits.undergrowth,
+            );
+        }
     }
 
     if command == "pngmerge" || command == "pngmergedepr" {
@@ -217,6 +294,23 @@ fn main() {
         return;
     }
 
+    if command == "pngtiles" || command == "pngtilesdepr" {
+        let mut zooms = args.iter().filter_map(|a| a.parse::<u32>().ok());
+        let minzoom = zooms.next();
+        let maxzoom = zooms.next();
+        let tms = args.iter().any(|a| a == "tms");
+        pullauta::tiles::pngtiles(
+            &fs,
+            &config,
+            minzoom,
+            maxzoom,
+            tms,
+            command == "pngtilesdepr",
+        )
+        .unwrap();
+        return;
+    }
+
     if command == "pngmergevege" {
         let mut scale = 1.0;
         if !args.is_empty() {
@@ -297,16 +391,44 @@ fn main() {
     }
 
     if command == "smoothjoin" {
-        pullauta::merge::smoothjoin(&fs, &config, &tmpfolder).unwrap();
+        let hmap = HeightMap::from_file(&fs, tmpfolder.join("xyz_knolls.hmap")).unwrap();
+        let out = BinaryDxf::from_file(&fs, tmpfolder.join("out.dxf.bin")).unwrap();
+        let smoothed = pullauta::merge::smoothjoin(&config, &hmap, &out).unwrap();
+        smoothed
+            .contours
+            .to_file(&fs, tmpfolder.join("out2.dxf.bin"), config.output_dxf)
+            .unwrap();
+        pullauta::util::write_object(
+            fs.create(tmpfolder.join("dotknolls.bin")).unwrap(),
+            &smoothed.dotknolls,
+        )
+        .unwrap();
+        fs.create(tmpfolder.join("depressions.txt"))
+            .and_then(|mut f| f.write_all(smoothed.depressions.as_bytes()))
+            .unwrap();
+        fs.create(tmpfolder.join("knollheads.txt"))
+            .and_then(|mut f| f.write_all(smoothed.knollheads.as_bytes()))
+            .unwrap();
     }
 
     if command == "xyzknolls" {
-        pullauta::knolls::xyzknolls(&fs, &config, &tmpfolder).unwrap();
+        let hmap = HeightMap::from_file(&fs, tmpfolder.join("xyz2.hmap")).unwrap();
+        // the pins of the knoll detector, if it was run
+        let pins_file = tmpfolder.join("pins.bin");
+        let pins: Vec<Pin> = if fs.exists(&pins_file) {
+            pullauta::util::read_object(fs.open(pins_file).unwrap()).unwrap()
+        } else {
+            Vec::new()
+        };
+        pullauta::knolls::xyzknolls(&config, &hmap, &pins)
+            .to_file(&fs, tmpfolder.join("xyz_knolls.hmap"))
+            .unwrap();
     }
 
     #[cfg(feature = "shapefile")]
     if command == "unzipmtk" {
-        pullauta::shapefile::unzip_and_render(&fs, &config, &tmpfolder, &args).unwrap();
+        let fs = mount_zips(fs.clone(), Path::new("temp_shapefiles"), &args);
+        pullauta::shapefile::render(&fs, &config, &tmpfolder, true).unwrap();
     }
 
     #[cfg(feature = "shapefile")]
@@ -319,21 +441,17 @@ fn main() {
         let xyzfilein = args[1].clone();
         let xyzfileout = args[2].clone();
         let dxffile = args[3].clone();
-        let hmap = pullauta::contours::xyz2heightmap(&fs, &config, &tmpfolder, &xyzfilein).unwrap();
+        let points = PointCloud::from_file(&fs, tmpfolder.join(xyzfilein)).unwrap();
+        let hmap = pullauta::contours::xyz2heightmap(&config, &points).unwrap();
 
         if xyzfileout != "null" && !xyzfileout.is_empty() {
             hmap.to_file(&fs, xyzfileout).unwrap();
         }
 
-        pullauta::contours::heightmap2contours(
-            &fs,
-            &tmpfolder,
-            cinterval,
-            &hmap,
-            &dxffile,
-            config.output_dxf,
-        )
-        .unwrap();
+        pullauta::contours::heightmap2contours(cinterval, &hmap)
+            .unwrap()
+            .to_file(&fs, tmpfolder.join(dxffile), config.output_dxf)
+            .unwrap();
         return;
     }
 
@@ -347,7 +465,7 @@ fn main() {
             .and_then(|s| s.parse::<usize>().ok())
             .expect("expected second argument to be nwidth");
         let nodepressions: bool = args.len() > 2 && args[2] == "nodepressions";
-        pullauta::render::render(
+        pullauta::render::render_files(
             &fs,
             &config,
             &thread,
@@ -360,87 +478,82 @@ fn main() {
         return;
     }
     let proc = config.processes;
-    if command.is_empty() && batch {
-        // inner function to reduce code duplication
-        fn launch_threads<F: FileSystem + Send + Clone + 'static>(
-            fs: F,
-            proc: u64,
-            config: &Arc<Config>,
-            zip_files: &[String],
-        ) {
-            let shapefiletmpdir = PathBuf::from("temp_shapefiles".to_string());
-            fs.create_dir_all(&shapefiletmpdir).unwrap();
-            if !zip_files.is_empty() {
-                crate::shapefile::unzip_shapefiles(&fs, zip_files).unwrap();
-            }
-            // do the processing
-            let mut handles: Vec<thread::JoinHandle<()>> = Vec::with_capacity((proc + 1) as usize);
-            for i in 0..proc {
-                let config = config.clone();
-                let fs = fs.clone();
-                let has_zip = !zip_files.is_empty();
-                let handle = thread::spawn(move || {
-                    info!("Starting thread");
-                    pullauta::process::batch_process(&config, &fs, &format!("{}", i + 1), has_zip);
-                    info!("Thread complete");
-                });
-                thread::sleep(time::Duration::from_millis(100));
-                handles.push(handle);
-            }
-            for handle in handles {
-                handle.join().unwrap();
-            }
-            fs.remove_dir_all(&shapefiletmpdir).unwrap();
+    if command == pullauta::worker::WORKER_COMMAND {
+        // a worker process of a batch with isolated workers, processing a single claimed tile
+        let laz = args
+            .first()
+            .expect("expected the tile to process as argument");
+        let (archives, has_zip) = input_archives(&fs, &config);
+        if config.experimental_use_in_memory_fs {
+            let memory =
+                OverlayFileSystem::with_memory([&config.batchoutfolder], memory_fs(&config));
+            let fs = mount_inputs(memory.clone(), &config, &archives);
+            process_batch_tile(&fs, &config, &thread, laz, has_zip);
+            log_memory_stats(memory.memory());
+        } else {
+            let fs = mount_inputs(fs, &config, &archives);
+            process_batch_tile(&fs, &config, &thread, laz, has_zip);
         }
+        return;
+    }
 
-        let Config { lazfolder, .. } = &*config;
-
-        let mut zip_files: Vec<String> = Vec::new();
-        for path in fs.list(lazfolder).unwrap() {
-            if let Some(extension) = path.extension() {
-                if extension == "zip" {
-                    zip_files.push(String::from(path.to_str().unwrap()));
-                }
-            }
+    #[cfg(feature = "server")]
+    if command == "serve" {
+        // process uploaded files with `processes` workers, see the README for the API
+        let addr = args.first().map_or("127.0.0.1:8080", |s| s.as_str());
+        let base = ini::Ini::load_from_file(pullauta::config::DEFAULT_CONFIG_FILE)
+            .expect("Could not open config file");
+        let server = pullauta::server::Server::bind(addr, base, proc as usize)
+            .unwrap_or_else(|e| panic!("Could not listen on {addr}: {e}"));
+        if let Some(addr) = server.local_addr() {
+            info!("Listening on http://{addr}");
         }
+        server.run();
+        return;
+    }
 
-        if config.experimental_use_in_memory_fs {
-            // copy all the input files into the memory file system
-            let fs = pullauta::io::fs::memory::MemoryFileSystem::new();
-
-            fs.create_dir_all(&config.lazfolder).unwrap();
-            for file in fs::read_dir(&config.lazfolder).unwrap() {
-                let file = file.unwrap();
-                let path = file.path();
-                println!("Copying {} into memory fs", path.display());
-                fs.load_from_disk(&path, &path).unwrap();
-            }
-            // if there is an input vector file, copy it over as well
-
-            if !config.vectorconf.is_empty() {
-                let path = Path::new(&config.vectorconf);
-                println!("Copying {} into memory fs", path.display());
-                fs.load_from_disk(path, path).unwrap();
-            }
-
-            launch_threads(fs.clone(), proc, &config, &zip_files);
-
-            // copy the output files back to disk
-            std::fs::create_dir_all(&config.batchoutfolder).unwrap();
-            for path in fs.list(&config.batchoutfolder).unwrap() {
-                info!("Copying {} from memory fs to disk", path.display());
-                fs.save_to_disk(&path, &path).unwrap();
+    if command == "watch" {
+        // process new and changed tiles of the batch input folder as they appear
+        let interval = args
+            .first()
+            .map(|s| {
+                s.parse::<u64>()
+                    .expect("expected the poll interval in seconds")
+            })
+            .unwrap_or(60);
+        let mut watcher = pullauta::watch::Watcher::new(&fs, &config);
+        info!("Watching {} every {interval} seconds", config.lazfolder);
+        loop {
+            let (archives, has_zip) = input_archives(&fs, &config);
+            let inputs = mount_inputs(fs.clone(), &config, &archives);
+            let changes = watcher.poll(&inputs, &config);
+            if !changes.is_empty() {
+                run_batch(
+                    fs.clone(),
+                    proc,
+                    &config,
+                    &archives,
+                    Some(changes.inputs.clone()),
+                    has_zip,
+                );
+                watcher.commit(&inputs, &config, &changes);
+                info!("Waiting for new or changed tiles");
             }
-        } else {
-            launch_threads(fs, proc, &config, &zip_files);
+            thread::sleep(time::Duration::from_secs(interval));
         }
+    }
+
+    if command.is_empty() && batch {
+        let (archives, has_zip) = input_archives(&fs, &config);
+        run_batch(fs, proc, &config, &archives, None, has_zip);
         return;
     }
 
     if command_lowercase.ends_with(".zip") {
         let mut zips: Vec<String> = vec![command];
         zips.extend(args);
-        pullauta::process::process_zip(&fs, &config, &thread, &tmpfolder, &zips, false).unwrap();
+        let fs = mount_zips(fs, Path::new("temp_shapefiles"), &zips);
+        pullauta::process::process_zip(&fs, &config, &thread, &tmpfolder, true).unwrap();
         return;
     }
 
@@ -455,36 +568,24 @@ fn main() {
         }
 
         if config.experimental_use_in_memory_fs {
-            let fs = pullauta::io::fs::memory::MemoryFileSystem::new();
-
-            debug!("Copying input file into memory fs: {command}");
-            // copy the input file into the memory file system
-            fs.load_from_disk(Path::new(&command), Path::new("input.laz"))
-                .expect("Could not copy input file into memory fs");
-
-            debug!("Done");
+            // read the input lazily from disk and only write the rendered maps to disk
+            let fs = OverlayFileSystem::with_memory(
+                ["pullautus", "pullautus_depr"]
+                    .iter()
+                    .flat_map(|name| [format!("{name}.png"), format!("{name}.pgw")]),
+                memory_fs(&config),
+            );
 
             pullauta::process::process_tile(
                 &fs,
                 &config,
                 &thread,
                 &tmpfolder,
-                // Path::new(&command),
-                Path::new("input.laz"),
+                Path::new(&command),
                 norender,
             )
             .unwrap();
-
-            // now write the output files to disk
-            fn copy(fs: &MemoryFileSystem, name: &str) {
-                if fs.exists(name) {
-                    info!("Copying {name} from memory fs to disk");
-                    fs.save_to_disk(name, name)
-                        .expect("Could not copy from memory fs to disk");
-                }
-            }
-            copy(&fs, "pullautus.png");
-            copy(&fs, "pullautus_depr.png");
+            log_memory_stats(fs.memory());
         } else {
             pullauta::process::process_tile(
                 &fs,
@@ -498,3 +599,228 @@ fn main() {
         }
     }
 }
+
+/// Reads the points and the heightmap of the tile processed into `tmpfolder`, for the single
+/// stage commands.
+fn read_tile(fs: &impl FileSystem, tmpfolder: &Path) -> (PointCloud, HeightMap) {
+    let points = PointCloud::from_file(fs, tmpfolder.join("xyztemp.xyz.bin"))
+        .expect("Could not read xyztemp.xyz.bin");
+    let hmap =
+        HeightMap::from_file(fs, tmpfolder.join("xyz2.hmap")).expect("Could not read xyz2.hmap");
+    (points, hmap)
+}
+
+fn write_png(fs: &impl FileSystem, path: PathBuf, image: &DynamicImage) {
+    image
+        .write_to(
+            &mut fs.create(path).expect("could not save output png"),
+            image::ImageFormat::Png,
+        )
+        .expect("could not write image");
+}
+
+/// Processes the batch with isolated worker processes, or with `proc` threads sharing the local
+/// or the in-memory file system, depending on the configuration. Processes all files of the input
+/// folder unless `laz_files` is given.
+fn run_batch(
+    fs: pullauta::io::fs::local::LocalFileSystem,
+    proc: u64,
+    config: &Arc<Config>,
+    archives: &[(PathBuf, ZipFileSystem)],
+    laz_files: Option<Vec<PathBuf>>,
+    has_zip: bool,
+) {
+    if config.isolated_workers {
+        // the workers use the in-memory file system themselves if configured
+        let fs = mount_inputs(fs, config, archives);
+        let laz_files =
+            laz_files.unwrap_or_else(|| pullauta::process::batch_input_files(&fs, config));
+        let executable = env::current_exe().expect("Could not find the pullauta executable");
+        let failed = pullauta::worker::run_batch(&fs, config, &executable, laz_files);
+        if failed > 0 {
+            info!("{failed} tiles failed, see the .failed.json files in the output folder");
+        }
+        if config.report {
+            pullauta::report::write_batch_report(&fs, config).unwrap();
+        }
+    } else if config.experimental_use_in_memory_fs {
+        // read the inputs lazily from disk, keep everything else in memory except for the
+        // output folder
+        let fs = OverlayFileSystem::with_memory([&config.batchoutfolder], memory_fs(config));
+        launch_threads(fs.clone(), proc, config, archives, laz_files, has_zip);
+        log_memory_stats(fs.memory());
+    } else {
+        launch_threads(fs, proc, config, archives, laz_files, has_zip);
+    }
+}
+
+/// Processes the batch with `proc` threads, reading the zip archives and the remote input folder
+/// in place. Processes all files of the input folder unless `laz_files` is given.
+fn launch_threads<F: FileSystem + Send + Sync + Clone + 'static>(
+    fs: F,
+    proc: u64,
+    config: &Arc<Config>,
+    archives: &[(PathBuf, ZipFileSystem)],
+    laz_files: Option<Vec<PathBuf>>,
+    has_zip: bool,
+) {
+    let fs = mount_inputs(fs, config, archives);
+    let laz_files =
+        Arc::new(laz_files.unwrap_or_else(|| pullauta::process::batch_input_files(&fs, config)));
+    // do the processing
+    let mut handles: Vec<thread::JoinHandle<()>> = Vec::with_capacity((proc + 1) as usize);
+    for i in 0..proc {
+        let config = config.clone();
+        let fs = fs.clone();
+        let laz_files = laz_files.clone();
+        let handle = thread::spawn(move || {
+            info!("Starting thread");
+            let thread = format!("{}", i + 1);
+            pullauta::process::batch_process_files(&config, &fs, &thread, &laz_files, has_zip);
+            info!("Thread complete");
+        });
+        thread::sleep(time::Duration::from_millis(100));
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    if config.report {
+        pullauta::report::write_batch_report(&fs, config).unwrap();
+    }
+}
+
+/// Makes the contents of the zip archives visible in `path` without extracting them.
+fn mount_zips<F: FileSystem>(fs: F, path: &Path, zips: &[String]) -> MountFileSystem<F> {
+    zips.iter().fold(MountFileSystem::new(fs), |fs, zip| {
+        info!("Opening zip file {zip}");
+        let archive =
+            ZipFileSystem::new(zip).unwrap_or_else(|e| panic!("Could not read {zip}: {e}"));
+        fs.mount(path, archive)
+    })
+}
+
+/// Finds the zip archives in the batch input folder. The directories of the archives with las/laz
+/// files are mounted in the input folder, the others are expected to contain shape files. Also
+/// returns whether there are shape files.
+fn input_archives(fs: &impl FileSystem, config: &Config) -> (Vec<(PathBuf, ZipFileSystem)>, bool) {
+    let lazfolder = &config.lazfolder;
+    let mut archives: Vec<(PathBuf, ZipFileSystem)> = Vec::new();
+    let mut has_zip = false;
+    // the local input folder does not need to exist when reading from a remote folder
+    let local_files = match fs.list(lazfolder) {
+        Err(_) if !config.experimental_remote_lazfolder.is_empty() => Vec::new(),
+        files => files.unwrap(),
+    };
+    for path in local_files {
+        if path.extension().is_some_and(|extension| extension == "zip") {
+            let archive = ZipFileSystem::new(&path)
+                .unwrap_or_else(|e| panic!("Could not read {}: {e}", path.display()));
+            let point_cloud_dirs = point_cloud_dirs(&archive);
+            if !point_cloud_dirs.is_empty() {
+                for dir in point_cloud_dirs {
+                    info!("Reading point clouds from {}", path.join(&dir).display());
+                    archives.push((PathBuf::from(lazfolder), archive.subdir(dir)));
+                }
+            } else {
+                info!("Reading shape files from {}", path.display());
+                archives.push((PathBuf::from("temp_shapefiles"), archive));
+                has_zip = true;
+            }
+        }
+    }
+    (archives, has_zip)
+}
+
+/// The directories of a zip archive with las/laz files in them, searched recursively.
+fn point_cloud_dirs(archive: &ZipFileSystem) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for path in archive.list(&dir).unwrap() {
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "laz" || extension == "las")
+            {
+                if !found.contains(&dir) {
+                    found.push(dir.clone());
+                }
+            } else if archive.list(&path).is_ok() {
+                dirs.push(path);
+            }
+        }
+    }
+    found
+}
+
+/// Makes the zip archives and the remote input folder visible in the file system, reading them in
+/// place instead of extracting or downloading them first.
+fn mount_inputs<F: FileSystem + Send + Sync + Clone + 'static>(
+    fs: F,
+    config: &Config,
+    archives: &[(PathBuf, ZipFileSystem)],
+) -> impl FileSystem + Send + Sync + Clone + 'static {
+    let fs = archives
+        .iter()
+        .fold(MountFileSystem::new(fs), |fs, (path, archive)| {
+            fs.mount(path, archive.clone())
+        });
+    #[cfg(feature = "http")]
+    let fs = {
+        let mut fs = MountFileSystem::new(fs);
+        if !config.experimental_remote_lazfolder.is_empty() {
+            info!(
+                "Reading point clouds from {}",
+                config.experimental_remote_lazfolder
+            );
+            let remote = HttpFileSystem::new(&config.experimental_remote_lazfolder);
+            fs = fs.mount(&config.lazfolder, remote);
+        }
+        fs
+    };
+    #[cfg(not(feature = "http"))]
+    let _ = config;
+    fs
+}
+
+/// Processes a single tile of a batch in a worker process.
+fn process_batch_tile(
+    fs: &impl FileSystem,
+    config: &Config,
+    thread: &String,
+    laz: &str,
+    has_zip: bool,
+) {
+    let laz_files = pullauta::process::batch_input_files(fs, config);
+    let laz_path = laz_files
+        .iter()
+        .find(|p| p.file_name().is_some_and(|name| name == laz))
+        .unwrap_or_else(|| panic!("{laz} not found in the input folder"));
+    pullauta::progress::with_tile(laz, thread, || {
+        pullauta::process::batch_process_tile(config, fs, thread, laz_path, &laz_files, has_zip)
+    });
+}
+
+/// Creates the memory file system for `experimental_use_in_memory_fs`.
+fn memory_fs(config: &Config) -> MemoryFileSystem {
+    if config.experimental_memory_fs_budget > 0 {
+        MemoryFileSystem::with_budget(
+            config.experimental_memory_fs_budget * 1024 * 1024,
+            "temp_spill",
+        )
+    } else {
+        MemoryFileSystem::new()
+    }
+}
+
+fn log_memory_stats(fs: &MemoryFileSystem) {
+    let stats = fs.stats();
+    info!(
+        "In-memory file system: {} files, {} MB in memory (peak {} MB), {} MB spilled to disk in {} spills",
+        stats.files,
+        stats.memory_bytes / 1024 / 1024,
+        stats.peak_memory_bytes / 1024 / 1024,
+        stats.spilled_bytes / 1024 / 1024,
+        stats.spills
+    );
+}
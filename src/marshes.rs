@@ -0,0 +1,260 @@
+use image::{DynamicImage, Rgba};
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::config::Config;
+use crate::drainage::Drainage;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// The smallest slope used for the wetness index, so it stays finite on flat ground.
+const MIN_SLOPE: f64 = 0.001;
+
+/// The output of the marsh detection.
+#[derive(Debug, Clone)]
+pub struct Marshes {
+    /// The marshes, using the grid of the heightmap with 1 pixel per meter.
+    pub image: DynamicImage,
+    /// The outlines of the marshes.
+    pub outlines: BinaryDxf,
+}
+
+/// Detects marshes from the heightmap and the ground points, as a raster and as outlines.
+///
+/// A cell is a marsh if the ground is flatter than `marshslope` and at least two of these hold:
+/// the topographic wetness index is at least `marshtwi`, the density of ground points is below
+/// `marshdensity` times the median of the tile (water absorbs the laser), and, if
+/// `marshintensity` is set, the mean intensity of the ground points is below it. Marshes smaller
+/// than `marshminarea` square meters are dropped.
+pub fn makemarshes(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<Marshes, Box<dyn Error>> {
+    info!("Detecting marshes...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the drainage, the point counts and intensities and the marsh masks
+    job::reserve(
+        config,
+        "makemarshes",
+        2 * job::grid_bytes::<f64>(w, h)
+            + job::grid_bytes::<Option<(u32, u32)>>(w, h)
+            + 2 * job::grid_bytes::<(u32, u32)>(w, h)
+            + job::grid_bytes::<u32>(w, h)
+            + 2 * job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let (counts, intensities) = ground_points(points, hmap)?;
+    let drainage = Drainage::new(hmap);
+    job::check_cancelled()?;
+
+    let marsh = classify(config, hmap, &drainage, &counts, &intensities);
+    let mut marsh = areas::majority_filter(&marsh);
+    let min_cells = (config.marshminarea / (hmap.scale * hmap.scale)).ceil() as usize;
+    areas::remove_small_areas(&mut marsh, min_cells);
+
+    let img = areas::mask_image(hmap, &[(&marsh, Rgba([0, 10, 220, 255]))]);
+
+    let mut lines = Polylines::new();
+    for area in areas::components(&marsh) {
+        for ring in areas::outlines(hmap, &area, 1) {
+            lines.push(ring, Classification::Marsh);
+        }
+    }
+    info!("Done");
+    Ok(Marshes {
+        image: DynamicImage::ImageRgba8(img),
+        outlines: BinaryDxf::new(
+            Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+            vec![lines.into()],
+        ),
+    })
+}
+
+/// The sum and number of the known intensities of the ground points of each cell.
+type Intensities = Vec2D<(u32, u32)>;
+
+/// Counts the ground points for each cell of the heightmap, and sums and counts the intensities
+/// of those that have one.
+fn ground_points(
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<(Vec2D<u32>, Intensities), Box<dyn Error>> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let mut counts = Vec2D::new(w, h, 0u32);
+    let mut intensities = Vec2D::new(w, h, (0u32, 0u32));
+
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
+        for r in chunk {
+            if r.classification != 2 {
+                continue;
+            }
+            let x = ((r.x - hmap.xoffset) / hmap.scale).round();
+            let y = ((r.y - hmap.yoffset) / hmap.scale).round();
+            if x < 0.0 || y < 0.0 || x >= w as f64 || y >= h as f64 {
+                continue;
+            }
+            counts[(x as usize, y as usize)] += 1;
+            if r.intensity > 0 {
+                let (sum, count) = &mut intensities[(x as usize, y as usize)];
+                *sum += r.intensity as u32;
+                *count += 1;
+            }
+        }
+    }
+    Ok((counts, intensities))
+}
+
+/// Marks the marsh cells, see [`makemarshes`].
+fn classify(
+    config: &Config,
+    hmap: &HeightMap,
+    drainage: &Drainage,
+    counts: &Vec2D<u32>,
+    intensities: &Intensities,
+) -> Vec2D<bool> {
+    let &Config {
+        marshslope,
+        marshtwi,
+        marshdensity,
+        marshintensity,
+        ..
+    } = config;
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let grid = &hmap.grid;
+
+    // the typical ground point count of a cell with data
+    let mut typical = grid
+        .iter()
+        .filter(|(_, _, z)| z.is_finite())
+        .map(|(x, y, _)| counts[(x, y)])
+        .collect::<Vec<_>>();
+    let median = if typical.is_empty() {
+        0
+    } else {
+        let middle = typical.len() / 2;
+        *typical.select_nth_unstable(middle).1
+    };
+
+    let mut marsh = Vec2D::new(w, h, false);
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let slope = areas::slope(hmap, x, y);
+            if slope.is_nan() || slope >= marshslope {
+                continue;
+            }
+
+            let twi = (drainage.area[(x, y)] / hmap.scale / slope.max(MIN_SLOPE)).ln();
+            let count = counts[(x, y)];
+            let sparse = median > 0 && (count as f64) < marshdensity * median as f64;
+            let (intensity, lit) = intensities[(x, y)];
+            let dark =
+                marshintensity > 0.0 && lit > 0 && (intensity as f64 / lit as f64) < marshintensity;
+
+            let cues = [twi >= marshtwi, sparse, dark];
+            marsh[(x, y)] = cues.iter().filter(|&&cue| cue).count() >= 2;
+        }
+    }
+    marsh
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::io::xyz::XyzRecord;
+    use std::path::Path;
+
+    #[test]
+    fn test_flat_sparse_valley_is_a_marsh() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // a valley with a flat, gently sloping bottom between x = 30 m and x = 50 m and steep
+        // sides, with a fourth of the ground points on the bottom
+        let mut grid = Vec2D::new(41, 41, 0.0);
+        let mut points = PointCloud::default();
+        for x in 0..41 {
+            for y in 0..41 {
+                let side = ((x as f64 - 20.0).abs() - 5.0).max(0.0);
+                grid[(x, y)] = 100.0 + side * 0.4 + y as f64 * 0.01;
+                let offsets: &[f64] = if side == 0.0 {
+                    &[0.0]
+                } else {
+                    &[-0.5, -0.25, 0.25, 0.5]
+                };
+                for &d in offsets {
+                    points.records.push(XyzRecord {
+                        x: 2.0 * x as f64 + d,
+                        y: 2.0 * y as f64 - d,
+                        z: grid[(x, y)] as f32,
+                        classification: 2,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let marshes = makemarshes(&config, &points, &hmap).unwrap();
+
+        let img = marshes.image.to_rgba8();
+        assert_eq!(img.dimensions(), (80, 80));
+        // the valley bottom is marsh, the sides are not
+        assert_eq!(img.get_pixel(40, 20)[3], 255);
+        assert_eq!(img.get_pixel(10, 20)[3], 0);
+        assert_eq!(img.get_pixel(70, 20)[3], 0);
+
+        let Geometry::Polylines2(lines) = marshes.outlines.take_geometry().swap_remove(0) else {
+            panic!("marshes should be polylines");
+        };
+        assert!(lines.len() > 0);
+        for (ring, class) in lines.iter() {
+            assert_eq!(*class, Classification::Marsh);
+            assert_eq!(ring.first(), ring.last());
+            for p in ring {
+                assert!(p.x > 24.0 && p.x < 56.0, "{p:?}");
+            }
+        }
+    }
+    #[test]
+    fn test_points_without_intensity_are_not_dark() {
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.marshtwi = f64::INFINITY;
+        config.marshintensity = 50.0;
+
+        // a gentle slope with sparse ground points at x < 10, which are dark at y >= 10 and have no
+        // intensity at y < 10
+        let mut grid = Vec2D::new(21, 21, 0.0);
+        let mut counts = Vec2D::new(21, 21, 4);
+        let mut intensities = Vec2D::new(21, 21, (400, 4));
+        for (x, y, z) in grid.iter_mut() {
+            *z = 100.0 + 0.01 * x as f64;
+            if x < 10 {
+                counts[(x, y)] = 1;
+                intensities[(x, y)] = if y < 10 { (0, 0) } else { (10, 1) };
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 1.0,
+            grid,
+        };
+
+        let marsh = classify(&config, &hmap, &Drainage::new(&hmap), &counts, &intensities);
+        assert!(marsh[(5, 15)]);
+        assert!(!marsh[(5, 5)]);
+        assert!(!marsh[(15, 15)]);
+    }
+}
@@ -2,14 +2,15 @@ use image::{RgbImage, Rgba, RgbaImage};
 use log::info;
 use rustc_hash::FxHashMap as HashMap;
 use std::error::Error;
+use std::fmt::Write as _;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use crate::config::Config;
 use crate::geometry::{BinaryDxf, Classification, Geometry, Point3, Points, Polylines};
-use crate::io::bytes::FromToBytes;
 use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
+use crate::knolls::{Dotknoll, Dotknolls};
 use crate::vec2d::Vec2D;
 use image::buffer::ConvertBuffer;
 
@@ -101,6 +102,19 @@ fn merge_png(
         }
     }
 
+    write_merged(fs, &im, outfilename, min_res * scale, xmin, ymax);
+    Ok(())
+}
+
+/// Writes a merged image as png and jpg with their world files.
+fn write_merged(
+    fs: &impl FileSystem,
+    im: &RgbaImage,
+    outfilename: &str,
+    res: f64,
+    xmin: f64,
+    ymax: f64,
+) {
     let im_rgb8: RgbImage = im.convert();
     im_rgb8
         .write_to(
@@ -125,10 +139,7 @@ fn merge_png(
     write!(
         &mut tfw_file,
         "{}\r\n0\r\n0\r\n{}\r\n{}\r\n{}\r\n",
-        min_res * scale,
-        -min_res * scale,
-        xmin,
-        ymax
+        res, -res, xmin, ymax
     )
     .expect("Could not write to file");
     drop(tfw_file);
@@ -137,7 +148,71 @@ fn merge_png(
         Path::new(&format!("{outfilename}.jgw")),
     )
     .expect("Could not copy file");
-    Ok(())
+}
+
+/// Reads the resolution and the coordinates of the top left corner from a world file.
+fn read_world_file(fs: &impl FileSystem, path: &Path) -> Option<(f64, f64, f64)> {
+    let data = fs.read_to_string(path).ok()?;
+    let d: Vec<&str> = data.split('\n').collect();
+    let res = d.first()?.trim().parse::<f64>().ok()?;
+    let tfw4 = d.get(4)?.trim().parse::<f64>().ok()?;
+    let tfw5 = d.get(5)?.trim().parse::<f64>().ok()?;
+    Some((res, tfw4, tfw5))
+}
+
+/// The scale that the merged image `outfilename` was written with by [`merge_png`], relative to
+/// the finest resolution of `png_files`. Returns `None` if there is no merged image.
+pub fn merged_scale(fs: &impl FileSystem, outfilename: &str, png_files: &[PathBuf]) -> Option<f64> {
+    let (merged_res, _, _) = read_world_file(fs, Path::new(&format!("{outfilename}.pgw")))?;
+    let min_res = png_files
+        .iter()
+        .filter_map(|png| read_world_file(fs, &png.with_extension("pgw")))
+        .map(|(res, _, _)| res)
+        .fold(f64::MAX, f64::min);
+    (min_res < f64::MAX).then(|| merged_res / min_res)
+}
+
+/// Draws re-rendered tiles over the merged image `outfilename` written earlier, instead of
+/// merging all the tiles again. Returns false without writing anything if there is no merged
+/// image or a tile does not fit in it, in which case everything has to be merged again.
+pub fn update_merged_png(
+    fs: &impl FileSystem,
+    outfilename: &str,
+    png_files: &[PathBuf],
+) -> Result<bool, Box<dyn Error>> {
+    let merged_png = format!("{outfilename}.png");
+    let Some((merged_res, xmin, ymax)) =
+        read_world_file(fs, Path::new(&format!("{outfilename}.pgw")))
+    else {
+        return Ok(false);
+    };
+    if !fs.exists(&merged_png) {
+        return Ok(false);
+    }
+    let mut im = fs.read_image_png(&merged_png)?.to_rgba8();
+
+    for png in png_files {
+        let Some((res, tfw4, tfw5)) = read_world_file(fs, &png.with_extension("pgw")) else {
+            continue;
+        };
+        let img = fs.read_image_png(png)?;
+        let width = (res / merged_res * img.width() as f64 + 0.5) as u32;
+        let height = (res / merged_res * img.height() as f64 + 0.5) as u32;
+        let x = ((tfw4 - xmin) / merged_res) as i64;
+        let y = ((ymax - tfw5) / merged_res) as i64;
+        if x < 0
+            || y < 0
+            || x + width as i64 > im.width() as i64 + 1
+            || y + height as i64 > im.height() as i64 + 1
+        {
+            return Ok(false);
+        }
+        let img2 = image::imageops::thumbnail(&img, width, height);
+        image::imageops::overlay(&mut im, &img2, x, y);
+    }
+
+    write_merged(fs, &im, outfilename, merged_res, xmin, ymax);
+    Ok(true)
 }
 
 pub fn pngmergevege(
@@ -172,16 +247,14 @@ pub fn pngmergevege(
     Ok(())
 }
 
-pub fn pngmerge(
+/// Lists the rendered map tiles (with or without depressions) in the batch output folder.
+pub(crate) fn list_map_png_files(
     fs: &impl FileSystem,
     config: &Config,
-    scale: f64,
     depr: bool,
-) -> Result<(), Box<dyn Error>> {
-    let batchoutfolder = &config.batchoutfolder;
-
+) -> Result<Vec<PathBuf>, std::io::Error> {
     let mut png_files: Vec<PathBuf> = Vec::new();
-    for path in fs.list(batchoutfolder).unwrap() {
+    for path in fs.list(&config.batchoutfolder)? {
         let filename = path.file_name().unwrap().to_str().unwrap();
         if filename.ends_with(".png")
             && !filename.ends_with("_undergrowth.png")
@@ -194,6 +267,16 @@ pub fn pngmerge(
             png_files.push(path);
         }
     }
+    Ok(png_files)
+}
+
+pub fn pngmerge(
+    fs: &impl FileSystem,
+    config: &Config,
+    scale: f64,
+    depr: bool,
+) -> Result<(), Box<dyn Error>> {
+    let png_files = list_map_png_files(fs, config, depr)?;
 
     if png_files.is_empty() {
         info!("No files to merge found in output directory");
@@ -221,6 +304,17 @@ pub fn bindxfmerge(fs: &impl FileSystem, config: &Config) -> anyhow::Result<()>
         "formlines",
         "dotknolls",
         "detected",
+        "streams",
+        "marshes",
+        "water",
+        "boulders",
+        "stony",
+        "pits",
+        "banks",
+        "paths",
+        "blocks",
+        "powerlines",
+        "pylons",
     ];
 
     // a list of files for each suffix
@@ -343,11 +437,24 @@ pub fn bindxfmerge(fs: &impl FileSystem, config: &Config) -> anyhow::Result<()>
     Ok(())
 }
 
+/// The output of `smoothjoin`
+pub struct SmoothContours {
+    /// the smoothed contours, out2.dxf.bin
+    pub contours: BinaryDxf,
+    pub dotknolls: Dotknolls,
+    /// the depression outlines, one `x,y|x,y|...` line per depression
+    pub depressions: String,
+    /// the first point of each knoll, one `x y` line per knoll
+    pub knollheads: String,
+}
+
+/// Joins and smooths the contours in `out` generated from the knoll-adjusted heightmap `hmap`,
+/// replacing the knolls too small to draw with dot knolls.
 pub fn smoothjoin(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-) -> Result<(), Box<dyn Error>> {
+    hmap: &HeightMap,
+    out: &BinaryDxf,
+) -> Result<SmoothContours, Box<dyn Error>> {
     info!("Smooth curves...");
 
     let &Config {
@@ -369,16 +476,13 @@ pub fn smoothjoin(
 
     let interval = halfinterval;
 
-    let heightmap_in = tmpfolder.join("xyz_knolls.hmap");
-    let hmap = HeightMap::from_bytes(&mut fs.open(heightmap_in)?)?;
-
     // in world coordinates
     let xstart = hmap.xoffset;
     let ystart = hmap.yoffset;
     let size = hmap.scale;
     let xmax = (hmap.grid.width() - 1) as u64;
     let ymax = (hmap.grid.height() - 1) as u64;
-    let xyz = hmap.grid;
+    let xyz = &hmap.grid;
 
     let mut steepness = Vec2D::new((xmax + 1) as usize, (ymax + 1) as usize, f64::NAN);
 
@@ -401,25 +505,18 @@ pub fn smoothjoin(
         }
     }
 
-    // read the binary input
-    let input = tmpfolder.join("out.dxf.bin");
-    let input_dxf =
-        BinaryDxf::from_reader(&mut fs.open(input)?).expect("Unable to read out.dxf.bin");
-
-    let input_bounds = input_dxf.bounds().clone(); // store the bounds for usage in the output
-    let Geometry::Polylines2(input_lines) = input_dxf.take_geometry().swap_remove(0) else {
+    let input_bounds = out.bounds().clone(); // store the bounds for usage in the output
+    let Some(Geometry::Polylines2(input_lines)) = out.geometry().first() else {
         return Err(anyhow::anyhow!("out.dxf.bin does not contain polylines").into());
     };
 
     let mut out2_lines = Polylines::<Point3, (Classification, f64)>::new();
 
-    let depr_output = tmpfolder.join("depressions.txt");
-    let mut depr_fp = fs.create(depr_output).expect("Unable to create file");
+    let mut depressions = String::new();
 
     let mut dotknolls = Vec::new();
 
-    let knollhead_output = tmpfolder.join("knollheads.txt");
-    let mut knollhead_fp = fs.create(knollhead_output).expect("Unable to create file");
+    let mut knollheads = String::new();
 
     // Internal type used to index into the hashmaps and vectors.
     // Since using f64 coordinates directly has problems with rounding (and do not impl Eq and
@@ -651,13 +748,11 @@ pub fn smoothjoin(
                 depression = 1;
                 if (h_center < h && hit % 2 == 1) || (h_center > h && hit % 2 != 1) {
                     depression = -1;
-                    write!(&mut depr_fp, "{},{}", el_x[l][0], el_y[l][0])
-                        .expect("Unable to write file");
+                    write!(&mut depressions, "{},{}", el_x[l][0], el_y[l][0])?;
                     for k in 1..el_x[l].len() {
-                        write!(&mut depr_fp, "|{},{}", el_x[l][k], el_y[l][k])
-                            .expect("Unable to write file");
+                        write!(&mut depressions, "|{},{}", el_x[l][k], el_y[l][k])?;
                     }
-                    writeln!(&mut depr_fp).expect("Unable to write file");
+                    writeln!(&mut depressions)?;
                 }
                 if !skip {
                     // Check if knoll is distinct enough
@@ -713,7 +808,7 @@ pub fn smoothjoin(
                 x_avg /= (el_x_len - 1) as f64;
                 y_avg /= (el_x_len - 1) as f64;
 
-                dotknolls.push(super::knolls::Dotknoll {
+                dotknolls.push(Dotknoll {
                     x: x_avg,
                     y: y_avg,
                     is_knoll: depression == 1,
@@ -724,8 +819,7 @@ pub fn smoothjoin(
 
             if !skip {
                 // not skipped, lets save first coordinate pair for later form line knoll PIP analysis
-                write!(&mut knollhead_fp, "{} {}\r\n", el_x[l][0], el_y[l][0])
-                    .expect("Unable to write to file");
+                write!(&mut knollheads, "{} {}\r\n", el_x[l][0], el_y[l][0])?;
                 // adaptive generalization
                 if el_x_len > 101 {
                     let mut newx: Vec<f64> = vec![];
@@ -931,21 +1025,11 @@ pub fn smoothjoin(
         }
     }
 
-    crate::util::write_object(
-        &mut fs.create(tmpfolder.join("dotknolls.bin"))?,
-        &super::knolls::Dotknolls { dotknolls },
-    )?;
-
-    let out2_dxf = BinaryDxf::new(input_bounds, vec![out2_lines.into()]);
-
-    let output = tmpfolder.join("out2.dxf.bin");
-    let mut fp = fs.create(output).expect("Unable to create file");
-    out2_dxf.to_writer(&mut fp)?;
-
-    if config.output_dxf {
-        out2_dxf.to_dxf(&mut fs.create(tmpfolder.join("out2.dxf"))?)?;
-    }
-
     info!("Done");
-    Ok(())
+    Ok(SmoothContours {
+        contours: BinaryDxf::new(input_bounds, vec![out2_lines.into()]),
+        dotknolls: Dotknolls { dotknolls },
+        depressions,
+        knollheads,
+    })
 }
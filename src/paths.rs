@@ -0,0 +1,364 @@
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Point3, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::vec2d::Vec2D;
+use crate::vegetation::Undergrowth;
+
+/// The size of the cells of the path grids in meters, about the width of a path.
+const CELL: f64 = 1.0;
+/// Cells with fewer hits than this, pooled with their neighbours, have no undergrowth density.
+const MIN_HITS: f64 = 3.0;
+/// The sides of a path are this many cells away from its centreline.
+const SIDES: [f64; 2] = [2.0, 3.0];
+/// The hits of a cell are pooled with this many cells before and after it along the path.
+const ALONG: isize = 2;
+/// Lines of fewer cells than this branching off a path are spurs of noise.
+const MIN_CELLS: usize = 4;
+/// The number of directions across a path that are tried, evenly spread over a half turn.
+const DIRECTIONS: usize = 8;
+/// The weights of the undergrowth, the micro-relief and the intensity in the confidence.
+const WEIGHTS: [f64; 3] = [0.6, 0.25, 0.15];
+
+/// The measurements of a cell of the path grids.
+#[derive(Debug, Default, Clone)]
+struct Cell {
+    undergrowth: Undergrowth,
+    /// The sum and number of the heights of the ground points relative to the heightmap.
+    relief: (f64, u32),
+    /// The sum and number of the intensities of the ground points.
+    intensity: (f64, u32),
+}
+
+impl Cell {
+    fn merge(&mut self, other: &Cell) {
+        self.undergrowth.merge(&other.undergrowth);
+        self.relief = (
+            self.relief.0 + other.relief.0,
+            self.relief.1 + other.relief.1,
+        );
+        self.intensity = (
+            self.intensity.0 + other.intensity.0,
+            self.intensity.1 + other.intensity.1,
+        );
+    }
+
+    /// The undergrowth density, NaN if the cell has too few hits.
+    fn density(&self) -> f64 {
+        if self.undergrowth.hits() >= MIN_HITS {
+            self.undergrowth.density()
+        } else {
+            f64::NAN
+        }
+    }
+
+    /// The mean height of the ground points relative to the heightmap, NaN without any.
+    fn relief(&self) -> f64 {
+        self.relief.0 / self.relief.1 as f64
+    }
+
+    /// The mean intensity of the ground points, NaN without any.
+    fn intensity(&self) -> f64 {
+        self.intensity.0 / self.intensity.1 as f64
+    }
+}
+
+/// Detects small paths from the points and the heightmap, and returns their centrelines with the
+/// confidence of each path, from 0 to 1, in place of the
+/// height. Paths are narrow corridors without undergrowth, often with slightly lower and more
+/// compacted ground than on their sides.
+///
+/// The confidence of a cell combines how much of the undergrowth on both sides of it (the
+/// density of [`crate::vegetation::makevege`]) is missing from it, with full confidence when the
+/// sides are at least `pathundergrowth` dense, how much lower the ground is than on its sides,
+/// `pathrelief` meters for full confidence, and how much brighter the ground returns are,
+/// `pathintensity` times brighter for full confidence. The intensity is left out if the points
+/// don't have it or `pathintensity` is 0. Lines of cells with at least `pathminconfidence`
+/// confidence in networks at least `pathminlength` meters long are paths.
+pub fn makepaths(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting paths...");
+
+    let w = ((hmap.grid.width().saturating_sub(1)) as f64 * hmap.scale / CELL) as usize + 1;
+    let h = ((hmap.grid.height().saturating_sub(1)) as f64 * hmap.scale / CELL) as usize + 1;
+    // the measurements, the confidence, the directions across and the masks
+    job::reserve(
+        config,
+        "makepaths",
+        job::grid_bytes::<Cell>(w, h)
+            + job::grid_bytes::<f64>(w, h)
+            + job::grid_bytes::<(isize, isize)>(w, h)
+            + 2 * job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let cells = measure(points, hmap, w, h)?;
+    job::check_cancelled()?;
+    let (confidence, mut centre) = confidence(config, &cells);
+    // drop the networks of lines too small to hold a path
+    for network in areas::components8(&centre) {
+        if (network.len() as f64) * CELL < config.pathminlength {
+            for cell in network {
+                centre[cell] = false;
+            }
+        }
+    }
+
+    let mut lines = Polylines::new();
+    for cells in areas::trace_lines(&centre) {
+        if cells.len() < MIN_CELLS {
+            continue;
+        }
+        let mean = cells.iter().map(|&c| confidence[c]).sum::<f64>() / cells.len() as f64;
+        let line = cells
+            .into_iter()
+            .map(|(x, y)| {
+                Point2::new(
+                    hmap.xoffset + CELL * x as f64,
+                    hmap.yoffset + CELL * y as f64,
+                )
+            })
+            .collect();
+        let line = areas::smooth_line(line, 2)
+            .into_iter()
+            .map(|p| Point3::new(p.x, p.y, mean))
+            .collect();
+        lines.push(line, (Classification::Path, mean));
+    }
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![lines.into()],
+    ))
+}
+
+/// Collects the undergrowth, the micro-relief and the intensity of each cell from the points.
+fn measure(
+    points: &PointCloud,
+    hmap: &HeightMap,
+    w: usize,
+    h: usize,
+) -> Result<Vec2D<Cell>, Box<dyn Error>> {
+    let mut cells = Vec2D::new(w, h, Cell::default());
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
+        for r in chunk {
+            let x = ((r.x - hmap.xoffset) / CELL).round();
+            let y = ((r.y - hmap.yoffset) / CELL).round();
+            if x < 0.0 || y < 0.0 || x >= w as f64 || y >= h as f64 {
+                continue;
+            }
+            let ground = hmap.interpolate(
+                (r.x - hmap.xoffset) / hmap.scale,
+                (r.y - hmap.yoffset) / hmap.scale,
+            );
+            let hh = r.z as f64 - ground;
+            let cell = &mut cells[(x as usize, y as usize)];
+            cell.undergrowth.add(r.classification, hh);
+            if r.classification == 2 {
+                cell.relief.0 += hh;
+                cell.relief.1 += 1;
+                if r.intensity > 0 {
+                    cell.intensity.0 += r.intensity as f64;
+                    cell.intensity.1 += 1;
+                }
+            }
+        }
+    }
+    Ok(cells)
+}
+
+/// The confidence of each cell being on a path, and the mask of the cells on the centrelines of
+/// paths: cells confident enough and at least as confident as their neighbours across the path.
+fn confidence(config: &Config, cells: &Vec2D<Cell>) -> (Vec2D<f64>, Vec2D<bool>) {
+    let (w, h) = (cells.width(), cells.height());
+    // the offsets of the cells at the given distances across a cell, each with its neighbours
+    // along the path so sparse points still give a density
+    let offsets = |(ax, ay): (f64, f64), distances: &[f64]| {
+        distances
+            .iter()
+            .flat_map(|&k| {
+                (-ALONG..=ALONG).map(move |t| {
+                    let t = t as f64;
+                    (
+                        (k * ax - t * ay).round() as isize,
+                        (k * ay + t * ax).round() as isize,
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    let directions = (0..DIRECTIONS)
+        .map(|i| {
+            let angle = std::f64::consts::PI * i as f64 / DIRECTIONS as f64;
+            let across = (angle.cos(), angle.sin());
+            let far = SIDES.map(|k| -k);
+            let step = (across.0.round() as isize, across.1.round() as isize);
+            let pools = [
+                offsets(across, &[0.0]),
+                offsets(across, &SIDES),
+                offsets(across, &far),
+            ];
+            (step, pools)
+        })
+        .collect::<Vec<_>>();
+    let pool = |x: usize, y: usize, offsets: &[(isize, isize)]| {
+        let mut pooled = Cell::default();
+        for &(dx, dy) in offsets {
+            pooled.merge(&cells[(x.wrapping_add_signed(dx), y.wrapping_add_signed(dy))]);
+        }
+        pooled
+    };
+
+    // the cells whose sides are all inside the grid
+    let margin = SIDES[SIDES.len() - 1] as usize + ALONG as usize;
+    let mut confidence = Vec2D::new(w, h, 0.0);
+    let mut across = Vec2D::new(w, h, (0, 0));
+    for x in margin..w.saturating_sub(margin) {
+        for y in margin..h.saturating_sub(margin) {
+            for (step, [centre, side1, side2]) in &directions {
+                let centre = pool(x, y, centre);
+                let (s1, s2) = (pool(x, y, side1), pool(x, y, side2));
+                // the sides must both be denser, higher or darker for the cue to count, and the
+                // undergrowth of the sides must be missing from the path
+                let side_density = s1.density().min(s2.density());
+                let cues = [
+                    (1.0 - centre.density() / side_density)
+                        * (side_density / config.pathundergrowth).min(1.0),
+                    (s1.relief().min(s2.relief()) - centre.relief()) / config.pathrelief,
+                    if config.pathintensity > 0.0 {
+                        (centre.intensity() / s1.intensity().max(s2.intensity()) - 1.0)
+                            / config.pathintensity
+                    } else {
+                        f64::NAN
+                    },
+                ];
+                let (sum, weight) = cues.iter().zip(WEIGHTS).fold(
+                    (0.0, 0.0),
+                    |(sum, weight), (&cue, cue_weight)| {
+                        if cue.is_finite() {
+                            (sum + cue.clamp(0.0, 1.0) * cue_weight, weight + cue_weight)
+                        } else {
+                            (sum, weight)
+                        }
+                    },
+                );
+                // the other cues can't make a path where the undergrowth can't be measured
+                if cues[0].is_finite() && sum / weight > confidence[(x, y)] {
+                    confidence[(x, y)] = sum / weight;
+                    across[(x, y)] = *step;
+                }
+            }
+        }
+    }
+
+    let mut centre = Vec2D::new(w, h, false);
+    for x in 0..w {
+        for y in 0..h {
+            let c = confidence[(x, y)];
+            let (ax, ay) = across[(x, y)];
+            let neighbour = |sign: isize| {
+                let nx = x.checked_add_signed(sign * ax)?;
+                let ny = y.checked_add_signed(sign * ay)?;
+                (nx < w && ny < h).then(|| confidence[(nx, ny)])
+            };
+            centre[(x, y)] = c >= config.pathminconfidence
+                && neighbour(1).is_none_or(|n| c >= n)
+                && neighbour(-1).is_none_or(|n| c > n);
+        }
+    }
+
+    // bridge the gaps of single cells where the confidence dips on a path, cells between two
+    // centreline cells that aren't otherwise connected
+    let distance = |a: (isize, isize), b: (isize, isize)| (a.0 - b.0).abs().max((a.1 - b.1).abs());
+    let mut bridged = centre.clone();
+    for x in 2..w.saturating_sub(2) {
+        for y in 2..h.saturating_sub(2) {
+            if centre[(x, y)] || confidence[(x, y)] < config.pathminconfidence / 2.0 {
+                continue;
+            }
+            let on_line = |(dx, dy): (isize, isize)| {
+                centre[(x.wrapping_add_signed(dx), y.wrapping_add_signed(dy))]
+            };
+            let around = |r: isize| {
+                (-r..=r)
+                    .flat_map(move |dx| (-r..=r).map(move |dy| (dx, dy)))
+                    .filter(|&cell| cell != (0, 0) && on_line(cell))
+            };
+            bridged[(x, y)] = around(1).any(|a| {
+                around(1).any(|b| {
+                    distance(a, b) == 2
+                        && !around(2).any(|c| distance(a, c) <= 1 && distance(b, c) <= 1)
+                })
+            });
+        }
+    }
+    (confidence, bridged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::io::xyz::XyzRecord;
+    use std::path::Path;
+
+    #[test]
+    fn test_path_through_undergrowth() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // flat ground with undergrowth everywhere except a path along x = 20 m, slightly
+        // lower and brighter than the ground around it
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid: Vec2D::new(21, 21, 100.0),
+        };
+        let mut points = PointCloud::default();
+        for i in 0..160 {
+            for j in 0..160 {
+                let (x, y) = (0.25 * i as f64, 0.25 * j as f64);
+                let path = (x - 20.0).abs() < 0.6;
+                let (z, classification, intensity) = match (path, (i + j) % 2) {
+                    (true, _) => (99.95, 2, 120),
+                    (false, 0) => (100.0, 2, 80),
+                    (false, _) => (100.7, 3, 60),
+                };
+                points.records.push(XyzRecord {
+                    x,
+                    y,
+                    z,
+                    classification,
+                    intensity,
+                    ..Default::default()
+                });
+            }
+        }
+
+        let dxf = makepaths(&config, &points, &hmap).unwrap();
+        let Geometry::Polylines3(lines) = dxf.take_geometry().swap_remove(0) else {
+            panic!("paths should be 3D polylines");
+        };
+        assert_eq!(lines.len(), 1);
+        for (line, (class, confidence)) in lines.iter() {
+            assert_eq!(*class, Classification::Path);
+            assert!(*confidence > 0.9, "{confidence}");
+            let (ymin, ymax) = line
+                .iter()
+                .fold((f64::MAX, f64::MIN), |(a, b), p| (a.min(p.y), b.max(p.y)));
+            assert!(ymin < 6.0 && ymax > 34.0, "{ymin} {ymax}");
+            for p in line {
+                assert!((p.x - 20.0).abs() <= 1.0, "{p:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,841 @@
+//! A typed API for running the processing stages on in-memory artifacts.
+//!
+//! The stage functions in the other modules take and return points, heightmaps, vector layers
+//! and images. A [`Pipeline`] runs them in the order and with the options of a tile, so callers
+//! never deal with file names or the current directory. [`process::process_tile`] runs a tile
+//! through [`Pipeline::layers_with_timing`] and writes the layers into its temp folder with
+//! [`MapLayers::write_to`].
+use image::DynamicImage;
+use log::info;
+use std::borrow::Borrow;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds};
+use crate::io::fs::FileSystem;
+use crate::io::fs::memory::MemoryFileSystem;
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::XyzRecord;
+use crate::job::{self, JobError};
+use crate::util::{self, Timing};
+use crate::{
+    banks, blocks, boulders, cliffs, contours, knolls, marshes, merge, paths, pits, powerlines,
+    process, render, stony, streams, vegetation, water,
+};
+
+pub use crate::blocks::Buildings;
+pub use crate::cliffs::Cliffs;
+pub use crate::io::worldfile::GeoRaster;
+pub use crate::io::xyz::PointCloud;
+pub use crate::marshes::Marshes;
+pub use crate::powerlines::PowerLines;
+pub use crate::render::RenderedMap;
+pub use crate::stony::StonyGround;
+pub use crate::vegetation::{Vegetation, VegetationBits};
+
+/// The output of the contour generation.
+#[derive(Debug)]
+pub struct ContourSet {
+    /// The final smoothed contours, including index contours and depressions.
+    pub contours: BinaryDxf,
+    /// Dot knolls and small depressions.
+    pub dotknolls: BinaryDxf,
+    /// The knolls detected by the knoll detector, if it was enabled.
+    pub detected: Option<BinaryDxf>,
+    /// The 0.3 m contours the knolls are detected from, if generated with the other contours.
+    pub contours03: Option<BinaryDxf>,
+    /// Contours with the `basemapcontours` interval, if it is set.
+    pub basemap: Option<BinaryDxf>,
+    /// The intermediate results, if generated with the other contours.
+    pub steps: Option<ContourSteps>,
+}
+
+/// The intermediate results of the contour generation, written by [`MapLayers::write_to`] for the
+/// single stage commands that continue from them.
+#[derive(Debug)]
+pub struct ContourSteps {
+    /// The heightmap with the knolls raised, xyz_knolls.hmap.
+    pub knoll_heightmap: HeightMap,
+    /// The contours before smoothing, out.dxf.bin.
+    pub raw: BinaryDxf,
+    /// The knolls found by the knoll detector, pins.bin.
+    pub pins: Vec<knolls::Pin>,
+    /// The dot knoll candidates of [`merge::smoothjoin`], dotknolls.bin.
+    pub dotknolls: knolls::Dotknolls,
+    /// The depression outlines and knoll heads of [`merge::smoothjoin`], depressions.txt and
+    /// knollheads.txt.
+    pub depressions: String,
+    pub knollheads: String,
+}
+
+/// All layers needed to render a map.
+#[derive(Debug)]
+pub struct MapLayers {
+    pub heightmap: HeightMap,
+    /// Contours, unless `vegeonly` or `cliffsonly` is enabled.
+    pub contours: Option<ContourSet>,
+    /// Vegetation, unless `cliffsonly` or `contoursonly` is enabled.
+    pub vegetation: Option<Vegetation>,
+    /// Cliffs, unless `vegeonly` or `contoursonly` is enabled.
+    pub cliffs: Option<Cliffs>,
+    /// Buildings detected from the points, if `detectbuildings` is enabled.
+    pub buildings: Option<Buildings>,
+    /// Streams and ditches, if `detectstreams` is enabled.
+    pub streams: Option<BinaryDxf>,
+    /// Marshes, if `detectmarshes` is enabled.
+    pub marshes: Option<Marshes>,
+    /// The shorelines of lakes and ponds, if `detectwater` is enabled.
+    pub water_bodies: Option<BinaryDxf>,
+    /// Boulders and boulder clusters, if `detectboulders` is enabled.
+    pub boulders: Option<BinaryDxf>,
+    /// Stony ground and boulder fields, if `detectstony` is enabled.
+    pub stony_ground: Option<StonyGround>,
+    /// Pits and small depressions, if `detectpits` is enabled.
+    pub pits: Option<BinaryDxf>,
+    /// Earth banks and erosion gullies, if `detectbanks` is enabled.
+    pub banks: Option<BinaryDxf>,
+    /// Paths with their confidence, if `detectpaths` is enabled.
+    pub paths: Option<BinaryDxf>,
+    /// Power lines and their pylons, if `detectpowerlines` is enabled.
+    pub power_lines: Option<PowerLines>,
+    /// The shape file layers drawn below the other layers, see `crate::shapefile::render`.
+    pub low: Option<DynamicImage>,
+    /// The shape file layers drawn above the other layers.
+    pub high: Option<DynamicImage>,
+}
+
+impl MapLayers {
+    /// Writes the layers into `tmpfolder` with the file names of the single stage commands, and
+    /// removes the files of the layers that are not there, so no stale layers of a previous run
+    /// end up in the map. The shape file layers are left alone.
+    pub fn write_to(
+        &self,
+        fs: &impl FileSystem,
+        config: &Config,
+        tmpfolder: &Path,
+    ) -> anyhow::Result<()> {
+        let output_dxf = config.output_dxf;
+        let dxf = |name: &str, dxf: Option<&BinaryDxf>| -> anyhow::Result<()> {
+            let path = tmpfolder.join(name);
+            match dxf {
+                Some(dxf) => dxf.to_file(fs, path, output_dxf),
+                None => {
+                    remove_stale(fs, &path.with_extension(""))?;
+                    remove_stale(fs, &path)
+                }
+            }
+        };
+        let text = |name: &str, text: Option<&String>| -> anyhow::Result<()> {
+            let path = tmpfolder.join(name);
+            match text {
+                Some(text) => Ok(fs.create(path)?.write_all(text.as_bytes())?),
+                None => remove_stale(fs, &path),
+            }
+        };
+        let png = |name: &str, image: Option<&DynamicImage>| -> anyhow::Result<()> {
+            let path = tmpfolder.join(name);
+            match image {
+                Some(image) => {
+                    image.write_to(&mut fs.create(path)?, image::ImageFormat::Png)?;
+                    Ok(())
+                }
+                None => remove_stale(fs, &path),
+            }
+        };
+        let raster = |name: &str, raster: Option<&GeoRaster>| -> anyhow::Result<()> {
+            let path = tmpfolder.join(name);
+            match raster {
+                Some(raster) => raster.to_file(fs, path),
+                None => {
+                    remove_stale(fs, &path.with_extension("pgw"))?;
+                    remove_stale(fs, &path)
+                }
+            }
+        };
+
+        self.heightmap.to_file(fs, tmpfolder.join("xyz2.hmap"))?;
+
+        let contours = self.contours.as_ref();
+        dxf("out2.dxf.bin", contours.map(|c| &c.contours))?;
+        dxf("dotknolls.dxf.bin", contours.map(|c| &c.dotknolls))?;
+        dxf(
+            "detected.dxf.bin",
+            contours.and_then(|c| c.detected.as_ref()),
+        )?;
+        dxf(
+            "contours03.dxf.bin",
+            contours.and_then(|c| c.contours03.as_ref()),
+        )?;
+        dxf("basemap.dxf.bin", contours.and_then(|c| c.basemap.as_ref()))?;
+        let steps = contours.and_then(|c| c.steps.as_ref());
+        match steps {
+            Some(steps) => {
+                steps
+                    .knoll_heightmap
+                    .to_file(fs, tmpfolder.join("xyz_knolls.hmap"))?;
+                util::write_object(fs.create(tmpfolder.join("pins.bin"))?, &steps.pins)?;
+                util::write_object(
+                    fs.create(tmpfolder.join("dotknolls.bin"))?,
+                    &steps.dotknolls,
+                )?;
+            }
+            None => {
+                for name in ["xyz_knolls.hmap", "pins.bin", "dotknolls.bin"] {
+                    remove_stale(fs, &tmpfolder.join(name))?;
+                }
+            }
+        }
+        dxf("out.dxf.bin", steps.map(|s| &s.raw))?;
+        text("depressions.txt", steps.map(|s| &s.depressions))?;
+        text("knollheads.txt", steps.map(|s| &s.knollheads))?;
+        // rendered from the contours
+        dxf("formlines.dxf.bin", None)?;
+
+        let vegetation = self.vegetation.as_ref();
+        raster("vegetation.png", vegetation.map(|v| &v.vegetation))?;
+        raster("undergrowth.png", vegetation.map(|v| &v.undergrowth))?;
+        png("blueblack.png", vegetation.and_then(|v| v.water.as_ref()))?;
+        let bits = vegetation.and_then(|v| v.bits.as_ref());
+        png("vegetation_bit.png", bits.map(|b| &b.vegetation))?;
+        png("undergrowth_bit.png", bits.map(|b| &b.undergrowth))?;
+
+        let cliffs = self.cliffs.as_ref();
+        dxf("c2g.dxf.bin", cliffs.map(|c| &c.small))?;
+        dxf("c3g.dxf.bin", cliffs.map(|c| &c.big))?;
+
+        let buildings = self.buildings.as_ref();
+        png("blocks.png", buildings.map(|b| &b.image))?;
+        dxf("blocks.dxf.bin", buildings.map(|b| &b.footprints))?;
+
+        dxf("streams.dxf.bin", self.streams.as_ref())?;
+
+        let marshes = self.marshes.as_ref();
+        png("marshes.png", marshes.map(|m| &m.image))?;
+        dxf("marshes.dxf.bin", marshes.map(|m| &m.outlines))?;
+
+        dxf("water.dxf.bin", self.water_bodies.as_ref())?;
+        dxf("boulders.dxf.bin", self.boulders.as_ref())?;
+
+        let stony_ground = self.stony_ground.as_ref();
+        png("stony.png", stony_ground.map(|s| &s.image))?;
+        dxf("stony.dxf.bin", stony_ground.map(|s| &s.outlines))?;
+
+        dxf("pits.dxf.bin", self.pits.as_ref())?;
+        dxf("banks.dxf.bin", self.banks.as_ref())?;
+        dxf("paths.dxf.bin", self.paths.as_ref())?;
+
+        let power_lines = self.power_lines.as_ref();
+        dxf("powerlines.dxf.bin", power_lines.map(|p| &p.lines))?;
+        dxf("pylons.dxf.bin", power_lines.map(|p| &p.pylons))?;
+        Ok(())
+    }
+
+    /// Reads the layers written by [`Self::write_to`] or the single stage commands from
+    /// `tmpfolder`, together with the shape file layers. The layers without files are left out.
+    pub fn read_from(fs: &impl FileSystem, tmpfolder: &Path) -> anyhow::Result<Self> {
+        let heightmap = HeightMap::from_file(fs, tmpfolder.join("xyz2.hmap"))?;
+        let bounds = Bounds::new(
+            heightmap.minx(),
+            heightmap.maxx(),
+            heightmap.miny(),
+            heightmap.maxy(),
+        );
+        let dxf = |name: &str| -> anyhow::Result<Option<BinaryDxf>> {
+            let path = tmpfolder.join(name);
+            if !fs.exists(&path) {
+                return Ok(None);
+            }
+            Ok(Some(BinaryDxf::from_file(fs, path)?))
+        };
+        // the missing half of a layer has no geometry
+        let empty = || BinaryDxf::new(bounds.clone(), Vec::new());
+        let png = |name: &str| -> anyhow::Result<Option<DynamicImage>> {
+            let path = tmpfolder.join(name);
+            if !fs.exists(&path) {
+                return Ok(None);
+            }
+            Ok(Some(fs.read_image_png(path)?))
+        };
+
+        let contours = match dxf("out2.dxf.bin")? {
+            Some(contours) => Some(ContourSet {
+                contours,
+                dotknolls: dxf("dotknolls.dxf.bin")?.unwrap_or_else(empty),
+                detected: dxf("detected.dxf.bin")?,
+                contours03: dxf("contours03.dxf.bin")?,
+                basemap: dxf("basemap.dxf.bin")?,
+                steps: None,
+            }),
+            None => None,
+        };
+        let vegetation = if fs.exists(tmpfolder.join("vegetation.png")) {
+            let bits = match (png("vegetation_bit.png")?, png("undergrowth_bit.png")?) {
+                (Some(vegetation), Some(undergrowth)) => Some(VegetationBits {
+                    vegetation,
+                    undergrowth,
+                }),
+                _ => None,
+            };
+            Some(Vegetation {
+                vegetation: GeoRaster::from_file(fs, tmpfolder.join("vegetation.png"))?,
+                undergrowth: GeoRaster::from_file(fs, tmpfolder.join("undergrowth.png"))?,
+                water: png("blueblack.png")?,
+                bits,
+            })
+        } else {
+            None
+        };
+        let cliffs = match (dxf("c2g.dxf.bin")?, dxf("c3g.dxf.bin")?) {
+            (None, None) => None,
+            (small, big) => Some(Cliffs {
+                small: small.unwrap_or_else(empty),
+                big: big.unwrap_or_else(empty),
+            }),
+        };
+        let buildings = match png("blocks.png")? {
+            Some(image) => Some(Buildings {
+                image,
+                footprints: dxf("blocks.dxf.bin")?.unwrap_or_else(empty),
+            }),
+            None => None,
+        };
+        let marshes = match png("marshes.png")? {
+            Some(image) => Some(Marshes {
+                image,
+                outlines: dxf("marshes.dxf.bin")?.unwrap_or_else(empty),
+            }),
+            None => None,
+        };
+        let stony_ground = match png("stony.png")? {
+            Some(image) => Some(StonyGround {
+                image,
+                outlines: dxf("stony.dxf.bin")?.unwrap_or_else(empty),
+            }),
+            None => None,
+        };
+        let power_lines = match (dxf("powerlines.dxf.bin")?, dxf("pylons.dxf.bin")?) {
+            (None, None) => None,
+            (lines, pylons) => Some(PowerLines {
+                lines: lines.unwrap_or_else(empty),
+                pylons: pylons.unwrap_or_else(empty),
+            }),
+        };
+
+        Ok(Self {
+            contours,
+            vegetation,
+            cliffs,
+            buildings,
+            streams: dxf("streams.dxf.bin")?,
+            marshes,
+            water_bodies: dxf("water.dxf.bin")?,
+            boulders: dxf("boulders.dxf.bin")?,
+            stony_ground,
+            pits: dxf("pits.dxf.bin")?,
+            banks: dxf("banks.dxf.bin")?,
+            paths: dxf("paths.dxf.bin")?,
+            power_lines,
+            low: png("low.png")?,
+            high: png("high.png")?,
+            heightmap,
+        })
+    }
+}
+
+fn remove_stale(fs: &impl FileSystem, path: &Path) -> anyhow::Result<()> {
+    if fs.exists(path) {
+        fs.remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Runs the processing stages on in-memory artifacts.
+///
+/// To make the stages cancellable, call them within [`crate::job::with_cancellation`]. A
+/// cancelled or over budget stage returns a [`JobError`].
+pub struct Pipeline<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self { config }
+    }
+
+    /// Reads the points from the contents of a .las, .laz, .xyz or .xyz.bin file. The format
+    /// is determined from the extension of `file_name`.
+    pub fn read_points(&self, file_name: &str, data: &[u8]) -> anyhow::Result<PointCloud> {
+        let input = Path::new(file_name)
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("invalid input file name {file_name}"))?;
+        // the readers of the formats read files
+        let fs = MemoryFileSystem::new();
+        fs.create(input)?.write_all(data)?;
+        process::ingest_points(&fs, self.config, Path::new(input)).map_err(stage_error)
+    }
+
+    /// Creates the heightmap of the ground points.
+    pub fn heightmap(&self, points: &PointCloud) -> anyhow::Result<HeightMap> {
+        contours::xyz2heightmap(self.config, points).map_err(stage_error)
+    }
+
+    /// Creates raw contours with the given interval from a heightmap.
+    pub fn contours(&self, heightmap: &HeightMap, interval: f64) -> anyhow::Result<BinaryDxf> {
+        contours::heightmap2contours(interval, heightmap).map_err(stage_error)
+    }
+
+    /// Runs the knoll detection and generates the final contours and dot knolls.
+    pub fn contour_set(&self, heightmap: &HeightMap) -> anyhow::Result<ContourSet> {
+        self.contour_set_with_timing(heightmap, &mut Timing::start_now("contour_set"))
+    }
+
+    fn contour_set_with_timing(
+        &self,
+        heightmap: &HeightMap,
+        timing: &mut Timing,
+    ) -> anyhow::Result<ContourSet> {
+        let config = self.config;
+        let &Config {
+            scalefactor,
+            contour_interval,
+            basemapcontours,
+            skipknolldetection,
+            ..
+        } = config;
+        let halfinterval = contour_interval / 2.0 * scalefactor;
+
+        let contours03 = self.contours(heightmap, scalefactor * 0.3)?;
+        let basemap = if basemapcontours != 0.0 {
+            info!("Basemap contours");
+            Some(self.contours(heightmap, basemapcontours)?)
+        } else {
+            None
+        };
+
+        let (detected, pins) = if !skipknolldetection {
+            info!("Knoll detection part 2");
+            timing.start_section("knoll detection part 2");
+            let (detected, pins) = knolls::knolldetector(config, heightmap, &contours03)?;
+            (Some(detected), pins)
+        } else {
+            (None, Vec::new())
+        };
+        info!("Contour generation part 1");
+        timing.start_section("contour generation part 1");
+        let knoll_heightmap = knolls::xyzknolls(config, heightmap, &pins);
+
+        info!("Contour generation part 2");
+        timing.start_section("contour generation part 2");
+        // without the knoll detection the contours follow the heightmap of the points
+        let out = if skipknolldetection {
+            self.contours(heightmap, halfinterval)?
+        } else {
+            self.contours(&knoll_heightmap, halfinterval)?
+        };
+
+        info!("Contour generation part 3");
+        timing.start_section("contour generation part 3");
+        let smoothed = merge::smoothjoin(config, &knoll_heightmap, &out).map_err(stage_error)?;
+
+        info!("Contour generation part 4");
+        timing.start_section("contour generation part 4");
+        let dotknolls = knolls::dotknolls(
+            config,
+            &knoll_heightmap,
+            &smoothed.contours,
+            &smoothed.dotknolls,
+        )
+        .map_err(stage_error)?;
+
+        Ok(ContourSet {
+            contours: smoothed.contours,
+            dotknolls,
+            detected,
+            contours03: Some(contours03),
+            basemap,
+            steps: Some(ContourSteps {
+                knoll_heightmap,
+                raw: out,
+                pins,
+                dotknolls: smoothed.dotknolls,
+                depressions: smoothed.depressions,
+                knollheads: smoothed.knollheads,
+            }),
+        })
+    }
+
+    /// Generates the vegetation rasters.
+    pub fn vegetation(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<Vegetation> {
+        vegetation::makevege(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Generates the cliffs.
+    pub fn cliffs(&self, points: &PointCloud, heightmap: &HeightMap) -> anyhow::Result<Cliffs> {
+        cliffs::makecliffs(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects buildings from the points.
+    pub fn buildings(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<Buildings> {
+        blocks::blocks(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects streams and ditches from the heightmap.
+    pub fn streams(&self, heightmap: &HeightMap) -> anyhow::Result<BinaryDxf> {
+        streams::makestreams(self.config, heightmap).map_err(stage_error)
+    }
+
+    /// Detects marshes from the points and the heightmap.
+    pub fn marshes(&self, points: &PointCloud, heightmap: &HeightMap) -> anyhow::Result<Marshes> {
+        marshes::makemarshes(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects lakes and ponds from the points and the heightmap.
+    pub fn water_bodies(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<BinaryDxf> {
+        water::makewater(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects boulders from the points.
+    pub fn boulders(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<BinaryDxf> {
+        boulders::makeboulders(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects stony ground and boulder fields from the points and the heightmap.
+    pub fn stony_ground(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<StonyGround> {
+        stony::makestony(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects earth banks and erosion gullies from the heightmap, leaving out the banks next to
+    /// `cliffs`.
+    pub fn banks(
+        &self,
+        heightmap: &HeightMap,
+        cliffs: Option<&Cliffs>,
+    ) -> anyhow::Result<BinaryDxf> {
+        banks::makebanks(self.config, heightmap, cliffs).map_err(stage_error)
+    }
+
+    /// Detects pits and small depressions from the heightmap, leaving out the ones next to the
+    /// u-depressions of `dotknolls`.
+    pub fn pits(
+        &self,
+        heightmap: &HeightMap,
+        dotknolls: Option<&BinaryDxf>,
+    ) -> anyhow::Result<BinaryDxf> {
+        pits::makepits(self.config, heightmap, dotknolls).map_err(stage_error)
+    }
+
+    /// Detects paths from the points and the heightmap.
+    pub fn paths(&self, points: &PointCloud, heightmap: &HeightMap) -> anyhow::Result<BinaryDxf> {
+        paths::makepaths(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Detects power lines and their pylons from the points and the heightmap.
+    pub fn power_lines(
+        &self,
+        points: &PointCloud,
+        heightmap: &HeightMap,
+    ) -> anyhow::Result<PowerLines> {
+        powerlines::makepowerlines(self.config, points, heightmap).map_err(stage_error)
+    }
+
+    /// Runs all the stages needed for rendering a map, honoring the `vegeonly`, `cliffsonly`,
+    /// `contoursonly` and `detect*` options.
+    pub fn layers(&self, points: &PointCloud) -> anyhow::Result<MapLayers> {
+        self.layers_with_timing(points, &mut Timing::start_now("layers"))
+    }
+
+    /// Same as [`Self::layers`], timing each stage in its own section of `timing`. The stages
+    /// reading the points run first, so points passed by value are freed before the contours and
+    /// the other stages working on the heightmap.
+    pub fn layers_with_timing(
+        &self,
+        points: impl Borrow<PointCloud>,
+        timing: &mut Timing,
+    ) -> anyhow::Result<MapLayers> {
+        let &Config {
+            vegeonly,
+            cliffsonly,
+            contoursonly,
+            detectbuildings,
+            detectstreams,
+            detectmarshes,
+            detectwater,
+            detectboulders,
+            detectstony,
+            detectbanks,
+            detectpaths,
+            detectpowerlines,
+            detectpits,
+            ..
+        } = self.config;
+        // the detected features are only drawn on full maps
+        let features = !vegeonly && !contoursonly && !cliffsonly;
+
+        let held = job::hold(
+            self.config,
+            "point cloud",
+            (points.borrow().records.len() * size_of::<XyzRecord>()) as u64,
+        )?;
+        let cloud = points.borrow();
+
+        info!("Knoll detection part 1");
+        timing.start_section("knoll detection part 1");
+        let heightmap = self.heightmap(cloud)?;
+
+        let mut vegetation = None;
+        if !cliffsonly && !contoursonly {
+            info!("Vegetation generation");
+            timing.start_section("vegetation generation");
+            vegetation = Some(self.vegetation(cloud, &heightmap)?);
+        }
+        let mut cliffs = None;
+        if !vegeonly && !contoursonly {
+            info!("Cliff generation");
+            timing.start_section("cliff generation");
+            cliffs = Some(self.cliffs(cloud, &heightmap)?);
+        }
+        let mut buildings = None;
+        if features && detectbuildings {
+            info!("Detecting buildings");
+            timing.start_section("detecting buildings");
+            buildings = Some(self.buildings(cloud, &heightmap)?);
+        }
+        let mut marshes = None;
+        if features && detectmarshes {
+            info!("Detecting marshes");
+            timing.start_section("detecting marshes");
+            marshes = Some(self.marshes(cloud, &heightmap)?);
+        }
+        // the water bodies replace the water of the vegetation stage
+        let mut water_bodies = None;
+        if !cliffsonly && !contoursonly && detectwater {
+            info!("Detecting water bodies");
+            timing.start_section("detecting water bodies");
+            water_bodies = Some(self.water_bodies(cloud, &heightmap)?);
+        }
+        let mut boulders = None;
+        if features && detectboulders {
+            info!("Detecting boulders");
+            timing.start_section("detecting boulders");
+            boulders = Some(self.boulders(cloud, &heightmap)?);
+        }
+        let mut stony_ground = None;
+        if features && detectstony {
+            info!("Detecting stony ground");
+            timing.start_section("detecting stony ground");
+            stony_ground = Some(self.stony_ground(cloud, &heightmap)?);
+        }
+        let mut paths = None;
+        if features && detectpaths {
+            info!("Detecting paths");
+            timing.start_section("detecting paths");
+            paths = Some(self.paths(cloud, &heightmap)?);
+        }
+        let mut power_lines = None;
+        if features && detectpowerlines {
+            info!("Detecting power lines");
+            timing.start_section("detecting power lines");
+            power_lines = Some(self.power_lines(cloud, &heightmap)?);
+        }
+        // the remaining stages only need the heightmap
+        drop(held);
+        drop(points);
+
+        let mut contours = None;
+        if !vegeonly && !cliffsonly {
+            contours = Some(self.contour_set_with_timing(&heightmap, timing)?);
+        }
+        let mut streams = None;
+        if features && detectstreams {
+            info!("Detecting streams");
+            timing.start_section("detecting streams");
+            streams = Some(self.streams(&heightmap)?);
+        }
+        let mut banks = None;
+        if features && detectbanks {
+            info!("Detecting earth banks and gullies");
+            timing.start_section("detecting earth banks and gullies");
+            banks = Some(self.banks(&heightmap, cliffs.as_ref())?);
+        }
+        // after the contours, so the pits next to their u-depressions are left out
+        let mut pits = None;
+        if !vegeonly && !cliffsonly && detectpits {
+            info!("Detecting pits");
+            timing.start_section("detecting pits");
+            let dotknolls = contours.as_ref().map(|c| &c.dotknolls);
+            pits = Some(self.pits(&heightmap, dotknolls)?);
+        }
+
+        Ok(MapLayers {
+            heightmap,
+            contours,
+            vegetation,
+            cliffs,
+            buildings,
+            streams,
+            marshes,
+            water_bodies,
+            boulders,
+            stony_ground,
+            pits,
+            banks,
+            paths,
+            power_lines,
+            low: None,
+            high: None,
+        })
+    }
+
+    /// Renders a map from its layers, with or without depressions.
+    pub fn render(&self, layers: &MapLayers, depressions: bool) -> anyhow::Result<RenderedMap> {
+        render::render(
+            self.config,
+            layers,
+            self.config.pnorthlinesangle,
+            self.config.pnorthlineswidth,
+            !depressions,
+        )
+        .map_err(stage_error)
+    }
+}
+
+/// The stage functions return errors that are not `Send + Sync`, so convert them by message,
+/// except for [`JobError`]s that callers may want to match on.
+fn stage_error(e: Box<dyn Error>) -> anyhow::Error {
+    match e.downcast::<JobError>() {
+        Ok(e) => (*e).into(),
+        Err(e) => anyhow::anyhow!("{e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::xyz::XyzRecord;
+
+    #[test]
+    fn test_heightmap_and_contours() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        let pipeline = Pipeline::new(&config);
+
+        // a 40x40 m slope rising 1 m per 10 m in the x-direction
+        let mut points = PointCloud::default();
+        for x in 0..40 {
+            for y in 0..40 {
+                points.records.push(XyzRecord {
+                    x: 1000.0 + x as f64,
+                    y: 2000.0 + y as f64,
+                    z: 100.0 + x as f32 / 10.0,
+                    classification: 2,
+                    number_of_returns: 1,
+                    return_number: 1,
+                    ..Default::default()
+                });
+            }
+        }
+
+        let heightmap = pipeline.heightmap(&points).unwrap();
+        assert_eq!(heightmap.minx(), 1000.0);
+        assert_eq!(heightmap.miny(), 2000.0);
+
+        let contours = pipeline.contours(&heightmap, 1.0).unwrap();
+        assert!(!contours.take_geometry().is_empty());
+    }
+
+    #[test]
+    fn test_write_contour_steps() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        let pipeline = Pipeline::new(&config);
+        let mut grid = crate::vec2d::Vec2D::new(20, 20, 0.0);
+        for (x, _, z) in grid.iter_mut() {
+            *z = 100.0 + x as f64 / 5.0;
+        }
+        let heightmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+        let layers = MapLayers {
+            contours: Some(pipeline.contour_set(&heightmap).unwrap()),
+            heightmap,
+            vegetation: None,
+            cliffs: None,
+            buildings: None,
+            streams: None,
+            marshes: None,
+            water_bodies: None,
+            boulders: None,
+            stony_ground: None,
+            pits: None,
+            banks: None,
+            paths: None,
+            power_lines: None,
+            low: None,
+            high: None,
+        };
+
+        // the single stage commands continue from the intermediate files
+        let fs = MemoryFileSystem::new();
+        let tmpfolder = Path::new("temp");
+        fs.create_dir_all(tmpfolder).unwrap();
+        layers.write_to(&fs, &config, tmpfolder).unwrap();
+        for name in [
+            "xyz_knolls.hmap",
+            "out.dxf.bin",
+            "pins.bin",
+            "dotknolls.bin",
+        ] {
+            assert!(fs.exists(tmpfolder.join(name)), "{name}");
+        }
+
+        // and they are removed with the contours
+        let layers = MapLayers {
+            contours: None,
+            ..layers
+        };
+        layers.write_to(&fs, &config, tmpfolder).unwrap();
+        assert!(!fs.exists(tmpfolder.join("pins.bin")));
+        assert!(!fs.exists(tmpfolder.join("out.dxf.bin")));
+    }
+
+    #[test]
+    fn test_cancelled_stage() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        let pipeline = Pipeline::new(&config);
+        let points = PointCloud {
+            records: vec![XyzRecord {
+                classification: 2,
+                ..Default::default()
+            }],
+        };
+
+        let token = crate::job::CancellationToken::new();
+        token.cancel();
+        let err =
+            crate::job::with_cancellation(&token, || pipeline.heightmap(&points)).unwrap_err();
+        assert_eq!(err.downcast::<JobError>().unwrap(), JobError::Cancelled);
+    }
+}
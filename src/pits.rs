@@ -0,0 +1,169 @@
+use log::info;
+use std::error::Error;
+use std::f64::consts::PI;
+
+use crate::areas;
+use crate::config::Config;
+use crate::drainage::Drainage;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Geometry, Point2, Points};
+use crate::io::heightmap::HeightMap;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// Cells filled less than this are at the rim of a sink rather than in it.
+const MIN_FILL: f64 = 0.1;
+/// Sinks closer than this to a u-depression of the dot knolls are already drawn from the
+/// contours.
+const DEPRESSION_DISTANCE: f64 = 5.0;
+
+/// Detects pits and small depressions from the sinks of the heightmap, and returns them as points
+/// at the deepest cell of each sink. Unlike the u-depressions of the knoll detection, they don't
+/// need a closed contour, so they find holes smaller than the contour interval.
+///
+/// A sink at least `pitmindepth` meters deep and at most `pitmaxdiameter` meters across is a pit
+/// if its sides are steeper than `pitsteepness` (its depth divided by its radius), and a small
+/// depression (`udepression`) otherwise. Sinks next to u-depressions already in
+/// `dotknolls` are left out.
+pub fn makepits(
+    config: &Config,
+    hmap: &HeightMap,
+    dotknolls: Option<&BinaryDxf>,
+) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting pits...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the drainage and the sink mask
+    job::reserve(
+        config,
+        "makepits",
+        2 * job::grid_bytes::<f64>(w, h)
+            + job::grid_bytes::<Option<(u32, u32)>>(w, h)
+            + job::grid_bytes::<(u32, u32)>(w, h)
+            + job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let depressions = depressions(dotknolls)?;
+    let drainage = Drainage::new(hmap);
+    job::check_cancelled()?;
+
+    let mut sinks = Vec2D::new(w, h, false);
+    for x in 0..w {
+        for y in 0..h {
+            sinks[(x, y)] = drainage.fill_depth(hmap, x, y) >= MIN_FILL;
+        }
+    }
+
+    let mut points = Points::new();
+    for sink in areas::components(&sinks) {
+        let Some(deepest) = sink.iter().copied().max_by(|&a, &b| {
+            let depth = |(x, y)| drainage.fill_depth(hmap, x, y);
+            depth(a).total_cmp(&depth(b))
+        }) else {
+            continue;
+        };
+        let depth = drainage.fill_depth(hmap, deepest.0, deepest.1);
+        let diameter = 2.0 * (sink.len() as f64 * hmap.scale * hmap.scale / PI).sqrt();
+        if depth < config.pitmindepth || diameter > config.pitmaxdiameter {
+            continue;
+        }
+
+        let point = Point2::new(
+            hmap.xoffset + hmap.scale * deepest.0 as f64,
+            hmap.yoffset + hmap.scale * deepest.1 as f64,
+        );
+        let drawn = depressions.iter().any(|p| {
+            let (dx, dy) = (p.x - point.x, p.y - point.y);
+            (dx * dx + dy * dy).sqrt() < DEPRESSION_DISTANCE
+        });
+        if drawn {
+            continue;
+        }
+
+        let class = if depth / (diameter / 2.0) >= config.pitsteepness {
+            Classification::Pit
+        } else {
+            Classification::Udepression
+        };
+        points.push(point, class);
+    }
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![points.into()],
+    ))
+}
+
+/// The u-depressions found by the knoll detection, if it has been run.
+fn depressions(dotknolls: Option<&BinaryDxf>) -> Result<Vec<Point2>, Box<dyn Error>> {
+    let Some(dxf) = dotknolls else {
+        return Ok(Vec::new());
+    };
+    let Some(Geometry::Points(points)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("the dot knolls should be points").into());
+    };
+    Ok(points
+        .iter()
+        .filter(|(_, class)| {
+            matches!(
+                class,
+                Classification::Udepression | Classification::UglyUdepression
+            )
+        })
+        .map(|(p, _)| p.clone())
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn detect(
+        config: &Config,
+        hmap: &HeightMap,
+        dotknolls: Option<&BinaryDxf>,
+    ) -> Vec<(Point2, Classification)> {
+        let dxf = makepits(config, hmap, dotknolls).unwrap();
+        let Geometry::Points(points) = dxf.take_geometry().swap_remove(0) else {
+            panic!("pits should be points");
+        };
+        points.iter().map(|(p, c)| (p.clone(), *c)).collect()
+    }
+
+    #[test]
+    fn test_pits_and_small_depressions() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // a gentle slope with a deep one-cell pit at (10, 10), a shallow bowl at (30, 10) and a
+        // dimple too shallow to draw at (10, 30)
+        let mut grid = Vec2D::new(41, 41, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            let bowl = (((x as f64 - 30.0).powi(2) + (y as f64 - 10.0).powi(2)).sqrt() - 1.5)
+                .clamp(0.0, 1.0);
+            *z = 100.0 + 0.02 * x as f64 - 0.8 * (1.0 - bowl);
+        }
+        grid[(10, 10)] -= 1.2;
+        grid[(10, 30)] -= 0.2;
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let found = detect(&config, &hmap, None);
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert!(found.contains(&(Point2::new(20.0, 20.0), Classification::Pit)));
+        let (bowl, class) = found.iter().find(|(p, _)| p.x > 40.0).unwrap();
+        assert_eq!(*class, Classification::Udepression);
+        assert!((bowl.x - 60.0).abs() <= 4.0 && (bowl.y - 20.0).abs() <= 4.0);
+
+        // the bowl is left out once the contours have a u-depression there
+        let mut dotknolls = Points::new();
+        dotknolls.push(Point2::new(60.5, 20.5), Classification::Udepression);
+        let dotknolls = BinaryDxf::new(Bounds::new(0.0, 80.0, 0.0, 80.0), vec![dotknolls.into()]);
+        let found = detect(&config, &hmap, Some(&dotknolls));
+        assert_eq!(found, vec![(Point2::new(20.0, 20.0), Classification::Pit)]);
+    }
+}
@@ -0,0 +1,692 @@
+use log::info;
+use rustc_hash::FxHashMap as HashMap;
+use std::error::Error;
+use std::f64::consts::PI;
+
+use crate::areas;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Point3, Points, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// The size of the cells the wire points are gridded in, in meters.
+const CELL: f64 = 1.0;
+/// The LAS classes of wire conductors and transmission towers.
+const WIRE_CLASS: u8 = 14;
+const TOWER_CLASS: u8 = 15;
+/// The LAS classes of low and high noise, which are never wires.
+const NOISE_CLASSES: [u8; 2] = [7, 18];
+/// Returns lower than this many meters above the ground are never wires.
+const MIN_RETURN_HEIGHT: f64 = 1.0;
+/// A power line has at least this many cells of wire points.
+const MIN_LINE_CELLS: usize = 5;
+/// The number of directions tried for the lines, evenly spread over a half turn.
+const ANGLES: usize = 180;
+/// The cells within this many meters from a line are on it.
+const BAND: f64 = 1.5;
+/// The conductors of a power line are within this many meters from its centreline.
+const CORRIDOR: f64 = 6.0;
+/// The longest gap between the wire points of a power line, in meters.
+const MAX_GAP: f64 = 25.0;
+/// The returns of a cell of unclassified wires are at most this many meters below its top.
+const MAX_LAYER: f64 = 2.0;
+/// The radius in meters of the neighbourhood in which a cell of unclassified wires is on a line.
+const LINEAR_RADIUS: isize = 10;
+/// The largest spread (standard deviation) in meters of the cells of a linear neighbourhood across
+/// the line, enough for the conductors next to each other.
+const MAX_WIDTH: f64 = 2.5;
+/// The largest ratio of the spread across a line to the spread along it in a linear
+/// neighbourhood, as variances.
+const MAX_FLATNESS: f64 = 0.35;
+/// The ends of a line are extended over the cells next to it with gaps of at most this many meters.
+const MAX_END_GAP: f64 = 3.0;
+/// The length in meters of the pieces of a wire whose highest points are compared for pylons.
+const PROFILE_STEP: f64 = 5.0;
+/// The wires sag at least `MIN_SAG` meters from a pylon within `SAG_WINDOW` meters of it.
+const MIN_SAG: f64 = 0.5;
+const SAG_WINDOW: f64 = 25.0;
+/// The size of the cells the tower points are grouped in, in meters.
+const TOWER_CELL: f64 = 2.0;
+/// A tower has at least this many points.
+const MIN_TOWER_POINTS: u32 = 3;
+
+/// A straight piece of a power line, in meters from the corner of the heightmap.
+#[derive(Debug, Clone)]
+struct Span {
+    start: Point2,
+    end: Point2,
+    /// The distances of the wire cells from `start` along the span, with their heights above the
+    /// ground.
+    profile: Vec<(f64, f64)>,
+    /// The wire cells the span was fitted to.
+    cells: Vec<(usize, usize)>,
+}
+
+/// The returns of the points relevant to power lines.
+struct Returns {
+    /// The highest wire class return of each cell above the ground, NaN without any.
+    wires: Vec2D<f32>,
+    /// The highest and lowest other non-ground returns of each cell above the ground, at least
+    /// `MIN_RETURN_HEIGHT` high, NaN without any.
+    tops: Vec2D<f32>,
+    lows: Vec2D<f32>,
+    /// The tower class points, in meters from the corner of the heightmap.
+    towers: Vec<Point2>,
+}
+
+/// The output of the power line detection.
+#[derive(Debug, Clone)]
+pub struct PowerLines {
+    /// The power lines projected on the ground.
+    pub lines: BinaryDxf,
+    /// The pylons of the power lines.
+    pub pylons: BinaryDxf,
+}
+
+/// Detects power lines and their pylons from the points and the heightmap, with the lines
+/// projected on the ground.
+///
+/// The wires are the points of the wire conductor class (14). Without any, they are the cells
+/// whose non-ground returns are all in a thin layer at least `powerlineminheight` meters above the
+/// ground and that line up with the cells like them around. Straight lines through the wires at
+/// least `powerlineminlength` meters long, without longer gaps than 25 meters, are found with a
+/// Hough transform and fitted to the wires around them. Lines meeting at their ends are joined.
+///
+/// The pylons are the groups of transmission tower (class 15) points. Without any, they are at the
+/// corners of the lines and where the wires are highest, with the wires sagging on both sides of
+/// them, or on one side at the ends of the lines inside the tile.
+pub fn makepowerlines(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<PowerLines, Box<dyn Error>> {
+    info!("Detecting power lines...");
+
+    let w = ((hmap.grid.width().saturating_sub(1)) as f64 * hmap.scale / CELL) as usize + 1;
+    let h = ((hmap.grid.height().saturating_sub(1)) as f64 * hmap.scale / CELL) as usize + 1;
+    // the wires, the tops and the lows, and the accumulator of the lines
+    job::reserve(
+        config,
+        "makepowerlines",
+        3 * job::grid_bytes::<f32>(w, h) + job::grid_bytes::<u32>(ANGLES, 2 * (w + h)),
+    )?;
+
+    let returns = measure(points, hmap, w, h)?;
+    job::check_cancelled()?;
+
+    let wires = cells(&returns.wires);
+    let (mut heights, candidates) = if wires.len() >= MIN_LINE_CELLS {
+        (returns.wires, wires)
+    } else {
+        let (tops, lows) = (&returns.tops, &returns.lows);
+        let mut layer = Vec2D::new(w, h, f32::NAN);
+        for x in 0..w {
+            for y in 0..h {
+                let top = tops[(x, y)] as f64;
+                if top >= config.powerlineminheight && top - lows[(x, y)] as f64 <= MAX_LAYER {
+                    layer[(x, y)] = tops[(x, y)];
+                }
+            }
+        }
+        let linear = linear_cells(&layer);
+        (layer, linear)
+    };
+    let mut spans = fit_lines(&candidates, config.powerlineminlength);
+    for cell in spans.iter().flat_map(|span| &span.cells) {
+        heights[*cell] = f32::NAN;
+    }
+    for span in &mut spans {
+        extend_span(span, &heights);
+    }
+    job::check_cancelled()?;
+
+    let lines = join_lines(&spans);
+    let pylons = if returns.towers.is_empty() {
+        let (width, height) = ((w - 1) as f64 * CELL, (h - 1) as f64 * CELL);
+        line_pylons(&spans, &lines, width, height)
+    } else {
+        towers(&returns.towers, w, h)
+    };
+
+    let world = |p: &Point2| Point2::new(hmap.xoffset + p.x, hmap.yoffset + p.y);
+    let bounds = Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy());
+
+    let mut polylines = Polylines::new();
+    for line in lines {
+        polylines.push(line.iter().map(world).collect(), Classification::PowerLine);
+    }
+    let mut pylon_points = Points::new();
+    for pylon in &pylons {
+        pylon_points.push(world(pylon), Classification::Pylon);
+    }
+    info!("Done");
+    Ok(PowerLines {
+        lines: BinaryDxf::new(bounds.clone(), vec![polylines.into()]),
+        pylons: BinaryDxf::new(bounds, vec![pylon_points.into()]),
+    })
+}
+
+/// Grids the returns of the points above the ground, and collects the tower points.
+fn measure(
+    points: &PointCloud,
+    hmap: &HeightMap,
+    w: usize,
+    h: usize,
+) -> Result<Returns, Box<dyn Error>> {
+    let mut returns = Returns {
+        wires: Vec2D::new(w, h, f32::NAN),
+        tops: Vec2D::new(w, h, f32::NAN),
+        lows: Vec2D::new(w, h, f32::NAN),
+        towers: Vec::new(),
+    };
+
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
+        for r in chunk {
+            let class = r.classification;
+            if class == 2 || NOISE_CLASSES.contains(&class) {
+                continue;
+            }
+            let (px, py) = (r.x - hmap.xoffset, r.y - hmap.yoffset);
+            if class == TOWER_CLASS {
+                returns.towers.push(Point2::new(px, py));
+                continue;
+            }
+            let (x, y) = ((px / CELL).round(), (py / CELL).round());
+            if x < 0.0 || y < 0.0 || x >= w as f64 || y >= h as f64 {
+                continue;
+            }
+            let cell = (x as usize, y as usize);
+            let height = r.z as f64 - hmap.interpolate(px / hmap.scale, py / hmap.scale);
+            if height < MIN_RETURN_HEIGHT {
+                continue;
+            }
+            let height = height as f32;
+            if class == WIRE_CLASS {
+                returns.wires[cell] = returns.wires[cell].max(height);
+            } else {
+                returns.tops[cell] = returns.tops[cell].max(height);
+                returns.lows[cell] = returns.lows[cell].min(height);
+            }
+        }
+    }
+    Ok(returns)
+}
+
+/// The centres of the cells with a height, with the height as `z`.
+fn cells(heights: &Vec2D<f32>) -> Vec<Point3> {
+    heights
+        .iter()
+        .filter(|(_, _, height)| height.is_finite())
+        .map(|(x, y, height)| Point3::new(x as f64 * CELL, y as f64 * CELL, height as f64))
+        .collect()
+}
+
+/// The cells with a height whose neighbourhood of cells with a height is a line.
+fn linear_cells(heights: &Vec2D<f32>) -> Vec<Point3> {
+    let (w, h) = (heights.width() as isize, heights.height() as isize);
+    cells(heights)
+        .into_iter()
+        .filter(|p| {
+            let (cx, cy) = ((p.x / CELL) as isize, (p.y / CELL) as isize);
+            let mut around = Vec::new();
+            for x in (cx - LINEAR_RADIUS).max(0)..=(cx + LINEAR_RADIUS).min(w - 1) {
+                for y in (cy - LINEAR_RADIUS).max(0)..=(cy + LINEAR_RADIUS).min(h - 1) {
+                    let (dx, dy) = (x - cx, y - cy);
+                    if dx * dx + dy * dy <= LINEAR_RADIUS * LINEAR_RADIUS
+                        && heights[(x as usize, y as usize)].is_finite()
+                    {
+                        around.push((dx as f64, dy as f64));
+                    }
+                }
+            }
+            if around.len() < MIN_LINE_CELLS {
+                return false;
+            }
+            let (major, minor) = spreads(&around).1;
+            minor <= MAX_WIDTH * MAX_WIDTH && minor <= MAX_FLATNESS * major
+        })
+        .collect()
+}
+
+/// The direction of the largest spread of points as an angle, and the largest and smallest
+/// spreads (the eigenvalues of the covariance).
+fn spreads(points: &[(f64, f64)]) -> (f64, (f64, f64)) {
+    let n = points.len() as f64;
+    let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0);
+    for &(x, y) in points {
+        sxx += (x - mx) * (x - mx) / n;
+        syy += (y - my) * (y - my) / n;
+        sxy += (x - mx) * (y - my) / n;
+    }
+    let mean = (sxx + syy) / 2.0;
+    let root = (((sxx - syy) / 2.0).powi(2) + sxy * sxy).sqrt();
+    (
+        0.5 * (2.0 * sxy).atan2(sxx - syy),
+        (mean + root, mean - root),
+    )
+}
+
+/// Finds the straight lines through the wire cells with a Hough transform. The cells on the
+/// strongest line are split at gaps longer than `MAX_GAP`, and each piece at least `min_length`
+/// long is fitted to the cells in its corridor, which are then left out of the next lines.
+fn fit_lines(candidates: &[Point3], min_length: f64) -> Vec<Span> {
+    let angles: Vec<(f64, f64)> = (0..ANGLES)
+        .map(|i| (i as f64 * PI / ANGLES as f64).sin_cos())
+        .collect();
+    let rmax = candidates
+        .iter()
+        .map(|p| p.x.abs() + p.y.abs())
+        .fold(0.0, f64::max);
+    let bins = (2.0 * rmax / BAND).ceil() as usize + 1;
+    let bin = |p: &Point3, (sin, cos): (f64, f64)| ((p.x * cos + p.y * sin + rmax) / BAND).round();
+
+    let mut accumulator = vec![0u32; ANGLES * bins];
+    for p in candidates {
+        for (a, &angle) in angles.iter().enumerate() {
+            accumulator[a * bins + bin(p, angle) as usize] += 1;
+        }
+    }
+    let mut remaining = vec![true; candidates.len()];
+
+    let mut spans = Vec::new();
+    loop {
+        let Some((peak, &count)) = accumulator.iter().enumerate().max_by_key(|(_, c)| **c) else {
+            break;
+        };
+        if (count as usize) < MIN_LINE_CELLS {
+            break;
+        }
+        let (sin, cos) = angles[peak / bins];
+        let rho = (peak % bins) as f64 * BAND - rmax;
+        let across = |p: &Point3| p.x * cos + p.y * sin - rho;
+        let along = |p: &Point3| -p.x * sin + p.y * cos;
+
+        let mut inliers: Vec<usize> = (0..candidates.len())
+            .filter(|&i| remaining[i] && across(&candidates[i]).abs() <= BAND)
+            .collect();
+        inliers.sort_by(|&i, &j| along(&candidates[i]).total_cmp(&along(&candidates[j])));
+        let runs =
+            inliers.chunk_by(|&i, &j| along(&candidates[j]) - along(&candidates[i]) <= MAX_GAP);
+        let mut corridors = Vec::new();
+        for run in runs {
+            let t0 = along(&candidates[run[0]]);
+            let t1 = along(&candidates[run[run.len() - 1]]);
+            if t1 - t0 < min_length || run.len() < MIN_LINE_CELLS {
+                continue;
+            }
+            let corridor: Vec<usize> = (0..candidates.len())
+                .filter(|&i| {
+                    let p = &candidates[i];
+                    remaining[i]
+                        && across(p).abs() <= CORRIDOR
+                        && (t0 - BAND..=t1 + BAND).contains(&along(p))
+                })
+                .collect();
+            spans.push(fit_span(candidates, &corridor));
+            corridors.push(corridor);
+        }
+        for i in corridors.into_iter().flatten().chain(inliers) {
+            if remaining[i] {
+                remaining[i] = false;
+                for (a, &angle) in angles.iter().enumerate() {
+                    accumulator[a * bins + bin(&candidates[i], angle) as usize] -= 1;
+                }
+            }
+        }
+    }
+    spans
+}
+
+/// Fits a span to the cells of its corridor along their direction of largest spread.
+fn fit_span(candidates: &[Point3], corridor: &[usize]) -> Span {
+    let points: Vec<(f64, f64)> = corridor
+        .iter()
+        .map(|&i| (candidates[i].x, candidates[i].y))
+        .collect();
+    let n = points.len() as f64;
+    let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (sin, cos) = spreads(&points).0.sin_cos();
+    let along: Vec<f64> = points
+        .iter()
+        .map(|p| (p.0 - mx) * cos + (p.1 - my) * sin)
+        .collect();
+    let t0 = along.iter().copied().fold(f64::INFINITY, f64::min);
+    let t1 = along.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    Span {
+        start: Point2::new(mx + t0 * cos, my + t0 * sin),
+        end: Point2::new(mx + t1 * cos, my + t1 * sin),
+        profile: corridor
+            .iter()
+            .zip(along)
+            .map(|(&i, t)| (t - t0, candidates[i].z))
+            .collect(),
+        cells: points
+            .iter()
+            .map(|p| ((p.0 / CELL).round() as usize, (p.1 / CELL).round() as usize))
+            .collect(),
+    }
+}
+
+/// Extends the ends of a span along its line over the cells with a height next to it that are not
+/// on any span yet, as the neighbourhoods of the cells at the ends of the wires are not linear
+/// enough.
+fn extend_span(span: &mut Span, heights: &Vec2D<f32>) {
+    let length = (span.end.x - span.start.x).hypot(span.end.y - span.start.y);
+    if length == 0.0 {
+        return;
+    }
+    let (dx, dy) = (
+        (span.end.x - span.start.x) / length,
+        (span.end.y - span.start.y) / length,
+    );
+    let (w, h) = (heights.width() as f64, heights.height() as f64);
+    // the distance the line goes on beyond a point, with the cells on the way
+    let follow = |from: &Point2, sign: f64| {
+        let (mut reached, mut found) = (0.0, Vec::new());
+        let mut t = CELL;
+        while t <= reached + MAX_END_GAP {
+            for offset in [-2.0, -1.0, 0.0, 1.0, 2.0] {
+                let x = ((from.x + sign * t * dx - offset * dy) / CELL).round();
+                let y = ((from.y + sign * t * dy + offset * dx) / CELL).round();
+                if x < 0.0 || y < 0.0 || x >= w || y >= h {
+                    continue;
+                }
+                let height = heights[(x as usize, y as usize)];
+                if height.is_finite() {
+                    found.push((t, height as f64));
+                    reached = t;
+                }
+            }
+            t += CELL;
+        }
+        (reached, found)
+    };
+
+    let (before, found_before) = follow(&span.start, -1.0);
+    let (after, found_after) = follow(&span.end, 1.0);
+    for point in &mut span.profile {
+        point.0 += before;
+    }
+    span.profile
+        .extend(found_before.into_iter().map(|(t, z)| (before - t, z)));
+    span.profile.extend(
+        found_after
+            .into_iter()
+            .map(|(t, z)| (before + length + t, z)),
+    );
+    span.start = Point2::new(span.start.x - before * dx, span.start.y - before * dy);
+    span.end = Point2::new(span.end.x + after * dx, span.end.y + after * dy);
+}
+
+/// The pylons of power lines without tower points. The corners of the lines are pylons, and the
+/// wires of the spans show the others.
+fn line_pylons(spans: &[Span], lines: &[Vec<Point2>], width: f64, height: f64) -> Vec<Point2> {
+    let mut pylons: Vec<Point2> = lines
+        .iter()
+        .flat_map(|line| line[1..line.len() - 1].iter().cloned())
+        .collect();
+    let wire_pylons = spans
+        .iter()
+        .flat_map(|span| wire_pylons(span, width, height))
+        .collect();
+    for pylon in merge_close(wire_pylons, CORRIDOR) {
+        // the ends of the spans at the corners are a bit off
+        if pylons
+            .iter()
+            .all(|p| (p.x - pylon.x).hypot(p.y - pylon.y) >= 2.0 * CORRIDOR)
+        {
+            pylons.push(pylon);
+        }
+    }
+    pylons
+}
+
+/// The pylons of a span from its profile, where the wires are highest with them sagging around.
+/// The pylons at the ends of a span only need the wires to sag on one side, and must be inside
+/// the tile of the given size.
+fn wire_pylons(span: &Span, width: f64, height: f64) -> Vec<Point2> {
+    let length = ((span.end.x - span.start.x).powi(2) + (span.end.y - span.start.y).powi(2)).sqrt();
+    if length == 0.0 {
+        return Vec::new();
+    }
+    let steps = (length / PROFILE_STEP) as usize + 1;
+    // the highest wire of each step and its distance along the span
+    let mut tops = vec![(f64::NAN, 0.0); steps];
+    for &(t, z) in &span.profile {
+        let top = &mut tops[((t / PROFILE_STEP) as usize).min(steps - 1)];
+        if top.0.is_nan() || z > top.0 {
+            *top = (z, t);
+        }
+    }
+
+    let window = (SAG_WINDOW / PROFILE_STEP).round() as usize;
+    let mut pylons = Vec::new();
+    for (i, &(top, t)) in tops.iter().enumerate() {
+        if top.is_nan() {
+            continue;
+        }
+        let before = &tops[i.saturating_sub(window)..i];
+        let after = &tops[i + 1..(i + 1 + window).min(steps)];
+        let (lowest_before, lowest_after) = (lowest(before), lowest(after));
+        if before.iter().chain(after).any(|&(z, _)| z > top) {
+            continue;
+        }
+        let sags = |lowest: f64| lowest <= top - MIN_SAG;
+        let point = Point2::new(
+            span.start.x + (span.end.x - span.start.x) * t / length,
+            span.start.y + (span.end.y - span.start.y) * t / length,
+        );
+        let inside = (CORRIDOR..=width - CORRIDOR).contains(&point.x)
+            && (CORRIDOR..=height - CORRIDOR).contains(&point.y);
+        let pylon = match (lowest_before.is_finite(), lowest_after.is_finite()) {
+            (true, true) => sags(lowest_before) && sags(lowest_after),
+            (false, true) => sags(lowest_after) && inside,
+            (true, false) => sags(lowest_before) && inside,
+            (false, false) => false,
+        };
+        if pylon {
+            pylons.push(point);
+        }
+    }
+    pylons
+}
+
+/// The lowest of the highest wires of some steps of a profile, infinite without any.
+fn lowest(tops: &[(f64, f64)]) -> f64 {
+    tops.iter()
+        .map(|&(z, _)| z)
+        .filter(|z| !z.is_nan())
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// Groups the tower points into towers at the centres of their points.
+fn towers(points: &[Point2], w: usize, h: usize) -> Vec<Point2> {
+    let (tw, th) = (
+        ((w - 1) as f64 * CELL / TOWER_CELL) as usize + 1,
+        ((h - 1) as f64 * CELL / TOWER_CELL) as usize + 1,
+    );
+    let mut sums: HashMap<(usize, usize), (f64, f64, u32)> = HashMap::default();
+    for p in points {
+        let (x, y) = ((p.x / TOWER_CELL).round(), (p.y / TOWER_CELL).round());
+        if x < 0.0 || y < 0.0 || x >= tw as f64 || y >= th as f64 {
+            continue;
+        }
+        let sum = sums.entry((x as usize, y as usize)).or_default();
+        *sum = (sum.0 + p.x, sum.1 + p.y, sum.2 + 1);
+    }
+    let mut mask = Vec2D::new(tw, th, false);
+    for &cell in sums.keys() {
+        mask[cell] = true;
+    }
+    areas::components8(&mask)
+        .into_iter()
+        .filter_map(|tower| {
+            let (x, y, n) = tower.iter().fold((0.0, 0.0, 0), |acc, cell| {
+                let sum = sums[cell];
+                (acc.0 + sum.0, acc.1 + sum.1, acc.2 + sum.2)
+            });
+            (n >= MIN_TOWER_POINTS).then(|| Point2::new(x / n as f64, y / n as f64))
+        })
+        .collect()
+}
+
+/// Merges the points closer than `distance` to each other into their mean.
+fn merge_close(points: Vec<Point2>, distance: f64) -> Vec<Point2> {
+    let mut groups: Vec<(f64, f64, usize)> = Vec::new();
+    for p in points {
+        let group = groups.iter_mut().find(|g| {
+            let n = g.2 as f64;
+            (g.0 / n - p.x).powi(2) + (g.1 / n - p.y).powi(2) < distance * distance
+        });
+        match group {
+            Some(g) => *g = (g.0 + p.x, g.1 + p.y, g.2 + 1),
+            None => groups.push((p.x, p.y, 1)),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(x, y, n)| Point2::new(x / n as f64, y / n as f64))
+        .collect()
+}
+
+/// Joins the spans whose ends are within `CORRIDOR` of each other into lines, meeting halfway
+/// between the ends, closest ends first.
+fn join_lines(spans: &[Span]) -> Vec<Vec<Point2>> {
+    let mut lines: Vec<Vec<Point2>> = spans
+        .iter()
+        .map(|span| vec![span.start.clone(), span.end.clone()])
+        .collect();
+    let distance = |a: &Point2, b: &Point2| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt();
+    loop {
+        let mut closest: Option<(f64, usize, bool, usize, bool)> = None;
+        for i in 0..lines.len() {
+            for j in i + 1..lines.len() {
+                for (i_last, j_last) in [(false, false), (false, true), (true, false), (true, true)]
+                {
+                    let a = if i_last {
+                        lines[i].last()
+                    } else {
+                        lines[i].first()
+                    };
+                    let b = if j_last {
+                        lines[j].last()
+                    } else {
+                        lines[j].first()
+                    };
+                    let d = distance(a.unwrap(), b.unwrap());
+                    if d < CORRIDOR && closest.is_none_or(|c| d < c.0) {
+                        closest = Some((d, i, i_last, j, j_last));
+                    }
+                }
+            }
+        }
+        let Some((_, i, i_last, j, j_last)) = closest else {
+            break;
+        };
+        let mut second = lines.swap_remove(j);
+        let first = &mut lines[i];
+        if !i_last {
+            first.reverse();
+        }
+        if j_last {
+            second.reverse();
+        }
+        let end = first.pop().unwrap();
+        let corner = intersection((&first[first.len() - 1], &end), (&second[0], &second[1]))
+            .filter(|p| {
+                distance(p, &end) < 2.0 * CORRIDOR && distance(p, &second[0]) < 2.0 * CORRIDOR
+            })
+            .unwrap_or_else(|| {
+                Point2::new((end.x + second[0].x) / 2.0, (end.y + second[0].y) / 2.0)
+            });
+        first.push(corner);
+        first.extend(second.into_iter().skip(1));
+    }
+    lines
+}
+
+/// The intersection of the lines through two segments, if they are not nearly parallel.
+fn intersection(a: (&Point2, &Point2), b: (&Point2, &Point2)) -> Option<Point2> {
+    let (dax, day) = (a.1.x - a.0.x, a.1.y - a.0.y);
+    let (dbx, dby) = (b.1.x - b.0.x, b.1.y - b.0.y);
+    let cross = dax * dby - day * dbx;
+    if cross.abs() < 0.1 * dax.hypot(day) * dbx.hypot(dby) {
+        return None;
+    }
+    let t = ((b.0.x - a.0.x) * dby - (b.0.y - a.0.y) * dbx) / cross;
+    Some(Point2::new(a.0.x + t * dax, a.0.y + t * day))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_power_line_with_pylons() {
+        // a power line with two spans of about 80 and 100 meters, bending at the pylon between
+        // them, with its conductors 3 meters apart sagging 4 meters from 15 meters high
+        let mut heights = Vec2D::new(201, 201, f32::NAN);
+        let corners: [(f64, f64); 3] = [(20.0, 20.0), (100.0, 36.0), (180.0, 100.0)];
+        for (start, end) in corners.iter().zip(&corners[1..]) {
+            let length = (end.0 - start.0).hypot(end.1 - start.1);
+            let (dx, dy) = ((end.0 - start.0) / length, (end.1 - start.1) / length);
+            for i in 0..=(length * 2.0) as usize {
+                let t = i as f64 / 2.0;
+                let sag = 4.0 * (1.0 - (2.0 * t / length - 1.0).powi(2));
+                for offset in [-1.5, 1.5] {
+                    let x = (start.0 + t * dx - offset * dy).round() as usize;
+                    let y = (start.1 + t * dy + offset * dx).round() as usize;
+                    heights[(x, y)] = heights[(x, y)].max(15.0 - sag as f32);
+                }
+            }
+        }
+        // scattered tree tops
+        let mut seed = 1u64;
+        let mut random = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((seed >> 33) % 201) as usize
+        };
+        for _ in 0..400 {
+            let cell = (random(), random());
+            heights[cell] = heights[cell].max(12.0);
+        }
+
+        let mut spans = fit_lines(&linear_cells(&heights), 30.0);
+        for cell in spans.iter().flat_map(|span| &span.cells) {
+            heights[*cell] = f32::NAN;
+        }
+        for span in &mut spans {
+            extend_span(span, &heights);
+        }
+        let lines = join_lines(&spans);
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        let line = &lines[0];
+        assert_eq!(line.len(), 3, "{line:?}");
+        for corner in corners {
+            assert!(
+                line.iter()
+                    .any(|p| (p.x - corner.0).hypot(p.y - corner.1) < 3.0),
+                "no corner at {corner:?} in {line:?}"
+            );
+        }
+
+        let pylons = line_pylons(&spans, &lines, 200.0, 200.0);
+        assert_eq!(pylons.len(), 3, "{pylons:?}");
+        for corner in corners {
+            assert!(
+                pylons
+                    .iter()
+                    .any(|p| (p.x - corner.0).hypot(p.y - corner.1) < 5.0),
+                "no pylon at {corner:?} in {pylons:?}"
+            );
+        }
+    }
+}
@@ -3,38 +3,47 @@ use las::{Reader, raw::Header};
 use log::debug;
 use log::info;
 use rand::prelude::*;
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::BufRead;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
 
-use crate::blocks;
-use crate::cliffs;
 use crate::config::Config;
-use crate::contours;
 use crate::crop;
+use crate::geometry::Bounds;
 use crate::io::fs::FileSystem;
-use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
 use crate::io::xyz::XyzInternalWriter;
 use crate::io::xyz::XyzRecord;
-use crate::knolls;
-use crate::merge;
+use crate::job::{self, JobError};
+use crate::lease::Lease;
+use crate::pipeline::Pipeline;
+use crate::progress::{self, Progress, ProgressEvent};
 use crate::render;
+use crate::report::{InputFile, ProducedFile, TILE_REPORT_FILE, TileReport, las_crs};
 use crate::util::Timing;
 use crate::util::read_lines_no_alloc;
-use crate::vegetation;
+use crate::worker::FAILURE_SUFFIX;
+
+/// The width in meters of the border of points read from the neighbouring tiles in batch mode.
+pub const BATCH_BUFFER: f64 = 127.0;
 
 // compute the number of elements we can buffer for 50MB of memory usage during LAZ -> XyzRecord conversion
 const LAZ_BUFFER_SIZE: usize =
     50 * 1024 * 1024 / (size_of::<las::Point>() + size_of::<XyzRecord>());
 
+/// Draws the shape files on the map of the tile and renders the png maps. With `mounted` the shape
+/// files are read from the zip archives mounted in `temp_shapefiles`, see
+/// [`crate::shapefile::render`].
 pub fn process_zip(
     fs: &impl FileSystem,
     config: &Config,
     thread: &String,
     tmpfolder: &Path,
-    filenames: &[String],
-    batch: bool,
+    mounted: bool,
 ) -> Result<(), Box<dyn Error>> {
     let mut timing = Timing::start_now("process_zip");
     let &Config {
@@ -44,18 +53,14 @@ pub fn process_zip(
     } = config;
     #[cfg(feature = "shapefile")]
     {
-        if !batch && !filenames.is_empty() {
-            info!("Rendering shape files");
-            timing.start_section("unzip and render shape files");
-            crate::shapefile::unzip_and_render(fs, config, tmpfolder, filenames).unwrap();
-        } else {
-            crate::shapefile::render(fs, config, tmpfolder, true).unwrap();
-        }
+        info!("Rendering shape files");
+        timing.start_section("render shape files");
+        crate::shapefile::render(fs, config, tmpfolder, mounted).unwrap();
     }
 
     info!("Rendering png map with depressions");
     timing.start_section("Rendering png map with depressions");
-    render::render(
+    render::render_files(
         fs,
         config,
         thread,
@@ -68,7 +73,7 @@ pub fn process_zip(
 
     info!("Rendering png map without depressions");
     timing.start_section("Rendering png map without depressions");
-    render::render(
+    render::render_files(
         fs,
         config,
         thread,
@@ -82,46 +87,129 @@ pub fn process_zip(
     Ok(())
 }
 
-pub fn process_tile(
-    fs: &impl FileSystem,
-    config: &Config,
-    thread: &String,
-    tmpfolder: &Path,
-    input_file: &Path,
-    skip_rendering: bool,
-) -> Result<(), Box<dyn Error>> {
-    let mut timing = Timing::start_now("process_tile");
-    fs.create_dir_all(tmpfolder)
-        .expect("Could not create tmp folder");
+/// The share of the points of a file that may be brighter than the intensity scaled to 255, so that
+/// a few outlier returns do not darken all the other points.
+const INTENSITY_OUTLIERS: f64 = 0.001;
 
-    let &Config {
-        pnorthlinesangle,
-        pnorthlineswidth,
-        skipknolldetection,
-        ..
-    } = config;
+/// Identifies a version of an input file by its path, size and modification time.
+type IntensityKey = (PathBuf, u64, Option<SystemTime>);
 
-    timing.start_section("preparing input file");
-    info!("Preparing input file");
+/// Scales the intensities of the points of a file to 0-255, since files use different ranges, often
+/// 8 or 12 bits instead of the 16 bits of the LAS format. Points with an intensity stay above 0,
+/// which means unknown.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IntensityScale {
+    max: u32,
+}
+
+impl IntensityScale {
+    /// Finds the scale from the intensities of all points of a file. The brightest
+    /// [`INTENSITY_OUTLIERS`] of the points are clamped to 255.
+    pub(crate) fn new(intensities: impl IntoIterator<Item = u16>) -> Self {
+        let mut histogram = vec![0u64; u16::MAX as usize + 1];
+        for intensity in intensities {
+            histogram[intensity as usize] += 1;
+        }
+        Self::from_histogram(&histogram)
+    }
+
+    /// Reads the intensities of the points of a las/laz file in chunks to find its scale. The
+    /// scales are kept for the rest of the batch, since every file is read by all the tiles whose
+    /// buffer it overlaps.
+    fn from_file(
+        fs: &impl FileSystem,
+        path: &Path,
+        options: las::ReaderOptions,
+    ) -> las::Result<Self> {
+        static SCALES: LazyLock<Mutex<HashMap<IntensityKey, IntensityScale>>> =
+            LazyLock::new(Default::default);
+        // a file changed in watch mode is read again
+        let key = (
+            path.to_path_buf(),
+            fs.file_size(path)?,
+            fs.modified(path).ok(),
+        );
+        if let Some(scale) = SCALES.lock().unwrap().get(&key) {
+            return Ok(*scale);
+        }
+
+        let mut reader = Reader::with_options(fs.open(path)?, options)?;
+        let mut points = Vec::with_capacity(LAZ_BUFFER_SIZE);
+        let mut histogram = vec![0u64; u16::MAX as usize + 1];
+        loop {
+            points.clear();
+            let n = reader.read_points_into(LAZ_BUFFER_SIZE as u64, &mut points)?;
+            if n == 0 || job::is_cancelled() {
+                break;
+            }
+            for pt in &points {
+                histogram[pt.intensity as usize] += 1;
+            }
+        }
+        let scale = Self::from_histogram(&histogram);
+        // the histogram of a cancelled read is incomplete
+        if !job::is_cancelled() {
+            SCALES.lock().unwrap().insert(key, scale);
+        }
+        Ok(scale)
+    }
 
+    /// The scale of the points counted by intensity, ignoring the unknown intensity 0.
+    fn from_histogram(histogram: &[u64]) -> Self {
+        let known: u64 = histogram[1..].iter().sum();
+        let outliers = (known as f64 * INTENSITY_OUTLIERS) as u64;
+        let mut brighter = 0;
+        let mut max = 1;
+        for (intensity, &count) in histogram.iter().enumerate().skip(1).rev() {
+            brighter += count;
+            if brighter > outliers {
+                max = intensity as u32;
+                break;
+            }
+        }
+        Self { max }
+    }
+
+    pub(crate) fn scale(&self, intensity: u16) -> u8 {
+        (intensity as u32 * 255).div_ceil(self.max).min(255) as u8
+    }
+}
+
+/// Reads the points of an input point cloud (.las, .laz, .xyz or .xyz.bin) for the processing
+/// stages.
+pub fn ingest_points(
+    fs: &impl FileSystem,
+    config: &Config,
+    input_file: &Path,
+) -> Result<PointCloud, Box<dyn Error>> {
     let filename = input_file
         .file_name()
         .ok_or_else(|| format!("No extension for input file {}", input_file.display()))?
         .to_string_lossy()
         .to_lowercase();
 
-    let target_file = tmpfolder.join("xyztemp.xyz.bin");
-
     if filename.ends_with(".xyz") {
         // if we are here we don't know if the file has at least 6 columns, but we assume that it is in the format
         // x y z classification number_of_returns return_number
 
-        info!("Converting points from .xyz to internal binary format");
+        info!("Reading points from .xyz");
 
-        debug!("Writing records to {:?}", &target_file);
-        let mut writer =
-            XyzInternalWriter::new(fs.create(&target_file).expect("Could not create writer"));
+        let mut records: Vec<XyzRecord> = Vec::new();
+        // the number of points is not known up front, so the buffer is checked as it grows
+        let mut reserved = Ok(());
         read_lines_no_alloc(fs, input_file, |line| {
+            if reserved.is_err() {
+                return;
+            }
+            if records.len() == records.capacity() {
+                let additional = records.capacity().max(1024);
+                let bytes = (records.len() + additional) * size_of::<XyzRecord>();
+                reserved = job::reserve(config, "ingest_points", bytes as u64);
+                if reserved.is_err() {
+                    return;
+                }
+                records.reserve_exact(additional);
+            }
             let mut parts = line.split(' ');
             let x = parts.next().unwrap().parse::<f64>().unwrap();
             let y = parts.next().unwrap().parse::<f64>().unwrap();
@@ -131,22 +219,22 @@ pub fn process_tile(
             let number_of_returns = parts.next().unwrap_or("0").parse::<u8>().unwrap();
             let return_number = parts.next().unwrap_or("0").parse::<u8>().unwrap();
 
-            writer
-                .write_records(&[crate::io::xyz::XyzRecord {
-                    x,
-                    y,
-                    z,
-                    classification,
-                    number_of_returns,
-                    return_number,
-                    ..Default::default()
-                }])
-                .expect("Could not write record");
+            records.push(XyzRecord {
+                x,
+                y,
+                z,
+                classification,
+                number_of_returns,
+                return_number,
+                ..Default::default()
+            });
         })
         .expect("Could not read file");
-        writer.finish().expect("Unable to finish writing");
+        reserved?;
+        job::check_cancelled()?;
+        Ok(PointCloud { records })
     } else if filename.ends_with(".laz") || filename.ends_with(".las") {
-        info!("Converting points from .laz/laz to internal binary format");
+        info!("Reading points from .laz/las");
         let &Config {
             thinfactor,
             xfactor,
@@ -172,12 +260,20 @@ pub fn process_tile(
             Reader::with_options(fs.open(input_file).expect("Could not open file"), options)
                 .expect("Could not create reader");
 
-        debug!("Writing records to {:?}", &target_file);
-        let mut writer =
-            XyzInternalWriter::new(fs.create(&target_file).expect("Could not create writer"));
+        // the intensities are kept until they can be scaled, if they are used at all
+        let uses_intensity = config.uses_intensity();
+        let n_points = reader.header().number_of_points();
+        let intensity_size = if uses_intensity { size_of::<u16>() } else { 0 };
+        job::reserve(
+            config,
+            "ingest_points",
+            n_points * (size_of::<XyzRecord>() + intensity_size) as u64,
+        )?;
 
+        let mut progress = Progress::new("point ingest", n_points);
         let mut points = Vec::with_capacity(LAZ_BUFFER_SIZE);
-        let mut records = Vec::with_capacity(LAZ_BUFFER_SIZE);
+        let mut records = Vec::new();
+        let mut intensities = Vec::new();
         loop {
             points.clear();
             let n = reader.read_points_into(LAZ_BUFFER_SIZE as u64, &mut points)?;
@@ -185,197 +281,189 @@ pub fn process_tile(
             if n == 0 {
                 break;
             }
+            progress.inc(n);
+            job::check_cancelled()?;
 
             // convert all read points to records
-            records.clear();
             for pt in &points {
                 if thinfactor == 1.0 || rng.sample(randdist) {
-                    records.push(crate::io::xyz::XyzRecord {
+                    records.push(XyzRecord {
                         x: pt.x * xfactor,
                         y: pt.y * yfactor,
                         z: (pt.z * zfactor + zoff) as f32,
                         classification: u8::from(pt.classification),
                         number_of_returns: pt.number_of_returns,
                         return_number: pt.return_number,
-                        ..Default::default()
+                        intensity: 0,
                     });
+                    if uses_intensity {
+                        intensities.push(pt.intensity);
+                    }
                 }
             }
-
-            // write all at once
-            writer.write_records(&records)?;
         }
-        writer.finish().expect("Unable to finish writing");
+        if uses_intensity {
+            let scale = IntensityScale::new(intensities.iter().copied());
+            for (record, intensity) in records.iter_mut().zip(intensities) {
+                record.intensity = scale.scale(intensity);
+            }
+        }
+        Ok(PointCloud { records })
     } else if filename.ends_with(".xyz.bin") {
-        info!("Copying input file");
-        fs.copy(input_file, target_file)
-            .expect("Could not copy file");
+        info!("Reading points");
+        job::reserve(config, "ingest_points", fs.file_size(input_file)?)?;
+        Ok(PointCloud::from_file(fs, input_file)?)
     } else {
-        return Err(format!("Unsupported input file: {}", input_file.display()).into());
+        Err(format!("Unsupported input file: {}", input_file.display()).into())
     }
+}
 
-    info!("Done");
-
-    info!("Knoll detection part 1");
-    timing.start_section("knoll detection part 1");
+pub fn process_tile(
+    fs: &impl FileSystem,
+    config: &Config,
+    thread: &String,
+    tmpfolder: &Path,
+    input_file: &Path,
+    skip_rendering: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut timing = Timing::start_now("process_tile");
+    fs.create_dir_all(tmpfolder)
+        .expect("Could not create tmp folder");
 
     let &Config {
-        scalefactor,
+        pnorthlinesangle,
+        pnorthlineswidth,
         vegeonly,
         cliffsonly,
         contoursonly,
         ..
     } = config;
 
-    let xyz_03 = contours::xyz2heightmap(
-        fs,
-        config,
-        tmpfolder,
-        "xyztemp.xyz.bin", //point cloud in
-    )
-    .expect("contour generation failed");
-    xyz_03.to_file(fs, tmpfolder.join("xyz_03.hmap")).unwrap();
-
-    if !(vegeonly || cliffsonly) {
-        contours::heightmap2contours(
-            fs,
-            tmpfolder,
-            scalefactor * 0.3,
-            &xyz_03,
-            "contours03.dxf.bin", // dxf curves generated from the heightmap
-            config.output_dxf,
-        )
-        .expect("contour generation failed");
-    }
-    drop(xyz_03);
+    timing.start_section("preparing input file");
+    info!("Preparing input file");
 
-    // copy the generated heightmap
-    fs.copy(tmpfolder.join("xyz_03.hmap"), tmpfolder.join("xyz2.hmap"))
-        .expect("Could not copy file");
+    let points = ingest_points(fs, config, input_file)?;
+    // for the single stage commands and the report
+    points.to_file(fs, tmpfolder.join("xyztemp.xyz.bin"))?;
 
-    let &Config {
-        contour_interval,
-        basemapcontours,
-        ..
-    } = config;
-    let halfinterval = contour_interval / 2.0 * scalefactor;
-
-    if !vegeonly && !cliffsonly {
-        if basemapcontours != 0.0 {
-            info!("Basemap contours");
-            let xyz2 = HeightMap::from_file(fs, tmpfolder.join("xyz2.hmap"))
-                .expect("could not read xyz2 heightmap");
-            contours::heightmap2contours(
-                fs,
-                tmpfolder,
-                basemapcontours,
-                &xyz2,
-                "basemap.dxf.bin", // generate dxf contours
-                config.output_dxf,
-            )
-            .expect("contour generation failed");
-        }
-        if !skipknolldetection {
-            info!("Knoll detection part 2");
-            timing.start_section("knoll detection part 2");
-            knolls::knolldetector(fs, config, tmpfolder).unwrap();
-        }
-        info!("Contour generation part 1");
-        timing.start_section("contour generation part 1");
-        knolls::xyzknolls(fs, config, tmpfolder).unwrap(); // modifies the heightmap (but does not change dimensions
-
-        info!("Contour generation part 2");
-        timing.start_section("contour generation part 2");
-        if !skipknolldetection {
-            // contours 2.5
-            let xyz_knolls = HeightMap::from_file(fs, tmpfolder.join("xyz_knolls.hmap"))
-                .expect("could not read xyz_knolls heightmap");
-            contours::heightmap2contours(
-                fs,
-                tmpfolder,
-                halfinterval,
-                &xyz_knolls,
-                "out.dxf.bin", // generates dxf curves
-                config.output_dxf,
-            )
-            .unwrap();
-        } else {
-            let hmap = contours::xyz2heightmap(fs, config, tmpfolder, "xyztemp.xyz.bin")
-                .expect("could not generate heightmap");
-            contours::heightmap2contours(
-                fs,
-                tmpfolder,
-                halfinterval,
-                &hmap,
-                "out.dxf.bin", // generate dxf curves
-                config.output_dxf,
-            )
-            .unwrap();
-        }
-        info!("Contour generation part 3");
-        timing.start_section("contour generation part 3");
-        merge::smoothjoin(fs, config, tmpfolder).unwrap();
-
-        info!("Contour generation part 4");
-        timing.start_section("contour generation part 4");
-        knolls::dotknolls(fs, config, tmpfolder).unwrap();
-    }
+    info!("Done");
 
-    if !cliffsonly && !contoursonly {
-        info!("Vegetation generation");
-        timing.start_section("vegetation generation");
-        vegetation::makevege(fs, config, tmpfolder).unwrap();
-    }
+    // the points are freed once the stages reading them are done
+    let layers = Pipeline::new(config).layers_with_timing(points, &mut timing)?;
+    layers.write_to(fs, config, tmpfolder)?;
 
-    if !vegeonly && !contoursonly {
-        info!("Cliff generation");
-        timing.start_section("cliff generation");
-        cliffs::makecliffs(fs, config, tmpfolder).unwrap();
-    }
-    if !vegeonly && !contoursonly && !cliffsonly && config.detectbuildings {
-        info!("Detecting buildings");
-        timing.start_section("detecting buildings");
-        blocks::blocks(fs, tmpfolder).unwrap();
-    }
     if !skip_rendering && !vegeonly && !contoursonly && !cliffsonly {
         info!("Rendering png map with depressions");
         timing.start_section("rendering png map with depressions");
-        render::render(
-            fs,
-            config,
-            thread,
-            tmpfolder,
-            pnorthlinesangle,
-            pnorthlineswidth,
-            false,
-        )
-        .unwrap();
+        render::render(config, &layers, pnorthlinesangle, pnorthlineswidth, false)?
+            .write_to(fs, config, thread, tmpfolder, false)?;
 
         info!("Rendering png map without depressions");
         timing.start_section("rendering png map without depressions");
-        render::render(
-            fs,
-            config,
-            thread,
-            tmpfolder,
-            pnorthlinesangle,
-            pnorthlineswidth,
-            true,
-        )
-        .unwrap();
-    } else if contoursonly {
+        render::render(config, &layers, pnorthlinesangle, pnorthlineswidth, true)?
+            .write_to(fs, config, thread, tmpfolder, true)?;
+    } else if let Some(contours) = layers.contours.as_ref().filter(|_| contoursonly) {
         info!("Rendering formlines");
         timing.start_section("rendering formlines");
+        let hmap = &layers.heightmap;
         let mut img = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
-        render::draw_curves(fs, config, &mut img, tmpfolder, false, false).unwrap();
+        let formlines = render::draw_curves(
+            config,
+            &mut img,
+            hmap,
+            &contours.contours,
+            hmap.minx(),
+            hmap.maxy(),
+            false,
+            false,
+        )?;
+        if let Some(formlines) = formlines {
+            formlines.to_file(fs, tmpfolder.join("formlines.dxf.bin"), config.output_dxf)?;
+        }
     } else {
         info!("Skipped rendering");
     }
+
+    if config.report {
+        timing.end_section();
+        let report = TileReport::new(fs, config, thread, tmpfolder, input_file, &timing)?;
+        report.to_file(fs, tmpfolder.join(TILE_REPORT_FILE))?;
+    }
     info!("All done!");
     Ok(())
 }
 
-pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_zip: bool) {
+/// Lists the las/laz files in the batch input folder.
+pub fn batch_input_files(fs: &impl FileSystem, conf: &Config) -> Vec<PathBuf> {
+    let mut laz_files: Vec<PathBuf> = Vec::new();
+    for path in fs.list(&conf.lazfolder).unwrap() {
+        if let Some(extension) = path.extension() {
+            if extension == "laz" || extension == "las" {
+                laz_files.push(path);
+            }
+        }
+    }
+    laz_files
+}
+
+pub fn batch_process(conf: &Config, fs: &(impl FileSystem + Sync), thread: &String, has_zip: bool) {
+    let laz_files = batch_input_files(fs, conf);
+    batch_process_files(conf, fs, thread, &laz_files, has_zip);
+}
+
+/// Processes the unclaimed tiles of `laz_files`, which are also the only files read for the
+/// buffers around the tiles.
+pub fn batch_process_files(
+    conf: &Config,
+    fs: &(impl FileSystem + Sync),
+    thread: &String,
+    laz_files: &[PathBuf],
+    has_zip: bool,
+) {
+    let batchoutfolder = &conf.batchoutfolder;
+    fs.create_dir_all(batchoutfolder)
+        .expect("Could not create output folder");
+
+    for laz_path in laz_files {
+        if job::is_cancelled() {
+            info!("Batch processing cancelled");
+            return;
+        }
+        let laz = laz_path.file_name().unwrap().to_str().unwrap();
+        let Some(mut lease) = Lease::claim_tile(fs, conf, laz, thread) else {
+            continue;
+        };
+
+        info!("{laz} -> {laz}.png");
+        let (done, held) = lease.hold(fs, || {
+            progress::with_tile(laz, thread, || {
+                batch_process_tile(conf, fs, thread, laz_path, laz_files, has_zip)
+            })
+        });
+        if !held {
+            // the tile was taken over by another instance
+            continue;
+        }
+        if !done {
+            lease.release(fs).expect("Could not release the lease");
+            info!("Batch processing cancelled");
+            return;
+        }
+        lease.finish(fs).expect("Could not finish the lease");
+    }
+}
+
+/// Processes a single tile of a batch into the batch output folder, reading the points of the
+/// neighbouring tiles in `laz_files` as a buffer. The tile must already have been claimed with a
+/// [`Lease`]. Returns false if the processing was cancelled.
+pub fn batch_process_tile(
+    conf: &Config,
+    fs: &impl FileSystem,
+    thread: &String,
+    laz_path: &Path,
+    laz_files: &[PathBuf],
+    has_zip: bool,
+) -> bool {
     let &Config {
         vegeonly,
         cliffsonly,
@@ -398,138 +486,274 @@ pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_z
     let mut rng = rand::rng();
     let randdist = rand::distr::Bernoulli::new(thinfactor).unwrap();
 
-    fs.create_dir_all(batchoutfolder)
-        .expect("Could not create output folder");
-
-    let mut laz_files: Vec<PathBuf> = Vec::new();
-    for path in fs.list(lazfolder).unwrap() {
-        if let Some(extension) = path.extension() {
-            if extension == "laz" || extension == "las" {
-                laz_files.push(path);
-            }
-        }
-    }
-
     let options = las::ReaderOptions::default().with_laz_parallelism(if conf.laz_parallell {
         las::LazParallelism::Yes
     } else {
         las::LazParallelism::No
     });
 
-    for laz_path in &laz_files {
-        let laz = laz_path.file_name().unwrap().to_str().unwrap();
-        let outfile = format!("{batchoutfolder}/{laz}.png");
-        if fs.exists(&outfile) {
-            info!("Skipping {laz}.png it exists already in output folder.");
-            continue;
-        }
+    let laz = laz_path.file_name().unwrap().to_str().unwrap();
+    let outfile = format!("{batchoutfolder}/{laz}.png");
 
-        info!("{laz} -> {laz}.png");
-        fs.create(&outfile).unwrap();
+    progress::emit(ProgressEvent::TileStarted {
+        tile: laz.to_string(),
+        thread: thread.clone(),
+    });
 
-        let headerfile = PathBuf::from(format!("header{thread}.xyz"));
-        if fs.exists(&headerfile) {
-            fs.remove_file(&headerfile).unwrap();
-        }
+    let headerfile = PathBuf::from(format!("header{thread}.xyz"));
+    if fs.exists(&headerfile) {
+        fs.remove_file(&headerfile).unwrap();
+    }
 
+    let mut file = fs.open(format!("{lazfolder}/{laz}")).unwrap();
+    let header = Header::read_from(&mut file).unwrap();
+    let minx = header.min_x;
+    let miny = header.min_y;
+    let maxx = header.max_x;
+    let maxy = header.max_y;
+
+    let minx2 = minx - BATCH_BUFFER;
+    let miny2 = miny - BATCH_BUFFER;
+    let maxx2 = maxx + BATCH_BUFFER;
+    let maxy2 = maxy + BATCH_BUFFER;
+
+    let tmp_filename = PathBuf::from(format!("temp{thread}.xyz.bin"));
+    debug!("Writing records to {:?}", &tmp_filename);
+    let mut writer =
+        XyzInternalWriter::new(fs.create(&tmp_filename).expect("Could not create writer"));
+
+    // read points from all LAZ files that have an overlap with the main tile file, the tile and
+    // the neighbours that points were read from are the inputs of the report
+    let mut used_files = Vec::new();
+    for laz_p in laz_files {
+        let laz = laz_p.as_path().file_name().unwrap().to_str().unwrap();
         let mut file = fs.open(format!("{lazfolder}/{laz}")).unwrap();
         let header = Header::read_from(&mut file).unwrap();
-        let minx = header.min_x;
-        let miny = header.min_y;
-        let maxx = header.max_x;
-        let maxy = header.max_y;
-
-        let minx2 = minx - 127.0;
-        let miny2 = miny - 127.0;
-        let maxx2 = maxx + 127.0;
-        let maxy2 = maxy + 127.0;
-
-        let tmp_filename = PathBuf::from(format!("temp{thread}.xyz.bin"));
-        debug!("Writing records to {:?}", &tmp_filename);
-        let mut writer =
-            XyzInternalWriter::new(fs.create(&tmp_filename).expect("Could not create writer"));
-
-        // read points from all LAZ files that have an overlap with the main tile file
-        for laz_p in &laz_files {
-            let laz = laz_p.as_path().file_name().unwrap().to_str().unwrap();
-            let mut file = fs.open(format!("{lazfolder}/{laz}")).unwrap();
-            let header = Header::read_from(&mut file).unwrap();
-            if header.max_x > minx2
-                && header.min_x < maxx2
-                && header.max_y > miny2
-                && header.min_y < maxy2
-            {
-                let mut reader =
-                    Reader::with_options(fs.open(laz_p).expect("Could not open file"), options)
-                        .expect("Could not create reader");
-
-                let mut points = Vec::with_capacity(LAZ_BUFFER_SIZE);
-                let mut records = Vec::with_capacity(LAZ_BUFFER_SIZE);
-                loop {
-                    points.clear();
-                    let n = reader
-                        .read_points_into(LAZ_BUFFER_SIZE as u64, &mut points)
-                        .expect("could not read LAZ points");
-
-                    if n == 0 {
-                        break;
-                    }
-
-                    // convert all read points to records
-                    records.clear();
-                    for pt in &points {
-                        if pt.x > minx2
-                            && pt.x < maxx2
-                            && pt.y > miny2
-                            && pt.y < maxy2
-                            && (thinfactor == 1.0 || rng.sample(randdist))
-                        {
-                            records.push(crate::io::xyz::XyzRecord {
-                                x: pt.x,
-                                y: pt.y,
-                                z: (pt.z + zoff) as f32,
-                                classification: u8::from(pt.classification),
-                                number_of_returns: pt.number_of_returns,
-                                return_number: pt.return_number,
-                                ..Default::default()
-                            });
-                        }
+        if header.max_x > minx2
+            && header.min_x < maxx2
+            && header.max_y > miny2
+            && header.min_y < maxy2
+        {
+            let mut used = laz_p.as_path() == laz_path;
+            let intensity_scale = conf.uses_intensity().then(|| {
+                IntensityScale::from_file(fs, laz_p, options).expect("could not read LAZ points")
+            });
+            let mut reader =
+                Reader::with_options(fs.open(laz_p).expect("Could not open file"), options)
+                    .expect("Could not create reader");
+
+            let mut progress = Progress::new("point ingest", reader.header().number_of_points());
+            let mut points = Vec::with_capacity(LAZ_BUFFER_SIZE);
+            let mut records = Vec::with_capacity(LAZ_BUFFER_SIZE);
+            loop {
+                points.clear();
+                let n = reader
+                    .read_points_into(LAZ_BUFFER_SIZE as u64, &mut points)
+                    .expect("could not read LAZ points");
+
+                // the tile is abandoned by process_tile below
+                if n == 0 || job::is_cancelled() {
+                    break;
+                }
+                progress.inc(n);
+
+                // convert all read points to records
+                records.clear();
+                for pt in &points {
+                    if pt.x > minx2
+                        && pt.x < maxx2
+                        && pt.y > miny2
+                        && pt.y < maxy2
+                        && (thinfactor == 1.0 || rng.sample(randdist))
+                    {
+                        records.push(crate::io::xyz::XyzRecord {
+                            x: pt.x,
+                            y: pt.y,
+                            z: (pt.z + zoff) as f32,
+                            classification: u8::from(pt.classification),
+                            number_of_returns: pt.number_of_returns,
+                            return_number: pt.return_number,
+                            intensity: intensity_scale.map_or(0, |s| s.scale(pt.intensity)),
+                        });
                     }
-
-                    // write all at once
-                    writer
-                        .write_records(&records)
-                        .expect("Could not write records");
                 }
-            }
-        }
-        writer.finish().expect("Unable to finish writing");
 
-        let tmpfolder = PathBuf::from(format!("temp{thread}"));
-
-        if !has_zip {
-            // Delete artifacts of a previous run where there would have been a zip
-            let low_file = tmpfolder.join("low.png");
-            if fs.exists(&low_file) {
-                fs.remove_file(low_file).unwrap();
+                // write all at once
+                used |= !records.is_empty();
+                writer
+                    .write_records(&records)
+                    .expect("Could not write records");
             }
-            let high_file = tmpfolder.join("high.png");
-            if fs.exists(&high_file) {
-                fs.remove_file(high_file).unwrap();
+            if used {
+                used_files.push(laz_p);
             }
         }
+    }
+    writer.finish().expect("Unable to finish writing");
 
-        // Process the tile
-        process_tile(fs, conf, thread, &tmpfolder, &tmp_filename, has_zip).unwrap();
+    let tmpfolder = PathBuf::from(format!("temp{thread}"));
 
-        if has_zip && !vegeonly && !cliffsonly && !contoursonly {
-            process_zip(fs, conf, thread, &tmpfolder, &[], true).unwrap();
+    if !has_zip {
+        // Delete artifacts of a previous run where there would have been a zip
+        let low_file = tmpfolder.join("low.png");
+        if fs.exists(&low_file) {
+            fs.remove_file(low_file).unwrap();
         }
+        let high_file = tmpfolder.join("high.png");
+        if fs.exists(&high_file) {
+            fs.remove_file(high_file).unwrap();
+        }
+    }
+
+    // Process the tile
+    if let Err(e) = process_tile(fs, conf, thread, &tmpfolder, &tmp_filename, has_zip) {
+        if let Some(JobError::Cancelled) = e.downcast_ref::<JobError>() {
+            info!("Processing {laz} was cancelled");
+            return false;
+        }
+        panic!("Processing {laz} failed: {e}");
+    }
+
+    if has_zip && !vegeonly && !cliffsonly && !contoursonly {
+        process_zip(fs, conf, thread, &tmpfolder, true).unwrap();
+    }
+
+    // crop
+    let tfw_in = PathBuf::from(format!("pullautus{thread}.pgw"));
+    if fs.exists(&tfw_in) {
+        let mut lines = fs.open(&tfw_in).expect("PGW file does not exist").lines();
+        let tfw0 = lines
+            .next()
+            .expect("no 1 line")
+            .expect("Could not read line 1")
+            .parse::<f64>()
+            .unwrap();
+        let tfw1 = lines
+            .next()
+            .expect("no 2 line")
+            .expect("Could not read line 2")
+            .parse::<f64>()
+            .unwrap();
+        let tfw2 = lines
+            .next()
+            .expect("no 3 line")
+            .expect("Could not read line 3")
+            .parse::<f64>()
+            .unwrap();
+        let tfw3 = lines
+            .next()
+            .expect("no 4 line")
+            .expect("Could not read line 4")
+            .parse::<f64>()
+            .unwrap();
+        let tfw4 = lines
+            .next()
+            .expect("no 5 line")
+            .expect("Could not read line 5")
+            .parse::<f64>()
+            .unwrap();
+        let tfw5 = lines
+            .next()
+            .expect("no 6 line")
+            .expect("Could not read line 6")
+            .parse::<f64>()
+            .unwrap();
 
-        // crop
-        let tfw_in = PathBuf::from(format!("pullautus{thread}.pgw"));
-        if fs.exists(&tfw_in) {
-            let mut lines = fs.open(&tfw_in).expect("PGW file does not exist").lines();
+        drop(lines);
+
+        let dx = minx - tfw4;
+        let dy = -maxy + tfw5;
+
+        let mut pgw_file_out = fs.create(&tfw_in).expect("Unable to create file");
+        write!(
+            &mut pgw_file_out,
+            "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+            tfw0,
+            tfw1,
+            tfw2,
+            tfw3,
+            minx + tfw0 / 2.0,
+            maxy - tfw0 / 2.0
+        )
+        .expect("Unable to write to file");
+
+        drop(pgw_file_out);
+        fs.copy(
+            Path::new(&format!("pullautus{thread}.pgw")),
+            Path::new(&format!("pullautus_depr{thread}.pgw")),
+        )
+        .expect("Could not copy file");
+
+        let orig_img = fs
+            .read_image_png(format!("pullautus{thread}.png"))
+            .expect("Opening image failed");
+        let mut img = RgbImage::from_pixel(
+            ((maxx - minx) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
+            ((maxy - miny) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
+            Rgb([255, 255, 255]),
+        );
+        image::imageops::overlay(
+            &mut img,
+            &orig_img.to_rgb8(),
+            (-dx * 600.0 / 254.0 / scalefactor) as i64,
+            (-dy * 600.0 / 254.0 / scalefactor) as i64,
+        );
+
+        img.write_to(
+            &mut fs
+                .create(format!("pullautus{thread}.png"))
+                .expect("could not save output png"),
+            image::ImageFormat::Png,
+        )
+        .expect("could not save output png");
+
+        let orig_img = fs
+            .read_image_png(format!("pullautus_depr{thread}.png"))
+            .expect("Opening image failed");
+        let mut img = RgbImage::from_pixel(
+            ((maxx - minx) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
+            ((maxy - miny) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
+            Rgb([255, 255, 255]),
+        );
+        image::imageops::overlay(
+            &mut img,
+            &orig_img.to_rgb8(),
+            (-dx * 600.0 / 254.0 / scalefactor) as i64,
+            (-dy * 600.0 / 254.0 / scalefactor) as i64,
+        );
+
+        img.write_to(
+            &mut fs
+                .create(format!("pullautus_depr{thread}.png"))
+                .expect("could not save output png"),
+            image::ImageFormat::Png,
+        )
+        .expect("could not save output png");
+
+        fs.copy(format!("pullautus{thread}.png"), &outfile)
+            .expect("Could not copy file to output folder");
+        fs.copy(
+            format!("pullautus{thread}.pgw"),
+            format!("{batchoutfolder}/{laz}.pgw"),
+        )
+        .expect("Could not copy file to output folder");
+        fs.copy(
+            format!("pullautus_depr{thread}.png"),
+            format!("{batchoutfolder}/{laz}_depr.png"),
+        )
+        .expect("Could not copy file to output folder");
+        fs.copy(
+            format!("pullautus_depr{thread}.pgw"),
+            format!("{batchoutfolder}/{laz}_depr.pgw"),
+        )
+        .expect("Could not copy file to output folder");
+    }
+
+    if savetempfiles {
+        if !contoursonly && !cliffsonly {
+            let path = format!("temp{thread}/undergrowth.pgw");
+            let tfw_in = Path::new(&path);
+            let mut lines = fs.open(tfw_in).expect("PGW file does not exist").lines();
             let tfw0 = lines
                 .next()
                 .expect("no 1 line")
@@ -567,12 +791,14 @@ pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_z
                 .parse::<f64>()
                 .unwrap();
 
-            drop(lines);
-
             let dx = minx - tfw4;
             let dy = -maxy + tfw5;
 
-            let mut pgw_file_out = fs.create(&tfw_in).expect("Unable to create file");
+            let mut pgw_file_out = fs
+                .create(PathBuf::from(&format!(
+                    "{batchoutfolder}/{laz}_undergrowth.pgw"
+                )))
+                .expect("Unable to create file");
             write!(
                 &mut pgw_file_out,
                 "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
@@ -584,277 +810,163 @@ pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_z
                 maxy - tfw0 / 2.0
             )
             .expect("Unable to write to file");
-
             drop(pgw_file_out);
-            fs.copy(
-                Path::new(&format!("pullautus{thread}.pgw")),
-                Path::new(&format!("pullautus_depr{thread}.pgw")),
-            )
-            .expect("Could not copy file");
 
-            let orig_img = fs
-                .read_image_png(format!("pullautus{thread}.png"))
-                .expect("Opening image failed");
-            let mut img = RgbImage::from_pixel(
+            let mut orig_img_reader = image::ImageReader::new(
+                fs.open(format!("temp{thread}/undergrowth.png"))
+                    .expect("Opening undergrowth image failed"),
+            );
+            orig_img_reader.set_format(image::ImageFormat::Png);
+            orig_img_reader.no_limits();
+            let orig_img = orig_img_reader.decode().unwrap();
+            let mut img = RgbaImage::from_pixel(
                 ((maxx - minx) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
                 ((maxy - miny) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
-                Rgb([255, 255, 255]),
+                Rgba([255, 255, 255, 0]),
             );
             image::imageops::overlay(
                 &mut img,
-                &orig_img.to_rgb8(),
+                &orig_img,
                 (-dx * 600.0 / 254.0 / scalefactor) as i64,
                 (-dy * 600.0 / 254.0 / scalefactor) as i64,
             );
 
             img.write_to(
                 &mut fs
-                    .create(format!("pullautus{thread}.png"))
+                    .create(format!("{batchoutfolder}/{laz}_undergrowth.png"))
                     .expect("could not save output png"),
                 image::ImageFormat::Png,
             )
             .expect("could not save output png");
 
-            let orig_img = fs
-                .read_image_png(format!("pullautus_depr{thread}.png"))
-                .expect("Opening image failed");
+            let mut orig_img_reader = image::ImageReader::new(
+                fs.open(format!("temp{thread}/vegetation.png"))
+                    .expect("Opening vegetation image failed"),
+            );
+            orig_img_reader.set_format(image::ImageFormat::Png);
+            orig_img_reader.no_limits();
+            let orig_img = orig_img_reader.decode().unwrap();
             let mut img = RgbImage::from_pixel(
-                ((maxx - minx) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
-                ((maxy - miny) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
+                ((maxx - minx) + 1.0) as u32,
+                ((maxy - miny) + 1.0) as u32,
                 Rgb([255, 255, 255]),
             );
-            image::imageops::overlay(
-                &mut img,
-                &orig_img.to_rgb8(),
-                (-dx * 600.0 / 254.0 / scalefactor) as i64,
-                (-dy * 600.0 / 254.0 / scalefactor) as i64,
-            );
+            image::imageops::overlay(&mut img, &orig_img.to_rgb8(), -dx as i64, -dy as i64);
 
             img.write_to(
                 &mut fs
-                    .create(format!("pullautus_depr{thread}.png"))
+                    .create(format!("{batchoutfolder}/{laz}_vege.png"))
                     .expect("could not save output png"),
                 image::ImageFormat::Png,
             )
             .expect("could not save output png");
 
-            fs.copy(format!("pullautus{thread}.png"), &outfile)
-                .expect("Could not copy file to output folder");
-            fs.copy(
-                format!("pullautus{thread}.pgw"),
-                format!("{batchoutfolder}/{laz}.pgw"),
-            )
-            .expect("Could not copy file to output folder");
-            fs.copy(
-                format!("pullautus_depr{thread}.png"),
-                format!("{batchoutfolder}/{laz}_depr.png"),
-            )
-            .expect("Could not copy file to output folder");
-            fs.copy(
-                format!("pullautus_depr{thread}.pgw"),
-                format!("{batchoutfolder}/{laz}_depr.pgw"),
+            let mut pgw_file_out = fs
+                .create(format!("{batchoutfolder}/{laz}_vege.pgw"))
+                .expect("Unable to create file");
+            write!(
+                &mut pgw_file_out,
+                "1.0\r\n0.0\r\n0.0\r\n-1.0\r\n{}\r\n{}\r\n",
+                minx + 0.5,
+                maxy - 0.5
             )
-            .expect("Could not copy file to output folder");
-        }
-
-        if savetempfiles {
-            if !contoursonly && !cliffsonly {
-                let path = format!("temp{thread}/undergrowth.pgw");
-                let tfw_in = Path::new(&path);
-                let mut lines = fs.open(tfw_in).expect("PGW file does not exist").lines();
-                let tfw0 = lines
-                    .next()
-                    .expect("no 1 line")
-                    .expect("Could not read line 1")
-                    .parse::<f64>()
-                    .unwrap();
-                let tfw1 = lines
-                    .next()
-                    .expect("no 2 line")
-                    .expect("Could not read line 2")
-                    .parse::<f64>()
-                    .unwrap();
-                let tfw2 = lines
-                    .next()
-                    .expect("no 3 line")
-                    .expect("Could not read line 3")
-                    .parse::<f64>()
-                    .unwrap();
-                let tfw3 = lines
-                    .next()
-                    .expect("no 4 line")
-                    .expect("Could not read line 4")
-                    .parse::<f64>()
-                    .unwrap();
-                let tfw4 = lines
-                    .next()
-                    .expect("no 5 line")
-                    .expect("Could not read line 5")
-                    .parse::<f64>()
-                    .unwrap();
-                let tfw5 = lines
-                    .next()
-                    .expect("no 6 line")
-                    .expect("Could not read line 6")
-                    .parse::<f64>()
-                    .unwrap();
+            .expect("Unable to write to file");
 
-                let dx = minx - tfw4;
-                let dy = -maxy + tfw5;
-
-                let mut pgw_file_out = fs
-                    .create(PathBuf::from(&format!(
-                        "{batchoutfolder}/{laz}_undergrowth.pgw"
-                    )))
-                    .expect("Unable to create file");
-                write!(
-                    &mut pgw_file_out,
-                    "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
-                    tfw0,
-                    tfw1,
-                    tfw2,
-                    tfw3,
-                    minx + tfw0 / 2.0,
-                    maxy - tfw0 / 2.0
-                )
-                .expect("Unable to write to file");
-                drop(pgw_file_out);
+            drop(pgw_file_out);
 
+            if vege_bitmode {
                 let mut orig_img_reader = image::ImageReader::new(
-                    fs.open(format!("temp{thread}/undergrowth.png"))
-                        .expect("Opening undergrowth image failed"),
+                    fs.open(format!("temp{thread}/vegetation_bit.png"))
+                        .expect("Opening vegetation bit bit image failed"),
                 );
                 orig_img_reader.set_format(image::ImageFormat::Png);
                 orig_img_reader.no_limits();
                 let orig_img = orig_img_reader.decode().unwrap();
-                let mut img = RgbaImage::from_pixel(
-                    ((maxx - minx) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
-                    ((maxy - miny) * 600.0 / 254.0 / scalefactor + 2.0) as u32,
-                    Rgba([255, 255, 255, 0]),
-                );
-                image::imageops::overlay(
-                    &mut img,
-                    &orig_img,
-                    (-dx * 600.0 / 254.0 / scalefactor) as i64,
-                    (-dy * 600.0 / 254.0 / scalefactor) as i64,
+                let mut img = GrayImage::from_pixel(
+                    ((maxx - minx) + 1.0) as u32,
+                    ((maxy - miny) + 1.0) as u32,
+                    Luma([0]),
                 );
-
+                image::imageops::overlay(&mut img, &orig_img.to_luma8(), -dx as i64, -dy as i64);
                 img.write_to(
                     &mut fs
-                        .create(format!("{batchoutfolder}/{laz}_undergrowth.png"))
+                        .create(format!("{batchoutfolder}/{laz}_vege_bit.png"))
                         .expect("could not save output png"),
                     image::ImageFormat::Png,
                 )
                 .expect("could not save output png");
 
                 let mut orig_img_reader = image::ImageReader::new(
-                    fs.open(format!("temp{thread}/vegetation.png"))
-                        .expect("Opening vegetation image failed"),
+                    fs.open(format!("temp{thread}/undergrowth_bit.png"))
+                        .expect("Opening undergrowth bit image failed"),
                 );
                 orig_img_reader.set_format(image::ImageFormat::Png);
                 orig_img_reader.no_limits();
                 let orig_img = orig_img_reader.decode().unwrap();
-                let mut img = RgbImage::from_pixel(
+                let mut img = GrayImage::from_pixel(
                     ((maxx - minx) + 1.0) as u32,
                     ((maxy - miny) + 1.0) as u32,
-                    Rgb([255, 255, 255]),
+                    Luma([0]),
                 );
-                image::imageops::overlay(&mut img, &orig_img.to_rgb8(), -dx as i64, -dy as i64);
-
+                image::imageops::overlay(&mut img, &orig_img.to_luma8(), -dx as i64, -dy as i64);
                 img.write_to(
                     &mut fs
-                        .create(format!("{batchoutfolder}/{laz}_vege.png"))
+                        .create(format!("{batchoutfolder}/{laz}_undergrowth_bit.png"))
                         .expect("could not save output png"),
                     image::ImageFormat::Png,
                 )
                 .expect("could not save output png");
 
-                let mut pgw_file_out = fs
-                    .create(format!("{batchoutfolder}/{laz}_vege.pgw"))
-                    .expect("Unable to create file");
-                write!(
-                    &mut pgw_file_out,
-                    "1.0\r\n0.0\r\n0.0\r\n-1.0\r\n{}\r\n{}\r\n",
-                    minx + 0.5,
-                    maxy - 0.5
+                fs.copy(
+                    format!("{batchoutfolder}/{laz}_vege.pgw"),
+                    format!("{batchoutfolder}/{laz}_vege_bit.pgw"),
                 )
-                .expect("Unable to write to file");
-
-                drop(pgw_file_out);
-
-                if vege_bitmode {
-                    let mut orig_img_reader = image::ImageReader::new(
-                        fs.open(format!("temp{thread}/vegetation_bit.png"))
-                            .expect("Opening vegetation bit bit image failed"),
-                    );
-                    orig_img_reader.set_format(image::ImageFormat::Png);
-                    orig_img_reader.no_limits();
-                    let orig_img = orig_img_reader.decode().unwrap();
-                    let mut img = GrayImage::from_pixel(
-                        ((maxx - minx) + 1.0) as u32,
-                        ((maxy - miny) + 1.0) as u32,
-                        Luma([0]),
-                    );
-                    image::imageops::overlay(
-                        &mut img,
-                        &orig_img.to_luma8(),
-                        -dx as i64,
-                        -dy as i64,
-                    );
-                    img.write_to(
-                        &mut fs
-                            .create(format!("{batchoutfolder}/{laz}_vege_bit.png"))
-                            .expect("could not save output png"),
-                        image::ImageFormat::Png,
-                    )
-                    .expect("could not save output png");
-
-                    let mut orig_img_reader = image::ImageReader::new(
-                        fs.open(format!("temp{thread}/undergrowth_bit.png"))
-                            .expect("Opening undergrowth bit image failed"),
-                    );
-                    orig_img_reader.set_format(image::ImageFormat::Png);
-                    orig_img_reader.no_limits();
-                    let orig_img = orig_img_reader.decode().unwrap();
-                    let mut img = GrayImage::from_pixel(
-                        ((maxx - minx) + 1.0) as u32,
-                        ((maxy - miny) + 1.0) as u32,
-                        Luma([0]),
-                    );
-                    image::imageops::overlay(
-                        &mut img,
-                        &orig_img.to_luma8(),
-                        -dx as i64,
-                        -dy as i64,
-                    );
-                    img.write_to(
-                        &mut fs
-                            .create(format!("{batchoutfolder}/{laz}_undergrowth_bit.png"))
-                            .expect("could not save output png"),
-                        image::ImageFormat::Png,
-                    )
-                    .expect("could not save output png");
-
-                    fs.copy(
-                        format!("{batchoutfolder}/{laz}_vege.pgw"),
-                        format!("{batchoutfolder}/{laz}_vege_bit.pgw"),
-                    )
-                    .expect("Could not copy file");
-
-                    fs.copy(
-                        format!("{batchoutfolder}/{laz}_vege.pgw"),
-                        format!("{batchoutfolder}/{laz}_undergrowth_bit.pgw"),
-                    )
-                    .expect("Could not copy file");
-                }
+                .expect("Could not copy file");
+
+                fs.copy(
+                    format!("{batchoutfolder}/{laz}_vege.pgw"),
+                    format!("{batchoutfolder}/{laz}_undergrowth_bit.pgw"),
+                )
+                .expect("Could not copy file");
             }
+        }
 
-            let out2_path = PathBuf::from(format!("temp{thread}/out2.dxf.bin"));
-            if fs.exists(&out2_path) {
+        let out2_path = PathBuf::from(format!("temp{thread}/out2.dxf.bin"));
+        if fs.exists(&out2_path) {
+            crop::polylinebindxfcrop(
+                fs,
+                &out2_path,
+                Path::new(&format!("{batchoutfolder}/{laz}_contours.dxf.bin")),
+                conf.output_dxf,
+                minx,
+                miny,
+                maxx,
+                maxy,
+            )
+            .unwrap();
+        }
+        let dxf_files = [
+            "c2g",
+            "c3g",
+            "contours03",
+            "detected",
+            "formlines",
+            "streams",
+            "marshes",
+            "water",
+            "stony",
+            "banks",
+            "paths",
+            "blocks",
+            "powerlines",
+        ];
+        for dxf_file in dxf_files.iter() {
+            let dxf_path = PathBuf::from(format!("temp{thread}/{dxf_file}.dxf.bin"));
+            if fs.exists(&dxf_path) {
                 crop::polylinebindxfcrop(
                     fs,
-                    &out2_path,
-                    Path::new(&format!("{batchoutfolder}/{laz}_contours.dxf.bin")),
+                    &dxf_path,
+                    Path::new(&format!("{batchoutfolder}/{laz}_{dxf_file}.dxf.bin")),
                     conf.output_dxf,
                     minx,
                     miny,
@@ -863,29 +975,14 @@ pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_z
                 )
                 .unwrap();
             }
-            let dxf_files = ["c2g", "c3g", "contours03", "detected", "formlines"];
-            for dxf_file in dxf_files.iter() {
-                let dxf_path = PathBuf::from(format!("temp{thread}/{dxf_file}.dxf.bin"));
-                if fs.exists(&dxf_path) {
-                    crop::polylinebindxfcrop(
-                        fs,
-                        &dxf_path,
-                        Path::new(&format!("{batchoutfolder}/{laz}_{dxf_file}.dxf.bin")),
-                        conf.output_dxf,
-                        minx,
-                        miny,
-                        maxx,
-                        maxy,
-                    )
-                    .unwrap();
-                }
-            }
-            let dotknolls_file = PathBuf::from(format!("temp{thread}/dotknolls.dxf.bin"));
-            if fs.exists(&dotknolls_file) {
+        }
+        for points_file in ["dotknolls", "boulders", "pits", "pylons"] {
+            let points_path = PathBuf::from(format!("temp{thread}/{points_file}.dxf.bin"));
+            if fs.exists(&points_path) {
                 crop::pointbindxfcrop(
                     fs,
-                    &dotknolls_file,
-                    Path::new(&format!("{batchoutfolder}/{laz}_dotknolls.dxf.bin")),
+                    &points_path,
+                    Path::new(&format!("{batchoutfolder}/{laz}_{points_file}.dxf.bin")),
                     conf.output_dxf,
                     minx,
                     miny,
@@ -895,31 +992,301 @@ pub fn batch_process(conf: &Config, fs: &impl FileSystem, thread: &String, has_z
                 .unwrap();
             }
         }
+    }
 
-        let basemap_file = PathBuf::from(format!("temp{thread}/basemap.dxf.bin"));
-        if fs.exists(&basemap_file) {
-            crop::polylinebindxfcrop(
-                fs,
-                &basemap_file,
-                Path::new(&format!("{batchoutfolder}/{laz}_basemap.dxf.bin")),
-                conf.output_dxf,
-                minx,
-                miny,
-                maxx,
-                maxy,
+    let basemap_file = PathBuf::from(format!("temp{thread}/basemap.dxf.bin"));
+    if fs.exists(&basemap_file) {
+        crop::polylinebindxfcrop(
+            fs,
+            &basemap_file,
+            Path::new(&format!("{batchoutfolder}/{laz}_basemap.dxf.bin")),
+            conf.output_dxf,
+            minx,
+            miny,
+            maxx,
+            maxy,
+        )
+        .unwrap();
+    }
+    if conf.report {
+        // describe the tile instead of the temporary merged point file
+        let mut report = TileReport::from_file(fs, tmpfolder.join(TILE_REPORT_FILE)).unwrap();
+        // the tile first, then its neighbours
+        used_files.sort_by_key(|p| p.as_path() != laz_path);
+        report.input_files = used_files
+            .iter()
+            .map(|p| InputFile::new(fs, p))
+            .collect::<Result<_, _>>()
+            .expect("Could not hash input files");
+        report.bounds = Some(Bounds::new(minx, maxx, miny, maxy));
+        let reader = Reader::new(fs.open(laz_path).unwrap()).expect("Could not create reader");
+        report.crs = las_crs(reader.header());
+        let mut outputs = fs.list(batchoutfolder).unwrap();
+        outputs.sort();
+        // the outputs of the tile, without the records of its processing
+        report.produced_files = outputs
+            .iter()
+            .filter(|p| {
+                let name = p.file_name().unwrap().to_str().unwrap();
+                name.starts_with(laz)
+                    && !Lease::is_record(p)
+                    && !name.ends_with(FAILURE_SUFFIX)
+                    && !name.ends_with(".report.json")
+            })
+            .map(|p| ProducedFile {
+                path: p.display().to_string(),
+                size: fs.file_size(p).unwrap(),
+            })
+            .collect();
+        report
+            .to_file(fs, format!("{batchoutfolder}/{laz}.report.json"))
+            .unwrap();
+    }
+
+    if savetempfolders {
+        fs.create_dir_all(format!("temp_{laz}_dir"))
+            .expect("Could not create output folder");
+        for path in fs.list(format!("temp{thread}")).unwrap() {
+            if fs.exists(&path) {
+                let filename = path.file_name().unwrap().to_str().unwrap();
+                fs.copy(&path, Path::new(&format!("temp_{laz}_dir/{filename}")))
+                    .unwrap();
+            }
+        }
+    }
+
+    if progress::enabled() {
+        // this tile is recorded as done when its lease is given up
+        let completed = laz_files
+            .iter()
+            .filter(|p| p.file_name() != laz_path.file_name())
+            .filter(|p| {
+                let tile = p.file_name().unwrap().to_string_lossy();
+                Lease::is_done(fs, batchoutfolder, &tile)
+            })
+            .count()
+            + 1;
+        progress::emit(ProgressEvent::TileFinished {
+            tile: laz.to_string(),
+            thread: thread.clone(),
+            completed,
+            total: laz_files.len(),
+        });
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intensity_scale() {
+        // 8-bit intensities stored as they are, which the 8 most significant bits would lose
+        let scale = IntensityScale::new([0, 1, 100, 200]);
+        let scaled: Vec<u8> = [0, 1, 100, 200].map(|i| scale.scale(i)).to_vec();
+        assert_eq!(scaled, [0, 2, 128, 255]);
+
+        let scale = IntensityScale::new([0, 0]);
+        assert_eq!(scale.scale(0), 0);
+
+        // a single bright outlier is clamped instead of darkening the other points
+        let intensities = (0..10_000).map(|i| 1000 + (i % 2) as u16).chain([u16::MAX]);
+        let scale = IntensityScale::new(intensities);
+        assert_eq!(scale.scale(1001), 255);
+        assert_eq!(scale.scale(500), 128);
+        assert_eq!(scale.scale(u16::MAX), 255);
+    }
+
+    #[cfg(feature = "shapefile")]
+    #[test]
+    fn test_process_zip_with_mounted_shapefiles() {
+        use crate::io::fs::archive::ZipFileSystem;
+        use crate::io::fs::memory::MemoryFileSystem;
+        use crate::io::fs::mount::MountFileSystem;
+        use crate::io::heightmap::HeightMap;
+        use crate::io::worldfile::{GeoRaster, WorldFile};
+        use shapefile::dbase::{FieldValue, Record, TableWriterBuilder};
+
+        // a stream across a 40x40 m tile, in a zip archive unique to this test
+        let dir = std::env::temp_dir().join(format!("pullauta_shapes_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let table =
+            TableWriterBuilder::new().add_numeric_field("LUOKKA".try_into().unwrap(), 10, 0);
+        let mut writer = shapefile::Writer::from_path(dir.join("streams.shp"), table).unwrap();
+        let stream = shapefile::Polyline::new(vec![
+            shapefile::Point::new(1005.0, 2005.0),
+            shapefile::Point::new(1035.0, 2035.0),
+        ]);
+        let mut record = Record::default();
+        record.insert("LUOKKA".to_string(), FieldValue::Numeric(Some(36311.0)));
+        writer.write_shape_and_record(&stream, &record).unwrap();
+        drop(writer);
+        let zip_path = dir.join("shapes.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+        for ext in ["shp", "shx", "dbf"] {
+            zip.start_file(
+                format!("streams.{ext}"),
+                zip::write::SimpleFileOptions::default(),
             )
             .unwrap();
+            zip.write_all(&std::fs::read(dir.join(format!("streams.{ext}"))).unwrap())
+                .unwrap();
         }
-        if savetempfolders {
-            fs.create_dir_all(format!("temp_{laz}_dir"))
-                .expect("Could not create output folder");
-            for path in fs.list(format!("temp{thread}")).unwrap() {
-                if fs.exists(&path) {
-                    let filename = path.file_name().unwrap().to_str().unwrap();
-                    fs.copy(&path, Path::new(&format!("temp_{laz}_dir/{filename}")))
-                        .unwrap();
-                }
+        zip.finish().unwrap();
+
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        let fs = MountFileSystem::new(MemoryFileSystem::new())
+            .mount("temp_shapefiles", ZipFileSystem::new(&zip_path).unwrap());
+        let tmpfolder = Path::new("temp");
+        fs.create_dir_all(tmpfolder).unwrap();
+        let heightmap = HeightMap {
+            xoffset: 1000.0,
+            yoffset: 2000.0,
+            scale: 2.0,
+            grid: crate::vec2d::Vec2D::new(20, 20, 100.0),
+        };
+        heightmap.to_file(&fs, tmpfolder.join("xyz2.hmap")).unwrap();
+        let raster = GeoRaster {
+            image: image::DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 40, Rgb([255; 3]))),
+            world_file: WorldFile::new(1.0, 1000.0, 2040.0),
+        };
+        raster
+            .to_file(&fs, tmpfolder.join("vegetation.png"))
+            .unwrap();
+        raster
+            .to_file(&fs, tmpfolder.join("undergrowth.png"))
+            .unwrap();
+
+        // the single zip command renders outside batch mode from the read-only archive
+        process_zip(&fs, &config, &"1".to_string(), tmpfolder, true).unwrap();
+        assert!(fs.exists("pullautus1.png"));
+        assert!(fs.exists("pullautus_depr1.png"));
+        assert!(fs.exists("temp_shapefiles/streams.shp"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Writes a 40x40 m tile of ground points with its lower left corner at `x`, `y`.
+    fn write_tile(fs: &impl FileSystem, path: &str, x: f64, y: f64) {
+        let mut builder = las::Builder::from((1, 2));
+        let transform = las::Transform {
+            scale: 0.01,
+            offset: 0.0,
+        };
+        builder.transforms = las::Vector {
+            x: transform,
+            y: transform,
+            z: transform,
+        };
+        let mut writer = las::Writer::new(
+            std::io::Cursor::new(Vec::new()),
+            builder.into_header().unwrap(),
+        )
+        .unwrap();
+        for dx in 0..40 {
+            for dy in 0..40 {
+                writer
+                    .write_point(las::Point {
+                        x: x + dx as f64,
+                        y: y + dy as f64,
+                        z: 100.0 + dx as f64 / 10.0,
+                        intensity: 1000 + dy,
+                        classification: las::point::Classification::Ground,
+                        ..Default::default()
+                    })
+                    .unwrap();
             }
         }
+        let data = writer.into_inner().unwrap().into_inner();
+        fs.create(path).unwrap().write_all(&data).unwrap();
+    }
+
+    #[test]
+    fn test_intensity_is_only_read_when_used() {
+        let fs = crate::io::fs::memory::MemoryFileSystem::new();
+        write_tile(&fs, "tile.las", 1000.0, 2000.0);
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.detectmarshes = true;
+
+        config.marshintensity = 0.0;
+        assert!(!config.uses_intensity());
+        let points = ingest_points(&fs, &config, Path::new("tile.las")).unwrap();
+        assert!(points.records.iter().all(|r| r.intensity == 0));
+
+        config.marshintensity = 50.0;
+        assert!(config.uses_intensity());
+        let points = ingest_points(&fs, &config, Path::new("tile.las")).unwrap();
+        let max = points.records.iter().map(|r| r.intensity).max();
+        assert_eq!(max, Some(255));
+    }
+
+    #[test]
+    fn test_report_lists_the_neighbours_read() {
+        let fs = crate::io::fs::memory::MemoryFileSystem::new();
+        fs.create_dir_all("in").unwrap();
+        fs.create_dir_all("out").unwrap();
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.lazfolder = "in".to_string();
+        config.batchoutfolder = "out".to_string();
+        config.report = true;
+        write_tile(&fs, "in/a.las", 1000.0, 2000.0);
+        write_tile(&fs, "in/b.las", 1040.0, 2000.0);
+        write_tile(&fs, "in/c.las", 5000.0, 2000.0);
+
+        // the records of an earlier failure and of the lease held while processing
+        fs.create("out/a.las.failed.json").unwrap();
+        let lease = Lease::claim_tile(&fs, &config, "a.las", "1").unwrap();
+
+        let laz_files = batch_input_files(&fs, &config);
+        let tile = laz_files.iter().find(|p| p.ends_with("a.las")).unwrap();
+        assert!(batch_process_tile(
+            &config,
+            &fs,
+            &"1".to_string(),
+            tile,
+            &laz_files,
+            false
+        ));
+        let report = TileReport::from_file(&fs, "out/a.las.report.json").unwrap();
+        let inputs: Vec<&str> = report.input_files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(inputs, ["in/a.las", "in/b.las"]);
+        lease.release(&fs).unwrap();
+
+        let produced: Vec<&str> = report
+            .produced_files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect();
+        assert!(!produced.is_empty());
+        assert!(
+            produced
+                .iter()
+                .all(|p| !p.ends_with(".lease") && !p.ends_with(".failed.json"))
+        );
+    }
+
+    #[test]
+    fn test_ingest_points_within_memory_budget() {
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.memory_budget = 1;
+        let fs = crate::io::fs::memory::MemoryFileSystem::new();
+        let write = |lines: usize| {
+            let mut file = fs.create("tile.xyz").unwrap();
+            for i in 0..lines {
+                writeln!(file, "{i} 0 100 2 1 1").unwrap();
+            }
+        };
+
+        write(10_000);
+        let points = ingest_points(&fs, &config, Path::new("tile.xyz")).unwrap();
+        assert_eq!(points.records.len(), 10_000);
+
+        // 100 000 points take about 2.3 MB
+        write(100_000);
+        let err = ingest_points(&fs, &config, Path::new("tile.xyz")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<job::JobError>(),
+            Some(job::JobError::MemoryBudgetExceeded { .. })
+        ));
     }
 }
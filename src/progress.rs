@@ -0,0 +1,204 @@
+//! Opt-in progress events for applications wrapping pullauta.
+//!
+//! Events are only produced when a handler has been installed with [`set_handler`], either
+//! from the library API or by the `progress_json` config option which prints every event as
+//! a JSON line on stdout.
+//!
+//! The stage events of a batch tile carry the tile and the batch thread set with [`with_tile`]
+//! for the thread processing it.
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+/// The batch tile and thread a stage event belongs to. Both are empty outside of batch mode.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TileContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+}
+
+/// A single progress event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A stage (a [`crate::util::Timing`] or one of its sections) was started.
+    StageStarted {
+        stage: String,
+        #[serde(flatten)]
+        context: TileContext,
+    },
+    /// A stage was finished.
+    StageFinished {
+        stage: String,
+        seconds: f64,
+        #[serde(flatten)]
+        context: TileContext,
+    },
+    /// Progress within a long running loop of a stage.
+    Progress {
+        stage: String,
+        percent: u8,
+        #[serde(flatten)]
+        context: TileContext,
+    },
+    /// A batch worker started processing a tile.
+    TileStarted { tile: String, thread: String },
+    /// A batch worker finished processing a tile.
+    TileFinished {
+        tile: String,
+        thread: String,
+        completed: usize,
+        total: usize,
+    },
+    /// A batch tile failed in an isolated worker process and was given up.
+    TileFailed {
+        tile: String,
+        thread: String,
+        reason: String,
+    },
+    /// Something failed.
+    Error { message: String },
+}
+
+type Handler = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+thread_local! {
+    static CONTEXT: RefCell<TileContext> = RefCell::new(TileContext::default());
+}
+
+/// Installs the handler that receives all progress events, replacing any previous one.
+pub fn set_handler(handler: impl Fn(&ProgressEvent) + Send + Sync + 'static) {
+    *HANDLER.write().unwrap() = Some(Arc::new(handler));
+}
+
+/// Removes the installed handler, if any.
+pub fn clear_handler() {
+    *HANDLER.write().unwrap() = None;
+}
+
+/// Installs a handler that prints every event as a JSON line on stdout.
+pub fn set_json_lines_handler() {
+    set_handler(|event| {
+        let mut stdout = std::io::stdout().lock();
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(stdout, "{line}");
+            let _ = stdout.flush();
+        }
+    });
+}
+
+/// Returns true if a handler is installed.
+pub fn enabled() -> bool {
+    HANDLER.read().unwrap().is_some()
+}
+
+/// Runs `f` with the stage events of the current thread belonging to `tile` processed by the
+/// batch `thread`.
+pub fn with_tile<R>(tile: &str, thread: &str, f: impl FnOnce() -> R) -> R {
+    // restore the previous context also if `f` panics
+    struct Restore(TileContext);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CONTEXT.with_borrow_mut(|c| *c = std::mem::take(&mut self.0));
+        }
+    }
+    let _restore = Restore(CONTEXT.replace(TileContext {
+        tile: Some(tile.to_string()),
+        thread: Some(thread.to_string()),
+    }));
+    f()
+}
+
+/// Returns the tile context of the current thread, for the events sent from it or on its behalf.
+pub fn context() -> TileContext {
+    CONTEXT.with_borrow(|c| c.clone())
+}
+
+/// Sends an event to the installed handler. Does nothing if no handler is installed.
+pub fn emit(event: ProgressEvent) {
+    // clone the handler so it is not called while holding the lock
+    let handler = HANDLER.read().unwrap().clone();
+    if let Some(handler) = handler {
+        handler(&event);
+    }
+}
+
+/// Helper for reporting the progress of a loop with a known number of steps. An event is only
+/// emitted when the whole percentage changes.
+pub struct Progress {
+    stage: &'static str,
+    context: TileContext,
+    total: u64,
+    current: u64,
+    percent: u8,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(stage: &'static str, total: u64) -> Self {
+        Self {
+            stage,
+            context: context(),
+            total,
+            current: 0,
+            percent: 0,
+            enabled: enabled(),
+        }
+    }
+
+    /// Advances the progress by `steps`.
+    pub fn inc(&mut self, steps: u64) {
+        if !self.enabled || self.total == 0 {
+            return;
+        }
+        self.current = (self.current + steps).min(self.total);
+        let percent = (self.current * 100 / self.total) as u8;
+        if percent > self.percent {
+            self.percent = percent;
+            emit(ProgressEvent::Progress {
+                stage: self.stage.to_string(),
+                percent,
+                context: self.context.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let event = ProgressEvent::Progress {
+            stage: "makevege".to_string(),
+            percent: 42,
+            context: TileContext::default(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"progress","stage":"makevege","percent":42}"#
+        );
+    }
+
+    #[test]
+    fn test_stage_events_carry_the_tile() {
+        let progress = with_tile("a.laz", "3", || Progress::new("makevege", 1));
+        assert_eq!(context(), TileContext::default());
+
+        let event = ProgressEvent::Progress {
+            stage: progress.stage.to_string(),
+            percent: 100,
+            context: progress.context,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"progress","stage":"makevege","percent":100,"tile":"a.laz","thread":"3"}"#
+        );
+    }
+}
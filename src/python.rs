@@ -0,0 +1,1139 @@
+//! Python bindings for running the processing stages on in-memory data, for example in notebooks.
+//!
+//! The module wraps the [`Pipeline`] API. Heightmaps and rasters are exchanged as numpy arrays
+//! together with their georeference, and vector outputs as `BinaryDxf` objects that can be
+//! inspected or written as DXF. The extension module is built with maturin, see `pyproject.toml`.
+use image::{DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+use ini::Ini;
+use numpy::ndarray::{Array2, ArrayD, ArrayView1, ArrayView2, ArrayViewD, IxDyn, ShapeError};
+use numpy::{
+    IntoPyArray, PyArray1, PyArray2, PyArrayDyn, PyArrayMethods, PyReadonlyArray1,
+    PyReadonlyArray2, PyUntypedArrayMethods,
+};
+use pyo3::exceptions::{PyAttributeError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::{Config, DEFAULT_CONFIG};
+use crate::geometry::{self, Geometry};
+use crate::io::heightmap::HeightMap;
+use crate::io::worldfile::WorldFile;
+use crate::io::xyz::XyzRecord;
+use crate::pipeline::{
+    self, Buildings, Cliffs, ContourSet, GeoRaster, Marshes, Pipeline, PointCloud, PowerLines,
+    StonyGround, Vegetation,
+};
+use crate::process::IntensityScale;
+use crate::vec2d::Vec2D;
+
+fn runtime_error(e: impl std::fmt::Display) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// The configuration, read from an .ini file or the defaults if no file is given, with the keyword arguments
+/// overriding single options, e.g. `Config(vegeonly=True, processes=1)`.
+#[pyclass(name = "Config", module = "pullauta", frozen)]
+pub struct PyConfig {
+    config: Arc<Config>,
+}
+
+#[pymethods]
+impl PyConfig {
+    #[new]
+    #[pyo3(signature = (path=None, **overrides))]
+    fn new(path: Option<PathBuf>, overrides: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let mut ini = match path {
+            Some(path) => Ini::load_from_file(path).map_err(runtime_error)?,
+            None => Ini::load_from_str(DEFAULT_CONFIG).map_err(runtime_error)?,
+        };
+        for (key, value) in overrides.into_iter().flatten() {
+            let key: String = key.extract()?;
+            let value = match value.extract::<bool>() {
+                Ok(value) => if value { "1" } else { "0" }.to_string(),
+                Err(_) => value.str()?.to_string(),
+            };
+            ini.with_general_section().set(key, value);
+        }
+        let config = Config::from_ini(&ini).map_err(runtime_error)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// The parsed options are available as attributes, e.g. `config.scalefactor`.
+    fn __getattr__<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        let options = serde_json::to_value(&*self.config).map_err(runtime_error)?;
+        let value = options
+            .get(name)
+            .ok_or_else(|| PyAttributeError::new_err(format!("no option {name}")))?;
+        py.import("json")?
+            .call_method1("loads", (value.to_string(),))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string_pretty(&*self.config).map_err(runtime_error)
+    }
+}
+
+/// Classified points with the coordinates as an (n, 3) array. The intensities are uint8 with 0 for
+/// unknown, uint16 intensities are scaled to uint8 like the ones read from las/laz files.
+#[pyclass(name = "PointCloud", module = "pullauta")]
+#[derive(Clone)]
+pub struct PyPointCloud {
+    points: PointCloud,
+}
+
+#[pymethods]
+impl PyPointCloud {
+    #[new]
+    #[pyo3(signature = (xyz, classification, number_of_returns=None, return_number=None, intensity=None))]
+    fn new(
+        xyz: PyReadonlyArray2<'_, f64>,
+        classification: PyReadonlyArray1<'_, u8>,
+        number_of_returns: Option<PyReadonlyArray1<'_, u8>>,
+        return_number: Option<PyReadonlyArray1<'_, u8>>,
+        intensity: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<Self> {
+        let xyz = xyz.as_array();
+        let n = xyz.nrows();
+        if xyz.ncols() != 3 {
+            return Err(PyValueError::new_err("xyz must have the shape (n, 3)"));
+        }
+        let column = |values: Option<PyReadonlyArray1<'_, u8>>, name: &str| match values {
+            Some(values) if values.len() != n => Err(PyValueError::new_err(format!(
+                "{name} must have as many values as there are points"
+            ))),
+            Some(values) => Ok(values.as_array().to_vec()),
+            None => Ok(vec![1; n]),
+        };
+        let classification = column(Some(classification), "classification")?;
+        let number_of_returns = column(number_of_returns, "number_of_returns")?;
+        let return_number = column(return_number, "return_number")?;
+        let intensity = match intensity {
+            Some(values) => {
+                let intensity = if let Ok(values) = values.extract::<PyReadonlyArray1<'_, u8>>() {
+                    values.as_array().to_vec()
+                } else if let Ok(values) = values.extract::<PyReadonlyArray1<'_, u16>>() {
+                    scale_intensities(values.as_array())
+                } else {
+                    return Err(PyValueError::new_err(
+                        "intensity must be an array of uint8 or uint16",
+                    ));
+                };
+                if intensity.len() != n {
+                    return Err(PyValueError::new_err(
+                        "intensity must have as many values as there are points",
+                    ));
+                }
+                intensity
+            }
+            None => vec![0; n],
+        };
+
+        let records = (0..n)
+            .map(|i| XyzRecord {
+                x: xyz[[i, 0]],
+                y: xyz[[i, 1]],
+                z: xyz[[i, 2]] as f32,
+                classification: classification[i],
+                number_of_returns: number_of_returns[i],
+                return_number: return_number[i],
+                intensity: intensity[i],
+            })
+            .collect();
+        Ok(Self {
+            points: PointCloud { records },
+        })
+    }
+
+    #[getter]
+    fn xyz<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray2<f64>> {
+        let records = &self.points.records;
+        Array2::from_shape_fn((records.len(), 3), |(i, j)| match j {
+            0 => records[i].x,
+            1 => records[i].y,
+            _ => records[i].z as f64,
+        })
+        .into_pyarray(py)
+    }
+
+    #[getter]
+    fn classification<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<u8>> {
+        self.column(py, |r| r.classification)
+    }
+
+    #[getter]
+    fn number_of_returns<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<u8>> {
+        self.column(py, |r| r.number_of_returns)
+    }
+
+    #[getter]
+    fn return_number<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<u8>> {
+        self.column(py, |r| r.return_number)
+    }
+
+    #[getter]
+    fn intensity<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<u8>> {
+        self.column(py, |r| r.intensity)
+    }
+
+    fn __len__(&self) -> usize {
+        self.points.records.len()
+    }
+}
+
+impl PyPointCloud {
+    fn column<'py>(
+        &self,
+        py: Python<'py>,
+        f: impl Fn(&XyzRecord) -> u8,
+    ) -> Bound<'py, PyArray1<u8>> {
+        self.points
+            .records
+            .iter()
+            .map(f)
+            .collect::<Vec<_>>()
+            .into_pyarray(py)
+    }
+}
+
+/// Scales 16-bit intensities to 8 bits with the scale of all of them, like for a las/laz file.
+fn scale_intensities(intensities: ArrayView1<'_, u16>) -> Vec<u8> {
+    let scale = IntensityScale::new(intensities.iter().copied());
+    intensities.iter().map(|&i| scale.scale(i)).collect()
+}
+
+/// A heightmap with the grid as a (width, height) array indexed by `[x, y]`, where the cell
+/// `[x, y]` is at `(xoffset + scale * x, yoffset + scale * y)`.
+#[pyclass(name = "HeightMap", module = "pullauta")]
+#[derive(Clone)]
+pub struct PyHeightMap {
+    heightmap: HeightMap,
+}
+
+#[pymethods]
+impl PyHeightMap {
+    #[new]
+    fn new(grid: PyReadonlyArray2<'_, f64>, xoffset: f64, yoffset: f64, scale: f64) -> Self {
+        Self {
+            heightmap: HeightMap {
+                xoffset,
+                yoffset,
+                scale,
+                grid: to_grid(grid.as_array()),
+            },
+        }
+    }
+
+    #[getter]
+    fn grid<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray2<f64>>> {
+        from_grid(&self.heightmap.grid)
+            .map(|grid| grid.into_pyarray(py))
+            .map_err(runtime_error)
+    }
+
+    #[setter]
+    fn set_grid(&mut self, grid: PyReadonlyArray2<'_, f64>) {
+        self.heightmap.grid = to_grid(grid.as_array());
+    }
+
+    #[getter]
+    fn xoffset(&self) -> f64 {
+        self.heightmap.xoffset
+    }
+
+    #[setter]
+    fn set_xoffset(&mut self, xoffset: f64) {
+        self.heightmap.xoffset = xoffset;
+    }
+
+    #[getter]
+    fn yoffset(&self) -> f64 {
+        self.heightmap.yoffset
+    }
+
+    #[setter]
+    fn set_yoffset(&mut self, yoffset: f64) {
+        self.heightmap.yoffset = yoffset;
+    }
+
+    #[getter]
+    fn scale(&self) -> f64 {
+        self.heightmap.scale
+    }
+
+    #[setter]
+    fn set_scale(&mut self, scale: f64) {
+        self.heightmap.scale = scale;
+    }
+
+    /// The extent as `(minx, miny, maxx, maxy)`.
+    #[getter]
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let hm = &self.heightmap;
+        (hm.minx(), hm.miny(), hm.maxx(), hm.maxy())
+    }
+}
+
+/// Converts an array indexed `[x, y]` to a grid, whatever the memory order of the array.
+fn to_grid(grid: ArrayView2<'_, f64>) -> Vec2D<f64> {
+    let (w, h) = grid.dim();
+    Vec2D::from_vec(w, h, grid.iter().copied().collect())
+}
+
+/// Converts a grid to an array indexed `[x, y]`.
+fn from_grid(grid: &Vec2D<f64>) -> Result<Array2<f64>, ShapeError> {
+    Array2::from_shape_vec((grid.width(), grid.height()), grid.as_slice().to_vec())
+}
+
+/// Vector data, like contours or cliffs, with its layers as named in the DXF output.
+#[pyclass(name = "BinaryDxf", module = "pullauta", frozen)]
+#[derive(Clone)]
+pub struct PyBinaryDxf {
+    dxf: geometry::BinaryDxf,
+}
+
+#[pymethods]
+impl PyBinaryDxf {
+    /// Reads the contents of a `.dxf.bin` file.
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        let dxf = geometry::BinaryDxf::from_reader(&mut &data[..]).map_err(runtime_error)?;
+        Ok(Self { dxf })
+    }
+
+    /// The contents of a `.dxf.bin` file.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let mut data = Vec::new();
+        self.dxf.to_writer(&mut data).map_err(runtime_error)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    fn to_dxf(&self) -> PyResult<String> {
+        let mut data = Vec::new();
+        self.dxf.to_dxf(&mut data).map_err(runtime_error)?;
+        String::from_utf8(data).map_err(runtime_error)
+    }
+
+    /// The extent as `(minx, miny, maxx, maxy)`.
+    #[getter]
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let b = self.dxf.bounds();
+        (b.xmin, b.ymin, b.xmax, b.ymax)
+    }
+
+    /// The points as a list of `(layer, x, y)`.
+    fn points(&self) -> Vec<(String, f64, f64)> {
+        let mut result = Vec::new();
+        for geometry in self.dxf.geometry() {
+            if let Geometry::Points(points) = geometry {
+                for (p, class) in points.iter() {
+                    result.push((class.to_layer().to_string(), p.x, p.y));
+                }
+            }
+        }
+        result
+    }
+
+    /// The polylines as a list of `(layer, coordinates)`, where the coordinates are an (n, 2)
+    /// array, or an (n, 3) array for lines with a height like contours.
+    fn polylines<'py>(&self, py: Python<'py>) -> Vec<(String, Bound<'py, PyArray2<f64>>)> {
+        let mut result = Vec::new();
+        for geometry in self.dxf.geometry() {
+            match geometry {
+                Geometry::Points(_) => {}
+                Geometry::Polylines2(lines) => {
+                    for (line, class) in lines.iter() {
+                        let coords = Array2::from_shape_fn((line.len(), 2), |(i, j)| {
+                            if j == 0 { line[i].x } else { line[i].y }
+                        });
+                        result.push((class.to_layer().to_string(), coords.into_pyarray(py)));
+                    }
+                }
+                Geometry::Polylines3(lines) => {
+                    for (line, (class, _)) in lines.iter() {
+                        let coords = Array2::from_shape_fn((line.len(), 3), |(i, j)| match j {
+                            0 => line[i].x,
+                            1 => line[i].y,
+                            _ => line[i].z,
+                        });
+                        result.push((class.to_layer().to_string(), coords.into_pyarray(py)));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A raster image as an array of shape (height, width) or (height, width, channels), together
+/// with its world file `(x_scale, y_skew, x_skew, y_scale, x, y)`.
+#[pyclass(name = "GeoRaster", module = "pullauta")]
+#[derive(Clone)]
+pub struct PyGeoRaster {
+    raster: GeoRaster,
+}
+
+#[pymethods]
+impl PyGeoRaster {
+    #[new]
+    fn new(
+        image: &Bound<'_, PyArrayDyn<u8>>,
+        world_file: (f64, f64, f64, f64, f64, f64),
+    ) -> PyResult<Self> {
+        Ok(Self {
+            raster: GeoRaster {
+                image: to_image(image)?,
+                world_file: to_world_file(world_file),
+            },
+        })
+    }
+
+    #[getter]
+    fn image<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArrayDyn<u8>>> {
+        from_image(py, &self.raster.image)
+    }
+
+    #[setter]
+    fn set_image(&mut self, image: &Bound<'_, PyArrayDyn<u8>>) -> PyResult<()> {
+        self.raster.image = to_image(image)?;
+        Ok(())
+    }
+
+    #[getter]
+    fn world_file(&self) -> (f64, f64, f64, f64, f64, f64) {
+        let w = &self.raster.world_file;
+        (w.x_scale, w.y_skew, w.x_skew, w.y_scale, w.x, w.y)
+    }
+
+    #[setter]
+    fn set_world_file(&mut self, world_file: (f64, f64, f64, f64, f64, f64)) {
+        self.raster.world_file = to_world_file(world_file);
+    }
+}
+
+fn to_world_file(
+    (x_scale, y_skew, x_skew, y_scale, x, y): (f64, f64, f64, f64, f64, f64),
+) -> WorldFile {
+    WorldFile {
+        x_scale,
+        y_skew,
+        x_skew,
+        y_scale,
+        x,
+        y,
+    }
+}
+
+fn from_image<'py>(py: Python<'py>, image: &DynamicImage) -> PyResult<Bound<'py, PyArrayDyn<u8>>> {
+    image_array(image)
+        .map(|array| array.into_pyarray(py))
+        .map_err(runtime_error)
+}
+
+fn to_image(array: &Bound<'_, PyArrayDyn<u8>>) -> PyResult<DynamicImage> {
+    array_image(array.readonly().as_array())
+}
+
+/// Converts an image to an array indexed `[y, x]` or `[y, x, channel]`, keeping the channels of
+/// 8-bit images.
+fn image_array(image: &DynamicImage) -> Result<ArrayD<u8>, ShapeError> {
+    let (w, h) = (image.width() as usize, image.height() as usize);
+    let (shape, data) = match image.color().channel_count() {
+        1 => (vec![h, w], image.to_luma8().into_raw()),
+        2 => (vec![h, w, 2], image.to_luma_alpha8().into_raw()),
+        3 => (vec![h, w, 3], image.to_rgb8().into_raw()),
+        _ => (vec![h, w, 4], image.to_rgba8().into_raw()),
+    };
+    ArrayD::from_shape_vec(IxDyn(&shape), data)
+}
+
+/// Converts an array indexed `[y, x]` or `[y, x, channel]` to an image.
+fn array_image(array: ArrayViewD<'_, u8>) -> PyResult<DynamicImage> {
+    let shape = array.shape().to_vec();
+    let data = array.iter().copied().collect::<Vec<u8>>();
+    let (h, w) = match shape[..] {
+        [h, w] | [h, w, _] => (h as u32, w as u32),
+        _ => {
+            return Err(PyValueError::new_err(
+                "an image must have 2 or 3 dimensions",
+            ));
+        }
+    };
+    let image = match shape.get(2) {
+        None | Some(1) => GrayImage::from_raw(w, h, data).map(DynamicImage::from),
+        Some(2) => GrayAlphaImage::from_raw(w, h, data).map(DynamicImage::from),
+        Some(3) => RgbImage::from_raw(w, h, data).map(DynamicImage::from),
+        Some(4) => RgbaImage::from_raw(w, h, data).map(DynamicImage::from),
+        _ => None,
+    };
+    image.ok_or_else(|| PyValueError::new_err("an image must have 1 to 4 channels"))
+}
+
+/// All layers needed to render a map. The attributes can be replaced to render a map with
+/// tweaked layers; layers set to `None` are left out.
+#[pyclass(name = "MapLayers", module = "pullauta", get_all, set_all)]
+pub struct PyMapLayers {
+    heightmap: Py<PyHeightMap>,
+    vegetation: Py<PyGeoRaster>,
+    undergrowth: Py<PyGeoRaster>,
+    contours: Option<Py<PyBinaryDxf>>,
+    dotknolls: Option<Py<PyBinaryDxf>>,
+    /// Water and buildings from classified points, using the georeference of `vegetation`.
+    water: Option<Py<PyArrayDyn<u8>>>,
+    cliffs_small: Option<Py<PyBinaryDxf>>,
+    cliffs_big: Option<Py<PyBinaryDxf>>,
+    /// Detected buildings, using the grid of `heightmap` with 1 pixel per meter.
+    buildings: Option<Py<PyArrayDyn<u8>>>,
+    building_footprints: Option<Py<PyBinaryDxf>>,
+    streams: Option<Py<PyBinaryDxf>>,
+    /// Detected marshes, using the grid of `heightmap` with 1 pixel per meter.
+    marshes: Option<Py<PyArrayDyn<u8>>>,
+    marsh_outlines: Option<Py<PyBinaryDxf>>,
+    water_bodies: Option<Py<PyBinaryDxf>>,
+    boulders: Option<Py<PyBinaryDxf>>,
+    /// Detected stony ground and boulder fields, using the grid of `heightmap` with 1 pixel per
+    /// meter.
+    stony_ground: Option<Py<PyArrayDyn<u8>>>,
+    stony_ground_outlines: Option<Py<PyBinaryDxf>>,
+    pits: Option<Py<PyBinaryDxf>>,
+    banks: Option<Py<PyBinaryDxf>>,
+    paths: Option<Py<PyBinaryDxf>>,
+    power_lines: Option<Py<PyBinaryDxf>>,
+    pylons: Option<Py<PyBinaryDxf>>,
+}
+
+#[pymethods]
+impl PyMapLayers {
+    #[new]
+    #[pyo3(signature = (
+        heightmap,
+        vegetation,
+        undergrowth,
+        contours=None,
+        dotknolls=None,
+        water=None,
+        cliffs_small=None,
+        cliffs_big=None,
+        buildings=None,
+        building_footprints=None,
+        streams=None,
+        marshes=None,
+        marsh_outlines=None,
+        water_bodies=None,
+        boulders=None,
+        stony_ground=None,
+        stony_ground_outlines=None,
+        pits=None,
+        banks=None,
+        paths=None,
+        power_lines=None,
+        pylons=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        heightmap: Py<PyHeightMap>,
+        vegetation: Py<PyGeoRaster>,
+        undergrowth: Py<PyGeoRaster>,
+        contours: Option<Py<PyBinaryDxf>>,
+        dotknolls: Option<Py<PyBinaryDxf>>,
+        water: Option<Py<PyArrayDyn<u8>>>,
+        cliffs_small: Option<Py<PyBinaryDxf>>,
+        cliffs_big: Option<Py<PyBinaryDxf>>,
+        buildings: Option<Py<PyArrayDyn<u8>>>,
+        building_footprints: Option<Py<PyBinaryDxf>>,
+        streams: Option<Py<PyBinaryDxf>>,
+        marshes: Option<Py<PyArrayDyn<u8>>>,
+        marsh_outlines: Option<Py<PyBinaryDxf>>,
+        water_bodies: Option<Py<PyBinaryDxf>>,
+        boulders: Option<Py<PyBinaryDxf>>,
+        stony_ground: Option<Py<PyArrayDyn<u8>>>,
+        stony_ground_outlines: Option<Py<PyBinaryDxf>>,
+        pits: Option<Py<PyBinaryDxf>>,
+        banks: Option<Py<PyBinaryDxf>>,
+        paths: Option<Py<PyBinaryDxf>>,
+        power_lines: Option<Py<PyBinaryDxf>>,
+        pylons: Option<Py<PyBinaryDxf>>,
+    ) -> Self {
+        Self {
+            heightmap,
+            vegetation,
+            undergrowth,
+            contours,
+            dotknolls,
+            water,
+            cliffs_small,
+            cliffs_big,
+            buildings,
+            building_footprints,
+            streams,
+            marshes,
+            marsh_outlines,
+            water_bodies,
+            boulders,
+            stony_ground,
+            stony_ground_outlines,
+            pits,
+            banks,
+            paths,
+            power_lines,
+            pylons,
+        }
+    }
+}
+
+impl PyMapLayers {
+    fn from_layers(py: Python<'_>, layers: pipeline::MapLayers) -> PyResult<Self> {
+        let dxf = |dxf| Py::new(py, PyBinaryDxf { dxf });
+        let raster = |raster| Py::new(py, PyGeoRaster { raster });
+        let image = |image: Option<DynamicImage>| {
+            image
+                .map(|image| from_image(py, &image).map(Bound::unbind))
+                .transpose()
+        };
+        let vegetation = layers
+            .vegetation
+            .ok_or_else(|| PyRuntimeError::new_err("the map layers have no vegetation"))?;
+        let (contours, dotknolls) = match layers.contours {
+            Some(set) => (Some(dxf(set.contours)?), Some(dxf(set.dotknolls)?)),
+            None => (None, None),
+        };
+        let (cliffs_small, cliffs_big) = match layers.cliffs {
+            Some(cliffs) => (Some(dxf(cliffs.small)?), Some(dxf(cliffs.big)?)),
+            None => (None, None),
+        };
+        let (buildings, building_footprints) = match layers.buildings {
+            Some(buildings) => (
+                image(Some(buildings.image))?,
+                Some(dxf(buildings.footprints)?),
+            ),
+            None => (None, None),
+        };
+        let (marshes, marsh_outlines) = match layers.marshes {
+            Some(marshes) => (image(Some(marshes.image))?, Some(dxf(marshes.outlines)?)),
+            None => (None, None),
+        };
+        let (power_lines, pylons) = match layers.power_lines {
+            Some(power_lines) => (
+                Some(dxf(power_lines.lines)?),
+                Some(dxf(power_lines.pylons)?),
+            ),
+            None => (None, None),
+        };
+        let (stony_ground, stony_ground_outlines) = match layers.stony_ground {
+            Some(stony) => (image(Some(stony.image))?, Some(dxf(stony.outlines)?)),
+            None => (None, None),
+        };
+        Ok(Self {
+            heightmap: Py::new(
+                py,
+                PyHeightMap {
+                    heightmap: layers.heightmap,
+                },
+            )?,
+            vegetation: raster(vegetation.vegetation)?,
+            undergrowth: raster(vegetation.undergrowth)?,
+            contours,
+            dotknolls,
+            water: image(vegetation.water)?,
+            cliffs_small,
+            cliffs_big,
+            buildings,
+            building_footprints,
+            streams: layers.streams.map(dxf).transpose()?,
+            marshes,
+            marsh_outlines,
+            water_bodies: layers.water_bodies.map(dxf).transpose()?,
+            boulders: layers.boulders.map(dxf).transpose()?,
+            stony_ground,
+            stony_ground_outlines,
+            pits: layers.pits.map(dxf).transpose()?,
+            banks: layers.banks.map(dxf).transpose()?,
+            paths: layers.paths.map(dxf).transpose()?,
+            power_lines,
+            pylons,
+        })
+    }
+
+    fn to_layers(&self, py: Python<'_>) -> PyResult<pipeline::MapLayers> {
+        let dxf = |dxf: &Option<Py<PyBinaryDxf>>| dxf.as_ref().map(|d| d.get().dxf.clone());
+        let image = |image: &Option<Py<PyArrayDyn<u8>>>| {
+            image.as_ref().map(|i| to_image(i.bind(py))).transpose()
+        };
+        let heightmap = self.heightmap.borrow(py).heightmap.clone();
+        // areas without outlines get empty ones covering the heightmap
+        let outlines = |outlines: &Option<Py<PyBinaryDxf>>| {
+            dxf(outlines).unwrap_or_else(|| {
+                geometry::BinaryDxf::new(
+                    geometry::Bounds::new(
+                        heightmap.minx(),
+                        heightmap.maxx(),
+                        heightmap.miny(),
+                        heightmap.maxy(),
+                    ),
+                    Vec::new(),
+                )
+            })
+        };
+        let buildings = image(&self.buildings)?.map(|image| Buildings {
+            image,
+            footprints: outlines(&self.building_footprints),
+        });
+        let marshes = image(&self.marshes)?.map(|image| Marshes {
+            image,
+            outlines: outlines(&self.marsh_outlines),
+        });
+        let stony_ground = image(&self.stony_ground)?.map(|image| StonyGround {
+            image,
+            outlines: outlines(&self.stony_ground_outlines),
+        });
+        Ok(pipeline::MapLayers {
+            heightmap,
+            contours: pair(dxf(&self.contours), dxf(&self.dotknolls)).map(
+                |(contours, dotknolls)| ContourSet {
+                    contours,
+                    dotknolls,
+                    detected: None,
+                    contours03: None,
+                    basemap: None,
+                    steps: None,
+                },
+            ),
+            vegetation: Some(Vegetation {
+                vegetation: self.vegetation.borrow(py).raster.clone(),
+                undergrowth: self.undergrowth.borrow(py).raster.clone(),
+                water: image(&self.water)?,
+                bits: None,
+            }),
+            cliffs: pair(dxf(&self.cliffs_small), dxf(&self.cliffs_big))
+                .map(|(small, big)| Cliffs { small, big }),
+            buildings,
+            streams: dxf(&self.streams),
+            marshes,
+            water_bodies: dxf(&self.water_bodies),
+            boulders: dxf(&self.boulders),
+            stony_ground,
+            pits: dxf(&self.pits),
+            banks: dxf(&self.banks),
+            paths: dxf(&self.paths),
+            power_lines: pair(dxf(&self.power_lines), dxf(&self.pylons))
+                .map(|(lines, pylons)| PowerLines { lines, pylons }),
+            low: None,
+            high: None,
+        })
+    }
+}
+
+/// Pairs layers that are rendered together, using an empty layer for a missing half.
+fn pair(
+    a: Option<geometry::BinaryDxf>,
+    b: Option<geometry::BinaryDxf>,
+) -> Option<(geometry::BinaryDxf, geometry::BinaryDxf)> {
+    let empty =
+        |other: &geometry::BinaryDxf| geometry::BinaryDxf::new(other.bounds().clone(), Vec::new());
+    match (a, b) {
+        (Some(a), Some(b)) => Some((a, b)),
+        (Some(a), None) => {
+            let b = empty(&a);
+            Some((a, b))
+        }
+        (None, Some(b)) => Some((empty(&b), b)),
+        (None, None) => None,
+    }
+}
+
+/// Runs the processing stages with a configuration. The stages release the GIL while they run.
+#[pyclass(name = "Pipeline", module = "pullauta", frozen)]
+pub struct PyPipeline {
+    config: Arc<Config>,
+}
+
+#[pymethods]
+impl PyPipeline {
+    #[new]
+    fn new(config: &PyConfig) -> Self {
+        Self {
+            config: config.config.clone(),
+        }
+    }
+
+    /// Reads the points of a .las, .laz, .xyz or .xyz.bin file, or of `data` if given, in which
+    /// case `path` only determines the format.
+    #[pyo3(signature = (path, data=None))]
+    fn read_points(
+        &self,
+        py: Python<'_>,
+        path: PathBuf,
+        data: Option<&[u8]>,
+    ) -> PyResult<PyPointCloud> {
+        let points = py.detach(|| {
+            let file_name = path.to_string_lossy();
+            match data {
+                Some(data) => self.pipeline().read_points(&file_name, data),
+                None => self
+                    .pipeline()
+                    .read_points(&file_name, &std::fs::read(&path)?),
+            }
+        });
+        Ok(PyPointCloud {
+            points: points.map_err(runtime_error)?,
+        })
+    }
+
+    fn heightmap(&self, py: Python<'_>, points: &PyPointCloud) -> PyResult<PyHeightMap> {
+        let heightmap = py.detach(|| self.pipeline().heightmap(&points.points));
+        Ok(PyHeightMap {
+            heightmap: heightmap.map_err(runtime_error)?,
+        })
+    }
+
+    /// Raw contours of a heightmap with the given interval.
+    fn contours(
+        &self,
+        py: Python<'_>,
+        heightmap: &PyHeightMap,
+        interval: f64,
+    ) -> PyResult<PyBinaryDxf> {
+        let dxf = py.detach(|| self.pipeline().contours(&heightmap.heightmap, interval));
+        Ok(PyBinaryDxf {
+            dxf: dxf.map_err(runtime_error)?,
+        })
+    }
+
+    /// The final contours, dot knolls and detected knolls, which are `None` if the knoll
+    /// detection is skipped.
+    fn contour_set(
+        &self,
+        py: Python<'_>,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(PyBinaryDxf, PyBinaryDxf, Option<PyBinaryDxf>)> {
+        let set = py
+            .detach(|| self.pipeline().contour_set(&heightmap.heightmap))
+            .map_err(runtime_error)?;
+        Ok((
+            PyBinaryDxf { dxf: set.contours },
+            PyBinaryDxf { dxf: set.dotknolls },
+            set.detected.map(|dxf| PyBinaryDxf { dxf }),
+        ))
+    }
+
+    /// The vegetation and undergrowth rasters and the water image, if any.
+    #[allow(clippy::type_complexity)]
+    fn vegetation<'py>(
+        &self,
+        py: Python<'py>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(PyGeoRaster, PyGeoRaster, Option<Bound<'py, PyArrayDyn<u8>>>)> {
+        let vegetation = py
+            .detach(|| {
+                self.pipeline()
+                    .vegetation(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok((
+            PyGeoRaster {
+                raster: vegetation.vegetation,
+            },
+            PyGeoRaster {
+                raster: vegetation.undergrowth,
+            },
+            vegetation
+                .water
+                .map(|water| from_image(py, &water))
+                .transpose()?,
+        ))
+    }
+
+    /// The small and big cliffs.
+    fn cliffs(
+        &self,
+        py: Python<'_>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(PyBinaryDxf, PyBinaryDxf)> {
+        let cliffs = py
+            .detach(|| self.pipeline().cliffs(&points.points, &heightmap.heightmap))
+            .map_err(runtime_error)?;
+        Ok((
+            PyBinaryDxf { dxf: cliffs.small },
+            PyBinaryDxf { dxf: cliffs.big },
+        ))
+    }
+
+    /// The building image and footprints.
+    fn buildings<'py>(
+        &self,
+        py: Python<'py>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(Bound<'py, PyArrayDyn<u8>>, PyBinaryDxf)> {
+        let buildings = py
+            .detach(|| {
+                self.pipeline()
+                    .buildings(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok((
+            from_image(py, &buildings.image)?,
+            PyBinaryDxf {
+                dxf: buildings.footprints,
+            },
+        ))
+    }
+
+    fn streams(&self, py: Python<'_>, heightmap: &PyHeightMap) -> PyResult<PyBinaryDxf> {
+        let dxf = py
+            .detach(|| self.pipeline().streams(&heightmap.heightmap))
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    /// Earth banks and erosion gullies, leaving out the banks next to the cliffs.
+    #[pyo3(signature = (heightmap, cliffs_small=None, cliffs_big=None))]
+    fn banks(
+        &self,
+        py: Python<'_>,
+        heightmap: &PyHeightMap,
+        cliffs_small: Option<&PyBinaryDxf>,
+        cliffs_big: Option<&PyBinaryDxf>,
+    ) -> PyResult<PyBinaryDxf> {
+        let cliffs = pair(
+            cliffs_small.map(|c| c.dxf.clone()),
+            cliffs_big.map(|c| c.dxf.clone()),
+        )
+        .map(|(small, big)| Cliffs { small, big });
+        let dxf = py
+            .detach(|| self.pipeline().banks(&heightmap.heightmap, cliffs.as_ref()))
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    /// Pits and small depressions, leaving out the ones next to the u-depressions of
+    /// `dotknolls`.
+    #[pyo3(signature = (heightmap, dotknolls=None))]
+    fn pits(
+        &self,
+        py: Python<'_>,
+        heightmap: &PyHeightMap,
+        dotknolls: Option<&PyBinaryDxf>,
+    ) -> PyResult<PyBinaryDxf> {
+        let dxf = py
+            .detach(|| {
+                self.pipeline()
+                    .pits(&heightmap.heightmap, dotknolls.map(|d| &d.dxf))
+            })
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    fn boulders(
+        &self,
+        py: Python<'_>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<PyBinaryDxf> {
+        let dxf = py
+            .detach(|| {
+                self.pipeline()
+                    .boulders(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    /// Paths with their confidence as the height of their lines.
+    fn paths(
+        &self,
+        py: Python<'_>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<PyBinaryDxf> {
+        let dxf = py
+            .detach(|| self.pipeline().paths(&points.points, &heightmap.heightmap))
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    /// The power lines and their pylons.
+    fn power_lines(
+        &self,
+        py: Python<'_>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(PyBinaryDxf, PyBinaryDxf)> {
+        let power_lines = py
+            .detach(|| {
+                self.pipeline()
+                    .power_lines(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok((
+            PyBinaryDxf {
+                dxf: power_lines.lines,
+            },
+            PyBinaryDxf {
+                dxf: power_lines.pylons,
+            },
+        ))
+    }
+
+    fn water_bodies(
+        &self,
+        py: Python<'_>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<PyBinaryDxf> {
+        let dxf = py
+            .detach(|| {
+                self.pipeline()
+                    .water_bodies(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok(PyBinaryDxf { dxf })
+    }
+
+    /// The marsh image and outlines.
+    fn marshes<'py>(
+        &self,
+        py: Python<'py>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(Bound<'py, PyArrayDyn<u8>>, PyBinaryDxf)> {
+        let marshes = py
+            .detach(|| {
+                self.pipeline()
+                    .marshes(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok((
+            from_image(py, &marshes.image)?,
+            PyBinaryDxf {
+                dxf: marshes.outlines,
+            },
+        ))
+    }
+
+    /// The stony ground image and outlines.
+    fn stony_ground<'py>(
+        &self,
+        py: Python<'py>,
+        points: &PyPointCloud,
+        heightmap: &PyHeightMap,
+    ) -> PyResult<(Bound<'py, PyArrayDyn<u8>>, PyBinaryDxf)> {
+        let stony = py
+            .detach(|| {
+                self.pipeline()
+                    .stony_ground(&points.points, &heightmap.heightmap)
+            })
+            .map_err(runtime_error)?;
+        Ok((
+            from_image(py, &stony.image)?,
+            PyBinaryDxf {
+                dxf: stony.outlines,
+            },
+        ))
+    }
+
+    /// Runs all the stages needed for rendering a map.
+    fn layers(&self, py: Python<'_>, points: &PyPointCloud) -> PyResult<PyMapLayers> {
+        let layers = py
+            .detach(|| self.pipeline().layers(&points.points))
+            .map_err(runtime_error)?;
+        PyMapLayers::from_layers(py, layers)
+    }
+
+    /// Renders the map and returns it with the form lines, if they are generated.
+    #[pyo3(signature = (layers, depressions=false))]
+    fn render(
+        &self,
+        py: Python<'_>,
+        layers: &PyMapLayers,
+        depressions: bool,
+    ) -> PyResult<(PyGeoRaster, Option<PyBinaryDxf>)> {
+        let layers = layers.to_layers(py)?;
+        let rendered = py
+            .detach(|| self.pipeline().render(&layers, depressions))
+            .map_err(runtime_error)?;
+        Ok((
+            PyGeoRaster {
+                raster: rendered.map,
+            },
+            rendered.formlines.map(|dxf| PyBinaryDxf { dxf }),
+        ))
+    }
+}
+
+impl PyPipeline {
+    fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline::new(&self.config)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "pullauta")]
+fn pullauta_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyConfig>()?;
+    m.add_class::<PyPointCloud>()?;
+    m.add_class::<PyHeightMap>()?;
+    m.add_class::<PyBinaryDxf>()?;
+    m.add_class::<PyGeoRaster>()?;
+    m.add_class::<PyMapLayers>()?;
+    m.add_class::<PyPipeline>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+    use numpy::ndarray::ShapeBuilder;
+
+    #[test]
+    fn test_grid_conversions() {
+        let mut grid = Vec2D::new(3, 2, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            *z = (10 * x + y) as f64;
+        }
+
+        // numpy arrays of heightmaps are indexed [x, y] like the grid
+        let array = from_grid(&grid).unwrap();
+        assert_eq!(array.dim(), (3, 2));
+        assert_eq!(array[[2, 1]], 21.0);
+        assert_eq!(to_grid(array.view()), grid);
+
+        // also from arrays in column-major order, like transposed numpy arrays
+        let fortran = Array2::from_shape_fn((3, 2).f(), |(x, y)| (10 * x + y) as f64);
+        assert_eq!(to_grid(fortran.view()), grid);
+    }
+
+    #[test]
+    fn test_intensity_scaling() {
+        // 12-bit intensities are scaled to the full 8 bits, and unknown stays 0
+        let intensities = numpy::ndarray::arr1(&[0u16, 1, 2048, 4095]);
+        assert_eq!(scale_intensities(intensities.view()), [0, 1, 128, 255]);
+    }
+
+    #[test]
+    fn test_image_conversions() {
+        let image = DynamicImage::from(RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8, y as u8, 7])));
+        let array = image_array(&image).unwrap();
+        // numpy arrays of images are indexed [y, x, channel] like in other image libraries
+        assert_eq!(array.shape(), [2, 3, 3]);
+        assert_eq!(array[[1, 2, 0]], 2);
+        assert_eq!(array[[1, 2, 1]], 1);
+        assert_eq!(array[[1, 2, 2]], 7);
+        assert_eq!(array_image(array.view()).unwrap(), image);
+
+        let gray = DynamicImage::from(GrayImage::from_fn(3, 2, |x, y| [(x + 10 * y) as u8].into()));
+        let array = image_array(&gray).unwrap();
+        assert_eq!(array.shape(), [2, 3]);
+        assert_eq!(array[[1, 2]], 12);
+        assert_eq!(array_image(array.view()).unwrap(), gray);
+
+        assert!(array_image(ArrayD::zeros(IxDyn(&[2, 3, 5])).view()).is_err());
+        assert!(array_image(ArrayD::zeros(IxDyn(&[2])).view()).is_err());
+    }
+}
@@ -4,9 +4,13 @@ use crate::geometry::Classification;
 use crate::geometry::Geometry;
 use crate::geometry::Point2;
 use crate::geometry::Polylines;
-use crate::io::bytes::FromToBytes;
 use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
+use crate::io::worldfile::{GeoRaster, WorldFile};
+use crate::job;
+use crate::pipeline::MapLayers;
+use crate::powerlines::PowerLines;
+use crate::stony;
 use crate::vec2d::Vec2D;
 use image::ImageBuffer;
 use image::Rgba;
@@ -14,11 +18,43 @@ use imageproc::drawing::{draw_filled_circle_mut, draw_line_segment_mut};
 use log::info;
 use std::error::Error;
 use std::f64::consts::PI;
-use std::io::BufRead;
-use std::io::Write;
 use std::path::Path;
 
-pub fn render(
+/// A rendered map.
+#[derive(Debug)]
+pub struct RenderedMap {
+    pub map: GeoRaster,
+    /// Form lines generated while rendering, if enabled.
+    pub formlines: Option<BinaryDxf>,
+}
+
+impl RenderedMap {
+    /// Writes the map into `pullautus{thread}.png` or `pullautus_depr{thread}.png` and its world
+    /// file, and the form lines into `formlines.dxf.bin` in `tmpfolder`.
+    pub fn write_to(
+        &self,
+        fs: &impl FileSystem,
+        config: &Config,
+        thread: &String,
+        tmpfolder: &Path,
+        nodepressions: bool,
+    ) -> anyhow::Result<()> {
+        let filename = if nodepressions {
+            format!("pullautus{thread}.png")
+        } else {
+            format!("pullautus_depr{thread}.png")
+        };
+        self.map.to_file(fs, filename)?;
+        if let Some(formlines) = &self.formlines {
+            formlines.to_file(fs, tmpfolder.join("formlines.dxf.bin"), config.output_dxf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders the map of the layers in `tmpfolder`, see [`MapLayers::read_from`] and
+/// [`RenderedMap::write_to`].
+pub fn render_files(
     fs: &impl FileSystem,
     config: &Config,
     thread: &String,
@@ -27,6 +63,21 @@ pub fn render(
     nwidth: usize,
     nodepressions: bool,
 ) -> Result<(), Box<dyn Error>> {
+    let layers = MapLayers::read_from(fs, tmpfolder)?;
+    let rendered = render(config, &layers, angle_deg, nwidth, nodepressions)?;
+    rendered.write_to(fs, config, thread, tmpfolder, nodepressions)?;
+    Ok(())
+}
+
+/// Renders a map of the layers with north lines of the given angle and width, with or without
+/// depressions.
+pub fn render(
+    config: &Config,
+    layers: &MapLayers,
+    angle_deg: f64,
+    nwidth: usize,
+    nodepressions: bool,
+) -> Result<RenderedMap, Box<dyn Error>> {
     info!("Rendering...");
 
     let scalefactor = config.scalefactor;
@@ -34,36 +85,15 @@ pub fn render(
     let angle = -angle_deg / 180.0 * PI;
 
     // Draw vegetation ----------
-    let tfw_in = tmpfolder.join("vegetation.pgw");
-    let mut lines = fs.open(tfw_in).expect("PGW file does not exist").lines();
-    let x0 = lines
-        .nth(4)
-        .expect("no 4 line")
-        .expect("Could not read line 5")
-        .parse::<f64>()
-        .unwrap();
-    let y0 = lines
-        .next()
-        .expect("no 5 line")
-        .expect("Could not read line 6")
-        .parse::<f64>()
-        .unwrap();
-
-    let mut img_reader = image::ImageReader::new(
-        fs.open(tmpfolder.join("vegetation.png"))
-            .expect("Opening vegetation image failed"),
-    );
-    img_reader.set_format(image::ImageFormat::Png);
-    img_reader.no_limits();
-    let img = img_reader.decode().unwrap();
+    let Some(vegetation) = &layers.vegetation else {
+        return Err(anyhow::anyhow!("rendering a map needs the vegetation layer").into());
+    };
+    let world_file = vegetation.vegetation.world_file;
+    let x0 = world_file.x;
+    let y0 = world_file.y;
 
-    let mut imgug_reader = image::ImageReader::new(
-        fs.open(tmpfolder.join("undergrowth.png"))
-            .expect("Opening undergrowth image failed"),
-    );
-    imgug_reader.set_format(image::ImageFormat::Png);
-    imgug_reader.no_limits();
-    let imgug = imgug_reader.decode().unwrap();
+    let img = &vegetation.vegetation.image;
+    let imgug = &vegetation.undergrowth.image;
 
     let w = img.width();
     let h = img.height();
@@ -75,15 +105,21 @@ pub fn render(
 
     let new_width = (w as f64 * 600.0 / 254.0 / scalefactor) as u32;
     let new_height = (h as f64 * 600.0 / 254.0 / scalefactor) as u32;
+    // the map, the resized undergrowth and one more layer at a time
+    job::reserve(
+        config,
+        "render",
+        3 * job::grid_bytes::<Rgba<u8>>(new_width as usize, new_height as usize),
+    )?;
     let mut img = image::imageops::resize(
-        &img,
+        img,
         new_width,
         new_height,
         image::imageops::FilterType::Nearest,
     );
 
     let imgug = image::imageops::resize(
-        &imgug,
+        imgug,
         new_width,
         new_height,
         image::imageops::FilterType::Nearest,
@@ -91,15 +127,9 @@ pub fn render(
 
     image::imageops::overlay(&mut img, &imgug, 0, 0);
 
-    let low_file = tmpfolder.join("low.png");
-    if fs.exists(&low_file) {
-        let mut low_reader =
-            image::ImageReader::new(fs.open(low_file).expect("Opening low image failed"));
-        low_reader.set_format(image::ImageFormat::Png);
-        low_reader.no_limits();
-        let low = low_reader.decode().unwrap();
+    if let Some(low) = &layers.low {
         let low = image::imageops::resize(
-            &low,
+            low,
             new_width,
             new_height,
             image::imageops::FilterType::Nearest,
@@ -107,6 +137,25 @@ pub fn render(
         image::imageops::overlay(&mut img, &low, 0, 0);
     }
 
+    // marshes -------------
+    if let Some(marshes) = &layers.marshes {
+        let mut marshes = marshes.image.to_rgba8();
+        let marshes = image::imageops::crop(&mut marshes, 0, 0, w, h).to_image();
+        let mut marshes_thumb = image::imageops::resize(
+            &marshes,
+            new_width,
+            new_height,
+            image::imageops::FilterType::Nearest,
+        );
+        // the marsh symbol is horizontal lines, 4 pixels wide every 14 pixels
+        for (_, y, p) in marshes_thumb.enumerate_pixels_mut() {
+            if y % 14 >= 4 {
+                p[3] = 0;
+            }
+        }
+        image::imageops::overlay(&mut img, &marshes_thumb, 0, 0);
+    }
+
     // north lines ----------------
     if angle != 999.0 {
         let mut i: f64 = eastoff - 600.0 * 250.0 / 254.0 / angle.cos() * 100.0 / scalefactor;
@@ -128,72 +177,77 @@ pub fn render(
         }
     }
 
-    draw_curves(fs, config, &mut img, tmpfolder, nodepressions, true).unwrap();
+    let mut formlines = None;
+    if let Some(contours) = &layers.contours {
+        formlines = draw_curves(
+            config,
+            &mut img,
+            &layers.heightmap,
+            &contours.contours,
+            x0,
+            y0,
+            nodepressions,
+            true,
+        )?;
+
+        // dotknolls----------
+        let Some(Geometry::Points(points)) = contours.dotknolls.geometry().first() else {
+            return Err(anyhow::anyhow!("dotknolls.dxf.bin should contain points").into());
+        };
+
+        for (point, layer) in points.iter() {
+            if *layer != Classification::Dotknoll {
+                continue;
+            }
 
-    // dotknolls----------
-    let input = tmpfolder.join("dotknolls.dxf.bin");
-    let data = BinaryDxf::from_reader(&mut fs.open(input)?)?;
-    let Geometry::Points(points) = data.take_geometry().swap_remove(0) else {
-        return Err(anyhow::anyhow!("dotknolls.dxf.bin should contain points").into());
-    };
+            // convert point to image coordinates
+            let x = (point.x - x0) * 600.0 / 254.0 / scalefactor;
+            let y = (y0 - point.y) * 600.0 / 254.0 / scalefactor;
 
-    for (point, layer) in points.iter() {
-        if *layer != Classification::Dotknoll {
-            continue;
+            let color = Rgba([166, 85, 43, 255]);
+            draw_filled_circle_mut(&mut img, (x as i32, y as i32), 7, color)
         }
+    }
 
-        // convert point to image coordinates
-        let x = (point.x - x0) * 600.0 / 254.0 / scalefactor;
-        let y = (y0 - point.y) * 600.0 / 254.0 / scalefactor;
-
-        let color = Rgba([166, 85, 43, 255]);
-        draw_filled_circle_mut(&mut img, (x as i32, y as i32), 7, color)
+    // pits -------------
+    if let Some(pits) = &layers.pits {
+        draw_pits(config, pits, &mut img, x0, y0)?;
     }
     // blocks -------------
-    let blocks_file = tmpfolder.join("blocks.png");
-    if fs.exists(&blocks_file) {
-        let mut blockpurple_reader =
-            image::ImageReader::new(fs.open(blocks_file).expect("Opening blocks image failed"));
-        blockpurple_reader.set_format(image::ImageFormat::Png);
-        blockpurple_reader.no_limits();
-        let blockpurple = blockpurple_reader.decode().unwrap();
-        let mut blockpurple = blockpurple.to_rgba8();
-        for p in blockpurple.pixels_mut() {
-            if p[0] == 255 && p[1] == 255 && p[2] == 255 {
-                p[3] = 0;
+    if let Some(buildings) = &layers.buildings {
+        if config.buildingfootprints {
+            draw_buildings(config, &buildings.footprints, &mut img, x0, y0)?;
+        } else {
+            let mut blockpurple = buildings.image.to_rgba8();
+            for p in blockpurple.pixels_mut() {
+                if p[0] == 255 && p[1] == 255 && p[2] == 255 {
+                    p[3] = 0;
+                }
             }
-        }
-        let blockpurple = image::imageops::crop(&mut blockpurple, 0, 0, w, h).to_image();
-        let blockpurple_thumb = image::imageops::resize(
-            &blockpurple,
-            new_width,
-            new_height,
-            image::imageops::FilterType::Nearest,
-        );
-
-        for i in 0..3 {
-            for j in 0..3 {
-                image::imageops::overlay(
-                    &mut img,
-                    &blockpurple_thumb,
-                    (i as i64 - 1) * 2,
-                    (j as i64 - 1) * 2,
-                );
+            let blockpurple = image::imageops::crop(&mut blockpurple, 0, 0, w, h).to_image();
+            let blockpurple_thumb = image::imageops::resize(
+                &blockpurple,
+                new_width,
+                new_height,
+                image::imageops::FilterType::Nearest,
+            );
+
+            for i in 0..3 {
+                for j in 0..3 {
+                    image::imageops::overlay(
+                        &mut img,
+                        &blockpurple_thumb,
+                        (i as i64 - 1) * 2,
+                        (j as i64 - 1) * 2,
+                    );
+                }
             }
+            image::imageops::overlay(&mut img, &blockpurple_thumb, 0, 0);
         }
-        image::imageops::overlay(&mut img, &blockpurple_thumb, 0, 0);
     }
     // blueblack -------------
-    let blueblack_file = tmpfolder.join("blueblack.png");
-    if fs.exists(&blueblack_file) {
-        let mut imgbb_reader = image::ImageReader::new(
-            fs.open(blueblack_file)
-                .expect("Opening blueblack image failed"),
-        );
-        imgbb_reader.set_format(image::ImageFormat::Png);
-        imgbb_reader.no_limits();
-        let imgbb = imgbb_reader.decode().unwrap();
-        let mut imgbb = imgbb.to_rgba8();
+    if let Some(water) = &vegetation.water {
+        let mut imgbb = water.to_rgba8();
         for p in imgbb.pixels_mut() {
             if p[0] == 255 && p[1] == 255 && p[2] == 255 {
                 p[3] = 0;
@@ -209,21 +263,73 @@ pub fn render(
         image::imageops::overlay(&mut img, &imgbb_thumb, 0, 0);
     }
 
-    draw_cliffs(fs, config, tmpfolder, "c2g.dxf.bin", &mut img, x0, y0)
-        .expect("draw cliffs c2g.dxf.bin");
-    draw_cliffs(fs, config, tmpfolder, "c3g.dxf.bin", &mut img, x0, y0)
-        .expect("draw cliffs c3g.dxf.bin");
+    // water -------------
+    if let Some(water_bodies) = &layers.water_bodies {
+        draw_water(config, water_bodies, &mut img, x0, y0)?;
+    }
+
+    // streams -------------
+    if let Some(streams) = &layers.streams {
+        draw_streams(config, streams, &mut img, x0, y0)?;
+    }
+
+    // stony ground -------------
+    if let Some(stony_ground) = &layers.stony_ground {
+        let mut stony = stony_ground.image.to_rgba8();
+        let stony = image::imageops::crop(&mut stony, 0, 0, w, h).to_image();
+        let stony_thumb = image::imageops::resize(
+            &stony,
+            new_width,
+            new_height,
+            image::imageops::FilterType::Nearest,
+        );
+        // stony ground is a sparse pattern of small dots and boulder fields a dense pattern of
+        // big dots, every other row shifted by half a step
+        let black = Rgba([0, 0, 0, 255]);
+        for (color, step, radius) in [
+            (stony::STONY_GROUND_COLOR, 24, 3),
+            (stony::BOULDER_FIELD_COLOR, 16, 4),
+        ] {
+            for (row, y) in (step / 2..new_height).step_by(step as usize).enumerate() {
+                let shift = if row % 2 == 0 { 0 } else { step / 2 };
+                for x in (shift + step / 4..new_width).step_by(step as usize) {
+                    if *stony_thumb.get_pixel(x, y) == color {
+                        draw_filled_circle_mut(&mut img, (x as i32, y as i32), radius, black);
+                    }
+                }
+            }
+        }
+    }
+
+    // earth banks and gullies -------------
+    if let Some(banks) = &layers.banks {
+        draw_banks(config, banks, &mut img, x0, y0)?;
+    }
+
+    // paths -------------
+    if let Some(paths) = &layers.paths {
+        draw_paths(config, paths, &mut img, x0, y0)?;
+    }
+
+    // power lines -------------
+    if let Some(power_lines) = &layers.power_lines {
+        draw_power_lines(config, power_lines, &mut img, x0, y0)?;
+    }
+
+    if let Some(cliffs) = &layers.cliffs {
+        draw_cliffs(config, &cliffs.small, &mut img, x0, y0)?;
+        draw_cliffs(config, &cliffs.big, &mut img, x0, y0)?;
+    }
+
+    // boulders -------------
+    if let Some(boulders) = &layers.boulders {
+        draw_boulders(config, boulders, &mut img, x0, y0)?;
+    }
 
     // high -------------
-    let high_file = tmpfolder.join("high.png");
-    if fs.exists(&high_file) {
-        let mut high_reader =
-            image::ImageReader::new(fs.open(high_file).expect("Opening high image failed"));
-        high_reader.set_format(image::ImageFormat::Png);
-        high_reader.no_limits();
-        let high = high_reader.decode().unwrap();
+    if let Some(high) = &layers.high {
         let high_thumb = image::imageops::resize(
-            &high,
+            high,
             new_width,
             new_height,
             image::imageops::FilterType::Nearest,
@@ -231,60 +337,43 @@ pub fn render(
         image::imageops::overlay(&mut img, &high_thumb, 0, 0);
     }
 
-    let filename = if nodepressions {
-        format!("pullautus{thread}")
-    } else {
-        format!("pullautus_depr{thread}")
+    let resolution = 254.0 / 600.0 * scalefactor;
+    let map = GeoRaster {
+        image: img.into(),
+        world_file: WorldFile {
+            x_scale: world_file.x_scale * resolution,
+            y_scale: world_file.y_scale * resolution,
+            ..world_file
+        },
     };
+    info!("Done");
+    Ok(RenderedMap { map, formlines })
+}
 
-    img.write_to(
-        &mut fs
-            .create(format!("{filename}.png"))
-            .expect("could not save output png"),
-        image::ImageFormat::Png,
+/// Scales and flips a point in map coordinates into the pixel coordinates of the map image with
+/// the top left corner at `x0`, `y0`.
+fn to_pixel(config: &Config, x0: f64, y0: f64, p: &Point2) -> Point2 {
+    Point2::new(
+        (p.x - x0) * 600.0 / 254.0 / config.scalefactor,
+        (y0 - p.y) * 600.0 / 254.0 / config.scalefactor,
     )
-    .expect("could not write image");
-
-    let file_in = tmpfolder.join("vegetation.pgw");
-    let mut pgw_file_out = fs
-        .create(format!("{filename}.pgw"))
-        .expect("Unable to create file");
-
-    if let Ok(lines) = fs.open(file_in) {
-        for (i, line) in lines.lines().enumerate() {
-            let ip = line.unwrap_or(String::new());
-            let x: f64 = ip.parse::<f64>().unwrap();
-            if i == 0 || i == 3 {
-                write!(&mut pgw_file_out, "{}\r\n", x / 600.0 * 254.0 * scalefactor)
-                    .expect("Unable to write to file");
-            } else {
-                write!(&mut pgw_file_out, "{ip}\r\n").expect("Unable to write to file");
-            }
-        }
-    }
-    info!("Done");
-    Ok(())
 }
 
 fn draw_cliffs(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-    file: &str,
+    dxf: &BinaryDxf,
     img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
     x0: f64,
     y0: f64,
 ) -> Result<(), Box<dyn Error>> {
     let scalefactor = config.scalefactor;
 
-    let input = tmpfolder.join(file);
-    let dxf = BinaryDxf::from_reader(&mut fs.open(input)?)?;
-
-    let Geometry::Polylines2(lines) = dxf.take_geometry().swap_remove(0) else {
+    let Some(Geometry::Polylines2(lines)) = dxf.geometry().first() else {
         return Err(anyhow::anyhow!("cliff data should contain polylines").into());
     };
 
-    for (mut line, class) in lines.into_iter() {
+    for (line, class) in lines.iter() {
+        let mut line = line.clone();
         // based on the layer we select the cliffcolor
         let cliffcolor = if config.cliffdebug {
             match class {
@@ -344,14 +433,391 @@ fn draw_cliffs(
     Ok(())
 }
 
+fn draw_streams(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let blue = Rgba([0, 10, 220, 255]);
+
+    let Some(Geometry::Polylines2(lines)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("stream data should contain polylines").into());
+    };
+
+    for (line, class) in lines.iter() {
+        // width and the dash and gap lengths in pixels
+        let (width, dash) = match class {
+            Classification::Stream => (6, None),
+            Classification::MinorStream => (4, None),
+            _ => (4, Some((24.0, 12.0))),
+        };
+
+        let line = line
+            .iter()
+            .map(|p| to_pixel(config, x0, y0, p))
+            .collect::<Vec<_>>();
+        draw_polyline(img, &line, width, dash, blue);
+    }
+    Ok(())
+}
+
+fn draw_banks(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let brown = Rgba([166, 85, 43, 255]);
+
+    let Some(Geometry::Polylines2(lines)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("bank data should contain polylines").into());
+    };
+
+    for (line, class) in lines.iter() {
+        let line = line
+            .iter()
+            .map(|p| to_pixel(config, x0, y0, p))
+            .collect::<Vec<_>>();
+        match class {
+            Classification::EarthBank => {
+                draw_polyline(img, &line, 3, None, brown);
+                // ticks on the lower side, on the right of the line
+                for (p, (dx, dy)) in points_along(&line, 14.0) {
+                    let tick = [p.clone(), Point2::new(p.x - 9.0 * dy, p.y + 9.0 * dx)];
+                    draw_polyline(img, &tick, 3, None, brown);
+                }
+            }
+            Classification::ErosionGully => draw_polyline(img, &line, 6, None, brown),
+            _ => {
+                for (p, _) in points_along(&line, 12.0) {
+                    draw_filled_circle_mut(img, (p.x as i32, p.y as i32), 3, brown);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn draw_paths(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let black = Rgba([0, 0, 0, 255]);
+
+    let Some(Geometry::Polylines3(lines)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("path data should contain 3D polylines").into());
+    };
+
+    for (line, _) in lines.iter() {
+        let line = line
+            .iter()
+            .map(|p| to_pixel(config, x0, y0, &Point2::new(p.x, p.y)))
+            .collect::<Vec<_>>();
+        draw_polyline(img, &line, 3, Some((20.0, 8.0)), black);
+    }
+    Ok(())
+}
+
+fn draw_power_lines(
+    config: &Config,
+    power_lines: &PowerLines,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let black = Rgba([0, 0, 0, 255]);
+
+    // a missing half of the layer has no geometry
+    if let Some(lines) = power_lines.lines.geometry().first() {
+        let Geometry::Polylines2(lines) = lines else {
+            return Err(anyhow::anyhow!("power line data should contain 2D polylines").into());
+        };
+        for (line, _) in lines.iter() {
+            let line = line
+                .iter()
+                .map(|p| to_pixel(config, x0, y0, p))
+                .collect::<Vec<_>>();
+            draw_polyline(img, &line, 5, None, black);
+        }
+    }
+
+    if let Some(pylons) = power_lines.pylons.geometry().first() {
+        let Geometry::Points(points) = pylons else {
+            return Err(anyhow::anyhow!("pylons.dxf.bin should contain points").into());
+        };
+        // a filled square for each pylon
+        let squares = points
+            .iter()
+            .map(|(point, _)| {
+                let p = to_pixel(config, x0, y0, point);
+                vec![
+                    Point2::new(p.x - 7.0, p.y - 7.0),
+                    Point2::new(p.x + 7.0, p.y - 7.0),
+                    Point2::new(p.x + 7.0, p.y + 7.0),
+                    Point2::new(p.x - 7.0, p.y + 7.0),
+                    Point2::new(p.x - 7.0, p.y - 7.0),
+                ]
+            })
+            .collect::<Vec<_>>();
+        fill_rings(img, &squares, black);
+    }
+    Ok(())
+}
+
+/// The points every `step` pixels along a polyline, starting half a step from its start, with
+/// the unit direction of the line at each point.
+fn points_along(line: &[Point2], step: f64) -> Vec<(Point2, (f64, f64))> {
+    let mut points = Vec::new();
+    // the distance along the line to the next point
+    let mut next = step / 2.0;
+    for segment in line.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+        let length = ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+        if length == 0.0 {
+            continue;
+        }
+        let direction = ((b.x - a.x) / length, (b.y - a.y) / length);
+        while next < length {
+            let p = Point2::new(a.x + direction.0 * next, a.y + direction.1 * next);
+            points.push((p, direction));
+            next += step;
+        }
+        next -= length;
+    }
+    points
+}
+
+fn draw_boulders(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let black = Rgba([0, 0, 0, 255]);
+
+    let Some(Geometry::Points(points)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("boulders.dxf.bin should contain points").into());
+    };
+
+    for (point, class) in points.iter() {
+        let p = to_pixel(config, x0, y0, point);
+        let (x, y) = (p.x as i32, p.y as i32);
+
+        match class {
+            Classification::BoulderCluster => {
+                // a triangle pointing up
+                let corners = [
+                    imageproc::point::Point::new(x, y - 10),
+                    imageproc::point::Point::new(x + 9, y + 6),
+                    imageproc::point::Point::new(x - 9, y + 6),
+                ];
+                imageproc::drawing::draw_polygon_mut(img, &corners, black);
+            }
+            Classification::LargeBoulder => draw_filled_circle_mut(img, (x, y), 7, black),
+            _ => draw_filled_circle_mut(img, (x, y), 5, black),
+        }
+    }
+    Ok(())
+}
+
+fn draw_pits(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let brown = Rgba([166, 85, 43, 255]);
+
+    let Some(Geometry::Points(points)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("pits.dxf.bin should contain points").into());
+    };
+
+    for (point, class) in points.iter() {
+        let Point2 { x, y } = to_pixel(config, x0, y0, point);
+
+        let symbol = if *class == Classification::Pit {
+            // a v
+            vec![
+                Point2::new(x - 7.0, y - 8.0),
+                Point2::new(x, y + 7.0),
+                Point2::new(x + 7.0, y - 8.0),
+            ]
+        } else {
+            // a u, a half circle with straight sides
+            let mut u = vec![Point2::new(x - 7.0, y - 8.0)];
+            u.extend((0..=8).map(|i| {
+                let angle = std::f64::consts::PI * (1.0 - i as f64 / 8.0);
+                Point2::new(x + 7.0 * angle.cos(), y + 7.0 * angle.sin())
+            }));
+            u.push(Point2::new(x + 7.0, y - 8.0));
+            u
+        };
+        draw_polyline(img, &symbol, 3, None, brown);
+    }
+    Ok(())
+}
+
+fn draw_buildings(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let (r, g, b) = config.buildingcolor;
+
+    let Some(Geometry::Polylines2(lines)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("building data should contain 2D polylines").into());
+    };
+
+    let rings = lines
+        .iter()
+        .map(|(ring, _)| {
+            ring.iter()
+                .map(|p| to_pixel(config, x0, y0, p))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    fill_rings(img, &rings, Rgba([r, g, b, 255]));
+    Ok(())
+}
+
+fn draw_water(
+    config: &Config,
+    dxf: &BinaryDxf,
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x0: f64,
+    y0: f64,
+) -> Result<(), Box<dyn Error>> {
+    let Some(Geometry::Polylines3(lines)) = dxf.geometry().first() else {
+        return Err(anyhow::anyhow!("water data should contain 3D polylines").into());
+    };
+
+    let rings = lines
+        .iter()
+        .map(|(ring, _)| {
+            ring.iter()
+                .map(|p| to_pixel(config, x0, y0, &Point2::new(p.x, p.y)))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    fill_rings(img, &rings, Rgba([29, 190, 255, 255]));
+    for ring in &rings {
+        draw_polyline(img, ring, 4, None, Rgba([0, 0, 0, 255]));
+    }
+    Ok(())
+}
+
+/// Fills the areas inside closed polylines of pixel coordinates with the even-odd rule, so the
+/// polylines inside other polylines are holes.
+fn fill_rings(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, rings: &[Vec<Point2>], color: Rgba<u8>) {
+    let mut crossings = Vec::new();
+    for py in 0..img.height() {
+        // the pixel centers
+        let y = py as f64 + 0.5;
+        crossings.clear();
+        for segment in rings.iter().flat_map(|ring| ring.windows(2)) {
+            let (a, b) = (&segment[0], &segment[1]);
+            if (a.y <= y) != (b.y <= y) {
+                crossings.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x));
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            let start = (span[0] - 0.5).ceil().max(0.0) as u32;
+            let end = ((span[1] - 0.5).floor() + 1.0).clamp(0.0, img.width() as f64) as u32;
+            for px in start..end {
+                img.put_pixel(px, py, color);
+            }
+        }
+    }
+}
+
+/// Draws a polyline of pixel coordinates `width` pixels wide, optionally dashed with the given
+/// dash and gap lengths.
+fn draw_polyline(
+    img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    line: &[Point2],
+    width: usize,
+    dash: Option<(f64, f64)>,
+    color: Rgba<u8>,
+) {
+    let offset = width as f64 / 2.0;
+    let mut draw_segment = |a: &Point2, b: &Point2| {
+        for n in 0..width {
+            for m in 0..width {
+                draw_line_segment_mut(
+                    img,
+                    (
+                        (a.x + n as f64 - offset).floor() as f32,
+                        (a.y + m as f64 - offset).floor() as f32,
+                    ),
+                    (
+                        (b.x + n as f64 - offset).floor() as f32,
+                        (b.y + m as f64 - offset).floor() as f32,
+                    ),
+                    color,
+                )
+            }
+        }
+    };
+
+    let Some((dashlength, gaplength)) = dash else {
+        for segment in line.windows(2) {
+            draw_segment(&segment[0], &segment[1]);
+        }
+        return;
+    };
+
+    // the distance along the line from the start of the current dash
+    let period = dashlength + gaplength;
+    let mut phase = 0.0;
+    for segment in line.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+        let length = ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+        let at = |t: f64| Point2 {
+            x: a.x + (b.x - a.x) * t / length,
+            y: a.y + (b.y - a.y) * t / length,
+        };
+        let mut t = 0.0;
+        while t < length {
+            let (end, drawn) = if phase < dashlength {
+                ((t + dashlength - phase).min(length), true)
+            } else {
+                ((t + period - phase).min(length), false)
+            };
+            if drawn {
+                draw_segment(&at(t), &at(end));
+            }
+            phase = (phase + end - t) % period;
+            t = end;
+        }
+    }
+}
+
+/// Draws the contours `out2` on the canvas, whose upper left corner is at `x0`, `y0`, and returns
+/// the form lines if they are enabled.
+#[allow(clippy::too_many_arguments)]
 pub fn draw_curves(
-    fs: &impl FileSystem,
     config: &Config,
     canvas: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
-    tmpfolder: &Path,
+    hmap: &HeightMap,
+    out2: &BinaryDxf,
+    x0: f64,
+    y0: f64,
     nodepressions: bool,
     draw_image: bool,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<Option<BinaryDxf>, Box<dyn Error>> {
     // Drawing curves --------------
     let &Config {
         scalefactor,
@@ -370,24 +836,6 @@ pub fn draw_curves(
     let mut size: f64 = 0.0;
     let mut xstart: f64 = 0.0;
     let mut ystart: f64 = 0.0;
-    let tfw_in = tmpfolder.join("vegetation.pgw");
-    let mut lines = fs.open(tfw_in).expect("PGW file does not exist").lines();
-    let x0 = lines
-        .nth(4)
-        .expect("no 4 line")
-        .expect("Could not read line 5")
-        .parse::<f64>()
-        .unwrap();
-    let y0 = lines
-        .next()
-        .expect("no 5 line")
-        .expect("Could not read line 6")
-        .parse::<f64>()
-        .unwrap();
-
-    let heightmap_in = tmpfolder.join("xyz2.hmap");
-    let mut reader = fs.open(heightmap_in)?;
-    let hmap = HeightMap::from_bytes(&mut reader)?;
     let xyz = &hmap.grid;
 
     let mut steepness = Vec2D::new(xyz.width(), xyz.height(), 0f64);
@@ -498,19 +946,17 @@ pub fn draw_curves(
         }
     }
 
-    // read the binary file
-
-    let input_dxf = BinaryDxf::from_reader(&mut fs.open(tmpfolder.join("out2.dxf.bin"))?)
-        .expect("Unable to read out2.dxf.bin");
-    let bounds = input_dxf.bounds().clone();
-    let Geometry::Polylines3(input_lines) = input_dxf.take_geometry().swap_remove(0) else {
+    let bounds = out2.bounds().clone();
+    let Some(Geometry::Polylines3(input_lines)) = out2.geometry().first() else {
         return Err(anyhow::anyhow!("out2.dxf.bin does not contain polylines").into());
     };
 
     let should_generate_formlines = formline == 2.0 && !nodepressions;
     let mut formlines = Polylines::<Point2, Classification>::new();
 
-    for (mut line, (layer, _height)) in input_lines.into_iter() {
+    for (line, &(layer, _height)) in input_lines.iter() {
+        job::check_cancelled()?;
+        let mut line = line.clone();
         // flip and scale the line points
         for p in line.iter_mut() {
             p.x = (p.x - x0) * 600.0 / 254.0 / scalefactor;
@@ -860,15 +1306,7 @@ pub fn draw_curves(
     }
 
     if should_generate_formlines {
-        let out_formlines = BinaryDxf::new(bounds, vec![formlines.into()]);
-        out_formlines
-            .to_writer(&mut fs.create(tmpfolder.join("formlines.dxf.bin"))?)
-            .expect("Could not write formlines.dxf.bin");
-
-        if config.output_dxf {
-            out_formlines.to_dxf(&mut fs.create(tmpfolder.join("formlines.dxf"))?)?;
-        }
+        return Ok(Some(BinaryDxf::new(bounds, vec![formlines.into()])));
     }
-
-    Ok(())
+    Ok(None)
 }
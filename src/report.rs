@@ -0,0 +1,327 @@
+//! JSON reports describing what a run did, for reproducing maps and tracking performance.
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::geometry::Bounds;
+use crate::io::fs::FileSystem;
+use crate::io::xyz::XyzInternalReader;
+use crate::util::Timing;
+
+/// The name of the report written into the temp folder by [`crate::process::process_tile`].
+pub const TILE_REPORT_FILE: &str = "report.json";
+
+/// The name of the report summarizing all tiles in the batch output folder.
+pub const BATCH_REPORT_FILE: &str = "batch_report.json";
+
+/// Report of the processing of a single tile.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileReport {
+    pub version: String,
+    /// Seconds since the unix epoch when the report was written.
+    pub created: u64,
+    /// The files the points were read from. In batch mode the tile comes first, followed by the
+    /// neighbouring tiles read as a buffer around it.
+    pub input_files: Vec<InputFile>,
+    pub bounds: Option<Bounds>,
+    /// The coordinate reference system of the input as WKT or `EPSG:<code>`, if known.
+    pub crs: Option<String>,
+    /// Number of points per LAS classification. In batch mode these include the points of the
+    /// neighbouring tiles read as a buffer around the tile.
+    pub point_counts: BTreeMap<u8, u64>,
+    pub timings: Vec<StageTiming>,
+    pub total_seconds: f64,
+    /// Peak resident memory of the whole process so far, if the platform supports it. With
+    /// several threads this is shared by all the tiles processed at the same time, so it is not
+    /// the memory used by this tile.
+    #[serde(alias = "peak_memory_bytes")]
+    pub process_peak_memory_bytes: Option<u64>,
+    pub produced_files: Vec<ProducedFile>,
+    /// The full effective configuration.
+    pub config: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTiming {
+    pub name: String,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProducedFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Report summarizing all tile reports in the batch output folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub version: String,
+    pub created: u64,
+    pub tiles: Vec<String>,
+    /// The tiles recorded as failed by the isolated worker processes.
+    #[serde(default)]
+    pub failed_tiles: Vec<String>,
+    /// The sum of the point counts of the tiles, so the buffer points are counted in every tile
+    /// they are read for.
+    pub point_counts: BTreeMap<u8, u64>,
+    /// The total time spent in each stage over all tiles.
+    pub timings: Vec<StageTiming>,
+    pub total_seconds: f64,
+    /// The highest process-wide peak memory of any tile report.
+    #[serde(alias = "peak_memory_bytes")]
+    pub process_peak_memory_bytes: Option<u64>,
+    pub config: serde_json::Value,
+}
+
+impl TileReport {
+    /// Creates a report for a tile that has been processed into `tmpfolder` using the given
+    /// timing. The point counts and bounds are taken from the `xyztemp.xyz.bin` file.
+    pub fn new(
+        fs: &impl FileSystem,
+        config: &Config,
+        thread: &String,
+        tmpfolder: &Path,
+        input_file: &Path,
+        timing: &Timing,
+    ) -> anyhow::Result<Self> {
+        let (point_counts, bounds) = count_points(fs, &tmpfolder.join("xyztemp.xyz.bin"))?;
+
+        let crs = if is_las(input_file) {
+            let reader = las::Reader::new(fs.open(input_file)?)?;
+            las_crs(reader.header())
+        } else {
+            None
+        };
+
+        let mut produced_files = Vec::new();
+        let mut outputs = fs.list(tmpfolder)?;
+        outputs.extend(
+            [
+                format!("pullautus{thread}.png"),
+                format!("pullautus{thread}.pgw"),
+                format!("pullautus_depr{thread}.png"),
+                format!("pullautus_depr{thread}.pgw"),
+            ]
+            .map(PathBuf::from),
+        );
+        for path in outputs {
+            if fs.exists(&path) && !path.ends_with(TILE_REPORT_FILE) {
+                produced_files.push(ProducedFile {
+                    size: fs.file_size(&path)?,
+                    path: path.display().to_string(),
+                });
+            }
+        }
+        produced_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: now(),
+            input_files: vec![InputFile::new(fs, input_file)?],
+            bounds,
+            crs,
+            point_counts,
+            timings: timing
+                .sections()
+                .iter()
+                .map(|(name, duration)| StageTiming {
+                    name: name.to_string(),
+                    seconds: duration.as_secs_f64(),
+                })
+                .collect(),
+            total_seconds: timing.elapsed().as_secs_f64(),
+            process_peak_memory_bytes: peak_memory(),
+            produced_files,
+            config: serde_json::to_value(config)?,
+        })
+    }
+
+    pub fn from_file(fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_reader(fs.open(path)?)?)
+    }
+
+    pub fn to_file(&self, fs: &impl FileSystem, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        serde_json::to_writer_pretty(fs.create(path)?, self)?;
+        Ok(())
+    }
+}
+
+impl InputFile {
+    /// Describes a file by its size and SHA-256 hash.
+    pub fn new(fs: &impl FileSystem, path: &Path) -> io::Result<Self> {
+        let mut reader = fs.open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; crate::ONE_MEGABYTE];
+        let mut size = 0;
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            size += n as u64;
+        }
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        Ok(Self {
+            path: path.display().to_string(),
+            size,
+            sha256,
+        })
+    }
+}
+
+/// Combines all tile reports in the batch output folder into a single batch report.
+pub fn write_batch_report(fs: &impl FileSystem, config: &Config) -> anyhow::Result<()> {
+    let batchoutfolder = Path::new(&config.batchoutfolder);
+
+    let mut report = BatchReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created: now(),
+        tiles: Vec::new(),
+        failed_tiles: Vec::new(),
+        point_counts: BTreeMap::new(),
+        timings: Vec::new(),
+        total_seconds: 0.0,
+        process_peak_memory_bytes: None,
+        config: serde_json::to_value(config)?,
+    };
+
+    let mut paths = fs.list(batchoutfolder)?;
+    paths.sort();
+    for path in paths {
+        let filename = path.file_name().unwrap().to_string_lossy();
+        if let Some(tile) = filename.strip_suffix(crate::worker::FAILURE_SUFFIX) {
+            report.failed_tiles.push(tile.to_string());
+            continue;
+        }
+        let Some(tile) = filename.strip_suffix(".report.json") else {
+            continue;
+        };
+        let tile_report = TileReport::from_file(fs, &path)?;
+
+        report.tiles.push(tile.to_string());
+        for (class, count) in tile_report.point_counts {
+            *report.point_counts.entry(class).or_default() += count;
+        }
+        for timing in tile_report.timings {
+            match report.timings.iter_mut().find(|t| t.name == timing.name) {
+                Some(t) => t.seconds += timing.seconds,
+                None => report.timings.push(timing),
+            }
+        }
+        report.total_seconds += tile_report.total_seconds;
+        report.process_peak_memory_bytes = report
+            .process_peak_memory_bytes
+            .max(tile_report.process_peak_memory_bytes);
+    }
+
+    info!(
+        "Writing report of {} tiles to {BATCH_REPORT_FILE}",
+        report.tiles.len()
+    );
+    serde_json::to_writer_pretty(fs.create(batchoutfolder.join(BATCH_REPORT_FILE))?, &report)?;
+    Ok(())
+}
+
+/// Counts the points per classification and their bounds in an internal xyz file.
+fn count_points(
+    fs: &impl FileSystem,
+    path: &Path,
+) -> anyhow::Result<(BTreeMap<u8, u64>, Option<Bounds>)> {
+    let mut counts = BTreeMap::new();
+    let mut bounds: Option<Bounds> = None;
+
+    let mut reader = XyzInternalReader::new(fs.open(path)?)?;
+    while let Some(chunk) = reader.next_chunk()? {
+        for r in chunk {
+            *counts.entry(r.classification).or_default() += 1;
+            let b = bounds.get_or_insert(Bounds::new(r.x, r.x, r.y, r.y));
+            b.xmin = b.xmin.min(r.x);
+            b.xmax = b.xmax.max(r.x);
+            b.ymin = b.ymin.min(r.y);
+            b.ymax = b.ymax.max(r.y);
+        }
+    }
+    Ok((counts, bounds))
+}
+
+/// Returns the coordinate reference system of a LAS file as WKT, or as an EPSG code from the
+/// GeoTIFF keys.
+pub fn las_crs(header: &las::Header) -> Option<String> {
+    if let Some(wkt) = header.get_wkt_crs_bytes() {
+        return Some(
+            String::from_utf8_lossy(wkt)
+                .trim_end_matches('\0')
+                .to_string(),
+        );
+    }
+
+    // ProjectedCSTypeGeoKey, falling back to GeographicTypeGeoKey
+    let geotiff = header.get_geotiff_crs().ok()??;
+    [3072, 2048].iter().find_map(|&id| {
+        geotiff.entries.iter().find_map(|e| match e.data {
+            las::crs::GeoTiffData::U16(code) if e.id == id && code != 0 && code != 32767 => {
+                Some(format!("EPSG:{code}"))
+            }
+            _ => None,
+        })
+    })
+}
+
+fn is_las(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+    name.ends_with(".laz") || name.ends_with(".las")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The peak resident set size of the process in bytes. Only available on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::fs::memory::MemoryFileSystem;
+    use std::io::Write;
+
+    #[test]
+    fn test_input_file_hash() {
+        let fs = MemoryFileSystem::new();
+        fs.create("input.xyz").unwrap().write_all(b"abc").unwrap();
+
+        let input = InputFile::new(&fs, Path::new("input.xyz")).unwrap();
+        assert_eq!(input.size, 3);
+        assert_eq!(
+            input.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,946 @@
+//! A small HTTP server processing uploaded point clouds into maps.
+//!
+//! Jobs are submitted by posting a .las, .laz or .xyz file, either as the raw request body or as
+//! the `file` field of a `multipart/form-data` form, with config overrides as query parameters or
+//! other form fields. Uploads are streamed into a [`MemoryFileSystem`] of their own, and a fixed
+//! number of worker threads run [`process::process_tile`] on each job in it, so nothing is written
+//! to disk, and the outputs are kept in memory until the job is deleted.
+//!
+//! | Request                          | Response                                     |
+//! |----------------------------------|----------------------------------------------|
+//! | `GET /`                          | an upload form                               |
+//! | `POST /jobs?name=tile.laz&k=v`   | `202` with the [`JobStatus`] of the new job  |
+//! | `GET /jobs`                      | the [`JobStatus`] of all jobs                |
+//! | `GET /jobs/<id>`                 | the [`JobStatus`] of a job                   |
+//! | `GET /jobs/<id>/outputs/<name>`  | an output file, like `map.png`               |
+//! | `GET /jobs/<id>/outputs.zip`     | all outputs in a zip archive                 |
+//! | `DELETE /jobs/<id>`              | cancels the job and removes it               |
+use ini::Ini;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::config::Config;
+use crate::geometry::BinaryDxf;
+use crate::io::fs::FileSystem;
+use crate::io::fs::memory::MemoryFileSystem;
+use crate::job::{self, CancellationToken};
+use crate::process;
+
+/// The largest accepted upload.
+const MAX_UPLOAD_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// How many jobs may wait for a worker before new jobs are rejected.
+const MAX_QUEUED_JOBS: usize = 32;
+
+/// How many bytes the uploads waiting for a worker may take in total before new jobs are rejected.
+const MAX_QUEUED_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The largest accepted form field other than the file.
+const MAX_FIELD_BYTES: u64 = 64 * 1024;
+
+/// How many requests are handled at the same time, so a slow upload doesn't block the others.
+const HANDLER_THREADS: usize = 4;
+
+/// How many finished jobs are kept before the oldest ones are removed.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// The files copied from the workspace of a job into its outputs, with their output names.
+const OUTPUT_FILES: [(&str, &str); 8] = [
+    ("pullautus.png", "map.png"),
+    ("pullautus.pgw", "map.pgw"),
+    ("pullautus_depr.png", "map_depr.png"),
+    ("pullautus_depr.pgw", "map_depr.pgw"),
+    ("temp/vegetation.png", "vegetation.png"),
+    ("temp/vegetation.pgw", "vegetation.pgw"),
+    ("temp/undergrowth.png", "undergrowth.png"),
+    ("temp/undergrowth.pgw", "undergrowth.pgw"),
+];
+
+/// The vector outputs of a job, converted to dxf, with their output names like in batch mode.
+const DXF_OUTPUT_FILES: [(&str, &str); 19] = [
+    ("temp/out2.dxf.bin", "contours.dxf"),
+    ("temp/contours03.dxf.bin", "contours03.dxf"),
+    ("temp/c2g.dxf.bin", "c2g.dxf"),
+    ("temp/c3g.dxf.bin", "c3g.dxf"),
+    ("temp/dotknolls.dxf.bin", "dotknolls.dxf"),
+    ("temp/detected.dxf.bin", "detected.dxf"),
+    ("temp/formlines.dxf.bin", "formlines.dxf"),
+    ("temp/basemap.dxf.bin", "basemap.dxf"),
+    ("temp/streams.dxf.bin", "streams.dxf"),
+    ("temp/marshes.dxf.bin", "marshes.dxf"),
+    ("temp/water.dxf.bin", "water.dxf"),
+    ("temp/boulders.dxf.bin", "boulders.dxf"),
+    ("temp/stony.dxf.bin", "stony.dxf"),
+    ("temp/pits.dxf.bin", "pits.dxf"),
+    ("temp/banks.dxf.bin", "banks.dxf"),
+    ("temp/paths.dxf.bin", "paths.dxf"),
+    ("temp/blocks.dxf.bin", "blocks.dxf"),
+    ("temp/powerlines.dxf.bin", "powerlines.dxf"),
+    ("temp/pylons.dxf.bin", "pylons.dxf"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// The status of a job as returned by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    /// The name of the uploaded file.
+    pub file: String,
+    pub state: JobState,
+    /// Why the job failed.
+    pub error: Option<String>,
+    /// The config options overridden for this job.
+    pub overrides: BTreeMap<String, String>,
+    /// Seconds since the unix epoch when the job was submitted, started and finished.
+    pub created: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    /// The names of the output files, once the job is done.
+    pub outputs: Vec<String>,
+}
+
+struct Job {
+    status: JobStatus,
+    /// The workspace with the uploaded file until a worker takes the job.
+    input: Option<(MemoryFileSystem, QueuedBytes)>,
+    outputs: BTreeMap<String, Arc<Vec<u8>>>,
+    token: CancellationToken,
+}
+
+struct Shared {
+    base: Ini,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    queue: Mutex<VecDeque<u64>>,
+    available: Condvar,
+    next_id: AtomicU64,
+    stopped: AtomicBool,
+    /// The size of the uploads waiting for a worker.
+    queued_bytes: Arc<AtomicU64>,
+}
+
+/// The job server. See the [module documentation](self).
+pub struct Server {
+    http: tiny_http::Server,
+    shared: Arc<Shared>,
+}
+
+impl Server {
+    /// Starts `workers` worker threads and listens on `addr`. Every job uses the configuration in
+    /// `base` with its overrides applied.
+    pub fn bind(addr: &str, base: Ini, workers: usize) -> io::Result<Self> {
+        let http = tiny_http::Server::http(addr).map_err(io::Error::other)?;
+        let shared = Arc::new(Shared {
+            base,
+            jobs: Mutex::new(BTreeMap::new()),
+            queue: Mutex::new(VecDeque::new()),
+            available: Condvar::new(),
+            next_id: AtomicU64::new(1),
+            stopped: AtomicBool::new(false),
+            queued_bytes: Arc::new(AtomicU64::new(0)),
+        });
+        for _ in 0..workers.max(1) {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.work());
+        }
+        Ok(Self { http, shared })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Handles requests on [`HANDLER_THREADS`] threads until [`Server::stop`] is called.
+    pub fn run(&self) {
+        std::thread::scope(|scope| {
+            for _ in 0..HANDLER_THREADS {
+                scope.spawn(|| {
+                    loop {
+                        let request = match self.http.recv() {
+                            _ if self.shared.stopped.load(Ordering::Relaxed) => break,
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("Could not receive request: {e}");
+                                continue;
+                            }
+                        };
+                        if let Err(e) = self.handle(request) {
+                            warn!("Could not respond to request: {e}");
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Stops handling requests, cancels the running jobs and stops the workers.
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        for job in self.shared.jobs.lock().unwrap().values() {
+            job.token.cancel();
+        }
+        self.shared.available.notify_all();
+        for _ in 0..HANDLER_THREADS {
+            self.http.unblock();
+        }
+    }
+
+    fn handle(&self, mut request: Request) -> io::Result<()> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let method = request.method().clone();
+
+        let response = match (&method, segments.as_slice()) {
+            (Method::Get, []) => Response::from_string(UPLOAD_FORM)
+                .with_header(header("Content-Type", "text/html; charset=utf-8")),
+            (Method::Get, ["jobs"]) => {
+                let jobs = self.shared.jobs.lock().unwrap();
+                json(200, &jobs.values().map(|j| &j.status).collect::<Vec<_>>())
+            }
+            (Method::Post, ["jobs"]) => match self.submit(&mut request, query) {
+                Ok(status) => json(202, &status)
+                    .with_header(header("Location", &format!("/jobs/{}", status.id))),
+                Err((code, message)) => error(code, &message),
+            },
+            (Method::Get, ["jobs", id]) => match self.with_job(id, |job| json(200, &job.status)) {
+                Some(response) => response,
+                None => error(404, "no such job"),
+            },
+            (Method::Delete, ["jobs", id]) => {
+                let removed = id.parse().ok().and_then(|id: u64| {
+                    self.shared
+                        .queue
+                        .lock()
+                        .unwrap()
+                        .retain(|queued| *queued != id);
+                    self.shared.jobs.lock().unwrap().remove(&id)
+                });
+                match removed {
+                    Some(job) => {
+                        job.token.cancel();
+                        Response::from_data(Vec::new()).with_status_code(204)
+                    }
+                    None => error(404, "no such job"),
+                }
+            }
+            (Method::Get, ["jobs", id, "outputs.zip"]) => {
+                match self.with_job(id, |job| (job.status.state, job.outputs.clone())) {
+                    Some((JobState::Done, outputs)) => match zip_outputs(&outputs) {
+                        Ok(data) => Response::from_data(data)
+                            .with_header(header("Content-Type", "application/zip"))
+                            .with_header(header(
+                                "Content-Disposition",
+                                &format!("attachment; filename=\"job{id}.zip\""),
+                            )),
+                        Err(e) => error(500, &e.to_string()),
+                    },
+                    Some(_) => error(409, "the job is not done"),
+                    None => error(404, "no such job"),
+                }
+            }
+            (Method::Get, ["jobs", id, "outputs", name]) => {
+                match self.with_job(id, |job| job.outputs.get(*name).cloned()) {
+                    Some(Some(data)) => Response::from_data(data.as_slice())
+                        .with_header(header("Content-Type", content_type(name))),
+                    _ => error(404, "no such output"),
+                }
+            }
+            (Method::Get | Method::Post | Method::Delete, _) => error(404, "not found"),
+            _ => error(405, "method not allowed"),
+        };
+        request.respond(response)
+    }
+
+    fn with_job<R>(&self, id: &str, f: impl FnOnce(&Job) -> R) -> Option<R> {
+        let id = id.parse::<u64>().ok()?;
+        self.shared.jobs.lock().unwrap().get(&id).map(f)
+    }
+
+    /// Reads an uploaded file and queues a job for it.
+    fn submit(&self, request: &mut Request, query: &str) -> Result<JobStatus, (u16, String)> {
+        let length = request.body_length().unwrap_or(0) as u64;
+        if length > MAX_UPLOAD_BYTES {
+            return Err((413, "the file is too large".to_string()));
+        }
+        if self.shared.queued_bytes.load(Ordering::Relaxed) + length > MAX_QUEUED_BYTES {
+            return Err((503, busy().to_string()));
+        }
+
+        let mut fields: BTreeMap<String, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (percent_decode(k), percent_decode(v)))
+            .collect();
+        let content_type = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Content-Type"))
+            .map(|h| h.value.to_string())
+            .unwrap_or_default();
+        let fs = MemoryFileSystem::new();
+        let mut queued = QueuedBytes::new(&self.shared.queued_bytes);
+        let file = match content_type.split_once("boundary=") {
+            Some((mime, boundary)) if mime.starts_with("multipart/form-data") => {
+                let mut form = Multipart::new(request.as_reader(), boundary.trim_matches('"'));
+                let mut file = None;
+                while let Some(part) = form.next_part().map_err(upload_error)? {
+                    if let ("file", Some(file_name)) = (part.name.as_str(), &part.file_name) {
+                        let name = input_name(file_name)?;
+                        let mut writer = fs.create(&name).map_err(|e| (500, e.to_string()))?;
+                        form.copy_to(&mut queued.writer(&mut writer), MAX_UPLOAD_BYTES)
+                            .map_err(upload_error)?;
+                        drop(writer);
+                        file = Some(name);
+                        continue;
+                    }
+                    let mut data = Vec::new();
+                    form.copy_to(&mut data, MAX_FIELD_BYTES)
+                        .map_err(upload_error)?;
+                    match (part.name.as_str(), part.file_name) {
+                        // lines of an ini file
+                        ("config", None) => {
+                            for line in String::from_utf8_lossy(&data).lines() {
+                                if let Some((k, v)) = line.split_once('=') {
+                                    fields.insert(k.trim().to_string(), v.trim().to_string());
+                                }
+                            }
+                        }
+                        (name, None) => {
+                            let value = String::from_utf8_lossy(&data).trim().to_string();
+                            if !value.is_empty() {
+                                fields.insert(name.to_string(), value);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                file.ok_or((400, "the form has no file field".to_string()))?
+            }
+            _ => {
+                let name = input_name(fields.get("name").map_or("input.laz", |name| name))?;
+                let mut writer = fs.create(&name).map_err(|e| (500, e.to_string()))?;
+                let size = io::copy(
+                    &mut request.as_reader().take(MAX_UPLOAD_BYTES + 1),
+                    &mut queued.writer(&mut writer),
+                )
+                .map_err(upload_error)?;
+                if size > MAX_UPLOAD_BYTES {
+                    return Err((413, "the file is too large".to_string()));
+                }
+                drop(writer);
+                name
+            }
+        };
+        fields.remove("name");
+
+        self.shared
+            .config(&fields)
+            .map_err(|e| (400, format!("invalid config: {e}")))?;
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_JOBS {
+            return Err((
+                503,
+                "too many jobs are waiting, try again later".to_string(),
+            ));
+        }
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let status = JobStatus {
+            id,
+            file,
+            state: JobState::Queued,
+            error: None,
+            overrides: fields,
+            created: now(),
+            started: None,
+            finished: None,
+            outputs: Vec::new(),
+        };
+        info!("Job {id}: queued {}", status.file);
+        jobs.insert(
+            id,
+            Job {
+                status: status.clone(),
+                input: Some((fs, queued)),
+                outputs: BTreeMap::new(),
+                token: CancellationToken::new(),
+            },
+        );
+        queue.push_back(id);
+        self.shared.available.notify_one();
+        Ok(status)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Shared {
+    /// The configuration of a job.
+    fn config(&self, overrides: &BTreeMap<String, String>) -> Result<Config, String> {
+        let mut ini = self.base.clone();
+        for (key, value) in overrides {
+            ini.with_general_section().set(key.as_str(), value.as_str());
+        }
+        Config::from_ini(&ini).map_err(|e| e.to_string())
+    }
+
+    /// Runs the queued jobs until the server is stopped.
+    fn work(&self) {
+        loop {
+            let id = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    if self.stopped.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    match queue.pop_front() {
+                        Some(id) => break id,
+                        None => queue = self.available.wait(queue).unwrap(),
+                    }
+                }
+            };
+
+            let Some((status, input, token)) = self.jobs.lock().unwrap().get_mut(&id).map(|job| {
+                job.status.state = JobState::Running;
+                job.status.started = Some(now());
+                (job.status.clone(), job.input.take(), job.token.clone())
+            }) else {
+                continue;
+            };
+            info!("Job {id}: processing {}", status.file);
+            // the upload is no longer waiting
+            let Some((fs, _)) = input else {
+                continue;
+            };
+            let result = self
+                .config(&status.overrides)
+                .and_then(|config| run_job(&config, &fs, &status.file, &token));
+
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(job) = jobs.get_mut(&id) else {
+                // deleted while running
+                continue;
+            };
+            job.status.finished = Some(now());
+            match result {
+                Ok(outputs) => {
+                    info!("Job {id}: done");
+                    job.status.state = JobState::Done;
+                    job.status.outputs = outputs.keys().cloned().collect();
+                    job.outputs = outputs;
+                }
+                Err(e) => {
+                    warn!("Job {id}: failed: {e}");
+                    job.status.state = JobState::Failed;
+                    job.status.error = Some(e);
+                }
+            }
+
+            let finished: Vec<u64> = jobs
+                .values()
+                .filter(|job| matches!(job.status.state, JobState::Done | JobState::Failed))
+                .map(|job| job.status.id)
+                .collect();
+            for id in finished
+                .iter()
+                .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+            {
+                jobs.remove(id);
+            }
+        }
+    }
+}
+
+/// Processes the uploaded `file` in its workspace `fs`, returning the outputs.
+fn run_job(
+    config: &Config,
+    fs: &MemoryFileSystem,
+    file: &str,
+    token: &CancellationToken,
+) -> Result<BTreeMap<String, Arc<Vec<u8>>>, String> {
+    let input_file = Path::new(file);
+    let tmpfolder = Path::new("temp");
+
+    // a panic only fails the job
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        job::with_cancellation(token, || {
+            process::process_tile(fs, config, &String::new(), tmpfolder, input_file, false)
+                .map_err(|e| e.to_string())
+        })
+    }));
+    match result {
+        Ok(result) => result?,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "processing panicked".to_string());
+            return Err(message);
+        }
+    }
+
+    let mut outputs = BTreeMap::new();
+    for (path, name) in OUTPUT_FILES {
+        if fs.exists(path) {
+            let mut data = Vec::new();
+            fs.open(path)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .map_err(|e| e.to_string())?;
+            outputs.insert(name.to_string(), Arc::new(data));
+        }
+    }
+    for (path, name) in DXF_OUTPUT_FILES {
+        if fs.exists(path) {
+            let mut data = Vec::new();
+            let mut reader = fs.open(path).map_err(|e| e.to_string())?;
+            BinaryDxf::from_reader(&mut reader)
+                .and_then(|dxf| dxf.to_dxf(&mut data))
+                .map_err(|e| e.to_string())?;
+            outputs.insert(name.to_string(), Arc::new(data));
+        }
+    }
+    Ok(outputs)
+}
+
+fn zip_outputs(outputs: &BTreeMap<String, Arc<Vec<u8>>>) -> zip::result::ZipResult<Vec<u8>> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for (name, data) in outputs {
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(data)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// The name of an uploaded file without its directories, if it's a .las, .laz or .xyz file.
+fn input_name(name: &str) -> Result<String, (u16, String)> {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let lowercase = name.to_lowercase();
+    if ![".las", ".laz", ".xyz"]
+        .iter()
+        .any(|extension| lowercase.ends_with(extension))
+    {
+        return Err((400, "expected a .las, .laz or .xyz file".to_string()));
+    }
+    Ok(name)
+}
+
+fn busy() -> io::Error {
+    io::Error::new(
+        ErrorKind::QuotaExceeded,
+        "too many jobs are waiting, try again later",
+    )
+}
+
+/// The response to a failed upload.
+fn upload_error(e: io::Error) -> (u16, String) {
+    let code = match e.kind() {
+        ErrorKind::FileTooLarge => 413,
+        ErrorKind::QuotaExceeded => 503,
+        _ => 400,
+    };
+    (code, e.to_string())
+}
+
+/// The size of an upload waiting for a worker, counted in the total of [`Shared::queued_bytes`]
+/// until dropped.
+struct QueuedBytes {
+    total: Arc<AtomicU64>,
+    bytes: u64,
+}
+
+impl QueuedBytes {
+    fn new(total: &Arc<AtomicU64>) -> Self {
+        Self {
+            total: total.clone(),
+            bytes: 0,
+        }
+    }
+
+    /// Wraps `writer` to count the bytes written, failing once the uploads waiting would take
+    /// more than [`MAX_QUEUED_BYTES`].
+    fn writer<'a, W: Write>(&'a mut self, writer: &'a mut W) -> impl Write + 'a {
+        struct Counted<'a, W> {
+            queued: &'a mut QueuedBytes,
+            writer: &'a mut W,
+        }
+        impl<W: Write> Write for Counted<'_, W> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let len = buf.len() as u64;
+                self.queued.bytes += len;
+                if self.queued.total.fetch_add(len, Ordering::Relaxed) + len > MAX_QUEUED_BYTES {
+                    return Err(busy());
+                }
+                self.writer.write_all(buf)?;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.writer.flush()
+            }
+        }
+        Counted {
+            queued: self,
+            writer,
+        }
+    }
+}
+
+impl Drop for QueuedBytes {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// The headers of a part of a `multipart/form-data` body.
+struct Part {
+    name: String,
+    file_name: Option<String>,
+}
+
+/// Reads a `multipart/form-data` body part by part, without keeping whole parts in memory.
+struct Multipart<R> {
+    reader: R,
+    /// The line break and the boundary that end each part.
+    delimiter: Vec<u8>,
+    /// Bytes read but not consumed yet.
+    buffer: Vec<u8>,
+    /// Whether the data of a part, or the preamble, is next.
+    in_data: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // the first boundary has no line break before it
+            buffer: b"\r\n".to_vec(),
+            in_data: true,
+        }
+    }
+
+    /// Reads the headers of the next part, skipping the data of the previous part if it wasn't
+    /// read. Returns `None` at the end of the form.
+    fn next_part(&mut self) -> io::Result<Option<Part>> {
+        if self.in_data {
+            self.copy_to(&mut io::sink(), u64::MAX)?;
+        }
+        while self.buffer.len() < 2 {
+            self.fill()?;
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(None);
+        }
+        let end = loop {
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buffer.len() as u64 > MAX_FIELD_BYTES {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "the headers of a form field are too long",
+                ));
+            }
+            self.fill()?;
+        };
+        let headers = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+        self.buffer.drain(..end + 4);
+        self.in_data = true;
+
+        let disposition = headers
+            .lines()
+            .find(|line| line.to_lowercase().starts_with("content-disposition"))
+            .unwrap_or_default();
+        Ok(Some(Part {
+            name: parameter(disposition, "name").unwrap_or_default(),
+            file_name: parameter(disposition, "filename"),
+        }))
+    }
+
+    /// Copies the data of the current part to `out`, failing if it's longer than `limit`.
+    fn copy_to(&mut self, out: &mut impl Write, limit: u64) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let found = find(&self.buffer, &self.delimiter);
+            // keep what could be the start of the delimiter
+            let end = found.unwrap_or(self.buffer.len().saturating_sub(self.delimiter.len() - 1));
+            copied += end as u64;
+            if copied > limit {
+                return Err(io::Error::new(
+                    ErrorKind::FileTooLarge,
+                    "the file is too large",
+                ));
+            }
+            out.write_all(&self.buffer[..end])?;
+            if found.is_some() {
+                self.buffer.drain(..end + self.delimiter.len());
+                self.in_data = false;
+                return Ok(copied);
+            }
+            self.buffer.drain(..end);
+            self.fill()?;
+        }
+    }
+
+    /// Reads more of the body, failing at its end.
+    fn fill(&mut self) -> io::Result<()> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + 64 * 1024, 0);
+        let read = self.reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+        match read? {
+            0 => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the form ended before its last boundary",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Gets a parameter like `name="file"` of a header value.
+fn parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';').find_map(|p| {
+        let (key, value) = p.trim().split_once('=')?;
+        (key == name).then(|| value.trim_matches('"').to_string())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("invalid header")
+}
+
+fn json(code: u16, value: &impl Serialize) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(serde_json::to_vec_pretty(value).expect("could not serialize"))
+        .with_status_code(code)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error(code: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json(code, &serde_json::json!({ "error": message }))
+}
+
+fn content_type(name: &str) -> &'static str {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        Some("dxf") => "application/dxf",
+        _ => "text/plain",
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+const UPLOAD_FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Karttapullautin</title></head>
+<body>
+<h1>Karttapullautin</h1>
+<form method="post" action="/jobs" enctype="multipart/form-data">
+<p><input type="file" name="file" accept=".las,.laz,.xyz" required></p>
+<p>Config overrides, one <code>option=value</code> per line:<br>
+<textarea name="config" rows="6" cols="40"></textarea></p>
+<p><input type="submit" value="Make map"></p>
+</form>
+<p>The job status is at <code>/jobs/&lt;id&gt;</code> and the finished map at
+<code>/jobs/&lt;id&gt;/outputs/map.png</code>. All jobs are listed at <a href="/jobs">/jobs</a>.</p>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A reader returning one byte at a time, so the boundaries are split between reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(1);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_multipart() {
+        let body = b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"config\"\r\n\r\n\
+            scalefactor=2\r\n--xyz\r\nContent-Disposition: form-data; name=\"skipped\"\r\n\r\n\
+            value\r\n--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.laz\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\x00\r\n--xy\x01\r\n--xyz--\r\n";
+        for whole in [true, false] {
+            let reader: Box<dyn Read> = match whole {
+                true => Box::new(&body[..]),
+                false => Box::new(Trickle(body)),
+            };
+            let mut form = Multipart::new(reader, "xyz");
+            let part = form.next_part().unwrap().unwrap();
+            assert_eq!(part.name, "config");
+            let mut data = Vec::new();
+            form.copy_to(&mut data, MAX_FIELD_BYTES).unwrap();
+            assert_eq!(data, b"scalefactor=2");
+
+            let part = form.next_part().unwrap().unwrap();
+            assert_eq!(part.name, "skipped");
+            let part = form.next_part().unwrap().unwrap();
+            assert_eq!(part.file_name.as_deref(), Some("a.laz"));
+            let mut data = Vec::new();
+            form.copy_to(&mut data, MAX_FIELD_BYTES).unwrap();
+            assert_eq!(data, b"\x00\r\n--xy\x01");
+            assert!(form.next_part().unwrap().is_none());
+        }
+
+        let mut form = Multipart::new(&body[..], "xyz");
+        form.next_part().unwrap();
+        let e = form.copy_to(&mut Vec::new(), 4).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FileTooLarge);
+        let mut form = Multipart::new(&body[..body.len() - 12], "xyz");
+        let e = std::iter::from_fn(|| form.next_part().transpose())
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        assert_eq!(percent_decode("a%20b+c%2"), "a b c%2");
+    }
+
+    #[test]
+    fn test_queued_bytes() {
+        let total = Arc::new(AtomicU64::new(MAX_QUEUED_BYTES - 4));
+        let mut queued = QueuedBytes::new(&total);
+        let mut data = Vec::new();
+        queued.writer(&mut data).write_all(b"abc").unwrap();
+        assert_eq!(total.load(Ordering::Relaxed), MAX_QUEUED_BYTES - 1);
+        let e = queued.writer(&mut data).write_all(b"de").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::QuotaExceeded);
+        assert_eq!(data, b"abc");
+        drop(queued);
+        assert_eq!(total.load(Ordering::Relaxed), MAX_QUEUED_BYTES - 4);
+    }
+
+    /// Sends a request and returns the status code and the body of the response.
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let code = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (code, body)
+    }
+
+    #[test]
+    fn test_submit_and_list_jobs() {
+        let base = Ini::load_from_file("pullauta.default.ini").unwrap();
+        // no workers pick up the jobs once the server is stopped
+        let server = Arc::new(Server::bind("127.0.0.1:0", base, 1).unwrap());
+        server.shared.stopped.store(true, Ordering::Relaxed);
+        server.shared.available.notify_all();
+        let addr = server.local_addr().unwrap();
+        let handle = {
+            let server = server.clone();
+            std::thread::spawn(move || {
+                for request in server.http.incoming_requests() {
+                    server.handle(request).unwrap();
+                }
+            })
+        };
+
+        let (code, body) = request(addr, "POST", "/jobs?name=tile.txt", b"");
+        assert_eq!(code, 400, "{body}");
+        let (code, body) = request(addr, "POST", "/jobs?name=tile.laz&scalefactor=2", b"data");
+        assert_eq!(code, 202, "{body}");
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["state"], "queued");
+        assert_eq!(status["overrides"]["scalefactor"], "2");
+        assert_eq!(server.shared.queued_bytes.load(Ordering::Relaxed), 4);
+
+        let (code, body) = request(addr, "GET", "/jobs", b"");
+        assert_eq!(code, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()[0]["id"],
+            1
+        );
+        let (code, _) = request(addr, "GET", "/jobs/1/outputs.zip", b"");
+        assert_eq!(code, 409);
+        let (code, _) = request(addr, "DELETE", "/jobs/1", b"");
+        assert_eq!(code, 204);
+        assert_eq!(server.shared.queued_bytes.load(Ordering::Relaxed), 0);
+        let (code, _) = request(addr, "GET", "/jobs/1", b"");
+        assert_eq!(code, 404);
+
+        server.http.unblock();
+        handle.join().unwrap();
+    }
+}
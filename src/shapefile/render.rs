@@ -18,11 +18,14 @@ use crate::{
 use shapefile::dbase::{FieldValue, Record};
 use shapefile::{Polygon, Polyline, Shape, ShapeType};
 
+/// Draws the shape files on the map of the tile in `tmpfolder`. With `mounted` the shape files are
+/// read from the zip archives mounted in `temp_shapefiles`, otherwise they are read from
+/// `tmpfolder` and removed once drawn.
 pub fn render(
     fs: &impl FileSystem,
     config: &Config,
     tmpfolder: &Path,
-    batch: bool,
+    mounted: bool,
 ) -> Result<(), Box<dyn Error>> {
     let low_file = tmpfolder.join("low.png");
     if fs.exists(&low_file) {
@@ -103,7 +106,7 @@ pub fn render(
     let marsh = (0, 10, 220);
     let olive = (194, 176, 33);
 
-    let shapetmpfolder = if batch {
+    let shapetmpfolder = if mounted {
         PathBuf::from("temp_shapefiles".to_string())
     } else {
         tmpfolder.to_path_buf()
@@ -733,13 +736,13 @@ pub fn render(
         debug!("Time elapsed in drawing shapes: {elapsed:.2?}");
         total_elapsed += elapsed;
 
-        // remove the shapefile and all associated files
-        if !batch {
+        // remove the shapefile and all associated files, the mounted archives are read-only
+        if !mounted {
             fs.remove_file(&file).unwrap();
             for ext in ["dbf", "sbx", "prj", "shx", "sbn", "cpg", "qmd"].iter() {
                 file.set_extension(ext);
                 if fs.exists(&file) {
-                    println!("Removing file: {file:?}");
+                    info!("Removing file: {file:?}");
                     fs.remove_file(&file).unwrap();
                 }
             }
@@ -0,0 +1,305 @@
+use image::{DynamicImage, Rgba};
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// The color of stony ground in the raster of [`StonyGround`].
+pub const STONY_GROUND_COLOR: Rgba<u8> = Rgba([128, 128, 128, 255]);
+/// The color of boulder fields in the raster of [`StonyGround`].
+pub const BOULDER_FIELD_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// The fewest ground points in a cell for its roughness to be measured.
+const MIN_POINTS: u32 = 6;
+/// Cells steeper than this are left out, as their roughness comes from cliffs and steps.
+const MAX_SLOPE: f64 = 1.0;
+
+/// The sums needed to fit a plane to the ground points of a cell by least squares, with the
+/// coordinates relative to the cell center and the heights relative to the heightmap.
+#[derive(Debug, Clone, Copy, Default)]
+struct Moments {
+    n: u32,
+    x: f64,
+    y: f64,
+    z: f64,
+    xx: f64,
+    xy: f64,
+    yy: f64,
+    xz: f64,
+    yz: f64,
+    zz: f64,
+}
+
+impl Moments {
+    fn add(&mut self, x: f64, y: f64, z: f64) {
+        self.n += 1;
+        self.x += x;
+        self.y += y;
+        self.z += z;
+        self.xx += x * x;
+        self.xy += x * y;
+        self.yy += y * y;
+        self.xz += x * z;
+        self.yz += y * z;
+        self.zz += z * z;
+    }
+
+    /// The root mean square of the residuals from the plane fitted to the points.
+    fn residual(&self) -> f64 {
+        let n = self.n as f64;
+        // the normal equations of z = a + b * x + c * y
+        let m = [
+            [n, self.x, self.y],
+            [self.x, self.xx, self.xy],
+            [self.y, self.xy, self.yy],
+        ];
+        let rhs = [self.z, self.xz, self.yz];
+        let det = det3(&m);
+        let sum_of_squares = if det.abs() < 1e-9 {
+            // the points are on a line, so only the mean can be fitted
+            self.zz - self.z * self.z / n
+        } else {
+            let solve = |column: usize| {
+                let mut m = m;
+                for (row, value) in rhs.iter().enumerate() {
+                    m[row][column] = *value;
+                }
+                det3(&m) / det
+            };
+            let (a, b, c) = (solve(0), solve(1), solve(2));
+            self.zz - a * self.z - b * self.xz - c * self.yz
+        };
+        (sum_of_squares.max(0.0) / n).sqrt()
+    }
+}
+
+fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// The output of the stony ground detection.
+#[derive(Debug, Clone)]
+pub struct StonyGround {
+    /// Stony ground and boulder fields, using the grid of the heightmap with 1 pixel per meter,
+    /// with [`STONY_GROUND_COLOR`] and [`BOULDER_FIELD_COLOR`].
+    pub image: DynamicImage,
+    /// The outlines of the stony ground and the boulder fields.
+    pub outlines: BinaryDxf,
+}
+
+/// Detects stony ground and boulder fields from the micro-roughness of the ground points, as a
+/// raster and as outlines.
+///
+/// The roughness of a cell of the heightmap is the root mean square of the residuals
+/// of its ground points from a plane fitted to them, after subtracting the heightmap. Cells
+/// rougher than `stonyroughness` meters are stony ground, and cells rougher than
+/// `boulderfieldroughness` are boulder fields. Areas smaller than `stonyminarea` square meters
+/// are dropped.
+pub fn makestony(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<StonyGround, Box<dyn Error>> {
+    info!("Detecting stony ground...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the moments, the roughness and the masks
+    job::reserve(
+        config,
+        "makestony",
+        job::grid_bytes::<Moments>(w, h)
+            + job::grid_bytes::<f64>(w, h)
+            + 4 * job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let moments = ground_moments(points, hmap)?;
+    job::check_cancelled()?;
+    let roughness = roughness(hmap, &moments);
+    drop(moments);
+
+    let min_cells = (config.stonyminarea / (hmap.scale * hmap.scale)).ceil() as usize;
+    let mask = |limit: f64| {
+        let mut mask = Vec2D::new(w, h, false);
+        for (x, y, r) in roughness.iter() {
+            mask[(x, y)] = r >= limit;
+        }
+        let mut mask = areas::majority_filter(&mask);
+        areas::remove_small_areas(&mut mask, min_cells);
+        mask
+    };
+    let boulder_field = mask(config.boulderfieldroughness);
+    let mut stony = mask(config.stonyroughness);
+    for (x, y, b) in boulder_field.iter() {
+        if b {
+            stony[(x, y)] = false;
+        }
+    }
+
+    let img = areas::mask_image(
+        hmap,
+        &[
+            (&boulder_field, BOULDER_FIELD_COLOR),
+            (&stony, STONY_GROUND_COLOR),
+        ],
+    );
+
+    let mut lines = Polylines::new();
+    for (mask, class) in [
+        (&stony, Classification::StonyGround),
+        (&boulder_field, Classification::BoulderField),
+    ] {
+        for area in areas::components(mask) {
+            for ring in areas::outlines(hmap, &area, 1) {
+                lines.push(ring, class);
+            }
+        }
+    }
+    info!("Done");
+    Ok(StonyGround {
+        image: DynamicImage::ImageRgba8(img),
+        outlines: BinaryDxf::new(
+            Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+            vec![lines.into()],
+        ),
+    })
+}
+
+/// Collects the moments of the ground points of each cell of the heightmap, with the heights
+/// relative to the heightmap interpolated at the points.
+fn ground_moments(points: &PointCloud, hmap: &HeightMap) -> Result<Vec2D<Moments>, Box<dyn Error>> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let mut moments = Vec2D::new(w, h, Moments::default());
+
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
+        for r in chunk {
+            if r.classification != 2 {
+                continue;
+            }
+            let fx = (r.x - hmap.xoffset) / hmap.scale;
+            let fy = (r.y - hmap.yoffset) / hmap.scale;
+            let (x, y) = (fx.round(), fy.round());
+            if x < 0.0 || y < 0.0 || x >= w as f64 || y >= h as f64 {
+                continue;
+            }
+            let ground = hmap.interpolate(fx, fy);
+            if !ground.is_finite() {
+                continue;
+            }
+            moments[(x as usize, y as usize)].add(
+                (fx - x) * hmap.scale,
+                (fy - y) * hmap.scale,
+                r.z as f64 - ground,
+            );
+        }
+    }
+    Ok(moments)
+}
+
+/// The roughness of each cell averaged over its 3x3 neighbourhood, 0 where it can't be measured.
+fn roughness(hmap: &HeightMap, moments: &Vec2D<Moments>) -> Vec2D<f64> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let mut cell_roughness = Vec2D::new(w, h, f64::NAN);
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let slope = areas::slope(hmap, x, y);
+            if moments[(x, y)].n >= MIN_POINTS && slope < MAX_SLOPE {
+                cell_roughness[(x, y)] = moments[(x, y)].residual();
+            }
+        }
+    }
+
+    let mut roughness = Vec2D::new(w, h, 0.0);
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let (sum, count) = (x - 1..=x + 1)
+                .flat_map(|nx| (y - 1..=y + 1).map(move |ny| (nx, ny)))
+                .map(|cell| cell_roughness[cell])
+                .filter(|r| !r.is_nan())
+                .fold((0.0, 0), |(sum, count), r| (sum + r, count + 1));
+            if count > 0 {
+                roughness[(x, y)] = sum / count as f64;
+            }
+        }
+    }
+    roughness
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::io::xyz::XyzRecord;
+    use std::path::Path;
+
+    #[test]
+    fn test_rough_ground_is_stony_or_boulder_field() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // a slope with smooth ground below x = 40 m, stony ground up to x = 80 m and a boulder
+        // field beyond, with ground points every 25 cm
+        let base = |x: f64, y: f64| 100.0 + 0.1 * x + 0.05 * y;
+        let noise = |x: f64, y: f64| ((x * 12.9898 + y * 78.233).sin() * 43758.5453).fract();
+        let mut grid = Vec2D::new(61, 31, 0.0);
+        for (x, y, z) in grid.iter_mut() {
+            *z = base(2.0 * x as f64, 2.0 * y as f64);
+        }
+        let mut points = PointCloud::default();
+        for i in 0..480 {
+            for j in 0..240 {
+                let (x, y) = (i as f64 * 0.25, j as f64 * 0.25);
+                let amplitude = if x < 40.0 {
+                    0.02
+                } else if x < 80.0 {
+                    0.3
+                } else {
+                    0.8
+                };
+                points.records.push(XyzRecord {
+                    x,
+                    y,
+                    z: (base(x, y) + amplitude * noise(x, y)) as f32,
+                    classification: 2,
+                    ..Default::default()
+                });
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let stony = makestony(&config, &points, &hmap).unwrap();
+
+        let img = stony.image.to_rgba8();
+        assert_eq!(img.dimensions(), (120, 60));
+        assert_eq!(img.get_pixel(20, 30)[3], 0);
+        assert_eq!(*img.get_pixel(60, 30), STONY_GROUND_COLOR);
+        assert_eq!(*img.get_pixel(100, 30), BOULDER_FIELD_COLOR);
+
+        let Geometry::Polylines2(lines) = stony.outlines.take_geometry().swap_remove(0) else {
+            panic!("stony ground should be polylines");
+        };
+        let classes = lines.iter().map(|(_, c)| *c).collect::<Vec<_>>();
+        assert!(classes.contains(&Classification::StonyGround));
+        assert!(classes.contains(&Classification::BoulderField));
+        for (ring, class) in lines.iter() {
+            assert_eq!(ring.first(), ring.last());
+            if *class == Classification::StonyGround {
+                for p in ring {
+                    assert!(p.x > 34.0 && p.x < 86.0, "{p:?}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::config::Config;
+use crate::drainage::Drainage;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point2, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// Channels through filled depressions deeper than this are ponds rather than streams.
+const MAX_FILL_DEPTH: f64 = 0.3;
+
+/// Detects streams and ditches from the heightmap. Channels are the cells draining more than
+/// `streamditcharea` square meters, and they are classified by their catchment area.
+pub fn makestreams(config: &Config, hmap: &HeightMap) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting streams...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the filled heights, receivers, catchment areas, classes and flooding order
+    job::reserve(
+        config,
+        "makestreams",
+        2 * job::grid_bytes::<f64>(w, h)
+            + job::grid_bytes::<Option<(u32, u32)>>(w, h)
+            + job::grid_bytes::<Option<Classification>>(w, h)
+            + job::grid_bytes::<(u32, u32)>(w, h),
+    )?;
+
+    let drainage = Drainage::new(hmap);
+    job::check_cancelled()?;
+    let lines = trace_channels(config, hmap, &drainage);
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![lines.into()],
+    ))
+}
+
+/// The class of a channel cell by its catchment area, `None` if it is not a channel.
+fn channel_class(
+    config: &Config,
+    hmap: &HeightMap,
+    drainage: &Drainage,
+    x: usize,
+    y: usize,
+) -> Option<Classification> {
+    let area = drainage.area[(x, y)];
+    if area < config.streamditcharea || drainage.fill_depth(hmap, x, y) > MAX_FILL_DEPTH {
+        None
+    } else if area < config.streamminorarea {
+        Some(Classification::Ditch)
+    } else if area < config.streamarea {
+        Some(Classification::MinorStream)
+    } else {
+        Some(Classification::Stream)
+    }
+}
+
+/// Traces the channels into polylines, starting a new line at channel heads, confluences and
+/// where the class changes.
+fn trace_channels(
+    config: &Config,
+    hmap: &HeightMap,
+    drainage: &Drainage,
+) -> Polylines<Point2, Classification> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let mut class = Vec2D::new(w, h, None);
+    for x in 0..w {
+        for y in 0..h {
+            class[(x, y)] = channel_class(config, hmap, drainage, x, y);
+        }
+    }
+
+    // the number of channels flowing into each cell, and the class of the last one
+    let mut inflows = Vec2D::new(w, h, (0u8, None));
+    for x in 0..w {
+        for y in 0..h {
+            if let (Some(c), Some((rx, ry))) = (class[(x, y)], drainage.receiver[(x, y)]) {
+                let inflow = &mut inflows[(rx as usize, ry as usize)];
+                inflow.0 += 1;
+                inflow.1 = Some(c);
+            }
+        }
+    }
+    let is_start = |x: usize, y: usize| {
+        let (count, upstream) = inflows[(x, y)];
+        class[(x, y)].is_some() && (count != 1 || upstream != class[(x, y)])
+    };
+
+    let to_world = |(x, y): (usize, usize)| Point2 {
+        x: hmap.xoffset + hmap.scale * x as f64,
+        y: hmap.yoffset + hmap.scale * y as f64,
+    };
+    let mut lines = Polylines::new();
+    for x in 0..w {
+        for y in 0..h {
+            if !is_start(x, y) {
+                continue;
+            }
+            let mut line = vec![to_world((x, y))];
+            let mut cell = (x, y);
+            while let Some((rx, ry)) = drainage.receiver[cell] {
+                let next = (rx as usize, ry as usize);
+                if class[next].is_none() {
+                    break;
+                }
+                line.push(to_world(next));
+                if is_start(next.0, next.1) {
+                    break;
+                }
+                cell = next;
+            }
+
+            let length = line.len() as f64 * hmap.scale;
+            let is_head = inflows[(x, y)].0 == 0;
+            if line.len() < 2 || (is_head && length < config.streamminlength) {
+                continue;
+            }
+            lines.push(areas::smooth_line(line, 2), class[(x, y)].unwrap());
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use std::path::Path;
+
+    #[test]
+    fn test_valley_gets_a_stream() {
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.streamditcharea = 500.0;
+        config.streamminorarea = 2000.0;
+        config.streamarea = 1e9;
+        config.streamminlength = 10.0;
+
+        // a valley along the y-axis at x = 50 m, sloping down towards y = 0
+        let mut grid = Vec2D::new(51, 51, 0.0);
+        for x in 0..51 {
+            for y in 0..51 {
+                grid[(x, y)] = 100.0 + (x as f64 - 25.0).abs() * 0.2 + y as f64 * 0.1;
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let dxf = makestreams(&config, &hmap).unwrap();
+        let Geometry::Polylines2(lines) = dxf.take_geometry().swap_remove(0) else {
+            panic!("streams should be polylines");
+        };
+        let classes = lines.iter().map(|(_, c)| *c).collect::<Vec<_>>();
+        assert!(classes.contains(&Classification::Ditch));
+        assert!(classes.contains(&Classification::MinorStream));
+        assert!(!classes.contains(&Classification::Stream));
+        // the lines follow the valley bottom
+        for (line, _) in lines.iter() {
+            for p in line {
+                assert!((p.x - 50.0).abs() < 1.0, "{p:?}");
+            }
+        }
+    }
+}
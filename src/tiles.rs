@@ -0,0 +1,560 @@
+//! Export of the rendered batch output into a slippy map tile pyramid (`z/x/y.png`).
+//!
+//! The tiles use the projected coordinate system of the input data: zoom level 0 covers the
+//! whole merged map in a single tile and every following level doubles the resolution. The
+//! finest level that does not need upsampling is the one matching the resolution of the input
+//! png files.
+use image::{Pixel, Rgba, RgbaImage};
+use log::info;
+use rustc_hash::FxHashSet as HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::io::fs::FileSystem;
+use crate::io::worldfile::WorldFile;
+use crate::merge::list_map_png_files;
+
+/// The width and height of a single tile in pixels.
+const TILE_SIZE: u32 = 256;
+/// The deepest zoom level, at which the tile numbers still fit in 32 bits.
+const MAX_ZOOM: u32 = 31;
+/// How many zoom levels can be rendered beyond the native resolution, each one quadruples the
+/// number of tiles.
+const MAX_OVERZOOM: u32 = 2;
+
+/// A georeferenced map png in the batch output folder.
+struct SourceImage {
+    path: PathBuf,
+    /// Size of a pixel in map units.
+    res: f64,
+    /// Coordinates of the upper left corner.
+    x0: f64,
+    y0: f64,
+    width: u32,
+    height: u32,
+}
+
+impl SourceImage {
+    fn x1(&self) -> f64 {
+        self.x0 + self.width as f64 * self.res
+    }
+
+    fn y1(&self) -> f64 {
+        self.y0 - self.height as f64 * self.res
+    }
+}
+
+/// The layout of the tile pyramid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pyramid {
+    /// Upper left corner of tile 0/0/0.
+    xmin: f64,
+    ymax: f64,
+    /// The pixel size at zoom level 0.
+    res0: f64,
+    /// The zoom level at which the pixel size equals the finest input resolution.
+    native_zoom: u32,
+}
+
+impl Pyramid {
+    fn new(xmin: f64, ymax: f64, width: f64, height: f64, min_res: f64) -> Self {
+        let extent = width.max(height);
+        let native_zoom = (extent / min_res / TILE_SIZE as f64).log2().ceil().max(0.0) as u32;
+        Self {
+            xmin,
+            ymax,
+            res0: min_res * 2f64.powi(native_zoom as i32),
+            native_zoom,
+        }
+    }
+
+    /// The pixel size at the given zoom level.
+    fn resolution(&self, zoom: u32) -> f64 {
+        self.res0 / 2f64.powi(zoom as i32)
+    }
+
+    /// The width and height of a tile in map units at the given zoom level.
+    fn tile_span(&self, zoom: u32) -> f64 {
+        self.resolution(zoom) * TILE_SIZE as f64
+    }
+}
+
+/// Writes a tile pyramid of the merged map tiles (with or without depressions) in the batch
+/// output folder into `tiles/` (or `tiles_depr/`), together with an `index.html` viewer page.
+///
+/// Zoom levels default to the full range from a single tile up to the native resolution of the
+/// input. With `tms` the tile rows are numbered from the bottom instead of from the top.
+pub fn pngtiles(
+    fs: &impl FileSystem,
+    config: &Config,
+    minzoom: Option<u32>,
+    maxzoom: Option<u32>,
+    tms: bool,
+    depr: bool,
+) -> anyhow::Result<()> {
+    let batchoutfolder = Path::new(&config.batchoutfolder);
+    let outfolder = Path::new(if depr { "tiles_depr" } else { "tiles" });
+
+    let mut sources = Vec::new();
+    for png in list_map_png_files(fs, config, depr)? {
+        let png = batchoutfolder.join(png.file_name().unwrap());
+        let pgw = png.with_extension("pgw");
+        if !fs.exists(&pgw) || fs.file_size(&png)? == 0 {
+            continue;
+        }
+        let world = WorldFile::from_file(fs, &pgw)
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {e}", pgw.display()))?;
+        let (res, x0, y0) = (world.x_scale, world.x, world.y);
+
+        let (width, height) = image::ImageReader::new(fs.open(&png)?)
+            .with_guessed_format()?
+            .into_dimensions()?;
+
+        sources.push(SourceImage {
+            path: png,
+            res,
+            x0,
+            y0,
+            width,
+            height,
+        });
+    }
+
+    if sources.is_empty() {
+        info!("No files to make tiles of found in output directory");
+        return Ok(());
+    }
+
+    let xmin = sources.iter().map(|s| s.x0).fold(f64::MAX, f64::min);
+    let ymax = sources.iter().map(|s| s.y0).fold(f64::MIN, f64::max);
+    let xmax = sources.iter().map(|s| s.x1()).fold(f64::MIN, f64::max);
+    let ymin = sources.iter().map(|s| s.y1()).fold(f64::MAX, f64::min);
+    let min_res = sources.iter().map(|s| s.res).fold(f64::MAX, f64::min);
+
+    let pyramid = Pyramid::new(xmin, ymax, xmax - xmin, ymax - ymin, min_res);
+    let maxzoom = maxzoom.unwrap_or(pyramid.native_zoom);
+    let deepest = (pyramid.native_zoom + MAX_OVERZOOM).min(MAX_ZOOM);
+    if maxzoom > deepest {
+        anyhow::bail!(
+            "The zoom level {maxzoom} is too deep, the deepest level is {deepest} (native resolution at level {})",
+            pyramid.native_zoom
+        );
+    }
+    let minzoom = minzoom.unwrap_or(0).min(maxzoom);
+
+    info!(
+        "Writing tiles for zoom levels {minzoom}-{maxzoom} (native resolution at level {}) into {}",
+        pyramid.native_zoom,
+        outfolder.display()
+    );
+
+    // the finest level is sampled directly from the input images
+    let mut tiles = render_level(fs, outfolder, &pyramid, &sources, maxzoom, xmax, ymin, tms)?;
+    info!("Level {maxzoom}: {} tiles", tiles.len());
+
+    // and every coarser level is downsampled from the level below it
+    for zoom in (minzoom..maxzoom).rev() {
+        tiles = downsample_level(fs, outfolder, &tiles, zoom, tms)?;
+        info!("Level {zoom}: {} tiles", tiles.len());
+    }
+
+    write_viewer(fs, outfolder, &pyramid, minzoom, maxzoom, tms, xmax, ymin)?;
+    info!("Done");
+    Ok(())
+}
+
+/// Renders all non-empty tiles of a zoom level from the source images and returns their
+/// (x, y) indices.
+#[allow(clippy::too_many_arguments)]
+fn render_level(
+    fs: &impl FileSystem,
+    outfolder: &Path,
+    pyramid: &Pyramid,
+    sources: &[SourceImage],
+    zoom: u32,
+    xmax: f64,
+    ymin: f64,
+    tms: bool,
+) -> anyhow::Result<HashSet<(u32, u32)>> {
+    let res = pyramid.resolution(zoom);
+    let span = pyramid.tile_span(zoom);
+    let cols = ((xmax - pyramid.xmin) / span).ceil() as u32;
+    let rows = ((pyramid.ymax - ymin) / span).ceil() as u32;
+
+    let mut written = HashSet::default();
+
+    // Go through the tiles one row at a time and only keep the source images that overlap
+    // the current row in memory.
+    let mut loaded: Vec<(usize, RgbaImage)> = Vec::new();
+    for ty in 0..rows {
+        let top = pyramid.ymax - ty as f64 * span;
+        let bottom = top - span;
+
+        loaded.retain(|(i, _)| sources[*i].y1() < top && sources[*i].y0 > bottom);
+        for (i, source) in sources.iter().enumerate() {
+            if source.y1() < top && source.y0 > bottom && !loaded.iter().any(|(j, _)| *j == i) {
+                loaded.push((i, fs.read_image_png(&source.path)?.to_rgba8()));
+            }
+        }
+
+        for tx in 0..cols {
+            let left = pyramid.xmin + tx as f64 * span;
+            let right = left + span;
+
+            let mut tile = RgbaImage::from_pixel(TILE_SIZE, TILE_SIZE, Rgba([255, 255, 255, 0]));
+            let mut empty = true;
+            for (i, img) in loaded.iter() {
+                let source = &sources[*i];
+                if source.x0 < right && source.x1() > left {
+                    empty &= !draw_source(&mut tile, source, img, left, top, res);
+                }
+            }
+
+            if !empty {
+                write_tile(fs, outfolder, zoom, tx, ty, tms, &tile)?;
+                written.insert((tx, ty));
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Draws the part of a source image that overlaps the tile with the given upper left corner and
+/// pixel size. Each tile pixel is the average of the source pixels it covers. Returns `true` if
+/// any pixel was drawn.
+fn draw_source(
+    tile: &mut RgbaImage,
+    source: &SourceImage,
+    img: &RgbaImage,
+    left: f64,
+    top: f64,
+    res: f64,
+) -> bool {
+    // the size of a tile pixel in source pixels, at least one
+    let footprint = (res / source.res).max(1.0);
+    let mut drawn = false;
+
+    for py in 0..TILE_SIZE {
+        let y = top - (py as f64 + 0.5) * res;
+        let sy = (source.y0 - y) / source.res - footprint / 2.0;
+        if sy + footprint <= 0.0 || sy >= source.height as f64 {
+            continue;
+        }
+        for px in 0..TILE_SIZE {
+            let x = left + (px as f64 + 0.5) * res;
+            let sx = (x - source.x0) / source.res - footprint / 2.0;
+            if sx + footprint <= 0.0 || sx >= source.width as f64 {
+                continue;
+            }
+
+            // a footprint that starts within the last half pixel still covers the last pixel
+            let sx0 = (sx.max(0.0).round() as u32).min(source.width - 1);
+            let sy0 = (sy.max(0.0).round() as u32).min(source.height - 1);
+            let sx1 = ((sx + footprint).round() as u32).clamp(sx0 + 1, source.width);
+            let sy1 = ((sy + footprint).round() as u32).clamp(sy0 + 1, source.height);
+            if sx0 >= sx1 || sy0 >= sy1 {
+                continue;
+            }
+
+            let pixels = (sy0..sy1).flat_map(|y| (sx0..sx1).map(move |x| img.get_pixel(x, y)));
+            if let Some(pixel) = average(pixels) {
+                tile.get_pixel_mut(px, py).blend(&pixel);
+                drawn = true;
+            }
+        }
+    }
+    drawn
+}
+
+/// Builds the tiles of a zoom level from the four child tiles of each tile at the level below.
+fn downsample_level(
+    fs: &impl FileSystem,
+    outfolder: &Path,
+    children: &HashSet<(u32, u32)>,
+    zoom: u32,
+    tms: bool,
+) -> anyhow::Result<HashSet<(u32, u32)>> {
+    let parents: HashSet<(u32, u32)> = children.iter().map(|(x, y)| (x / 2, y / 2)).collect();
+
+    let half = TILE_SIZE / 2;
+    for &(tx, ty) in parents.iter() {
+        let mut tile = RgbaImage::from_pixel(TILE_SIZE, TILE_SIZE, Rgba([255, 255, 255, 0]));
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let (cx, cy) = (tx * 2 + dx, ty * 2 + dy);
+            if !children.contains(&(cx, cy)) {
+                continue;
+            }
+            let child = fs
+                .read_image_png(tile_path(outfolder, zoom + 1, cx, cy, tms))?
+                .to_rgba8();
+
+            for y in 0..half {
+                for x in 0..half {
+                    let pixels = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .map(|(ox, oy)| child.get_pixel(x * 2 + ox, y * 2 + oy));
+                    if let Some(pixel) = average(pixels.into_iter()) {
+                        tile.put_pixel(dx * half + x, dy * half + y, pixel);
+                    }
+                }
+            }
+        }
+        write_tile(fs, outfolder, zoom, tx, ty, tms, &tile)?;
+    }
+    Ok(parents)
+}
+
+/// Averages pixels weighted by their alpha, so that fully transparent pixels do not bleed into
+/// the colors. Returns `None` if all the pixels are transparent.
+fn average<'a>(pixels: impl Iterator<Item = &'a Rgba<u8>>) -> Option<Rgba<u8>> {
+    let mut sum = [0u64; 4];
+    let mut count = 0;
+    for p in pixels {
+        let a = p[3] as u64;
+        sum[0] += p[0] as u64 * a;
+        sum[1] += p[1] as u64 * a;
+        sum[2] += p[2] as u64 * a;
+        sum[3] += a;
+        count += 1;
+    }
+    if sum[3] == 0 {
+        return None;
+    }
+    Some(Rgba([
+        ((sum[0] + sum[3] / 2) / sum[3]) as u8,
+        ((sum[1] + sum[3] / 2) / sum[3]) as u8,
+        ((sum[2] + sum[3] / 2) / sum[3]) as u8,
+        ((sum[3] + count / 2) / count) as u8,
+    ]))
+}
+
+fn tile_path(outfolder: &Path, zoom: u32, tx: u32, ty: u32, tms: bool) -> PathBuf {
+    let ty = if tms { (1 << zoom) - 1 - ty } else { ty };
+    outfolder
+        .join(zoom.to_string())
+        .join(tx.to_string())
+        .join(format!("{ty}.png"))
+}
+
+fn write_tile(
+    fs: &impl FileSystem,
+    outfolder: &Path,
+    zoom: u32,
+    tx: u32,
+    ty: u32,
+    tms: bool,
+    tile: &RgbaImage,
+) -> anyhow::Result<()> {
+    let path = tile_path(outfolder, zoom, tx, ty, tms);
+    fs.create_dir_all(path.parent().unwrap())?;
+    tile.write_to(&mut fs.create(&path)?, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// Writes a self-contained html page for browsing the tiles without any external libraries.
+#[allow(clippy::too_many_arguments)]
+fn write_viewer(
+    fs: &impl FileSystem,
+    outfolder: &Path,
+    pyramid: &Pyramid,
+    minzoom: u32,
+    maxzoom: u32,
+    tms: bool,
+    xmax: f64,
+    ymin: f64,
+) -> anyhow::Result<()> {
+    // the center of the map in pixels at zoom level 0
+    let cx = (xmax - pyramid.xmin) / pyramid.res0 / 2.0;
+    let cy = (pyramid.ymax - ymin) / pyramid.res0 / 2.0;
+
+    let mut f = fs.create(outfolder.join("index.html"))?;
+    write!(
+        f,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Karttapullautin map</title>
+<style>
+html, body {{ margin: 0; height: 100%; overflow: hidden; background: #fff; font-family: sans-serif; }}
+#map {{ position: absolute; inset: 0; cursor: grab; touch-action: none; }}
+#map img {{ position: absolute; width: {size}px; height: {size}px; user-select: none; -webkit-user-drag: none; }}
+#zoom {{ position: absolute; top: 10px; left: 10px; z-index: 1; }}
+#zoom button {{ display: block; width: 30px; height: 30px; font-size: 18px; }}
+</style>
+</head>
+<body>
+<div id="map"></div>
+<div id="zoom"><button id="in">+</button><button id="out">&minus;</button></div>
+<script>
+// pyramid: tile 0/0/0 has its upper left corner at ({xmin}, {ymax}) with {res0} units per pixel
+const MIN_ZOOM = {minzoom}, MAX_ZOOM = {maxzoom}, TMS = {tms}, SIZE = {size};
+const map = document.getElementById("map");
+const tiles = new Map();
+let zoom = MIN_ZOOM, cx = {cx}, cy = {cy};
+
+function draw() {{
+  const scale = 2 ** zoom, n = 2 ** zoom;
+  const w = map.clientWidth, h = map.clientHeight;
+  const ox = cx * scale - w / 2, oy = cy * scale - h / 2;
+  const seen = new Set();
+  for (let y = Math.max(0, Math.floor(oy / SIZE)); y <= Math.min(n - 1, Math.floor((oy + h) / SIZE)); y++) {{
+    for (let x = Math.max(0, Math.floor(ox / SIZE)); x <= Math.min(n - 1, Math.floor((ox + w) / SIZE)); x++) {{
+      const key = zoom + "/" + x + "/" + (TMS ? n - 1 - y : y);
+      seen.add(key);
+      let img = tiles.get(key);
+      if (!img) {{
+        img = document.createElement("img");
+        img.onerror = () => (img.style.visibility = "hidden");
+        img.src = key + ".png";
+        map.appendChild(img);
+        tiles.set(key, img);
+      }}
+      img.style.left = x * SIZE - ox + "px";
+      img.style.top = y * SIZE - oy + "px";
+    }}
+  }}
+  for (const [key, img] of tiles) {{
+    if (!seen.has(key)) {{
+      img.remove();
+      tiles.delete(key);
+    }}
+  }}
+}}
+
+function setZoom(z, px, py) {{
+  z = Math.min(MAX_ZOOM, Math.max(MIN_ZOOM, z));
+  if (z === zoom) return;
+  // keep the point under the cursor in place
+  const before = 2 ** zoom, after = 2 ** z;
+  const dx = px - map.clientWidth / 2, dy = py - map.clientHeight / 2;
+  cx += dx / before - dx / after;
+  cy += dy / before - dy / after;
+  zoom = z;
+  draw();
+}}
+
+let drag = null;
+map.addEventListener("pointerdown", (e) => {{
+  drag = {{ x: e.clientX, y: e.clientY }};
+  map.setPointerCapture(e.pointerId);
+  map.style.cursor = "grabbing";
+}});
+map.addEventListener("pointermove", (e) => {{
+  if (!drag) return;
+  cx -= (e.clientX - drag.x) / 2 ** zoom;
+  cy -= (e.clientY - drag.y) / 2 ** zoom;
+  drag = {{ x: e.clientX, y: e.clientY }};
+  draw();
+}});
+map.addEventListener("pointerup", () => {{
+  drag = null;
+  map.style.cursor = "grab";
+}});
+map.addEventListener("wheel", (e) => {{
+  e.preventDefault();
+  setZoom(zoom + (e.deltaY < 0 ? 1 : -1), e.clientX, e.clientY);
+}}, {{ passive: false }});
+document.getElementById("in").onclick = () => setZoom(zoom + 1, map.clientWidth / 2, map.clientHeight / 2);
+document.getElementById("out").onclick = () => setZoom(zoom - 1, map.clientWidth / 2, map.clientHeight / 2);
+window.addEventListener("resize", draw);
+draw();
+</script>
+</body>
+</html>
+"#,
+        size = TILE_SIZE,
+        xmin = pyramid.xmin,
+        ymax = pyramid.ymax,
+        res0 = pyramid.res0,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pyramid_levels() {
+        // a 1000x600 m area with 0.5 m pixels needs 2000 px, i.e. 8 tiles => level 3
+        let pyramid = Pyramid::new(0.0, 600.0, 1000.0, 600.0, 0.5);
+        assert_eq!(pyramid.native_zoom, 3);
+        assert_eq!(pyramid.resolution(3), 0.5);
+        assert_eq!(pyramid.resolution(0), 4.0);
+        assert_eq!(pyramid.tile_span(0), 1024.0);
+
+        // anything smaller than a tile is level 0
+        let pyramid = Pyramid::new(0.0, 10.0, 10.0, 10.0, 0.5);
+        assert_eq!(pyramid.native_zoom, 0);
+        assert_eq!(pyramid.resolution(0), 0.5);
+    }
+
+    #[test]
+    fn test_draw_source_at_edge() {
+        let source = SourceImage {
+            path: PathBuf::new(),
+            res: 1.0,
+            x0: 0.0,
+            y0: 4.0,
+            width: 4,
+            height: 4,
+        };
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        img.put_pixel(3, 3, Rgba([255, 0, 0, 255]));
+
+        // the first tile pixel starts half a pixel before the right and bottom edges
+        let mut tile = RgbaImage::from_pixel(TILE_SIZE, TILE_SIZE, Rgba([255, 255, 255, 0]));
+        assert!(draw_source(&mut tile, &source, &img, 3.5, 0.5, 1.0));
+        assert_eq!(*tile.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(tile.get_pixel(1, 0)[3], 0);
+    }
+
+    #[test]
+    fn test_maxzoom_is_limited() {
+        use crate::io::fs::memory::MemoryFileSystem;
+        use crate::io::worldfile::GeoRaster;
+
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.batchoutfolder = "out".to_string();
+        // a single tile at the native resolution, level 0
+        GeoRaster {
+            image: image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+                16,
+                16,
+                Rgba([255, 0, 0, 255]),
+            )),
+            world_file: WorldFile::new(1.0, 0.0, 16.0),
+        }
+        .to_file(&fs, "out/a.laz.png")
+        .unwrap();
+
+        assert!(pngtiles(&fs, &config, None, Some(MAX_OVERZOOM + 1), false, false).is_err());
+        pngtiles(&fs, &config, None, Some(MAX_OVERZOOM), false, false).unwrap();
+        assert!(fs.exists(format!("tiles/{MAX_OVERZOOM}/0/0.png")));
+    }
+
+    #[test]
+    fn test_average_ignores_transparent() {
+        let red = Rgba([255, 0, 0, 255]);
+        let clear = Rgba([255, 255, 255, 0]);
+        assert_eq!(
+            average([red, clear, clear, red].iter()),
+            Some(Rgba([255, 0, 0, 128]))
+        );
+        assert_eq!(average([clear, clear].iter()), None);
+    }
+
+    #[test]
+    fn test_tms_rows_are_flipped() {
+        let out = Path::new("tiles");
+        assert_eq!(tile_path(out, 2, 1, 0, false), out.join("2/1/0.png"));
+        assert_eq!(tile_path(out, 2, 1, 0, true), out.join("2/1/3.png"));
+        assert_eq!(
+            tile_path(out, MAX_ZOOM, 0, 0, true),
+            out.join("31/0/2147483647.png")
+        );
+    }
+}
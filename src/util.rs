@@ -2,13 +2,14 @@ use std::{
     fmt::Debug,
     io::{self, BufRead},
     path::Path,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use log::debug;
 
 use crate::io::fs::FileSystem;
+use crate::progress::{self, ProgressEvent, TileContext};
 
 /// Iterates over the lines in a file and calls the callback with a &str reference to each line.
 /// This function does not allocate new strings for each line, as opposed to using
@@ -66,11 +67,14 @@ where
 
 /// Helper struct to time operations. Keeps track of the total time taken until the object is
 /// dropped, as well as timing between individual sub-sections of the operation.
-/// Timing information is printed using debug level log messages.
+/// Timing information is printed using debug level log messages and sent as progress events,
+/// and the durations of all finished sections are kept for reporting.
 pub struct Timing {
     name: &'static str,
+    context: TileContext,
     start: Instant,
     current_section: Option<TimingSection>,
+    sections: Vec<(&'static str, Duration)>,
 }
 
 struct TimingSection {
@@ -82,10 +86,17 @@ impl Timing {
     /// Start a new timing from now.
     pub fn start_now(name: &'static str) -> Self {
         debug!("[timing: {name}] Starting timing");
+        let context = progress::context();
+        progress::emit(ProgressEvent::StageStarted {
+            stage: name.to_string(),
+            context: context.clone(),
+        });
         Self {
             name,
+            context,
             start: Instant::now(),
             current_section: None,
+            sections: Vec::new(),
         }
     }
 
@@ -94,6 +105,10 @@ impl Timing {
         let now = self.end_section().unwrap_or(Instant::now());
 
         debug!("[timing: {}] Entering section '{}'", self.name, name);
+        progress::emit(ProgressEvent::StageStarted {
+            stage: format!("{}/{}", self.name, name),
+            context: self.context.clone(),
+        });
 
         self.current_section = Some(TimingSection { name, start: now })
     }
@@ -110,11 +125,27 @@ impl Timing {
                 s.name,
                 now - s.start
             );
+            progress::emit(ProgressEvent::StageFinished {
+                stage: format!("{}/{}", self.name, s.name),
+                seconds: (now - s.start).as_secs_f64(),
+                context: self.context.clone(),
+            });
+            self.sections.push((s.name, now - s.start));
             Some(now)
         } else {
             None
         }
     }
+
+    /// The name and duration of every finished section, in the order they were run.
+    pub fn sections(&self) -> &[(&'static str, Duration)] {
+        &self.sections
+    }
+
+    /// The total time elapsed since the timing was started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
 }
 
 impl Drop for Timing {
@@ -126,6 +157,11 @@ impl Drop for Timing {
             self.name,
             self.start.elapsed()
         );
+        progress::emit(ProgressEvent::StageFinished {
+            stage: self.name.to_string(),
+            seconds: self.start.elapsed().as_secs_f64(),
+            context: std::mem::take(&mut self.context),
+        });
     }
 }
 
@@ -20,6 +20,26 @@ impl<T> Vec2D<T> {
         }
     }
 
+    /// Creates a grid from data ordered by x and then by y, so that `(x, y)` is at
+    /// `data[x * h + y]`.
+    pub fn from_vec(w: usize, h: usize, data: Vec<T>) -> Vec2D<T> {
+        assert_eq!(
+            data.len(),
+            w * h,
+            "the data does not match the size of the grid"
+        );
+        Vec2D {
+            data: data.into(),
+            w,
+            h,
+        }
+    }
+
+    /// The data ordered like in [`Vec2D::from_vec`].
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
     pub fn width(&self) -> usize {
         self.w
     }
@@ -125,6 +145,14 @@ mod tests {
         assert_eq!(vec2d.data, vec![0; 6].into());
     }
 
+    #[test]
+    fn test_from_vec() {
+        let vec2d: Vec2D<i32> = Vec2D::from_vec(3, 2, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(vec2d[(0, 1)], 1);
+        assert_eq!(vec2d[(2, 0)], 4);
+        assert_eq!(vec2d.as_slice(), &[0, 1, 2, 3, 4, 5]);
+    }
+
     #[test]
     fn test_index() {
         let vec2d: Vec2D<i32> = Vec2D::new(3, 2, 1);
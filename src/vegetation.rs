@@ -5,27 +5,83 @@ use imageproc::rect::Rect;
 use log::info;
 use std::error::Error;
 use std::f32::consts::SQRT_2;
-use std::io::Write;
-use std::path::Path;
 
 use crate::config::{Config, Zone};
-use crate::io::bytes::FromToBytes;
-use crate::io::fs::FileSystem;
 use crate::io::heightmap::HeightMap;
-use crate::io::xyz::XyzInternalReader;
+use crate::io::worldfile::{GeoRaster, WorldFile};
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::progress::Progress;
 use crate::vec2d::Vec2D;
 
+/// The hits of a cell of the undergrowth density grid.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Undergrowth {
+    /// The ground hits, with the hits above the undergrowth counting a little.
+    ugg: f32,
+    /// The undergrowth hits.
+    ug: u32,
+}
+
+impl Undergrowth {
+    /// Counts a point of the given classification `hh` meters above the ground.
+    pub(crate) fn add(&mut self, classification: u8, hh: f64) {
+        if hh > 1.2 {
+            self.ugg += 0.05;
+        } else if classification != 2 && hh > 0.25 {
+            self.ug += 1;
+        } else {
+            self.ugg += 1.0;
+        }
+    }
+
+    /// Adds the hits of another cell to this one.
+    pub(crate) fn merge(&mut self, other: &Undergrowth) {
+        self.ugg += other.ugg;
+        self.ug += other.ug;
+    }
+
+    /// The number of hits, the ground hits counted by their weight.
+    pub(crate) fn hits(&self) -> f64 {
+        self.ug as f64 + self.ugg as f64
+    }
+
+    /// The share of the undergrowth hits, from 0 to 1.
+    pub(crate) fn density(&self) -> f64 {
+        self.ug as f64 / (self.hits() + 0.01)
+    }
+}
+
+/// The output of the vegetation generation.
+#[derive(Debug, Clone)]
+pub struct Vegetation {
+    /// The green and yellow areas.
+    pub vegetation: GeoRaster,
+    /// The undergrowth pattern.
+    pub undergrowth: GeoRaster,
+    /// Water and buildings from classified points, using the georeference of `vegetation`.
+    /// Always generated, but optional for layers from other sources.
+    pub water: Option<DynamicImage>,
+    /// The vegetation and the undergrowth as class values, if `vege_bitmode` is enabled.
+    pub bits: Option<VegetationBits>,
+}
+
+/// The vegetation as class values instead of colors, see `vege_bitmode`.
+#[derive(Debug, Clone)]
+pub struct VegetationBits {
+    /// The green and yellow classes, using the georeference of [`Vegetation::vegetation`].
+    pub vegetation: DynamicImage,
+    /// The undergrowth classes, using the georeference of [`Vegetation::undergrowth`].
+    pub undergrowth: DynamicImage,
+}
+
 pub fn makevege(
-    fs: &impl FileSystem,
     config: &Config,
-    tmpfolder: &Path,
-) -> Result<(), Box<dyn Error>> {
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<Vegetation, Box<dyn Error>> {
     info!("Generating vegetation...");
 
-    let heightmap_in = tmpfolder.join("xyz2.hmap");
-    let mut reader = fs.open(heightmap_in)?;
-    let hmap = HeightMap::from_bytes(&mut reader)?;
-
     // in world coordinates
     let size = hmap.scale;
     let xyz = &hmap.grid;
@@ -56,8 +112,6 @@ pub fn makevege(
     } = config;
     let greenshades = &config.greenshades;
 
-    let xyz_file_in = tmpfolder.join("xyztemp.xyz.bin");
-
     let xmin = hmap.minx();
     let ymin = hmap.miny();
     let xmax = hmap.maxx();
@@ -73,13 +127,26 @@ pub fn makevege(
     let w_3 = ((xmax - xmin) / 3.0).ceil() as usize;
     let h_3 = ((ymax - ymin) / 3.0).ceil() as usize;
 
+    // the grids of both passes and the rendered images (at most 4 bytes per pixel each)
+    let img_pixels = (w_block as f64 * block) as usize * (h_block as f64 * block) as usize;
+    job::reserve(
+        config,
+        "makevege",
+        6 * job::grid_bytes::<f32>(w_block, h_block)
+            + 2 * job::grid_bytes::<u32>(w_3, h_3)
+            + 15 * img_pixels as u64,
+    )?;
+
     let mut top = Vec2D::new(w_block, h_block, 0.0); // block
     let mut yhit = Vec2D::new(w_3, h_3, 0_u32); // 3.0
     let mut noyhit = Vec2D::new(w_3, h_3, 0_u32); // 3.0
 
     let mut i = 0;
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-    while let Some(chunk) = reader.next_chunk()? {
+    // both passes over the points are counted
+    let mut progress = Progress::new("makevege", 2 * points.records.len() as u64);
+    for chunk in points.chunks() {
+        progress.inc(chunk.len() as u64);
+        job::check_cancelled()?;
         for r in chunk {
             if vegethin == 0 || ((i + 1) as u32) % vegethin == 0 {
                 let x: f64 = r.x;
@@ -126,16 +193,12 @@ pub fn makevege(
     let w_block_step = ((xmax - xmin) / (block * step as f64)).ceil() as usize;
     let h_block_step = ((ymax - ymin) / (block * step as f64)).ceil() as usize;
 
-    #[derive(Default, Clone)]
-    struct UggItem {
-        ugg: f32,
-        ug: u32,
-    }
-    let mut ug = Vec2D::new(w_block_step, h_block_step, UggItem::default()); // block / step
+    let mut ug = Vec2D::new(w_block_step, h_block_step, Undergrowth::default()); // block / step
 
     let mut i = 0;
-    let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-    while let Some(chunk) = reader.next_chunk()? {
+    for chunk in points.chunks() {
+        progress.inc(chunk.len() as u64);
+        job::check_cancelled()?;
         for r in chunk {
             if vegethin == 0 || ((i + 1) as u32) % vegethin == 0 {
                 let x: f64 = r.x;
@@ -185,18 +248,7 @@ pub fn makevege(
                 let xx = ((x - xmin) / block / (step as f64)) as usize;
                 let yy = ((y - ymin) / block / (step as f64)) as usize;
                 let hh = h - thelele;
-                let ug_entry = &mut ug[(xx, yy)];
-                if hh <= 1.2 {
-                    if r3 == 2 {
-                        ug_entry.ugg += 1.0;
-                    } else if hh > 0.25 {
-                        ug_entry.ug += 1;
-                    } else {
-                        ug_entry.ugg += 1.0;
-                    }
-                } else {
-                    ug_entry.ugg += 0.05;
-                }
+                ug[(xx, yy)].add(r3, hh);
 
                 let xx = ((x - xmin) / block) as usize;
                 let yy = ((y - ymin) / block) as usize;
@@ -249,6 +301,7 @@ pub fn makevege(
     let ye2 = Rgba([255, 219, 166, 255]);
     let mut imgye2 = RgbaImage::from_pixel(img_width, img_height, Rgba([255, 255, 255, 0]));
     for x in 0..(w_3 - 2) {
+        job::check_cancelled()?;
         for y in 0..(h_3 - 2) {
             let mut ghit2 = 0;
             let mut highhit2 = 0;
@@ -299,6 +352,7 @@ pub fn makevege(
 
     let mut imggr1 = RgbImage::from_pixel(img_width, img_height, Rgb([255, 255, 255]));
     for x in 0..w_block {
+        job::check_cancelled()?;
         for y in 0..h_block {
             let roof = top[(x, y)]
                 - xyz[(
@@ -381,43 +435,11 @@ pub fn makevege(
     } else if medyellow > 0 {
         imgye2 = median_filter(&imgye2, medyellow / 2, medyellow / 2);
     }
-    imgye2
-        .write_to(
-            &mut fs
-                .create(tmpfolder.join("yellow.png"))
-                .expect("error saving png"),
-            image::ImageFormat::Png,
-        )
-        .expect("could not save output png");
-
-    imggr1
-        .write_to(
-            &mut fs
-                .create(tmpfolder.join("greens.png"))
-                .expect("error saving png"),
-            image::ImageFormat::Png,
-        )
-        .expect("could not save output png");
-
-    let mut img = DynamicImage::ImageRgb8(imggr1);
-    image::imageops::overlay(&mut img, &DynamicImage::ImageRgba8(imgye2), 0, 0);
-
-    img.write_to(
-        &mut fs
-            .create(tmpfolder.join("vegetation.png"))
-            .expect("error saving png"),
-        image::ImageFormat::Png,
-    )
-    .expect("could not save output png");
-
-    // drop img to free memory
-    drop(img);
-
-    if vege_bitmode {
-        let g_img = fs
-            .read_image_png(tmpfolder.join("greens.png"))
-            .expect("Opening image failed");
-        let mut g_img = g_img.to_rgb8();
+    let mut img = DynamicImage::ImageRgb8(imggr1.clone());
+    image::imageops::overlay(&mut img, &DynamicImage::ImageRgba8(imgye2.clone()), 0, 0);
+
+    let vegetation_bit = if vege_bitmode {
+        let mut g_img = imggr1;
         for pixel in g_img.pixels_mut() {
             let mut found = false;
             for (idx, color) in greens.iter().enumerate() {
@@ -433,19 +455,7 @@ pub fn makevege(
         }
         let g_img = DynamicImage::ImageRgb8(g_img).to_luma8();
 
-        g_img
-            .write_to(
-                &mut fs
-                    .create(tmpfolder.join("greens_bit.png"))
-                    .expect("error saving png"),
-                image::ImageFormat::Png,
-            )
-            .expect("could not save output png");
-
-        let y_img = fs
-            .read_image_png(tmpfolder.join("yellow.png"))
-            .expect("Opening image failed");
-        let mut y_img = y_img.to_rgba8();
+        let mut y_img = imgye2;
         for pixel in y_img.pixels_mut() {
             if pixel[0] == ye2[0] && pixel[1] == ye2[1] && pixel[2] == ye2[2] && pixel[3] == ye2[3]
             {
@@ -456,37 +466,23 @@ pub fn makevege(
         }
         let y_img = DynamicImage::ImageRgba8(y_img).to_luma_alpha8();
 
-        y_img
-            .write_to(
-                &mut fs
-                    .create(tmpfolder.join("yellow_bit.png"))
-                    .expect("error saving png"),
-                image::ImageFormat::Png,
-            )
-            .expect("could not save output png");
-
         let mut img_bit = DynamicImage::ImageLuma8(g_img);
         let img_bit2 = DynamicImage::ImageLumaA8(y_img);
         image::imageops::overlay(&mut img_bit, &img_bit2, 0, 0);
-
-        img_bit
-            .write_to(
-                &mut fs
-                    .create(tmpfolder.join("vegetation_bit.png"))
-                    .expect("error saving png"),
-                image::ImageFormat::Png,
-            )
-            .expect("could not save output png");
-    }
+        Some(img_bit)
+    } else {
+        None
+    };
 
     let mut imgwater = RgbImage::from_pixel(img_width, img_height, Rgb([255, 255, 255]));
     let black = Rgb([0, 0, 0]);
     let blue = Rgb([29, 190, 255]);
     let buildings = config.buildings;
-    let water = config.water;
+    // with water detection the water bodies are drawn from their shorelines instead
+    let water = if config.detectwater { 0 } else { config.water };
     if buildings > 0 || water > 0 {
-        let mut reader = XyzInternalReader::new(fs.open(&xyz_file_in)?)?;
-        while let Some(chunk) = reader.next_chunk()? {
+        for chunk in points.chunks() {
+            job::check_cancelled()?;
             for r in chunk {
                 let (x, y) = (r.x, r.y);
                 let c: u8 = r.classification;
@@ -510,7 +506,7 @@ pub fn makevege(
     }
 
     for (x, y, hh) in hmap.iter() {
-        if hh < config.waterele {
+        if hh < config.waterele && !config.detectwater {
             draw_filled_rect_mut(
                 &mut imgwater,
                 Rect::at((x - xmin) as i32 - 1, (ymax - y) as i32 - 1).of_size(3, 3),
@@ -519,17 +515,6 @@ pub fn makevege(
         }
     }
 
-    imgwater
-        .write_to(
-            &mut fs
-                .create(tmpfolder.join("blueblack.png"))
-                .expect("error saving png"),
-            image::ImageFormat::Png,
-        )
-        .expect("could not save output png");
-
-    drop(imgwater); // explicitly drop imgwater to free memory
-
     let scalefactor = config.scalefactor;
 
     let underg = Rgba([64, 121, 0, 255]);
@@ -563,8 +548,7 @@ pub fn makevege(
             let xx = (x / bf32 / step) as usize;
             let yy = (y / bf32 / step) as usize;
 
-            let ug_entry = &ug[(xx, yy)];
-            let value = ug_entry.ug as f64 / (ug_entry.ug as f64 + ug_entry.ugg as f64 + 0.01);
+            let value = ug[(xx, yy)].density();
             if value > uglimit {
                 draw_line_segment_mut(
                     &mut imgug,
@@ -664,48 +648,25 @@ pub fn makevege(
         }
         x += bf32 * step;
     }
-    imgug
-        .write_to(
-            &mut fs
-                .create(tmpfolder.join("undergrowth.png"))
-                .expect("error saving png"),
-            image::ImageFormat::Png,
-        )
-        .expect("could not save output png");
-
-    let img_ug_bit_b = median_filter(&img_ug_bit, (bf32 * step) as u32, (bf32 * step) as u32);
-
-    img_ug_bit_b
-        .write_to(
-            &mut fs
-                .create(tmpfolder.join("undergrowth_bit.png"))
-                .expect("error saving png"),
-            image::ImageFormat::Png,
-        )
-        .expect("could not save output png");
-
-    let mut writer = fs
-        .create(tmpfolder.join("undergrowth.pgw"))
-        .expect("cannot create pgw file");
-    write!(
-        &mut writer,
-        "{}\r\n0.0\r\n0.0\r\n{}\r\n{}\r\n{}\r\n",
-        1.0 / tmpfactor,
-        -1.0 / tmpfactor,
-        xmin,
-        ymax,
-    )
-    .expect("Cannot write pgw file");
-
-    let mut writer = fs
-        .create(tmpfolder.join("vegetation.pgw"))
-        .expect("cannot create pgw file");
-    write!(
-        &mut writer,
-        "1.0\r\n0.0\r\n0.0\r\n-1.0\r\n{xmin}\r\n{ymax}\r\n"
-    )
-    .expect("Cannot write pgw file");
+    let bits = vegetation_bit.map(|vegetation| {
+        let undergrowth = median_filter(&img_ug_bit, (bf32 * step) as u32, (bf32 * step) as u32);
+        VegetationBits {
+            vegetation,
+            undergrowth: DynamicImage::ImageLuma8(undergrowth),
+        }
+    });
 
     info!("Done");
-    Ok(())
+    Ok(Vegetation {
+        vegetation: GeoRaster {
+            image: img,
+            world_file: WorldFile::new(1.0, xmin, ymax),
+        },
+        undergrowth: GeoRaster {
+            image: DynamicImage::ImageRgba8(imgug),
+            world_file: WorldFile::new((1.0 / tmpfactor) as f64, xmin, ymax),
+        },
+        water: Some(DynamicImage::ImageRgb8(imgwater)),
+        bits,
+    })
 }
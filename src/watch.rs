@@ -0,0 +1,402 @@
+//! Watching the batch input folder for new and changed tiles.
+//!
+//! The [`Watcher`] polls `lazfolder` and compares the files with the state saved in the batch
+//! output folder. A file is only picked up once it has stayed the same between two polls, so that
+//! deliveries that are still being copied are not processed. The done record and the png of a new
+//! or changed tile are removed so that [`crate::process::batch_process_files`] processes it again,
+//! together with the neighbours whose buffer overlaps it. After the batch the merged images written
+//! earlier are updated with the re-rendered tiles.
+use las::raw::Header;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::geometry::Bounds;
+use crate::io::fs::FileSystem;
+use crate::lease::Lease;
+use crate::merge;
+use crate::process::{BATCH_BUFFER, batch_input_files};
+
+/// The name of the file in the batch output folder that records the processed tiles.
+pub const STATE_FILE: &str = "watch_state.json";
+
+/// How much of the end of a file is included in its fingerprint.
+const FINGERPRINT_TAIL: u64 = 64 * 1024;
+
+/// A processed input file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileState {
+    pub size: u64,
+    /// Hash of the header and the end of the file, to notice files replaced with the same size.
+    pub fingerprint: u64,
+    pub bounds: Bounds,
+}
+
+/// The tiles to process in one round.
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    /// New or changed input files.
+    pub updated: BTreeMap<String, TileState>,
+    /// Input files that were removed.
+    pub removed: Vec<String>,
+    /// Unchanged tiles that are processed again because a new or changed tile overlaps their
+    /// buffer.
+    pub neighbours: Vec<String>,
+    /// The input files that have stayed the same between polls, to process the batch with.
+    pub inputs: Vec<PathBuf>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    /// The tiles whose outputs change.
+    fn rendered(&self) -> impl Iterator<Item = &String> {
+        self.updated.keys().chain(&self.neighbours)
+    }
+}
+
+/// Finds the new and changed tiles of a batch. See the [module documentation](self).
+#[derive(Debug, Default)]
+pub struct Watcher {
+    tiles: BTreeMap<String, TileState>,
+    /// The files seen in the previous poll that have not been processed yet.
+    pending: HashMap<String, (u64, u64)>,
+    /// True until the first poll if there was no saved state.
+    adopt_existing: bool,
+}
+
+impl Watcher {
+    /// Loads the state saved in the batch output folder. Without a saved state, tiles that
+    /// already have an output png are considered processed.
+    pub fn new(fs: &impl FileSystem, config: &Config) -> Self {
+        let path = Path::new(&config.batchoutfolder).join(STATE_FILE);
+        let tiles = match fs.read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid {}: {e}", path.display());
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            adopt_existing: tiles.is_empty(),
+            tiles,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Looks for new, changed and removed input files. The outputs of the tiles that have to be
+    /// processed again are removed, so the caller should run the batch and then call
+    /// [`Watcher::commit`] if the returned changes are not empty.
+    pub fn poll(&mut self, fs: &impl FileSystem, config: &Config) -> Changes {
+        let batchoutfolder = Path::new(&config.batchoutfolder);
+        let adopt_existing = std::mem::take(&mut self.adopt_existing);
+        let mut changes = Changes::default();
+        let mut present = Vec::new();
+
+        for path in batch_input_files(fs, config) {
+            let tile = path.file_name().unwrap().to_string_lossy().to_string();
+            present.push(tile.clone());
+            let Ok(state) = read_state(fs, &path) else {
+                // most likely still being copied
+                self.pending.remove(&tile);
+                continue;
+            };
+            if self.tiles.get(&tile) == Some(&state) {
+                self.pending.remove(&tile);
+                continue;
+            }
+            if adopt_existing
+                && Lease::is_done(fs, batchoutfolder, &tile)
+                && !Lease::exists(fs, batchoutfolder, &tile)
+            {
+                self.tiles.insert(tile, state);
+                continue;
+            }
+            // wait until the file stays the same between two polls
+            let seen = (state.size, state.fingerprint);
+            if self.pending.insert(tile.clone(), seen) == Some(seen) {
+                changes.updated.insert(tile, state);
+            }
+        }
+        changes.removed = self
+            .tiles
+            .keys()
+            .filter(|tile| !present.contains(tile))
+            .cloned()
+            .collect();
+        self.pending.retain(|tile, _| present.contains(tile));
+        changes.inputs = batch_input_files(fs, config)
+            .into_iter()
+            .filter(|path| {
+                let tile = path.file_name().unwrap().to_string_lossy();
+                self.tiles.contains_key(tile.as_ref())
+                    || changes.updated.contains_key(tile.as_ref())
+            })
+            .collect();
+
+        if changes.is_empty() {
+            return changes;
+        }
+
+        // the tiles whose buffer contains points of the new, changed or removed tiles
+        let mut changed_bounds: Vec<&Bounds> =
+            changes.updated.values().map(|s| &s.bounds).collect();
+        for tile in changes.updated.keys().chain(&changes.removed) {
+            if let Some(old) = self.tiles.get(tile) {
+                changed_bounds.push(&old.bounds);
+            }
+        }
+        changes.neighbours = self
+            .tiles
+            .iter()
+            .filter(|(tile, _)| {
+                !changes.updated.contains_key(*tile) && !changes.removed.contains(tile)
+            })
+            .filter(|(_, state)| changed_bounds.iter().any(|b| in_buffer(b, &state.bounds)))
+            .map(|(tile, _)| tile.clone())
+            .collect();
+
+        for tile in &changes.removed {
+            info!("{tile} was removed, removing its outputs");
+            remove_outputs(fs, batchoutfolder, tile);
+        }
+        for tile in changes.updated.keys() {
+            info!("{tile} is new or changed");
+        }
+        for tile in &changes.neighbours {
+            info!("{tile} is processed again as a neighbour of a changed tile");
+        }
+        for tile in changes.rendered() {
+            Lease::reset(fs, batchoutfolder, tile).expect("Could not remove the done record");
+        }
+        changes
+    }
+
+    /// Records the changes as processed and updates the merged outputs.
+    pub fn commit(&mut self, fs: &impl FileSystem, config: &Config, changes: &Changes) {
+        for tile in &changes.removed {
+            self.tiles.remove(tile);
+        }
+        for (tile, state) in &changes.updated {
+            self.pending.remove(tile);
+            self.tiles.insert(tile.clone(), state.clone());
+        }
+        self.save(fs, config)
+            .expect("Could not save the watch state");
+
+        if let Err(e) = update_merged(fs, config, changes) {
+            warn!("Could not update the merged outputs: {e}");
+        }
+    }
+
+    fn save(&self, fs: &impl FileSystem, config: &Config) -> io::Result<()> {
+        let file = fs.create(Path::new(&config.batchoutfolder).join(STATE_FILE))?;
+        serde_json::to_writer_pretty(file, &self.tiles)?;
+        Ok(())
+    }
+}
+
+/// Updates the merged images and vector files that exist in the working folder. Images are only
+/// merged again completely if a tile was removed or extends them.
+fn update_merged(
+    fs: &impl FileSystem,
+    config: &Config,
+    changes: &Changes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let batchoutfolder = Path::new(&config.batchoutfolder);
+    for (outfilename, suffix) in [
+        ("merged", ".png"),
+        ("merged_depr", "_depr.png"),
+        ("merged_vege", "_vege.png"),
+    ] {
+        if !fs.exists(format!("{outfilename}.pgw")) {
+            continue;
+        }
+        let png_files: Vec<PathBuf> = changes
+            .rendered()
+            .map(|tile| batchoutfolder.join(format!("{tile}{suffix}")))
+            .filter(|png| fs.exists(png))
+            .collect();
+        if changes.removed.is_empty() && merge::update_merged_png(fs, outfilename, &png_files)? {
+            info!("Updated {outfilename}.png");
+            continue;
+        }
+
+        info!("Merging {outfilename}.png again");
+        let scale = merge::merged_scale(fs, outfilename, &png_files).unwrap_or(1.0);
+        match outfilename {
+            "merged_vege" => merge::pngmergevege(fs, config, scale, false)?,
+            _ => merge::pngmerge(fs, config, scale, outfilename == "merged_depr")?,
+        }
+    }
+
+    // the merged vector files do not record which tile the geometries came from
+    if fs.exists("merged.dxf.bin") {
+        info!("Merging the dxf files again");
+        merge::bindxfmerge(fs, config)?;
+    }
+    Ok(())
+}
+
+/// Check if `tile` overlaps the buffer of points read around `neighbour`.
+fn in_buffer(tile: &Bounds, neighbour: &Bounds) -> bool {
+    tile.xmax > neighbour.xmin - BATCH_BUFFER
+        && tile.xmin < neighbour.xmax + BATCH_BUFFER
+        && tile.ymax > neighbour.ymin - BATCH_BUFFER
+        && tile.ymin < neighbour.ymax + BATCH_BUFFER
+}
+
+/// Removes the files written for `tile` into the batch output folder.
+fn remove_outputs(fs: &impl FileSystem, batchoutfolder: &Path, tile: &str) {
+    let Ok(paths) = fs.list(batchoutfolder) else {
+        return;
+    };
+    for path in paths {
+        let filename = path.file_name().unwrap().to_string_lossy();
+        if let Some(rest) = filename.strip_prefix(tile) {
+            if rest.starts_with('.') || rest.starts_with('_') {
+                fs.remove_file(&path).expect("Could not remove output file");
+            }
+        }
+    }
+}
+
+/// Reads the size, fingerprint and bounds of an input file.
+fn read_state(fs: &impl FileSystem, path: &Path) -> io::Result<TileState> {
+    let size = fs.file_size(path)?;
+    let mut file = fs.open(path)?;
+    let header = Header::read_from(&mut file).map_err(io::Error::other)?;
+
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    (&mut file)
+        .take(u64::from(header.header_size))
+        .read_to_end(&mut data)?;
+    file.seek(SeekFrom::Start(size.saturating_sub(FINGERPRINT_TAIL)))?;
+    file.read_to_end(&mut data)?;
+
+    Ok(TileState {
+        size,
+        fingerprint: fnv1a(&data),
+        bounds: Bounds::new(header.min_x, header.max_x, header.min_y, header.max_y),
+    })
+}
+
+/// A hash that stays the same between versions, as the fingerprints are saved.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::fs::memory::MemoryFileSystem;
+    use std::io::Write;
+
+    fn write_las(fs: &MemoryFileSystem, path: &str, x: f64, y: f64, z: f64) {
+        let mut builder = las::Builder::from((1, 2));
+        builder.transforms = las::Vector {
+            x: las::Transform {
+                scale: 0.01,
+                offset: 0.0,
+            },
+            y: las::Transform {
+                scale: 0.01,
+                offset: 0.0,
+            },
+            z: las::Transform {
+                scale: 0.01,
+                offset: 0.0,
+            },
+        };
+        let mut writer = las::Writer::new(
+            std::io::Cursor::new(Vec::new()),
+            builder.into_header().unwrap(),
+        )
+        .unwrap();
+        for (dx, dy) in [(0.0, 0.0), (1000.0, 1000.0)] {
+            let point = las::Point {
+                x: x + dx,
+                y: y + dy,
+                z,
+                ..Default::default()
+            };
+            writer.write_point(point).unwrap();
+        }
+        let data = writer.into_inner().unwrap().into_inner();
+        fs.create(path).unwrap().write_all(&data).unwrap();
+    }
+
+    fn config() -> Config {
+        let mut config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+        config.lazfolder = "in".to_string();
+        config.batchoutfolder = "out".to_string();
+        config
+    }
+
+    #[test]
+    fn test_changed_tiles_and_neighbours_are_processed() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("in").unwrap();
+        fs.create_dir_all("out").unwrap();
+        let config = config();
+        write_las(&fs, "in/a.las", 0.0, 0.0, 1.0);
+        write_las(&fs, "in/b.las", 1000.0, 0.0, 1.0);
+        write_las(&fs, "in/c.las", 5000.0, 0.0, 1.0);
+
+        let mut watcher = Watcher::new(&fs, &config);
+        // the files are only processed once they stay the same between two polls
+        assert!(watcher.poll(&fs, &config).is_empty());
+        let changes = watcher.poll(&fs, &config);
+        assert_eq!(changes.updated.len(), 3);
+        assert!(changes.neighbours.is_empty());
+        for tile in ["a", "b", "c"] {
+            fs.create(format!("out/{tile}.las.done")).unwrap();
+        }
+        watcher.commit(&fs, &config, &changes);
+        assert!(watcher.poll(&fs, &config).is_empty());
+
+        // a changed tile is processed again together with the neighbours sharing its buffer
+        write_las(&fs, "in/a.las", 0.0, 0.0, 2.0);
+        assert!(watcher.poll(&fs, &config).is_empty());
+        let changes = watcher.poll(&fs, &config);
+        assert_eq!(changes.updated.keys().collect::<Vec<_>>(), ["a.las"]);
+        assert_eq!(changes.neighbours, ["b.las"]);
+        assert!(!fs.exists("out/a.las.done"));
+        assert!(!fs.exists("out/b.las.done"));
+        assert!(fs.exists("out/c.las.done"));
+        watcher.commit(&fs, &config, &changes);
+
+        // the state is saved, and the outputs of removed tiles are removed
+        let mut watcher = Watcher::new(&fs, &config);
+        fs.remove_file("in/c.las").unwrap();
+        let changes = watcher.poll(&fs, &config);
+        assert_eq!(changes.removed, ["c.las"]);
+        assert!(changes.neighbours.is_empty());
+        assert!(!fs.exists("out/c.las.done"));
+    }
+
+    #[test]
+    fn test_existing_outputs_are_adopted() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("in").unwrap();
+        fs.create_dir_all("out").unwrap();
+        let config = config();
+        write_las(&fs, "in/a.las", 0.0, 0.0, 1.0);
+        write_las(&fs, "in/b.las", 1000.0, 0.0, 1.0);
+        fs.create("out/a.las.done").unwrap();
+
+        let mut watcher = Watcher::new(&fs, &config);
+        assert!(watcher.poll(&fs, &config).is_empty());
+        let changes = watcher.poll(&fs, &config);
+        assert_eq!(changes.updated.keys().collect::<Vec<_>>(), ["b.las"]);
+        assert_eq!(changes.neighbours, ["a.las"]);
+    }
+}
@@ -0,0 +1,180 @@
+use log::info;
+use std::error::Error;
+
+use crate::areas;
+use crate::config::Config;
+use crate::geometry::{BinaryDxf, Bounds, Classification, Point3, Polylines};
+use crate::io::heightmap::HeightMap;
+use crate::io::xyz::PointCloud;
+use crate::job;
+use crate::vec2d::Vec2D;
+
+/// Detects lakes and ponds from the heightmap and the points, and returns their shorelines.
+/// All shorelines of a water body are at its hydro-flattened
+/// water level, the lowest ground next to it.
+///
+/// A cell is water if it has points of the `waterclass` class, if it is lower than
+/// `waterelevation`, or if it has no points at all (water absorbs the laser) and the ground is
+/// flatter than `waterslope`. Water bodies smaller than `waterminarea` square meters are dropped,
+/// and the shorelines are smoothed with `watersmoothing` rounds of smoothing.
+pub fn makewater(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<BinaryDxf, Box<dyn Error>> {
+    info!("Detecting water bodies...");
+
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    // the point counts and the water masks
+    job::reserve(
+        config,
+        "makewater",
+        2 * job::grid_bytes::<u32>(w, h) + 3 * job::grid_bytes::<bool>(w, h),
+    )?;
+
+    let water = classify(config, points, hmap)?;
+    job::check_cancelled()?;
+    let mut water = areas::majority_filter(&water);
+    let min_cells = (config.waterminarea / (hmap.scale * hmap.scale)).ceil() as usize;
+    areas::remove_small_areas(&mut water, min_cells);
+
+    let mut lines = Polylines::new();
+    for area in areas::components(&water) {
+        let level = water_level(hmap, &water, &area);
+        for ring in areas::outlines(hmap, &area, config.watersmoothing) {
+            let ring = ring
+                .into_iter()
+                .map(|p| Point3::new(p.x, p.y, level))
+                .collect();
+            lines.push(ring, (Classification::Water, level));
+        }
+    }
+
+    info!("Done");
+    Ok(BinaryDxf::new(
+        Bounds::new(hmap.minx(), hmap.maxx(), hmap.miny(), hmap.maxy()),
+        vec![lines.into()],
+    ))
+}
+
+/// Marks the water cells, see [`makewater`].
+fn classify(
+    config: &Config,
+    points: &PointCloud,
+    hmap: &HeightMap,
+) -> Result<Vec2D<bool>, Box<dyn Error>> {
+    let (w, h) = (hmap.grid.width(), hmap.grid.height());
+    let grid = &hmap.grid;
+
+    // the number of all points and of the water points in each cell
+    let mut counts = Vec2D::new(w, h, 0u32);
+    let mut water_points = Vec2D::new(w, h, 0u32);
+    for chunk in points.chunks() {
+        job::check_cancelled()?;
+        for r in chunk {
+            let x = ((r.x - hmap.xoffset) / hmap.scale).round();
+            let y = ((r.y - hmap.yoffset) / hmap.scale).round();
+            if x < 0.0 || y < 0.0 || x >= w as f64 || y >= h as f64 {
+                continue;
+            }
+            counts[(x as usize, y as usize)] += 1;
+            if r.classification == config.water_class {
+                water_points[(x as usize, y as usize)] += 1;
+            }
+        }
+    }
+
+    let mut water = Vec2D::new(w, h, false);
+    for x in 1..w.saturating_sub(1) {
+        for y in 1..h.saturating_sub(1) {
+            let z = grid[(x, y)];
+            let flat = areas::slope(hmap, x, y) < config.waterslope;
+
+            water[(x, y)] =
+                water_points[(x, y)] > 0 || z < config.waterele || (counts[(x, y)] == 0 && flat);
+        }
+    }
+    Ok(water)
+}
+
+/// The hydro-flattened level of a water body: the lowest cell next to it, or the lowest cell of
+/// the water body if it has no neighbours with data.
+fn water_level(hmap: &HeightMap, water: &Vec2D<bool>, area: &[(usize, usize)]) -> f64 {
+    let (w, h) = (water.width(), water.height());
+    let lowest = |cells: &mut dyn Iterator<Item = (usize, usize)>| {
+        cells
+            .map(|cell| hmap.grid[cell])
+            .filter(|z| z.is_finite())
+            .min_by(f64::total_cmp)
+    };
+    let mut shore = area
+        .iter()
+        .flat_map(|&(x, y)| areas::neighbours4(x, y))
+        .filter(|&(nx, ny)| nx < w && ny < h && !water[(nx, ny)]);
+    lowest(&mut shore)
+        .or_else(|| lowest(&mut area.iter().copied()))
+        .unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::io::xyz::XyzRecord;
+    use std::path::Path;
+
+    #[test]
+    fn test_pond_without_returns_is_water() {
+        let config = Config::from_file(Path::new("pullauta.default.ini")).unwrap();
+
+        // a valley with a flat pond between 20 m and 40 m without any points, except for water
+        // points at its edges, and the shore rising to the sides
+        let mut grid = Vec2D::new(31, 31, 0.0);
+        let mut points = PointCloud::default();
+        for x in 0..31 {
+            for y in 0..31 {
+                let pond = (10..=20).contains(&x) && (10..=20).contains(&y);
+                grid[(x, y)] = if pond {
+                    50.5
+                } else {
+                    50.5 + ((x as f64 - 15.0).abs() - 3.0).max(0.0) * 0.2
+                };
+                let edge = x == 10 || x == 20 || y == 10 || y == 20;
+                if !pond || edge {
+                    points.records.push(XyzRecord {
+                        x: 2.0 * x as f64,
+                        y: 2.0 * y as f64,
+                        z: grid[(x, y)] as f32,
+                        classification: if pond { 9 } else { 2 },
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        let hmap = HeightMap {
+            xoffset: 0.0,
+            yoffset: 0.0,
+            scale: 2.0,
+            grid,
+        };
+
+        let dxf = makewater(&config, &points, &hmap).unwrap();
+        let Geometry::Polylines3(lines) = dxf.take_geometry().swap_remove(0) else {
+            panic!("water should be 3D polylines");
+        };
+        assert_eq!(lines.len(), 1);
+        for (ring, (class, level)) in lines.iter() {
+            assert_eq!(*class, Classification::Water);
+            // the lowest shore is at the ends of the valley
+            assert_eq!(*level, 50.5);
+            assert_eq!(ring.first(), ring.last());
+            for p in ring {
+                assert!(
+                    p.x > 16.0 && p.x < 44.0 && p.y > 16.0 && p.y < 44.0,
+                    "{p:?}"
+                );
+                assert_eq!(p.z, 50.5);
+            }
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Batch processing with every tile in its own `pullauta` worker process.
+//!
+//! The coordinator claims the tiles and runs a child process for each of them, so a panic, an out
+//! of memory kill or a hang in one tile only fails that tile, and all memory is returned to the
+//! operating system between tiles. Workers exceeding the time or memory limit of the [`Config`]
+//! are killed. The resident memory of the workers is only known on Linux, on other unix systems
+//! the memory limit is enforced by limiting the address space of the workers. Failed tiles are retried with a fresh process, and once the retries are used up
+//! the failure is recorded as `<tile>.failed.json` in the batch output folder.
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::io::fs::FileSystem;
+use crate::job;
+use crate::lease::Lease;
+use crate::progress::{self, ProgressEvent};
+
+/// The suffix of the failure records written into the batch output folder.
+pub const FAILURE_SUFFIX: &str = ".failed.json";
+
+/// The command line argument that makes `pullauta` process a single claimed batch tile.
+pub const WORKER_COMMAND: &str = "batchtile";
+
+/// How often the workers are checked against the limits.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The limits enforced on each worker process.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerLimits {
+    /// The longest time a worker may spend on a tile.
+    pub timeout: Option<Duration>,
+    /// The largest resident memory of a worker in bytes. On unix it also limits the address space
+    /// of the worker.
+    pub memory_limit: Option<u64>,
+    /// How many times a failed tile is tried again.
+    pub retries: u32,
+}
+
+impl WorkerLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            timeout: (config.worker_timeout > 0)
+                .then(|| Duration::from_secs(config.worker_timeout)),
+            memory_limit: (config.worker_memory_limit > 0)
+                .then(|| config.worker_memory_limit * 1024 * 1024),
+            retries: config.worker_retries,
+        }
+    }
+}
+
+/// Record of a tile that could not be processed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileFailure {
+    pub version: String,
+    /// Seconds since the unix epoch when the record was written.
+    pub created: u64,
+    pub tile: String,
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub reason: String,
+    /// The exit code of the last worker, if it exited normally.
+    pub exit_code: Option<i32>,
+    /// Seconds spent in the last attempt.
+    pub seconds: f64,
+    /// Peak resident memory of the last worker, if the platform supports it.
+    pub peak_memory_bytes: Option<u64>,
+}
+
+/// Why a worker did not finish its tile.
+#[derive(Debug)]
+enum Failure {
+    Spawn(std::io::Error),
+    Exited(ExitStatus),
+    TimedOut(Duration),
+    OutOfMemory(u64),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Spawn(e) => write!(f, "could not start worker: {e}"),
+            Failure::Exited(status) => match signal(status) {
+                Some(9) => write!(f, "worker was killed, possibly by running out of memory"),
+                Some(signal) => write!(f, "worker was killed by signal {signal}"),
+                None => write!(f, "worker failed with {status}"),
+            },
+            Failure::TimedOut(limit) => {
+                write!(f, "worker exceeded the time limit of {} s", limit.as_secs())
+            }
+            Failure::OutOfMemory(limit) => write!(
+                f,
+                "worker exceeded the memory limit of {} MB",
+                limit / 1024 / 1024
+            ),
+        }
+    }
+}
+
+/// The result of running one worker.
+#[derive(Debug)]
+struct Attempt {
+    result: Result<(), Failure>,
+    seconds: f64,
+    peak_memory_bytes: Option<u64>,
+}
+
+/// Processes the unclaimed tiles of `laz_files` with `config.processes` worker processes running
+/// `executable`, which must be a `pullauta` binary.
+///
+/// Returns the number of tiles that failed.
+pub fn run_batch(
+    fs: &(impl FileSystem + Sync),
+    config: &Config,
+    executable: &Path,
+    laz_files: Vec<PathBuf>,
+) -> usize {
+    let batchoutfolder = Path::new(&config.batchoutfolder);
+    fs.create_dir_all(batchoutfolder)
+        .expect("Could not create output folder");
+
+    let limits = WorkerLimits::from_config(config);
+    if limits.memory_limit.is_some() && resident_memory(std::process::id()).is_none() {
+        if cfg!(unix) {
+            warn!(
+                "The memory use of the workers can not be read on this platform, worker_memory_limit only limits their address space"
+            );
+        } else {
+            warn!(
+                "The memory use of the workers can not be read on this platform, worker_memory_limit is ignored"
+            );
+        }
+    }
+    let tiles = Mutex::new(VecDeque::from(laz_files));
+    let failed = Mutex::new(0);
+
+    // the workers are supervised from threads that share the cancellation token of the caller
+    let token = job::current_token().unwrap_or_default();
+    std::thread::scope(|scope| {
+        for i in 0..config.processes.max(1) {
+            let thread = format!("{}", i + 1);
+            let (tiles, failed, limits, token) = (&tiles, &failed, &limits, &token);
+            scope.spawn(move || {
+                job::with_cancellation(token, || {
+                    while !job::is_cancelled() {
+                        let Some(tile) = tiles.lock().unwrap().pop_front() else {
+                            break;
+                        };
+                        let laz = tile.file_name().unwrap().to_string_lossy().to_string();
+                        let Some(mut lease) = Lease::claim_tile(fs, config, &laz, &thread) else {
+                            continue;
+                        };
+                        info!("{laz} -> {laz}.png");
+
+                        let (done, held) = lease.hold(fs, || {
+                            run_tile(fs, batchoutfolder, executable, &thread, &laz, limits)
+                        });
+                        if !held {
+                            // the tile was taken over by another instance
+                            continue;
+                        }
+                        if done {
+                            lease.finish(fs).expect("Could not finish the lease");
+                        } else {
+                            if !job::is_cancelled() {
+                                *failed.lock().unwrap() += 1;
+                            }
+                            lease.release(fs).expect("Could not release the lease");
+                        }
+                    }
+                })
+            });
+            // stagger the start of the workers like the batch threads
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    });
+
+    failed.into_inner().unwrap()
+}
+
+/// Runs workers for a claimed tile until it succeeds or the retries are used up. Returns false
+/// if the tile failed.
+fn run_tile(
+    fs: &impl FileSystem,
+    batchoutfolder: &Path,
+    executable: &Path,
+    thread: &str,
+    laz: &str,
+    limits: &WorkerLimits,
+) -> bool {
+    let failure_file = batchoutfolder.join(format!("{laz}{FAILURE_SUFFIX}"));
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let mut command = Command::new(executable);
+        command.arg(thread).arg(WORKER_COMMAND).arg(laz);
+        let attempt = run_worker(command, limits);
+
+        let failure = match attempt.result {
+            Ok(()) => {
+                // a previous run may have failed
+                if fs.exists(&failure_file) {
+                    fs.remove_file(&failure_file).unwrap();
+                }
+                return true;
+            }
+            Err(failure) => failure,
+        };
+
+        if job::is_cancelled() {
+            info!("Processing {laz} was cancelled");
+            return false;
+        }
+        if attempts <= limits.retries {
+            warn!("Processing {laz} failed: {failure}, restarting the worker");
+            continue;
+        }
+
+        warn!("Processing {laz} failed: {failure}, giving up after {attempts} attempts");
+        progress::emit(ProgressEvent::TileFailed {
+            tile: laz.to_string(),
+            thread: thread.to_string(),
+            reason: failure.to_string(),
+        });
+        let record = TileFailure {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            tile: laz.to_string(),
+            attempts,
+            reason: failure.to_string(),
+            exit_code: match &failure {
+                Failure::Exited(status) => status.code(),
+                _ => None,
+            },
+            seconds: attempt.seconds,
+            peak_memory_bytes: attempt.peak_memory_bytes,
+        };
+        let file = fs
+            .create(&failure_file)
+            .expect("Could not write failure record");
+        serde_json::to_writer_pretty(file, &record).expect("Could not write failure record");
+        return false;
+    }
+}
+
+/// Runs a worker process to completion, killing it if it exceeds the limits or the batch is
+/// cancelled.
+fn run_worker(mut command: Command, limits: &WorkerLimits) -> Attempt {
+    #[cfg(unix)]
+    if let Some(limit) = limits.memory_limit {
+        limit_address_space(&mut command, limit);
+    }
+    let start = Instant::now();
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            return Attempt {
+                result: Err(Failure::Spawn(e)),
+                seconds: 0.0,
+                peak_memory_bytes: None,
+            };
+        }
+    };
+
+    let mut peak_memory_bytes = None;
+    let result = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break Ok(()),
+            Ok(Some(status)) => break Err(Failure::Exited(status)),
+            Ok(None) => {}
+            Err(e) => break Err(Failure::Spawn(e)),
+        }
+
+        let memory = resident_memory(child.id());
+        peak_memory_bytes = peak_memory_bytes.max(memory);
+        if let (Some(limit), Some(memory)) = (limits.memory_limit, memory) {
+            if memory > limit {
+                kill(&mut child);
+                break Err(Failure::OutOfMemory(limit));
+            }
+        }
+        if let Some(timeout) = limits.timeout {
+            if start.elapsed() > timeout {
+                kill(&mut child);
+                break Err(Failure::TimedOut(timeout));
+            }
+        }
+        if job::is_cancelled() {
+            kill(&mut child);
+            break Err(Failure::Exited(child.wait().unwrap()));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    Attempt {
+        result,
+        seconds: start.elapsed().as_secs_f64(),
+        peak_memory_bytes,
+    }
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Makes the process started by `command` fail to allocate more than `limit` bytes of address
+/// space, which also covers the memory allocated between the checks of the resident memory.
+#[cfg(unix)]
+fn limit_address_space(command: &mut Command, limit: u64) {
+    use std::os::unix::process::CommandExt;
+    let rlimit = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    // SAFETY: the closure only calls setrlimit, which is async-signal-safe, and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_AS, &rlimit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// The current resident memory of a process, if the platform supports it.
+fn resident_memory(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(PathBuf::from(format!("/proc/{pid}/status"))).ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(unix)]
+fn signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn test_worker_exit_status() {
+        let limits = WorkerLimits::default();
+        assert!(run_worker(shell("exit 0"), &limits).result.is_ok());
+
+        let attempt = run_worker(shell("exit 3"), &limits);
+        let failure = attempt.result.unwrap_err();
+        assert!(matches!(&failure, Failure::Exited(status) if status.code() == Some(3)));
+        assert_eq!(failure.to_string(), "worker failed with exit status: 3");
+    }
+
+    #[test]
+    fn test_worker_time_limit() {
+        let limits = WorkerLimits {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let attempt = run_worker(shell("sleep 10"), &limits);
+        assert!(matches!(attempt.result, Err(Failure::TimedOut(_))));
+        assert!(attempt.seconds < 5.0);
+    }
+
+    #[test]
+    fn test_worker_memory_limit() {
+        use crate::io::fs::memory::MemoryFileSystem;
+        use std::os::unix::fs::PermissionsExt;
+
+        // a worker buffering a line that never ends
+        let dir = std::env::temp_dir().join(format!("pullauta_worker_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let executable = dir.join("worker.sh");
+        std::fs::write(&executable, "#!/bin/sh\nexec tail /dev/zero\n").unwrap();
+        std::fs::set_permissions(&executable, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("out").unwrap();
+        let limits = WorkerLimits {
+            timeout: Some(Duration::from_secs(60)),
+            memory_limit: Some(64 * 1024 * 1024),
+            retries: 1,
+        };
+        let done = run_tile(&fs, Path::new("out"), &executable, "1", "a.laz", &limits);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!done);
+        let record: TileFailure =
+            serde_json::from_reader(fs.open("out/a.laz.failed.json").unwrap()).unwrap();
+        assert_eq!(record.attempts, 2);
+        assert!(record.seconds < 30.0, "{record:?}");
+    }
+}